@@ -0,0 +1,78 @@
+//! Comparisons of `FileHandle`s, as made by the maps keyed by them, over 10k handles.
+//!
+//! To compare two revisions, run
+//! `cargo bench -p libfuse-fs --bench file_handle -- --save-baseline before` on the first and
+//! `cargo bench -p libfuse-fs --bench file_handle -- --baseline before` on the second.
+
+use std::hint::black_box;
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use libfuse_fs::passthrough::FileHandle;
+
+const HANDLES: usize = 10_000;
+const HANDLE_SIZE: usize = 128;
+
+/// A handle built through `FileHandle::from_bytes()`, whose format is a version byte,
+/// `mnt_id`, `handle_type` and `handle_bytes` in little endian, then the handle.
+fn handle(mnt_id: u64, handle_type: i32, payload: &[u8]) -> FileHandle {
+    let mut buf = FileHandle::default().to_bytes();
+    buf[1..9].copy_from_slice(&mnt_id.to_le_bytes());
+    buf[9..13].copy_from_slice(&handle_type.to_le_bytes());
+    buf[13..17].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+    FileHandle::from_bytes(&buf).unwrap()
+}
+
+/// Handles on `mounts` mounts, differing only in the last bytes within a mount, which is the
+/// most expensive case for the handle comparison.
+fn handles(mounts: u64) -> Vec<FileHandle> {
+    (0..HANDLES)
+        .map(|i| {
+            let mut payload = [0xa5; HANDLE_SIZE];
+            payload[HANDLE_SIZE - 4..].copy_from_slice(&(i as u32).to_be_bytes());
+            handle(i as u64 % mounts, 1, &payload)
+        })
+        .rev()
+        .collect()
+}
+
+fn bench_cmp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("file_handle");
+    for (name, mounts) in [("sort_many_mounts", HANDLES as u64), ("sort_one_mount", 1)] {
+        let handles = handles(mounts);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || handles.clone(),
+                |mut handles| {
+                    handles.sort_unstable();
+                    handles
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    let handles = handles(1);
+    let copies = handles.clone();
+    group.bench_function("eq_copies", |b| {
+        b.iter(|| {
+            handles
+                .iter()
+                .zip(&copies)
+                .filter(|(a, b)| black_box(a) == black_box(b))
+                .count()
+        })
+    });
+    group.bench_function("eq_self", |b| {
+        b.iter(|| {
+            handles
+                .iter()
+                .filter(|a| black_box(*a) == black_box(*a))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_cmp);
+criterion_main!(benches);
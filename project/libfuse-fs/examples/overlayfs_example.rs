@@ -29,7 +29,7 @@ struct Args {
     mapping: Option<String>,
     #[arg(long)]
     allow_other: bool,
-    /// Bind mounts in format "source:target" (repeatable)
+    /// Bind mounts in format "source:target[:rec]" (repeatable)
     #[arg(long = "bind")]
     bind_mounts: Vec<String>,
 }
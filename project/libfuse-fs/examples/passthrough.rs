@@ -3,7 +3,7 @@
 // Simple passthrough filesystem example for integration tests.
 
 use clap::Parser;
-use libfuse_fs::passthrough::{PassthroughArgs, new_passthroughfs_layer};
+use libfuse_fs::passthrough::{Config, PassthroughFs, XattrFilter};
 use libfuse_fs::util::bind_mount::{BindMount, BindMountManager};
 use rfuse3::raw::logfs::LoggingFileSystem;
 use rfuse3::{MountOptions, raw::Session};
@@ -69,12 +69,20 @@ async fn main() {
     // Create bind mount manager
     let bind_manager = BindMountManager::new(&args.mountpoint);
 
-    let fs = new_passthroughfs_layer(PassthroughArgs {
-        root_dir: args.rootdir,
-        mapping: args.options,
-    })
-    .await
-    .expect("Failed to init passthrough fs");
+    let mut config = Config {
+        root_dir: args.rootdir.into(),
+        xattr: true,
+        do_import: true,
+        // An unprivileged mount can't set `trusted.*` or `security.*` xattrs on the host, so
+        // it doesn't show the ones there either.
+        xattr_namespace_filter: (!args.privileged).then(XattrFilter::unprivileged),
+        ..Default::default()
+    };
+    if let Some(options) = &args.options {
+        config.mapping = options.parse().expect("Failed to parse mapping options");
+    }
+    let fs = PassthroughFs::<()>::new(config).expect("Failed to init passthrough fs");
+    fs.import().await.expect("Failed to init passthrough fs");
 
     let fs = LoggingFileSystem::new(fs);
     let mount_path = OsString::from(&args.mountpoint);
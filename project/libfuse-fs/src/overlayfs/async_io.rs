@@ -50,6 +50,7 @@ impl Filesystem for OverlayFs {
 
         Ok(ReplyInit {
             max_write: NonZeroU32::new(128 * 1024).unwrap(),
+            ..Default::default()
         })
     }
 
@@ -1111,7 +1112,11 @@ impl Filesystem for OverlayFs {
 }
 #[cfg(test)]
 mod tests {
-    use std::{ffi::OsString, path::PathBuf, sync::Arc};
+    use std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
 
     use rfuse3::{MountOptions, raw::Session};
     use tokio::signal;
@@ -1119,10 +1124,306 @@ mod tests {
 
     use crate::{
         overlayfs::{OverlayFs, config::Config},
-        passthrough::{PassthroughArgs, new_passthroughfs_layer},
+        passthrough::{PassthroughArgs, PassthroughFs, new_passthroughfs_layer},
     };
     use rfuse3::raw::logfs::LoggingFileSystem;
 
+    /// A passthrough layer over `root_dir` as the overlay tests stack them.
+    async fn new_layer(root_dir: &Path) -> Arc<PassthroughFs> {
+        Arc::new(
+            new_passthroughfs_layer(PassthroughArgs {
+                root_dir,
+                mapping: None::<&str>,
+            })
+            .await
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_copy_up_preserves_times() {
+        use rfuse3::raw::{Filesystem as _, Request};
+        use std::os::unix::fs::MetadataExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let src = lower.path().join("file");
+        std::fs::write(&src, b"hello").unwrap();
+        let atime = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_100_000_000, 987_654_321);
+        std::fs::File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_times(
+                std::fs::FileTimes::new()
+                    .set_accessed(atime)
+                    .set_modified(mtime),
+            )
+            .unwrap();
+
+        let lower_layer = new_layer(lower.path()).await;
+        let upper_layer = new_layer(upper.path()).await;
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(Some(upper_layer), vec![lower_layer], config, 1).unwrap();
+        fs.import().await.unwrap();
+
+        let req = Request::default();
+        let entry = fs
+            .lookup(req, 1, std::ffi::OsStr::new("file"))
+            .await
+            .unwrap();
+        let reply = fs
+            .open(req, entry.attr.ino, libc::O_WRONLY as u32)
+            .await
+            .unwrap();
+        fs.release(req, entry.attr.ino, reply.fh, 0, 0, false)
+            .await
+            .unwrap();
+
+        let src_md = std::fs::metadata(&src).unwrap();
+        let dst_md = std::fs::metadata(upper.path().join("file")).unwrap();
+        assert_eq!(std::fs::read(upper.path().join("file")).unwrap(), b"hello");
+        assert_eq!(dst_md.mtime(), src_md.mtime());
+        assert_eq!(dst_md.mtime_nsec(), 987_654_321);
+        assert_eq!(dst_md.mtime_nsec(), src_md.mtime_nsec());
+        // Reading the lower file during copy-up may bump its atime, so compare against
+        // the value it had before.
+        assert_eq!(dst_md.atime(), 1_000_000_000);
+        assert_eq!(dst_md.atime_nsec(), 123_456_789);
+    }
+
+    #[tokio::test]
+    async fn test_setattr_copies_up() {
+        use rfuse3::raw::{Filesystem as _, Request};
+        use std::os::unix::fs::PermissionsExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let src = lower.path().join("file");
+        std::fs::write(&src, b"hello").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        // A read-only lower fails any change reaching it with EROFS.
+        let lower_layer = crate::passthrough::PassthroughFs::new(crate::passthrough::Config {
+            root_dir: lower.path().to_path_buf(),
+            xattr: true,
+            do_import: true,
+            readonly: true,
+            ..Default::default()
+        })
+        .unwrap();
+        lower_layer.import().await.unwrap();
+        let upper_layer = new_passthroughfs_layer(PassthroughArgs {
+            root_dir: upper.path().to_path_buf(),
+            mapping: None::<&str>,
+        })
+        .await
+        .unwrap();
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(
+            Some(Arc::new(upper_layer)),
+            vec![Arc::new(lower_layer)],
+            config,
+            1,
+        )
+        .unwrap();
+        fs.import().await.unwrap();
+
+        let req = Request::default();
+        let entry = fs
+            .lookup(req, 1, std::ffi::OsStr::new("file"))
+            .await
+            .unwrap();
+        let set_attr = rfuse3::SetAttr {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let reply = fs
+            .setattr(req, entry.attr.ino, None, set_attr)
+            .await
+            .unwrap();
+        assert_eq!(reply.attr.ino, entry.attr.ino);
+        assert_eq!(reply.attr.perm, 0o600);
+
+        let dst_md = std::fs::metadata(upper.path().join("file")).unwrap();
+        assert_eq!(dst_md.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(std::fs::read(upper.path().join("file")).unwrap(), b"hello");
+        let src_md = std::fs::metadata(&src).unwrap();
+        assert_eq!(src_md.permissions().mode() & 0o7777, 0o644);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_whiteouts() {
+        use crate::overlayfs::layer::Layer as _;
+        use futures::StreamExt;
+        use rfuse3::raw::{Filesystem as _, Request};
+        use std::os::unix::fs::FileTypeExt;
+
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        std::fs::create_dir(lower.path().join("dir")).unwrap();
+        let names: Vec<String> = (0..8).map(|i| format!("f{i}")).collect();
+        for name in &names {
+            std::fs::write(lower.path().join("dir").join(name), b"").unwrap();
+        }
+
+        let lower_layer = new_layer(lower.path()).await;
+        let upper_layer = new_layer(upper.path()).await;
+        let config = Config {
+            do_import: true,
+            ..Default::default()
+        };
+        let fs = Arc::new(
+            OverlayFs::new(Some(upper_layer.clone()), vec![lower_layer], config, 1).unwrap(),
+        );
+        fs.import().await.unwrap();
+        let req = Request::default();
+        let dir = fs
+            .lookup(req, 1, std::ffi::OsStr::new("dir"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        // Every entry is removed twice at the same time, only one of the two may succeed.
+        let tasks: Vec<_> = names
+            .iter()
+            .chain(names.iter())
+            .map(|name| {
+                let fs = fs.clone();
+                let name = name.clone();
+                tokio::spawn(async move { fs.unlink(req, dir, std::ffi::OsStr::new(&name)).await })
+            })
+            .collect();
+        let mut removed = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(()) => removed += 1,
+                Err(e) => {
+                    let e: std::io::Error = e.into();
+                    if e.raw_os_error() == Some(libc::EPERM) {
+                        eprintln!("skip test_concurrent_whiteouts: {e}");
+                        return;
+                    }
+                    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+                }
+            }
+        }
+        assert_eq!(removed, names.len());
+
+        // Exactly one whiteout per removed entry.
+        let mut whiteouts: Vec<String> = std::fs::read_dir(upper.path().join("dir"))
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                assert!(e.file_type().unwrap().is_char_device());
+                e.file_name().into_string().unwrap()
+            })
+            .collect();
+        whiteouts.sort();
+        assert_eq!(whiteouts, names);
+
+        // None of them shows up again.
+        let fh = fs.opendir(req, dir, 0).await.unwrap().fh;
+        let listed: Vec<_> = fs
+            .readdir(req, dir, fh, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap().name.into_string().unwrap())
+            .collect()
+            .await;
+        assert!(listed.iter().all(|n| n == "." || n == ".."), "{listed:?}");
+        for name in &names {
+            fs.lookup(req, dir, std::ffi::OsStr::new(name))
+                .await
+                .unwrap_err();
+        }
+
+        // Creating a whiteout which is there already is a no-op.
+        let upper_dir = upper_layer
+            .lookup(req, upper_layer.root_inode(), std::ffi::OsStr::new("dir"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let entry = upper_layer
+            .create_whiteout(req, upper_dir, std::ffi::OsStr::new(&names[0]))
+            .await
+            .unwrap();
+        assert!(crate::overlayfs::layer::is_whiteout(&entry.attr));
+    }
+
+    #[tokio::test]
+    async fn test_max_path_depth() {
+        use rfuse3::raw::{Filesystem as _, Request};
+
+        // lower/d/d/.../d/file, 40 directories deep.
+        let lower = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        let mut dir = lower.path().to_path_buf();
+        for _ in 0..40 {
+            dir.push("d");
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"deep").unwrap();
+        std::fs::write(lower.path().join("d/d/shallow"), b"ok").unwrap();
+
+        let lower_layer = new_layer(lower.path()).await;
+        let upper_layer = new_layer(upper.path()).await;
+        let config = Config {
+            do_import: true,
+            max_path_depth: Some(10),
+            ..Default::default()
+        };
+        let fs = OverlayFs::new(Some(upper_layer), vec![lower_layer], config, 1).unwrap();
+        fs.import().await.unwrap();
+
+        let req = Request::default();
+        let mut inodes = vec![1];
+        for _ in 0..40 {
+            let parent = *inodes.last().unwrap();
+            let entry = fs
+                .lookup(req, parent, std::ffi::OsStr::new("d"))
+                .await
+                .unwrap();
+            inodes.push(entry.attr.ino);
+        }
+        let open_for_write = async |parent: u64, name: &str| {
+            let entry = fs.lookup(req, parent, std::ffi::OsStr::new(name)).await?;
+            let reply = fs.open(req, entry.attr.ino, libc::O_WRONLY as u32).await?;
+            fs.release(req, entry.attr.ino, reply.fh, 0, 0, false).await
+        };
+
+        // Reading deep entries is flat and unaffected.
+        assert_eq!(
+            fs.getattr(req, inodes[40], None, 0)
+                .await
+                .unwrap()
+                .attr
+                .kind,
+            rfuse3::FileType::Directory
+        );
+        // Copying up the deep file would recurse through all 40 ancestors.
+        let err: std::io::Error = open_for_write(inodes[40], "file").await.unwrap_err().into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+        assert!(!upper.path().join("d").exists());
+
+        // Within the limit, copy-up works as usual.
+        open_for_write(inodes[2], "shallow").await.unwrap();
+        assert_eq!(
+            std::fs::read(upper.path().join("d/d/shallow")).unwrap(),
+            b"ok"
+        );
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_a_ovlfs() {
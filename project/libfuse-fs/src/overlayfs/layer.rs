@@ -60,7 +60,21 @@ pub trait Layer: Filesystem {
         let dev = libc::makedev(0, 0);
         let mode = libc::S_IFCHR | 0o777;
         #[allow(clippy::unnecessary_cast)]
-        self.mknod(ctx, ino, name, mode as u32, dev as u32).await
+        let res = self.mknod(ctx, ino, name, mode as u32, dev as u32).await;
+        match res {
+            // Someone else created the whiteout since the lookup above.
+            Err(e) if e.is_exist() => {
+                let v = self.lookup(ctx, ino, name).await?;
+                if is_whiteout(&v.attr) {
+                    return Ok(v);
+                }
+                if v.attr.ino != 0 {
+                    self.forget(ctx, v.attr.ino, 1).await;
+                }
+                Err(e)
+            }
+            res => res,
+        }
     }
 
     /// Delete whiteout file with name <name>.
@@ -184,7 +198,7 @@ pub trait Layer: Filesystem {
 }
 impl Layer for PassthroughFs {
     fn root_inode(&self) -> Inode {
-        1
+        self.root_ino()
     }
 }
 pub(crate) fn is_dir(st: &FileAttr) -> bool {
@@ -34,6 +34,7 @@ const SLASH_ASCII: char = '/';
 use futures::future::join_all;
 use futures::stream::iter;
 
+use crate::passthrough::inode_lock::InodeLockMap;
 use crate::passthrough::{PassthroughArgs, PassthroughFs, new_passthroughfs_layer};
 use crate::util::convert_stat64_to_file_attr;
 use inode_store::InodeStore;
@@ -104,6 +105,9 @@ pub struct OverlayFs {
     killpriv_v2: AtomicBool,
     perfile_dax: AtomicBool,
     root_inodes: u64,
+    // Per-directory locks serializing removals and renames, so that whiteouts in a directory
+    // are created one at a time.
+    inode_locks: InodeLockMap,
 }
 
 // This is a wrapper of one inode in specific layer, It can't impl Clone trait.
@@ -279,15 +283,11 @@ impl RealInode {
             Err(e) => {
                 let ioerror: std::io::Error = e.into();
                 match ioerror.raw_os_error() {
-                    Some(raw_error) => {
-                        if raw_error == libc::ENOSYS {
-                            // We can still call readdir with inode if opendir is not supported in this layer.
-                            ReplyOpen { fh: 0, flags: 0 }
-                        } else {
-                            return Err(e.into());
-                        }
+                    Some(raw_error) if raw_error == libc::ENOSYS => {
+                        // We can still call readdir with inode if opendir is not supported in this layer.
+                        ReplyOpen { fh: 0, flags: 0 }
                     }
-                    None => {
+                    _ => {
                         return Err(e.into());
                     }
                 }
@@ -998,6 +998,7 @@ impl OverlayFs {
             killpriv_v2: AtomicBool::new(false),
             perfile_dax: AtomicBool::new(false),
             root_inodes: root_inode,
+            inode_locks: InodeLockMap::default(),
         })
     }
 
@@ -1824,6 +1825,7 @@ impl OverlayFs {
     ) -> Result<()> {
         let name_str = name.to_str().unwrap();
         let new_name_str = new_name.to_str().unwrap();
+        let _inode_lock = self.inode_locks.lock_many(&[parent, new_parent]).await;
 
         let parent_node = self.lookup_node(req, parent, "").await?;
         let new_parent_node = self.lookup_node(req, new_parent, "").await?;
@@ -2100,6 +2102,7 @@ impl OverlayFs {
         };
 
         if !parent_node.in_upper_layer().await {
+            self.check_path_depth(&parent_node).await?;
             parent_node.clone().create_upper_dir(ctx, None).await?;
         }
 
@@ -2197,6 +2200,7 @@ impl OverlayFs {
         );
 
         if !parent_node.in_upper_layer().await {
+            self.check_path_depth(&parent_node).await?;
             parent_node.clone().create_upper_dir(ctx, None).await?;
         }
 
@@ -2267,7 +2271,7 @@ impl OverlayFs {
         // need to use work directory and then rename file to
         // final destination for atomic reasons.. not deal with it for now,
         // use stupid copy at present.
-        // FIXME: this need a lot of work here, ntimes, xattr, etc.
+        // FIXME: this need a lot of work here, xattr, etc.
 
         // Copy from lower real inode to upper real inode.
         // TODO: use sendfile here.
@@ -2304,6 +2308,19 @@ impl OverlayFs {
                     return Err(e);
                 }
             }
+            // Carry the lower file's timestamps over to the copy. This is done after
+            // release, since flushing cached writes would bump mtime again.
+            // Layers which can't set times (ENOSYS), or callers who may not (EPERM), keep
+            // fresh timestamps.
+            if !self.config.update_times_on_copy
+                && let Err(e) = ri
+                    .layer
+                    .do_utimensat(ctx, ri.inode, st.attr.atime, st.attr.mtime)
+                    .await
+                && !matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM))
+            {
+                return Err(e);
+            }
             node.add_upper_inode(ri, true).await;
         } else {
             error!("BUG: upper real inode is None after copy up");
@@ -2341,6 +2358,7 @@ impl OverlayFs {
         let st = node.stat64(ctx).await?;
         match st.attr.kind {
             FileType::Directory => {
+                self.check_path_depth(&node).await?;
                 node.clone().create_upper_dir(ctx, None).await?;
                 Ok(node)
             }
@@ -2359,12 +2377,37 @@ impl OverlayFs {
         }
     }
 
+    /// Check that `node` isn't nested deeper below the root than `Config::max_path_depth`.
+    ///
+    /// Copy-up recurses through the ancestry of a node (and copying a directory up through its
+    /// descendants), this bounds the recursion for pathologically deep trees.
+    async fn check_path_depth(&self, node: &Arc<OverlayInode>) -> Result<()> {
+        let Some(max_depth) = self.config.max_path_depth else {
+            return Ok(());
+        };
+        let mut depth = 0;
+        let mut parent = node.parent.lock().await.upgrade();
+        while let Some(p) = parent {
+            depth += 1;
+            if depth > max_depth {
+                debug!(
+                    "path depth of inode {} exceeds the limit of {}",
+                    node.inode, max_depth
+                );
+                return Err(Error::from_raw_os_error(libc::ENAMETOOLONG));
+            }
+            parent = p.parent.lock().await.upgrade();
+        }
+        Ok(())
+    }
+
     /// recursively copy directory and all its contents to upper layer
     async fn copy_directory_up(
         &self,
         ctx: Request,
         node: Arc<OverlayInode>,
     ) -> Result<Arc<OverlayInode>> {
+        self.check_path_depth(&node).await?;
         // Ensure the directory itself is copied up first
         self.copy_node_up(ctx, node.clone()).await?;
 
@@ -2411,6 +2454,9 @@ impl OverlayFs {
         if self.upper_layer.is_none() {
             return Err(Error::from_raw_os_error(libc::EROFS));
         }
+        // Concurrent removals in the same directory take turns, so the second removal of an
+        // entry finds the whiteout of the first one instead of creating another.
+        let _inode_lock = self.inode_locks.lock_many(&[parent]).await;
 
         // 2. Locate the parent Overlay Inode.
         // Find parent Overlay Inode.
@@ -2,45 +2,69 @@ use crate::util::open_options::OpenOptions;
 use bytes::Bytes;
 use futures::stream;
 use libc::{off_t, pread, size_t};
-use rfuse3::{Errno, Inode, Result, raw::prelude::*};
+use rfuse3::{
+    Errno, Inode, Result, Timestamp,
+    raw::{flags::FUSE_WRITE_CACHE, prelude::*},
+};
 use std::{
     ffi::{CStr, CString, OsStr, OsString},
     fs::File,
-    io,
+    io::{self, IoSlice},
     mem::MaybeUninit,
     num::NonZeroU32,
     os::{
         fd::{AsRawFd, RawFd},
         raw::c_int,
-        unix::ffi::OsStringExt,
+        unix::ffi::{OsStrExt, OsStringExt},
     },
+    path::{Component, Path},
     sync::{Arc, atomic::Ordering},
     time::Duration,
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 use vm_memory::{ByteValued, bitmap::BitmapSlice};
 
 use crate::{
-    passthrough::{CURRENT_DIR_CSTR, EMPTY_CSTR, FileUniqueKey, PARENT_DIR_CSTR, statx::statx},
-    util::{convert_stat64_to_file_attr, filetype_from_mode},
+    passthrough::{
+        CURRENT_DIR_CSTR, EMPTY_CSTR, FileUniqueKey, PARENT_DIR_CSTR, statx::dio_offset_align,
+    },
+    util::{convert_stat64_to_file_attr, d_type_to_filetype},
 };
 
 use super::ebadf;
+use super::inode_store::InodeId;
+use super::mirror::MirrorOp;
+use super::mmap::get_page_size;
+#[cfg(target_os = "macos")]
+use super::util::xattr_options;
 use super::util::{
-    self, AT_EMPTY_PATH, SLASH_ASCII, einval, enosys, is_safe_inode, osstr_to_cstr, set_creds,
-    stat_fd, stat64,
+    self, AT_EMPTY_PATH, SLASH_ASCII, einval, enosys, is_dir, is_safe_inode, is_special_inode,
+    osstr_to_cstr, set_creds, stat_fd, stat64,
 };
-use super::{Handle, HandleData, PassthroughFs, config::CachePolicy, os_compat::LinuxDirent64};
+use super::{
+    Handle, HandleData, InodeData, PassthroughFs, ROOT_ID, config::CachePolicy,
+    os_compat::LinuxDirent64,
+};
+/// The `f_type` of FUSE mounts.
+pub const FUSE_SUPER_MAGIC: i64 = 0x6573_5546;
+
+/// The `whence` of `lseek` for the next data and the next hole. FUSE passes on the values of
+/// Linux, which macOS has swapped.
+pub const FUSE_SEEK_DATA: u32 = 3;
+pub const FUSE_SEEK_HOLE: u32 = 4;
+
 #[cfg(target_os = "macos")]
 pub const O_DIRECT: libc::c_int = 0;
 #[cfg(target_os = "linux")]
 pub use libc::O_DIRECT;
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
-    async fn open_inode(&self, inode: Inode, flags: i32) -> io::Result<File> {
+    pub(super) async fn open_inode(&self, inode: Inode, flags: i32) -> io::Result<File> {
         let data = self.inode_map.get(inode).await?;
-        if !is_safe_inode(data.mode) {
+        if is_special_inode(data.mode) {
+            self.open_special_inode(&data, flags)
+        } else if !is_safe_inode(data.mode) {
             Err(ebadf())
         } else {
             let mut new_flags = self.get_writeback_open_flags(flags).await;
@@ -48,8 +72,42 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             if !self.cfg.allow_direct_io && flags & O_DIRECT != 0 {
                 new_flags &= !O_DIRECT;
             }
-            data.open_file(new_flags | libc::O_CLOEXEC, &self.proc_self_fd)
+            util::retry_io(self.cfg.io_retry.as_ref(), || {
+                data.open_file(new_flags | libc::O_CLOEXEC, &self.proc_self_fd)
+            })
+            .await
+        }
+    }
+
+    /// Open the FIFO, device or socket `data`, see `Config::allow_special_file_io`.
+    #[allow(clippy::unnecessary_cast)]
+    fn open_special_inode(&self, data: &InodeData, flags: i32) -> io::Result<File> {
+        if !self.cfg.allow_special_file_io
+            || data.mode & (libc::S_IFMT as u32) == libc::S_IFSOCK as u32
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENXIO));
+        }
+        // Without `O_NONBLOCK`, opening a FIFO waits for its other end.
+        let file = data.open_file(
+            flags | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            &self.proc_self_fd,
+        )?;
+        if flags & libc::O_NONBLOCK == 0 {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let fd_flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+            if fd_flags < 0
+                || unsafe {
+                    libc::fcntl(
+                        file.as_raw_fd(),
+                        libc::F_SETFL,
+                        fd_flags & !libc::O_NONBLOCK,
+                    )
+                } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
         }
+        Ok(file)
     }
 
     /// Check the HandleData flags against the flags from the current request
@@ -83,6 +141,19 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
         let data = self.get_dirdata(handle, inode, libc::O_RDONLY).await?;
 
+        if let Some(entries) = self.union_entries(&data, offset).await? {
+            for mut entry in entries {
+                let name = osstr_to_cstr(&entry.name)?;
+                let reply = self.do_lookup(inode, &name).await?;
+                let mut inodes = self.inode_map.inodes.write().await;
+                self.forget_one(&mut inodes, reply.attr.ino, 1).await;
+                entry.inode = reply.attr.ino;
+                entry.kind = reply.attr.kind;
+                entry_list.push(Ok(entry));
+            }
+            return Ok(());
+        }
+
         // Since we are going to work with the kernel offset, we have to acquire the file lock
         // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
         // changes the kernel offset while we are using it.
@@ -153,9 +224,10 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         })?
                         .to_bytes();
 
+                    let kind = d_type_to_filetype(dirent64.d_ty);
                     let mut entry = DirectoryEntry {
                         inode: dirent64.d_ino,
-                        kind: filetype_from_mode(dirent64.d_ty as u32 * 0x1000u32),
+                        kind: kind.unwrap_or(FileType::RegularFile),
                         name: OsString::from_vec(name.to_vec()),
                         offset: dirent64.d_off,
                     };
@@ -168,6 +240,10 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
                     self.forget_one(&mut inodes, _entry.attr.ino, 1).await;
                     entry.inode = _entry.attr.ino;
+                    // The host didn't say what type the entry is, the lookup did.
+                    if kind.is_none() {
+                        entry.kind = _entry.attr.kind;
+                    }
                     entry_list.push(Ok(entry));
 
                     // move to next entry
@@ -204,6 +280,13 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     break; // no more
                 }
 
+                // `base` is where this block started, and the directory position after the read
+                // is the only cookie the filesystem guarantees to accept back for the block end.
+                let block_end = unsafe { libc::lseek(dir.as_raw_fd(), 0, libc::SEEK_CUR) };
+                if block_end < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
                 let mut offset = 0;
                 while offset < bytes_read {
                     let p = unsafe { buffer.as_ptr().add(offset) };
@@ -246,16 +329,23 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         continue;
                     }
 
-                    // Generate a resume offset for the next readdir call.
-                    let current_entry_offset = base as u64 + offset as u64 + d_reclen as u64;
+                    // Generate a resume offset for the next readdir call. Only the last entry of
+                    // the block gets a real cookie, the others get their byte position within
+                    // the block, which only file systems with such cookies accept back.
+                    let current_entry_offset = if offset + d_reclen as usize >= bytes_read {
+                        block_end as u64
+                    } else {
+                        base as u64 + offset as u64 + d_reclen as u64
+                    };
 
                     // Extract the entry name from the buffer.
 
                     let name_vec = name_slice.to_vec();
 
+                    let kind = d_type_to_filetype(d_type);
                     let mut entry = DirectoryEntry {
                         inode: d_ino,
-                        kind: filetype_from_mode(d_type as u32 * 0x1000),
+                        kind: kind.unwrap_or(FileType::RegularFile),
                         name: OsString::from_vec(name_vec.clone()),
                         offset: current_entry_offset as i64,
                     };
@@ -284,6 +374,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     let mut inodes = self.inode_map.inodes.write().await;
                     self.forget_one(&mut inodes, _entry.attr.ino, 1).await;
                     entry.inode = _entry.attr.ino;
+                    if kind.is_none() {
+                        entry.kind = _entry.attr.kind;
+                    }
 
                     entry_list.push(Ok(entry));
 
@@ -306,11 +399,43 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
         let data = self.get_dirdata(handle, inode, libc::O_RDONLY).await?;
 
+        if let Some(entries) = self.union_entries(&data, offset).await? {
+            for entry in entries {
+                let name = osstr_to_cstr(&entry.name)?;
+                let reply = self.do_lookup(inode, &name).await?;
+                entry_list.push(Ok(DirectoryEntryPlus {
+                    inode: reply.attr.ino,
+                    generation: reply.generation,
+                    kind: reply.attr.kind,
+                    name: entry.name,
+                    offset: entry.offset,
+                    attr: reply.attr,
+                    entry_ttl: reply.ttl,
+                    attr_ttl: reply.ttl,
+                }));
+            }
+            return Ok(());
+        }
+
         // Since we are going to work with the kernel offset, we have to acquire the file lock
         // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
         // changes the kernel offset while we are using it.
         let (_guard, dir) = data.get_file_mut().await;
 
+        // Entries without a node id tell the kernel that no attributes come with them, it
+        // ignores the rest of their attributes and takes no lookup reference.
+        let names_only = match self.cfg.readdirplus_max_dir_size {
+            Some(max) => {
+                let st = stat_fd(dir, None)?;
+                (st.st_size as u64 > max).then(|| {
+                    let mut attr = convert_stat64_to_file_attr(st);
+                    attr.ino = 0;
+                    attr
+                })
+            }
+            None => None,
+        };
+
         // Allocate buffer; pay attention to alignment.
         #[allow(unused_mut)]
         let mut buffer = vec![0u8; BUFFER_SIZE];
@@ -353,6 +478,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                 break;
             }
 
+            let mut entries = Vec::new();
             let mut offset = 0;
             while offset < bytes_read {
                 //size_of::<LinuxDirent64>()
@@ -381,40 +507,97 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     })?
                     .to_bytes();
 
-                let mut entry = DirectoryEntry {
-                    inode: dirent64.d_ino,
-                    kind: filetype_from_mode((dirent64.d_ty as u16 * 0x1000u16).into()),
-                    name: OsString::from_vec(name.to_vec()),
-                    offset: dirent64.d_off,
-                };
-                // Safe because do_readdir() has ensured dir_entry.name is a
-                // valid [u8] generated by CStr::to_bytes().
-                let name = osstr_to_cstr(&entry.name)?;
-                debug!("readdir:{}", name.to_str().unwrap());
-                let _entry = self.do_lookup(inode, &name).await?;
-                entry.inode = _entry.attr.ino;
+                let kind = d_type_to_filetype(dirent64.d_ty);
+                entries.push((
+                    kind,
+                    DirectoryEntry {
+                        inode: dirent64.d_ino,
+                        kind: kind.unwrap_or(FileType::RegularFile),
+                        name: OsString::from_vec(name.to_vec()),
+                        offset: dirent64.d_off,
+                    },
+                ));
+                // add the offset.
+                offset += dirent64.d_reclen as usize;
+            }
+
+            if let Some(attr) = names_only {
+                entry_list.extend(entries.into_iter().map(|(_, entry)| {
+                    Ok(DirectoryEntryPlus {
+                        inode: entry.inode,
+                        generation: 0,
+                        kind: entry.kind,
+                        name: entry.name,
+                        offset: entry.offset,
+                        attr,
+                        entry_ttl: Duration::ZERO,
+                        attr_ttl: Duration::ZERO,
+                    })
+                }));
+                continue;
+            }
+
+            // Fetch the attributes of this batch with a bounded number of lookups in flight, so
+            // a large directory doesn't flood a slow backing store with stat calls.
+            let lookups = util::bounded_map_ordered(
+                entries,
+                self.cfg.readdirplus_concurrency,
+                |(kind, mut entry)| async move {
+                    // Safe because the name was generated by CStr::to_bytes() above.
+                    let name = osstr_to_cstr(&entry.name)?;
+                    debug!("readdir:{}", name.to_str().unwrap());
+                    let reply = self.do_lookup(inode, &name).await?;
+                    // The host didn't say what type the entry is, the lookup did.
+                    if kind.is_none() {
+                        entry.kind = reply.attr.kind;
+                    }
+                    Ok::<_, io::Error>((entry, reply))
+                },
+            )
+            .await;
 
+            for lookup in lookups {
+                let (entry, reply) = lookup?;
                 entry_list.push(Ok(DirectoryEntryPlus {
-                    inode: entry.inode,
-                    generation: _entry.generation,
+                    inode: reply.attr.ino,
+                    generation: reply.generation,
                     kind: entry.kind,
                     name: entry.name,
                     offset: entry.offset,
-                    attr: _entry.attr,
-                    entry_ttl: _entry.ttl,
-                    attr_ttl: _entry.ttl,
+                    attr: reply.attr,
+                    entry_ttl: reply.ttl,
+                    attr_ttl: reply.ttl,
                 }));
-                // add the offset.
-                offset += dirent64.d_reclen as usize;
             }
         }
         Ok(())
     }
 
     async fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
-        let file = self.open_inode(inode, flags as i32).await?;
+        let flags_i32 = flags as i32;
+        if flags_i32 & libc::O_ACCMODE != libc::O_RDONLY || flags_i32 & libc::O_TRUNC != 0 {
+            self.check_writable()?;
+        }
+
+        // O_TRUNC is applied by the open itself, so no one can observe the file between the
+        // open and a separate truncate.
+        let file = self.open_inode(inode, flags_i32).await?;
+        if flags_i32 & libc::O_TRUNC != 0 {
+            self.invalidate_mmap_cache(inode, 0).await;
+            self.mirror(MirrorOp::Truncate { inode, size: 0 }).await?;
+        }
+
+        let changed = if self.cfg.drop_cache_on_change && flags & (libc::O_DIRECTORY as u32) == 0 {
+            self.inode_map
+                .get(inode)
+                .await?
+                .update_change_stamp(&file)?
+        } else {
+            false
+        };
 
-        let data = HandleData::new(inode, file, flags);
+        let origin = self.opened_origin(&file).await;
+        let data = HandleData::new(inode, file, flags).with_origin(origin);
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         self.handle_map.insert(handle, data).await;
 
@@ -440,6 +623,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
             _ => {}
         };
+        if changed {
+            opts.remove(OpenOptions::KEEP_CACHE);
+        }
+        // With writeback caching the kernel would append at its cached end of the file, which is
+        // stale if the file is shared. Bypass the cache so `write()` can append on the host.
+        if self.writeback.load(Ordering::Relaxed) && flags & libc::O_APPEND as u32 != 0 {
+            opts |= OpenOptions::DIRECT_IO;
+        }
 
         Ok((Some(handle), opts))
     }
@@ -488,7 +679,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
             e
         })?;
-        st.st_ino = inode;
+        st.st_ino = data.inode;
         if mapping {
             st.st_uid = self.cfg.mapping.find_mapping(st.st_uid, true, true);
             st.st_gid = self.cfg.mapping.find_mapping(st.st_gid, true, false);
@@ -503,14 +694,96 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     /// `mapping: true` to ensure clients see attributes from the container's perspective.
     async fn do_getattr(&self, inode: Inode, fh: Option<u64>) -> io::Result<(stat64, Duration)> {
         let inode_data = self.inode_map.get(inode).await?;
-        if let Some(handle) = fh {
+        let mut st = if let Some(handle) = fh {
             let hd = self.handle_map.get(handle, inode).await?;
-            let file = hd.get_file();
-            return util::stat_fd(file, None).map(|st| (st, self.cfg.attr_timeout));
+            util::stat_fd(hd.get_file(), None)?
+        } else {
+            let file = inode_data.get_file()?;
+            util::stat_fd(&file, None)?
+        };
+        if self.is_root(inode) {
+            st.st_ino = self.root_ino();
         }
+        st.st_uid = self.cfg.mapping.find_mapping(st.st_uid, true, true);
+        st.st_gid = self.cfg.mapping.find_mapping(st.st_gid, true, false);
+        Ok((st, self.cfg.attr_timeout))
+    }
 
-        let file = inode_data.get_file()?;
-        util::stat_fd(&file, None).map(|st| (st, self.cfg.attr_timeout))
+    #[cfg(target_os = "linux")]
+    async fn do_fstatvfs(&self, inode: Inode) -> io::Result<libc::statvfs64> {
+        let data = self.inode_map.get(inode).await?;
+        let file = data.get_file()?;
+        #[cfg(test)]
+        self.statfs_calls.fetch_add(1, Ordering::Relaxed);
+        let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
+        // Safe because this only writes to `out` and we check the return value.
+        match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
+            0 => Ok(unsafe { out.assume_init() }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    async fn do_fstatvfs(&self, inode: Inode) -> io::Result<libc::statvfs> {
+        let data = self.inode_map.get(inode).await?;
+        let file = data.get_file()?;
+        #[cfg(test)]
+        self.statfs_calls.fetch_add(1, Ordering::Relaxed);
+        let mut out = MaybeUninit::<libc::statvfs>::zeroed();
+        // Safe because this only writes to `out` and we check the return value.
+        match unsafe { libc::fstatvfs(file.as_raw_fd(), out.as_mut_ptr()) } {
+            0 => Ok(unsafe { out.assume_init() }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// The `statfs` reply for `inode`, straight from the host.
+    async fn do_statfs_reply(&self, inode: Inode) -> io::Result<ReplyStatFs> {
+        let statfs = self.do_fstatvfs(inode).await?;
+
+        Ok(
+            // Populate the ReplyStatFs structure with the necessary information
+            ReplyStatFs {
+                blocks: statfs.f_blocks as u64,
+                bfree: statfs.f_bfree as u64,
+                bavail: statfs.f_bavail as u64,
+                files: statfs.f_files as u64,
+                ffree: statfs.f_ffree as u64,
+                bsize: statfs.f_bsize as u32,
+                namelen: statfs.f_namemax as u32,
+                frsize: statfs.f_frsize as u32,
+            },
+        )
+    }
+
+    /// `statfs(2)` of the host filesystem holding `inode`, which unlike `statvfs` reports the
+    /// filesystem type.
+    #[cfg(target_os = "linux")]
+    async fn do_statfs(&self, inode: Inode) -> io::Result<libc::statfs64> {
+        let data = self.inode_map.get(inode).await?;
+        let file = data.get_file()?;
+        let mut out = MaybeUninit::<libc::statfs64>::zeroed();
+        // Safe because this only writes to `out` and we check the return value.
+        match unsafe { libc::fstatfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
+            0 => Ok(unsafe { out.assume_init() }),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// The type (`f_type` magic number) of the host filesystem holding `inode`, or
+    /// `FUSE_SUPER_MAGIC` if it can't be determined or on macOS.
+    ///
+    /// The FUSE protocol has no room for the type in its `statfs` reply, the kernel always
+    /// reports `FUSE_SUPER_MAGIC` to `statfs(2)` callers on the mount. This is how embedders and
+    /// the `fs_type` control xattr (see `Config::control_xattr`) learn the real one.
+    pub async fn host_fs_type(&self, inode: Inode) -> i64 {
+        #[cfg(target_os = "linux")]
+        if let Ok(st) = self.do_statfs(inode).await {
+            #[allow(clippy::unnecessary_cast)]
+            return st.f_type as i64;
+        }
+        let _ = inode;
+        FUSE_SUPER_MAGIC
     }
 
     /// Internal `getattr` helper that skips ID mapping.
@@ -527,10 +800,55 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         self.do_getattr_inner(inode, fh, false).await
     }
 
+    /// Internal helper that sets the access and modification times of `inode` on behalf of
+    /// `req`.
+    ///
+    /// It is used by `overlayfs` copy-up to carry the timestamps of the source file over to
+    /// its copy. Like explicit times passed to `setattr`, this is reserved to the owner.
+    pub async fn do_utimensat(
+        &self,
+        req: Request,
+        inode: Inode,
+        atime: Timestamp,
+        mtime: Timestamp,
+    ) -> io::Result<()> {
+        self.check_writable()?;
+        let _inode_lock = self.lock_inodes(&[inode]).await;
+        let data = self.inode_map.get(inode).await?;
+        let file = data.get_file()?;
+        self.check_set_times(&req, &stat_fd(&file, None)?, true)?;
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tvs = [
+            libc::timespec {
+                tv_sec: atime.sec,
+                tv_nsec: atime.nsec as i64,
+            },
+            libc::timespec {
+                tv_sec: mtime.sec,
+                tv_nsec: mtime.nsec as i64,
+            },
+        ];
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::utimensat(
+                self.proc_self_fd.as_raw_fd(),
+                pathname.as_ptr(),
+                tvs.as_ptr(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     async fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
         let data = self.inode_map.get(parent).await?;
         let file = data.get_file()?;
-        let st = statx(&file, Some(name)).ok();
+        let st = self.statx(&file, Some(name)).ok();
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
@@ -548,6 +866,200 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
     }
 
+    /// Keep the entry `name` of `parent` under a hidden name instead of letting it go if a
+    /// handle is open on it, see `Config::defer_unlink`. For an unlink the entry is renamed to
+    /// the hidden name. For a rename over it, `replace`, a non-directory is linked to the hidden
+    /// name, so `name` stays in place until the rename replaces it. Returns the inode and hidden
+    /// name if that was done.
+    async fn defer_unlink(
+        &self,
+        parent: Inode,
+        name: &CStr,
+        replace: bool,
+    ) -> io::Result<Option<(Inode, CString)>> {
+        if !self.cfg.defer_unlink {
+            return Ok(None);
+        }
+        let parent_data = self.inode_map.get(parent).await?;
+        let dir = parent_data.get_file()?;
+        let st = match self.statx(&dir, Some(name)) {
+            Err(e) if replace && e.raw_os_error() == Some(libc::ENOENT) => return Ok(None),
+            res => res?,
+        };
+        if replace && is_dir(st.st.st_mode.into()) {
+            return Ok(None);
+        }
+        let id = InodeId::from_stat(&st);
+        let open = self.inode_map.inodes.read().await.inode_by_id(&id).copied();
+        let Some(inode) = open else {
+            return Ok(None);
+        };
+        if !self.handle_map.has_inode(inode).await {
+            return Ok(None);
+        }
+
+        #[cfg(target_os = "linux")]
+        let flags = libc::RENAME_NOREPLACE;
+        #[cfg(target_os = "macos")]
+        let flags = 0;
+        for n in 0u32.. {
+            let hidden = CString::new(format!(".fuse_hidden{inode:016x}{n:08x}")).unwrap();
+            let res = if replace {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::linkat(
+                        dir.as_raw_fd(),
+                        name.as_ptr(),
+                        dir.as_raw_fd(),
+                        hidden.as_ptr(),
+                        0,
+                    )
+                };
+                if res < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            } else {
+                self.do_renameat2(&dir, name, &dir, &hidden, flags)
+            };
+            match res {
+                Ok(()) => {
+                    self.deferred_unlinks
+                        .lock()
+                        .await
+                        .entry(inode)
+                        .or_default()
+                        .push((parent_data.clone(), hidden.clone()));
+                    return Ok(Some((inode, hidden)));
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from_raw_os_error(libc::EEXIST))
+    }
+
+    /// Drop the hidden entry `defer_unlink()` made for a rename over `parent`'s entry, when
+    /// the rename failed.
+    async fn undo_defer_unlink(&self, parent: Inode, deferred: Option<(Inode, CString)>) {
+        let Some((inode, hidden)) = deferred else {
+            return;
+        };
+        if let Some(entries) = self.deferred_unlinks.lock().await.get_mut(&inode) {
+            entries.retain(|(_, name)| *name != hidden);
+        }
+        if let Ok(parent) = self.inode_map.get(parent).await
+            && let Ok(dir) = parent.get_file()
+        {
+            // Safe because this doesn't modify any memory and we check the return value.
+            if unsafe { libc::unlinkat(dir.as_raw_fd(), hidden.as_ptr(), 0) } < 0 {
+                warn!(
+                    "passthrough: failed to unlink hidden entry {hidden:?}: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    fn do_renameat2(
+        &self,
+        old_dir: &impl AsRawFd,
+        old_name: &CStr,
+        new_dir: &impl AsRawFd,
+        new_name: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if !self.syscalls.renameat2 {
+            if flags != 0 {
+                return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+            }
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::renameat(
+                    old_dir.as_raw_fd(),
+                    old_name.as_ptr(),
+                    new_dir.as_raw_fd(),
+                    new_name.as_ptr(),
+                )
+            };
+            return if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            };
+        }
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            #[cfg(target_os = "linux")]
+            {
+                libc::renameat2(
+                    old_dir.as_raw_fd(),
+                    old_name.as_ptr(),
+                    new_dir.as_raw_fd(),
+                    new_name.as_ptr(),
+                    flags,
+                )
+            }
+            #[cfg(target_os = "macos")]
+            {
+                if flags == 0 {
+                    libc::renameat(
+                        old_dir.as_raw_fd(),
+                        old_name.as_ptr(),
+                        new_dir.as_raw_fd(),
+                        new_name.as_ptr(),
+                    )
+                } else {
+                    // Stub renameat2 with ENOSYS on Mac
+                    *libc::__error() = libc::ENOSYS;
+                    -1
+                }
+            }
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// A plain rename, which with `Config::atomic_dir_replace` replaces a non-empty directory by
+    /// swapping the two directories. Returns the `renameat2` flags the host applied, for the
+    /// mirror.
+    fn do_rename_replacing(
+        &self,
+        old_dir: &impl AsRawFd,
+        old_name: &CStr,
+        new_dir: &impl AsRawFd,
+        new_name: &CStr,
+    ) -> io::Result<u32> {
+        let err = match self.do_renameat2(old_dir, old_name, new_dir, new_name, 0) {
+            Err(e)
+                if self.cfg.atomic_dir_replace
+                    && matches!(e.raw_os_error(), Some(libc::ENOTEMPTY | libc::EEXIST)) =>
+            {
+                e
+            }
+            res => return res.map(|()| 0),
+        };
+
+        // Only a non-empty directory fails a rename over it like this, and only a directory can
+        // be renamed over a directory, so this swaps two directories. The old one is left under
+        // the source name: removing a tree takes permissions on everything in it, which are the
+        // caller's to have, not ours. Without support for the exchange the rename fails as it
+        // would have anyway.
+        #[cfg(target_os = "linux")]
+        if self
+            .do_renameat2(old_dir, old_name, new_dir, new_name, libc::RENAME_EXCHANGE)
+            .is_ok()
+        {
+            return Ok(libc::RENAME_EXCHANGE);
+        }
+        Err(err)
+    }
+
     async fn get_dirdata(
         &self,
         handle: Handle,
@@ -571,13 +1083,120 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     ) -> io::Result<Arc<HandleData>> {
         let no_open = self.no_open.load(Ordering::Relaxed);
         if !no_open {
-            self.handle_map.get(handle, inode).await
+            let data = self.handle_map.get(handle, inode).await?;
+            self.reopen_if_replaced(handle, data).await
         } else {
             let file = self.open_inode(inode, flags).await?;
             Ok(Arc::new(HandleData::new(inode, file, flags as u32)))
         }
     }
 
+    /// The parent directory and name `file` was opened at, tracked for
+    /// `Config::reopen_on_replace`. The directory is reached from the root inode one component
+    /// at a time, and the name has to still refer to `file`.
+    async fn opened_origin(&self, file: &File) -> Option<Arc<(File, CString)>> {
+        if !self.cfg.reopen_on_replace {
+            return None;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let root = self.readlinkat_proc_file(ROOT_ID).await.ok()?;
+            let fd = CString::new(file.as_raw_fd().to_string()).ok()?;
+            let path = Self::readlinkat(self.proc_self_fd.as_raw_fd(), &fd).ok()?;
+            let rel = path.strip_prefix(&root).ok()?;
+            let name = CString::new(rel.file_name()?.as_bytes()).ok()?;
+
+            let root_data = self.inode_map.get(ROOT_ID).await.ok()?;
+            let dir = self
+                .open_dir_below(&root_data.get_file().ok()?, rel.parent()?)
+                .ok()?;
+            let st = self.statx(&dir, Some(&name)).ok()?.st;
+            let opened = stat_fd(file, None).ok()?;
+            if st.st_dev != opened.st_dev || st.st_ino != opened.st_ino {
+                return None;
+            }
+            Some(Arc::new((dir, name)))
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let _ = file;
+            None
+        }
+    }
+
+    /// Open the directory `rel` below `dir` one component at a time, never following a symlink.
+    fn open_dir_below(&self, dir: &impl AsRawFd, rel: &Path) -> io::Result<File> {
+        #[cfg(target_os = "linux")]
+        let flags = libc::O_PATH | libc::O_DIRECTORY;
+        #[cfg(target_os = "macos")]
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+
+        let mut dir = self.open_file_restricted(dir, c".", flags, 0)?;
+        for component in rel.components() {
+            let Component::Normal(name) = component else {
+                return Err(io::Error::from_raw_os_error(libc::EXDEV));
+            };
+            let name = CString::new(name.as_bytes()).map_err(|_| einval())?;
+            dir = self.open_file_restricted(&dir, &name, flags, 0)?;
+        }
+        Ok(dir)
+    }
+
+    /// Switch `handle` over to the file currently at the name it was opened with if the file it
+    /// has open was unlinked in the meantime, see `Config::reopen_on_replace`.
+    async fn reopen_if_replaced(
+        &self,
+        handle: Handle,
+        data: Arc<HandleData>,
+    ) -> io::Result<Arc<HandleData>> {
+        let Some(origin) = data.origin.clone() else {
+            return Ok(data);
+        };
+        let (dir, name) = &*origin;
+        if stat_fd(data.get_file(), None)?.st_nlink != 0 {
+            return Ok(data);
+        }
+
+        // If the name doesn't refer to a regular file anymore, stick with the open inode.
+        let flags = data.get_flags().await;
+        let open_flags = flags as i32 & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+        let file = match self.open_file_restricted(dir, name, open_flags, 0) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!("reopen_if_replaced: failed to reopen {name:?}: {e}");
+                return Ok(data);
+            }
+        };
+        if stat_fd(&file, None)?.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return Ok(data);
+        }
+
+        debug!("reopen_if_replaced: handle {handle} reopened {name:?}");
+        self.invalidate_mmap_cache(data.inode, 0).await;
+        let new_data = HandleData::new(data.inode, file, flags).with_origin(Some(origin.clone()));
+        Ok(self.handle_map.replace(handle, &data, new_data).await)
+    }
+
+    /// Thin wrapper around `lseek64`/`lseek` returning the resulting offset.
+    fn raw_lseek(fd: RawFd, offset: i64, whence: libc::c_int) -> io::Result<i64> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            #[cfg(target_os = "linux")]
+            {
+                libc::lseek64(fd, offset as libc::off64_t, whence)
+            }
+            #[cfg(target_os = "macos")]
+            {
+                libc::lseek(fd, offset as libc::off_t, whence)
+            }
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as i64)
+        }
+    }
+
     /// Core implementation for `create`.
     ///
     /// It uses the provided `uid` and `gid` for credential switching if they are `Some`;
@@ -594,24 +1213,51 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> Result<ReplyCreated> {
+        self.check_writable()?;
         let name = osstr_to_cstr(name).unwrap();
         let name = name.as_ref();
         self.validate_path_component(name)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
 
         let dir = self.inode_map.get(parent).await?;
         let dir_file = dir.get_file()?;
 
-        let new_file = {
-            // Here we need to adjust the code order because guard doesn't allowed to cross await point
-            let flags = self.get_writeback_open_flags(flags as i32).await;
-            let _guard = set_creds(
-                uid.unwrap_or(self.cfg.mapping.get_uid(req.uid)),
-                gid.unwrap_or(self.cfg.mapping.get_gid(req.gid)),
-            )?;
-            Self::create_file_excl(&dir_file, name, flags, mode)?
-        };
+        // Racing creates of the same name converge on one inode: `create_file_excl()` lets only
+        // one of them create the file, the others open it, and `do_lookup()` hands all of them
+        // the inode of the first lookup of its identity. Without `serialize_inode_ops` the file
+        // may be unlinked between finding it exists and looking it up; create it again then, as
+        // open(2) would.
+        let mut attempts = 0;
+        let (new_file, mut entry) = loop {
+            attempts += 1;
+            let new_file = {
+                // Here we need to adjust the code order because guard doesn't allowed to cross await point
+                let flags = self.get_writeback_open_flags(flags as i32).await;
+                let _guard = set_creds(
+                    uid.unwrap_or(self.cfg.mapping.get_uid(req.uid)),
+                    gid.unwrap_or(self.cfg.mapping.get_gid(req.gid)),
+                )?;
+                Self::create_file_excl(&dir_file, name, flags, mode)?
+            };
 
-        let entry = self.do_lookup(parent, name).await?;
+            // Opening an existing directory for writing fails with EISDIR, but an O_RDONLY create
+            // would succeed and hand out a directory as a created file. Check up front, so that
+            // all flags get the same error and no lookup reference is taken.
+            if new_file.is_none() {
+                let st = match self.statx(&dir_file, Some(name)) {
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) && attempts < 3 => continue,
+                    st => st?,
+                };
+                if st.st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                    return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+                }
+            }
+
+            match self.do_lookup(parent, name).await {
+                Err(e) if new_file.is_none() && e.is_not_exist() && attempts < 3 => continue,
+                entry => break (new_file, entry?),
+            }
+        };
         let file = match new_file {
             // File didn't exist, now created by create_file_excl()
             Some(f) => f,
@@ -653,9 +1299,27 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
         };
 
+        // The attributes from `do_lookup()` predate the truncation of an existing file, don't
+        // let the client cache the old size.
+        if flags as i32 & libc::O_TRUNC != 0 {
+            let attr = convert_stat64_to_file_attr(stat_fd(&file, None)?);
+            entry.attr.size = attr.size;
+            entry.attr.blocks = attr.blocks;
+            entry.attr.mtime = attr.mtime;
+            entry.attr.ctime = attr.ctime;
+            self.invalidate_mmap_cache(entry.attr.ino, 0).await;
+        }
+
         let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
             let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-            let data = HandleData::new(entry.attr.ino, file, flags);
+            let origin = if self.cfg.reopen_on_replace {
+                self.open_dir_below(&dir_file, Path::new(""))
+                    .ok()
+                    .map(|dir| Arc::new((dir, name.to_owned())))
+            } else {
+                None
+            };
+            let data = HandleData::new(entry.attr.ino, file, flags).with_origin(origin);
             self.handle_map.insert(handle, data).await;
             handle
         } else {
@@ -669,6 +1333,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
         };
+        if self.writeback.load(Ordering::Relaxed) && flags & libc::O_APPEND as u32 != 0 {
+            opts |= OpenOptions::DIRECT_IO;
+        }
         Ok(ReplyCreated {
             ttl: entry.ttl,
             attr: entry.attr,
@@ -712,9 +1379,11 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> Result<ReplyEntry> {
+        self.check_writable()?;
         let name = osstr_to_cstr(name).unwrap();
         let name = name.as_ref();
         self.validate_path_component(name)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
 
         let data = self.inode_map.get(parent).await?;
         let file = data.get_file()?;
@@ -773,11 +1442,13 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> Result<ReplyEntry> {
+        self.check_writable()?;
         let name = osstr_to_cstr(name).unwrap();
         let name = name.as_ref();
         let link = osstr_to_cstr(link).unwrap();
         let link = link.as_ref();
         self.validate_path_component(name)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
 
         let data = self.inode_map.get(parent).await?;
         let file = data.get_file()?;
@@ -822,9 +1493,13 @@ impl Filesystem for PassthroughFs {
         if self.cfg.do_import {
             self.import().await?;
         }
+        // The session negotiates `FUSE_WRITEBACK_CACHE` from `MountOptions::write_back`, which
+        // is to be set along with `Config::writeback`.
+        self.writeback.store(self.cfg.writeback, Ordering::Relaxed);
 
         Ok(ReplyInit {
             max_write: NonZeroU32::new(128 * 1024).unwrap(),
+            time_gran: NonZeroU32::new(self.cfg.time_gran.clamp(1, 1_000_000_000)).unwrap(),
         })
     }
 
@@ -834,6 +1509,7 @@ impl Filesystem for PassthroughFs {
     /// <https://sourceforge.net/p/fuse/mailman/message/31995737/>
     async fn destroy(&self, _req: Request) {
         self.handle_map.clear().await;
+        self.open_dirs.store(0, Ordering::Release);
         self.inode_map.clear().await;
 
         if let Err(e) = self.import().await {
@@ -843,12 +1519,14 @@ impl Filesystem for PassthroughFs {
 
     /// look up a directory entry by name and get its attributes.
     async fn lookup(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<ReplyEntry> {
+        self.check_backend()?;
         // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
         if name.to_string_lossy().as_bytes().contains(&SLASH_ASCII) {
             return Err(einval().into());
         }
         let name = osstr_to_cstr(name).unwrap();
         // trace!("lookup: parent={}, name={}", parent, name.to_str().unwrap());
+        self.throttle_metadata().await;
         self.do_lookup(parent, name.as_ref()).await
     }
 
@@ -875,10 +1553,26 @@ impl Filesystem for PassthroughFs {
         fh: Option<u64>,
         _flags: u32,
     ) -> Result<ReplyAttr> {
-        let re = self.do_getattr(inode, fh).await?;
+        self.check_backend()?;
+        self.throttle_metadata().await;
+        let (mut st, ttl) = self.do_getattr(inode, fh).await?;
+        // `FUSE_GETATTR_FH`, the only getattr flag, is what sets `fh`. Queried through a handle
+        // opened with `O_DIRECT`, report a block size the direct I/O alignment divides, so
+        // clients sizing their buffers by it issue requests the host accepts.
+        #[allow(clippy::bad_bit_mask)]
+        if let Some(fh) = fh
+            && self.cfg.allow_direct_io
+        {
+            let hd = self.handle_map.get(fh, inode).await?;
+            if hd.get_flags().await as i32 & O_DIRECT != 0
+                && let Some(align) = dio_offset_align(hd.get_file())
+            {
+                st.st_blksize = ((st.st_blksize as u32).max(align).div_ceil(align) * align) as _;
+            }
+        }
         Ok(ReplyAttr {
-            ttl: re.1,
-            attr: convert_stat64_to_file_attr(re.0),
+            ttl,
+            attr: convert_stat64_to_file_attr(st),
         })
     }
 
@@ -890,6 +1584,9 @@ impl Filesystem for PassthroughFs {
         fh: Option<u64>,
         set_attr: SetAttr,
     ) -> Result<ReplyAttr> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let _inode_lock = self.lock_inodes(&[inode]).await;
         let inode_data = self.inode_map.get(inode).await?;
 
         enum Data {
@@ -933,10 +1630,14 @@ impl Filesystem for PassthroughFs {
             }
         }
 
-        if let (Some(uid_in), Some(gid_in)) = (set_attr.uid, set_attr.gid) {
-            //valid.intersects(SetattrValid::UID | SetattrValid::GID)
-            let uid = self.cfg.mapping.get_uid(uid_in);
-            let gid = self.cfg.mapping.get_gid(gid_in);
+        if set_attr.uid.is_some() || set_attr.gid.is_some() {
+            // `chown(2)` leaves an id of -1 as it is, as when only the owner or group is set.
+            let uid = set_attr
+                .uid
+                .map_or(libc::uid_t::MAX, |uid| self.cfg.mapping.get_uid(uid));
+            let gid = set_attr
+                .gid
+                .map_or(libc::gid_t::MAX, |gid| self.cfg.mapping.get_gid(gid));
 
             // Safe because this is a constant value and a valid C string.
             let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
@@ -973,6 +1674,7 @@ impl Filesystem for PassthroughFs {
             if res < 0 {
                 return Err(io::Error::last_os_error().into());
             }
+            self.mirror(MirrorOp::Truncate { inode, size }).await?;
         }
 
         if set_attr.atime.is_some() || set_attr.mtime.is_some() {
@@ -985,46 +1687,48 @@ impl Filesystem for PassthroughFs {
             // - If both nsec == 0 and timestamp is in the past: likely utime(&times)
             // - Otherwise: likely utime(NULL) which gets current time with nsec precision
 
-            // SAFETY: libc::time with null pointer is a read-only syscall that always
-            // succeeds and doesn't modify memory.
-            let now = unsafe { libc::time(std::ptr::null_mut()) };
+            // Not `time()`, which reads a coarse clock that can still be in the previous second
+            // when the caller took its timestamp.
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+
+            // With writeback caching the kernel keeps the mtime of cached writes and flushes it
+            // later along with the ctime, from whichever task writes the inode back: its
+            // credentials are those of the flusher, not of the writer. Such an update only needs
+            // what a write would, and the kernel's writeback worker runs as root. The kernel
+            // sends it with the handle the writes went through, an explicit utimensat() comes
+            // without one even when the file is open.
+            let written_through = match data {
+                Data::Handle(ref h) => {
+                    // Safe because this doesn't modify any memory and we check the return value.
+                    let fl = unsafe { libc::fcntl(h.borrow_fd().as_raw_fd(), libc::F_GETFL) };
+                    fl >= 0 && fl & libc::O_ACCMODE != libc::O_RDONLY
+                }
+                Data::ProcPath(_) => false,
+            };
+            let flushed_times = self.writeback.load(Ordering::Relaxed)
+                && written_through
+                && set_attr.atime.is_none()
+                && set_attr.ctime.is_some()
+                && set_attr.mtime.is_some_and(|mtime| mtime.sec <= now);
 
             // Heuristic: utime(&times) typically sets whole seconds (both nsec=0) to past times.
             // utime(NULL) sets current time which usually has non-zero nsec.
             // Both timestamps and both conditions must be satisfied to avoid false positives.
-            let is_utime_times =
-                if let (Some(atime_ts), Some(mtime_ts)) = (set_attr.atime, set_attr.mtime) {
-                    (atime_ts.nsec == 0 && mtime_ts.nsec == 0)
-                        && (atime_ts.sec < now && mtime_ts.sec < now)
-                } else {
-                    // If one is None, it's likely a specific update, treat as requiring ownership.
-                    true
-                };
+            let is_utime_times = if flushed_times {
+                false
+            } else if let (Some(atime_ts), Some(mtime_ts)) = (set_attr.atime, set_attr.mtime) {
+                (atime_ts.nsec == 0 && mtime_ts.nsec == 0)
+                    && (atime_ts.sec < now && mtime_ts.sec < now)
+            } else {
+                // If one is None, it's likely a specific update, treat as requiring ownership.
+                true
+            };
 
             let st = stat_fd(&file, None)?;
-            let uid = self.cfg.mapping.get_uid(req.uid);
-            let gid = self.cfg.mapping.get_gid(req.gid);
-
-            let is_owner = st.st_uid == uid;
-
-            if !is_owner {
-                if is_utime_times {
-                    // utime(&times): only owner allowed
-                    return Err(io::Error::from_raw_os_error(libc::EPERM).into());
-                } else {
-                    // utime(NULL): check for write permission
-                    // Check user, group, and other permissions
-                    // NOTE: This currently only checks the primary gid. A complete POSIX-compliant
-                    // implementation should check all supplementary groups from req.groups if available.
-                    // However, rfuse3::Request currently doesn't expose supplementary group information.
-                    let has_user_write = st.st_uid == uid && st.st_mode & 0o200 != 0;
-                    let has_group_write = st.st_gid == gid && st.st_mode & 0o020 != 0;
-                    let has_other_write = st.st_mode & 0o002 != 0;
-
-                    if !has_user_write && !has_group_write && !has_other_write {
-                        return Err(io::Error::from_raw_os_error(libc::EPERM).into());
-                    }
-                }
+            if !(flushed_times && self.cfg.mapping.get_uid(req.uid) == 0) {
+                self.check_set_times(&req, &st, is_utime_times)?;
             }
             let mut tvs: [libc::timespec; 2] = [
                 libc::timespec {
@@ -1073,6 +1777,7 @@ impl Filesystem for PassthroughFs {
 
     /// read symbolic link.
     async fn readlink(&self, _req: Request, inode: Inode) -> Result<ReplyData> {
+        self.check_backend()?;
         // Safe because this is a constant value and a valid C string.
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
         let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
@@ -1096,6 +1801,16 @@ impl Filesystem for PassthroughFs {
         // Safe because we trust the value returned by kernel.
         unsafe { buf.set_len(res as usize) };
 
+        if self.cfg.confine_symlinks && buf.starts_with(b"/") {
+            let root = self.readlinkat_proc_file(ROOT_ID).await?;
+            let path = self.readlinkat_proc_file(inode).await?;
+            let depth = path
+                .strip_prefix(&root)
+                .map(|rel| rel.components().count().saturating_sub(1))
+                .unwrap_or(0);
+            buf = util::confine_symlink_target(&buf, depth);
+        }
+
         Ok(ReplyData {
             data: Bytes::from(buf),
         })
@@ -1109,8 +1824,17 @@ impl Filesystem for PassthroughFs {
         name: &OsStr,
         link: &OsStr,
     ) -> Result<ReplyEntry> {
-        self.do_symlink_inner(req, parent, name, link, None, None)
-            .await
+        self.check_backend()?;
+        let entry = self
+            .do_symlink_inner(req, parent, name, link, None, None)
+            .await?;
+        self.mirror(MirrorOp::Symlink {
+            parent,
+            name,
+            target: link,
+        })
+        .await?;
+        Ok(entry)
     }
 
     /// create file node. Create a regular file, character device, block device, fifo or socket
@@ -1124,9 +1848,12 @@ impl Filesystem for PassthroughFs {
         mode: u32,
         rdev: u32,
     ) -> Result<ReplyEntry> {
-        let name = osstr_to_cstr(name).unwrap();
-        let name = name.as_ref();
-        self.validate_path_component(name)?;
+        self.check_backend()?;
+        self.check_writable()?;
+        let cname = osstr_to_cstr(name).unwrap();
+        let cname = cname.as_ref();
+        self.validate_path_component(cname)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
 
         let data = self.inode_map.get(parent).await?;
         let file = data.get_file()?;
@@ -1141,17 +1868,27 @@ impl Filesystem for PassthroughFs {
             unsafe {
                 libc::mknodat(
                     file.as_raw_fd(),
-                    name.as_ptr(),
+                    cname.as_ptr(),
                     (mode) as libc::mode_t,
                     rdev as libc::dev_t,
                 )
             }
         };
         if res < 0 {
-            Err(io::Error::last_os_error().into())
-        } else {
-            self.do_lookup(parent, name).await
+            return Err(io::Error::last_os_error().into());
+        }
+        let entry = self.do_lookup(parent, cname).await?;
+        if util::filetype_from_mode(mode) == FileType::RegularFile {
+            // mknod only creates new files, which are empty.
+            self.mirror(MirrorOp::Create {
+                parent,
+                name,
+                mode,
+                truncate: true,
+            })
+            .await?;
         }
+        Ok(entry)
     }
 
     /// create a directory.
@@ -1163,26 +1900,41 @@ impl Filesystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> Result<ReplyEntry> {
-        self.do_mkdir_inner(req, parent, name, mode, umask, None, None)
-            .await
+        self.check_backend()?;
+        let entry = self
+            .do_mkdir_inner(req, parent, name, mode, umask, None, None)
+            .await?;
+        let mode = mode & !umask;
+        self.mirror(MirrorOp::Mkdir { parent, name, mode }).await?;
+        Ok(entry)
     }
 
     /// remove a file.
-    async fn unlink(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        let name = osstr_to_cstr(name).unwrap();
-        let name = name.as_ref();
-        self.validate_path_component(name)?;
-        self.do_unlink(parent, name, 0).await.map_err(|e| e.into())
+    async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let cname = osstr_to_cstr(name).unwrap();
+        let cname = cname.as_ref();
+        self.validate_path_component(cname)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
+        self.check_sticky(&req, parent, cname).await?;
+        if self.defer_unlink(parent, cname, false).await?.is_none() {
+            self.do_unlink(parent, cname, 0).await?;
+        }
+        self.mirror(MirrorOp::Unlink { parent, name }).await
     }
 
     /// remove a directory.
-    async fn rmdir(&self, _req: Request, parent: Inode, name: &OsStr) -> Result<()> {
-        let name = osstr_to_cstr(name).unwrap();
-        let name = name.as_ref();
-        self.validate_path_component(name)?;
-        self.do_unlink(parent, name, libc::AT_REMOVEDIR)
-            .await
-            .map_err(|e| e.into())
+    async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let cname = osstr_to_cstr(name).unwrap();
+        let cname = cname.as_ref();
+        self.validate_path_component(cname)?;
+        let _inode_lock = self.lock_inodes(&[parent]).await;
+        self.check_sticky(&req, parent, cname).await?;
+        self.do_unlink(parent, cname, libc::AT_REMOVEDIR).await?;
+        self.mirror(MirrorOp::Rmdir { parent, name }).await
     }
 
     /// create a hard link.
@@ -1193,6 +1945,8 @@ impl Filesystem for PassthroughFs {
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<ReplyEntry> {
+        self.check_backend()?;
+        self.check_writable()?;
         trace!(
             "passthrough: link: inode={}, new_parent={}, new_name={}",
             inode,
@@ -1202,6 +1956,7 @@ impl Filesystem for PassthroughFs {
         let newname = osstr_to_cstr(new_name).unwrap();
         let newname = newname.as_ref();
         self.validate_path_component(newname)?;
+        let _inode_lock = self.lock_inodes(&[new_parent]).await;
 
         trace!("link: trying to get inode {inode}");
         let data = self.inode_map.get(inode).await?;
@@ -1210,6 +1965,12 @@ impl Filesystem for PassthroughFs {
         let file = data.get_file()?;
         let new_file = new_inode.get_file()?;
 
+        if let Some(max_links) = self.cfg.max_links
+            && stat_fd(&file, None)?.st_nlink as u64 >= max_links
+        {
+            return Err(io::Error::from_raw_os_error(libc::EMLINK).into());
+        }
+
         // Safe because this is a constant value and a valid C string.
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
 
@@ -1230,7 +1991,14 @@ impl Filesystem for PassthroughFs {
                 new_parent,
                 newname.to_str().unwrap()
             );
-            self.do_lookup(new_parent, newname).await
+            let entry = self.do_lookup(new_parent, newname).await?;
+            self.mirror(MirrorOp::Link {
+                inode,
+                new_parent,
+                new_name,
+            })
+            .await?;
+            Ok(entry)
         } else {
             trace!(
                 "passthrough: link: inode={}, new_parent={}, new_name={}, res={}",
@@ -1258,6 +2026,7 @@ impl Filesystem for PassthroughFs {
     /// [fuse_common.h](https://libfuse.github.io/doxygen/include_2fuse__common_8h_source.html) for
     /// more details.
     async fn open(&self, _req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        self.check_backend()?;
         if self.no_open.load(Ordering::Relaxed) {
             info!("fuse: open is not supported.");
             Err(enosys().into())
@@ -1283,6 +2052,7 @@ impl Filesystem for PassthroughFs {
         offset: u64,
         size: u32,
     ) -> Result<ReplyData> {
+        self.check_backend()?;
         let data = self.get_data(fh, inode, libc::O_RDONLY).await?;
         let _guard = data.lock.lock().await;
         let raw_fd = data.borrow_fd().as_raw_fd();
@@ -1312,54 +2082,65 @@ impl Filesystem for PassthroughFs {
                 const ALIGN: usize = 4096;
                 let open_flags = data.get_flags().await;
                 #[allow(clippy::bad_bit_mask)]
-                let ret = if (open_flags as i32 & O_DIRECT) != 0 {
-                    let mut aligned_buf = unsafe {
-                        let layout = std::alloc::Layout::from_size_align(size as _, ALIGN).unwrap();
-                        let ptr = std::alloc::alloc(layout);
-                        if ptr.is_null() {
-                            return Err(io::Error::from_raw_os_error(libc::ENOMEM).into());
+                let ret = util::retry_io(self.cfg.io_retry.as_ref(), || {
+                    let ret = if (open_flags as i32 & O_DIRECT) != 0 {
+                        let mut aligned_buf = unsafe {
+                            let layout =
+                                std::alloc::Layout::from_size_align(size as _, ALIGN).unwrap();
+                            let ptr = std::alloc::alloc(layout);
+                            if ptr.is_null() {
+                                return Err(io::Error::from_raw_os_error(libc::ENOMEM));
+                            }
+                            Vec::from_raw_parts(ptr, size as _, size as _)
+                        };
+                        let ret = unsafe {
+                            pread(
+                                raw_fd as c_int,
+                                aligned_buf.as_mut_ptr() as *mut libc::c_void,
+                                size as size_t,
+                                offset as off_t,
+                            )
+                        };
+
+                        if ret >= 0 {
+                            let bytes_read = ret as usize;
+                            buf.as_mut_slice()[..bytes_read]
+                                .copy_from_slice(&aligned_buf[..bytes_read]);
+                        }
+                        ret
+                    } else {
+                        unsafe {
+                            pread(
+                                raw_fd as c_int,
+                                buf.as_mut_ptr() as *mut libc::c_void,
+                                size as size_t,
+                                offset as off_t,
+                            )
                         }
-                        Vec::from_raw_parts(ptr, size as _, size as _)
-                    };
-                    let ret = unsafe {
-                        pread(
-                            raw_fd as c_int,
-                            aligned_buf.as_mut_ptr() as *mut libc::c_void,
-                            size as size_t,
-                            offset as off_t,
-                        )
                     };
-
-                    if ret >= 0 {
+                    if ret < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(ret)
+                    }
+                })
+                .await;
+                match ret {
+                    Ok(ret) => {
                         let bytes_read = ret as usize;
-                        buf.as_mut_slice()[..bytes_read]
-                            .copy_from_slice(&aligned_buf[..bytes_read]);
+                        buf.truncate(bytes_read);
                     }
-                    ret
-                } else {
-                    unsafe {
-                        pread(
-                            raw_fd as c_int,
-                            buf.as_mut_ptr() as *mut libc::c_void,
-                            size as size_t,
-                            offset as off_t,
-                        )
+                    Err(e) => {
+                        error!("read error: {e:?}");
+                        error!(
+                            "pread raw_fd={}, pointer={:p}, size={}, offset={}",
+                            raw_fd,
+                            buf.as_mut_ptr(),
+                            size,
+                            offset
+                        );
+                        return Err(e.into());
                     }
-                };
-                if ret < 0 {
-                    let e = io::Error::last_os_error();
-                    error!("read error: {e:?}");
-                    error!(
-                        "pread raw_fd={}, pointer={:p}, size={}, offset={}",
-                        raw_fd,
-                        buf.as_mut_ptr(),
-                        size,
-                        offset
-                    );
-                    return Err(e.into());
-                } else {
-                    let bytes_read = ret as usize;
-                    buf.truncate(bytes_read);
                 }
             }
         }
@@ -1368,7 +2149,36 @@ impl Filesystem for PassthroughFs {
             data: Bytes::from(buf),
         })
     }
-
+
+    /// read data, replying with a range of the host file when `Config::use_splice` is enabled.
+    async fn read_splice(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyRead> {
+        self.check_backend()?;
+        if cfg!(target_os = "linux") && self.cfg.use_splice {
+            let data = self.get_data(fh, inode, libc::O_RDONLY).await?;
+            #[allow(clippy::bad_bit_mask)]
+            if data.get_flags().await as i32 & O_DIRECT == 0 {
+                if offset > i64::MAX as u64 {
+                    error!("read error: offset too large: {}", offset);
+                    return Err(Errno::from(libc::EOVERFLOW));
+                }
+                return Ok(ReplyRead::Splice {
+                    fd: data,
+                    offset,
+                    len: size,
+                });
+            }
+        }
+        self.read(req, inode, fh, offset, size)
+            .await
+            .map(Into::into)
+    }
     /// write data. Write should return exactly the number of bytes requested except on error. An
     /// exception to this is when the file has been opened in `direct_io` mode, in which case the
     /// return value of the write system call will reflect the return value of this operation. `fh`
@@ -1384,15 +2194,29 @@ impl Filesystem for PassthroughFs {
         fh: u64,
         offset: u64,
         data: &[u8],
-        _write_flags: u32,
+        write_flags: u32,
         flags: u32,
     ) -> Result<ReplyWrite> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let _inode_lock = self.lock_inodes(&[inode]).await;
         let handle_data = self.get_data(fh, inode, libc::O_RDWR).await?;
         let file = &handle_data.file;
         let _guard = handle_data.lock.lock().await;
         let raw_fd = handle_data.borrow_fd().as_raw_fd();
 
-        let res = if self.cfg.use_mmap {
+        // Writes through an `O_APPEND` handle are appended by the host at wherever the file ends
+        // by then, ignoring `offset`, so they stay atomic with other appenders. Writes of cached
+        // pages go to their offset even if the kernel sends them through such a handle.
+        let cached = write_flags & FUSE_WRITE_CACHE != 0;
+        let append = !cached && flags & libc::O_APPEND as u32 != 0;
+        let fd_flags = if cached {
+            flags & !(libc::O_APPEND as u32)
+        } else {
+            flags
+        };
+
+        let res = if self.cfg.use_mmap && !append {
             self.write_to_mmap(inode, offset, data, file).await.ok()
         } else {
             None
@@ -1406,32 +2230,89 @@ impl Filesystem for PassthroughFs {
                     error!("write error: offset too large: {}", offset);
                     return Err(Errno::from(libc::EOVERFLOW));
                 }
-                self.check_fd_flags(&handle_data, raw_fd, flags).await?;
-                let ret = unsafe {
-                    libc::pwrite(
-                        raw_fd as c_int,
-                        data.as_ptr() as *const libc::c_void,
-                        size as size_t,
-                        offset as off_t,
-                    )
+                self.check_fd_flags(&handle_data, raw_fd, fd_flags).await?;
+                #[allow(clippy::bad_bit_mask)]
+                let direct = !append && self.cfg.allow_direct_io && fd_flags as i32 & O_DIRECT != 0;
+                // Without `STATX_DIOALIGN`, whether the write is aligned is left to the host.
+                let align = if direct {
+                    dio_offset_align(file).unwrap_or(1)
+                } else {
+                    0
                 };
-                if ret >= 0 {
-                    ret
+                // A write of cached pages keeps them as separate buffers, as the kernel sent them.
+                // macOS writes one buffer per call, so it gets them all in one.
+                let segments = if cfg!(target_os = "linux") && cached {
+                    let page_size = get_page_size().unwrap_or(4096);
+                    util::page_segments(data, offset, page_size)
                 } else {
-                    let e = io::Error::last_os_error();
-                    error!("write error: {e:?}");
-                    error!(
-                        "pwrite raw_fd={}, pointer={:p}, size={}, offset={}",
-                        raw_fd,
-                        data.as_ptr(),
-                        size,
-                        offset
-                    );
-                    return Err(Errno::from(e.raw_os_error().unwrap_or(-1)));
+                    vec![IoSlice::new(data)]
+                };
+                let ret = util::retry_io(self.cfg.io_retry.as_ref(), || {
+                    if direct {
+                        util::pwrite_direct(
+                            raw_fd,
+                            data,
+                            offset,
+                            align.into(),
+                            self.cfg.direct_io_writes,
+                        )
+                    } else if append {
+                        util::writev(raw_fd, &segments)
+                    } else {
+                        util::pwritev(raw_fd, &segments, offset)
+                    }
+                })
+                .await;
+                // A short write is returned as is, the kernel retries the remainder and gets
+                // the ENOSPC/EDQUOT from the next call. An append retried here could be split
+                // around another appender's data.
+                // Nothing about the inode's size is cached here, so the client refreshes it from
+                // the host on the next getattr.
+                match ret {
+                    Ok(ret) => ret as isize,
+                    Err(e) => {
+                        if matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT)) {
+                            debug!("write: inode={inode}, offset={offset}, size={size}: {e}");
+                            return Err(e.into());
+                        }
+                        error!("write error: {e:?}");
+                        error!(
+                            "pwrite raw_fd={}, pointer={:p}, size={}, offset={}",
+                            raw_fd,
+                            data.as_ptr(),
+                            size,
+                            offset
+                        );
+                        return Err(Errno::from(e.raw_os_error().unwrap_or(-1)));
+                    }
                 }
             }
         };
 
+        // The host put the data just before the file position the append left behind.
+        let offset = if append && self.cfg.mirror_dir.is_some() {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let pos = unsafe { libc::lseek(raw_fd, 0, libc::SEEK_CUR) };
+            if pos < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            pos as u64 - ret as u64
+        } else {
+            offset
+        };
+        self.mirror(MirrorOp::Write {
+            inode,
+            offset,
+            data: &data[..ret as usize],
+        })
+        .await?;
+        // What the client wrote is in its cache already.
+        if self.cfg.drop_cache_on_change
+            && let Ok(data) = self.inode_map.get(inode).await
+        {
+            let _ = data.update_change_stamp(file);
+        }
+
         Ok(ReplyWrite {
             written: ret as u32,
         })
@@ -1439,40 +2320,15 @@ impl Filesystem for PassthroughFs {
 
     /// get filesystem statistics.
     async fn statfs(&self, _req: Request, inode: Inode) -> Result<ReplyStatFs> {
-        let data = self.inode_map.get(inode).await?;
-        let file = data.get_file()?;
-
-        #[cfg(target_os = "linux")]
-        let statfs = {
-            let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
-            match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
-                0 => unsafe { out.assume_init() },
-                _ => return Err(io::Error::last_os_error().into()),
-            }
-        };
-
-        #[cfg(target_os = "macos")]
-        let statfs = {
-            let mut out = MaybeUninit::<libc::statvfs>::zeroed();
-            match unsafe { libc::fstatvfs(file.as_raw_fd(), out.as_mut_ptr()) } {
-                0 => unsafe { out.assume_init() },
-                _ => return Err(io::Error::last_os_error().into()),
-            }
+        self.check_backend()?;
+        let Some(cache) = &self.statfs_cache else {
+            return Ok(self.do_statfs_reply(inode).await?);
         };
-
-        Ok(
-            // Populate the ReplyStatFs structure with the necessary information
-            ReplyStatFs {
-                blocks: statfs.f_blocks as u64,
-                bfree: statfs.f_bfree as u64,
-                bavail: statfs.f_bavail as u64,
-                files: statfs.f_files as u64,
-                ffree: statfs.f_ffree as u64,
-                bsize: statfs.f_bsize as u32,
-                namelen: statfs.f_namemax as u32,
-                frsize: statfs.f_frsize as u32,
-            },
-        )
+        let dev = self.inode_map.get(inode).await?.id.dev;
+        cache
+            .try_get_with(dev, self.do_statfs_reply(inode))
+            .await
+            .map_err(|e| io::Error::from_raw_os_error(e.raw_os_error().unwrap_or(libc::EIO)).into())
     }
 
     /// release an open file. Release is called when there are no more references to an open file:
@@ -1501,6 +2357,7 @@ impl Filesystem for PassthroughFs {
     /// synchronize file contents. If the `datasync` is true, then only the user data should be
     /// flushed, not the metadata.
     async fn fsync(&self, _req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.check_backend()?;
         let data = self.get_data(fh, inode, libc::O_RDONLY).await?;
         let fd = data.borrow_fd();
 
@@ -1529,17 +2386,27 @@ impl Filesystem for PassthroughFs {
     /// set an extended attribute.
     async fn setxattr(
         &self,
-        _req: Request,
+        req: Request,
         inode: Inode,
         name: &OsStr,
         value: &[u8],
         flags: u32,
         _position: u32,
     ) -> Result<()> {
+        if self.is_control_xattr(inode, name) {
+            return Ok(self.control_setxattr(&req, name, value).await?);
+        }
+        self.check_backend()?;
         if !self.cfg.xattr {
             return Err(enosys().into());
         }
-        let name = osstr_to_cstr(name).unwrap();
+        let name = self.host_xattr_name(name)?;
+        self.check_writable()?;
+        if let Some(max) = self.cfg.max_xattr_size
+            && value.len() > max
+        {
+            return Err(io::Error::from_raw_os_error(libc::E2BIG).into());
+        }
         let name = name.as_ref();
         let data = self.inode_map.get(inode).await?;
         let file = data.get_file()?;
@@ -1548,7 +2415,11 @@ impl Filesystem for PassthroughFs {
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to use the {set,get,remove,list}xattr variants.
+        // need to use the {set,get,remove,list}xattr variants. Following the fd's link in /proc
+        // ends at the inode the fd was opened on, so for a symlink, which is opened with
+        // `O_NOFOLLOW`, that's the link itself, while the l* variants would take the link in
+        // /proc for the inode. On macOS the fd of a symlink is opened with `O_SYMLINK`, see
+        // `xattr_options()` for the rest.
         // Safe because this doesn't modify any memory and we check the return value.
         let res = match () {
             #[cfg(target_os = "linux")]
@@ -1569,7 +2440,7 @@ impl Filesystem for PassthroughFs {
                     value.as_ptr() as *const libc::c_void,
                     value.len(),
                     0,
-                    flags as libc::c_int,
+                    xattr_options(data.mode, flags),
                 )
             },
         };
@@ -1592,11 +2463,21 @@ impl Filesystem for PassthroughFs {
         name: &OsStr,
         size: u32,
     ) -> Result<ReplyXAttr> {
+        if self.is_control_xattr(inode, name) {
+            let value = self.control_getxattr(name).await?;
+            return if size == 0 {
+                Ok(ReplyXAttr::Size(value.len() as u32))
+            } else if value.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE).into())
+            } else {
+                Ok(ReplyXAttr::Data(Bytes::from(value)))
+            };
+        }
+        self.check_backend()?;
         if !self.cfg.xattr {
             return Err(enosys().into());
         }
-        let name =
-            osstr_to_cstr(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let name = self.host_xattr_name(name)?;
         let name = name.as_ref();
         let data = self.inode_map.get(inode).await?;
         let file = data.get_file()?;
@@ -1626,7 +2507,7 @@ impl Filesystem for PassthroughFs {
                     buf.as_mut_ptr() as *mut libc::c_void,
                     size as libc::size_t,
                     0,
-                    0,
+                    xattr_options(data.mode, 0),
                 )
             },
         };
@@ -1650,60 +2531,104 @@ impl Filesystem for PassthroughFs {
     /// If `size` is too small, return `Err<ERANGE>`.  Otherwise, use
     /// [`ReplyXAttr::Data`] to send the attribute list, or return an error.
     async fn listxattr(&self, _req: Request, inode: Inode, size: u32) -> Result<ReplyXAttr> {
+        self.check_backend()?;
         if !self.cfg.xattr {
             return Err(enosys().into());
         }
 
         let data = self.inode_map.get(inode).await?;
         let file = data.get_file()?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
         #[cfg(target_os = "linux")]
         let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this will only modify the contents of `buf`.
-        let res = match () {
-            #[cfg(target_os = "linux")]
-            () => unsafe {
-                libc::listxattr(
-                    pathname.as_ptr(),
-                    buf.as_mut_ptr() as *mut libc::c_char,
-                    size as libc::size_t,
-                )
-            },
-            #[cfg(target_os = "macos")]
-            () => unsafe {
-                libc::flistxattr(
-                    file.as_raw_fd(),
-                    buf.as_mut_ptr() as *mut libc::c_char,
-                    size as libc::size_t,
-                    0,
-                )
-            },
+        let list = |buf: &mut Vec<u8>, size: usize| {
+            // Safe because this will only modify the contents of `buf`, which has room for `size`
+            // bytes.
+            let res = match () {
+                #[cfg(target_os = "linux")]
+                () => unsafe {
+                    libc::listxattr(
+                        pathname.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_char,
+                        size as libc::size_t,
+                    )
+                },
+                #[cfg(target_os = "macos")]
+                () => unsafe {
+                    libc::flistxattr(
+                        file.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_char,
+                        size as libc::size_t,
+                        xattr_options(data.mode, 0),
+                    )
+                },
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if size != 0 {
+                // Safe because we trust the value returned by kernel.
+                unsafe { buf.set_len(res as usize) };
+            }
+            Ok(res as usize)
         };
-        if res < 0 {
-            let e = io::Error::last_os_error();
-            // error!("listxattr error: {e:?}");
-            return Err(e.into());
+
+        if self.cfg.xattr_namespace_filter.is_some() || !self.cfg.xattr_name_map.is_empty() {
+            // The hidden and renamed names change the size too, so even a probe for the size
+            // needs the whole list.
+            let mut buf = Vec::new();
+            loop {
+                let len = list(&mut buf, 0)?;
+                if len == 0 {
+                    break;
+                }
+                buf.reserve(len);
+                match list(&mut buf, len) {
+                    Ok(_) => break,
+                    // The list grew in between.
+                    Err(e) if e.raw_os_error() == Some(libc::ERANGE) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let mut names = Vec::new();
+            for name in buf.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+                if let Some(name) = self.client_xattr_name(name) {
+                    names.extend_from_slice(&name);
+                    names.push(0);
+                }
+            }
+            return if size == 0 {
+                Ok(ReplyXAttr::Size(names.len() as u32))
+            } else if names.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE).into())
+            } else {
+                Ok(ReplyXAttr::Data(Bytes::from(names)))
+            };
         }
 
+        let mut buf = Vec::<u8>::with_capacity(size as usize);
+        let res = list(&mut buf, size as usize)?;
         if size == 0 {
             Ok(ReplyXAttr::Size(res as u32))
         } else {
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
             Ok(ReplyXAttr::Data(Bytes::from(buf)))
         }
     }
 
     /// remove an extended attribute.
     async fn removexattr(&self, _req: Request, inode: Inode, name: &OsStr) -> Result<()> {
+        if self.is_control_xattr(inode, name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM).into());
+        }
+        self.check_backend()?;
         if !self.cfg.xattr {
             return Err(enosys().into());
         }
-        let name = osstr_to_cstr(name).unwrap();
+        let name = self.host_xattr_name(name)?;
+        self.check_writable()?;
         let name = name.as_ref();
         let data = self.inode_map.get(inode).await?;
         let file = data.get_file()?;
@@ -1714,7 +2639,9 @@ impl Filesystem for PassthroughFs {
         #[cfg(target_os = "linux")]
         let res = unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) };
         #[cfg(target_os = "macos")]
-        let res = unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr(), 0) };
+        let res = unsafe {
+            libc::fremovexattr(file.as_raw_fd(), name.as_ptr(), xattr_options(data.mode, 0))
+        };
         if res == 0 {
             Ok(())
         } else {
@@ -1785,13 +2712,32 @@ impl Filesystem for PassthroughFs {
     /// sets [`MountOptions::no_open_dir_support`][rfuse3::MountOptions::no_open_dir_support] and
     /// if the kernel supports `FUSE_NO_OPENDIR_SUPPORT`.
     async fn opendir(&self, _req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        self.check_backend()?;
         if self.no_opendir.load(Ordering::Relaxed) {
             info!("fuse: opendir is not supported.");
             Err(enosys().into())
         } else {
-            let t = self
+            // Reserve a slot up front so that concurrent opendirs can't overshoot the limit.
+            let max = self.cfg.max_open_dirs.unwrap_or(usize::MAX);
+            if self
+                .open_dirs
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    (n < max).then_some(n + 1)
+                })
+                .is_err()
+            {
+                return Err(io::Error::from_raw_os_error(libc::EMFILE).into());
+            }
+            let t = match self
                 .do_open(inode, flags | (libc::O_DIRECTORY as u32))
-                .await?;
+                .await
+            {
+                Ok(t) => t,
+                Err(e) => {
+                    self.open_dirs.fetch_sub(1, Ordering::AcqRel);
+                    return Err(e.into());
+                }
+            };
             let fd = t.0.unwrap();
             Ok(ReplyOpen {
                 fh: fd,
@@ -1814,9 +2760,11 @@ impl Filesystem for PassthroughFs {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntry>> + Send + 'a,
         >,
     > {
+        self.check_backend()?;
         if self.no_readdir.load(Ordering::Relaxed) {
             return Err(enosys().into());
         }
+        self.throttle_metadata().await;
         let mut entry_list = Vec::new();
         self.do_readdir(parent, fh, offset as u64, &mut entry_list)
             .await?;
@@ -1839,9 +2787,11 @@ impl Filesystem for PassthroughFs {
             impl futures_util::stream::Stream<Item = Result<DirectoryEntryPlus>> + Send + 'a,
         >,
     > {
+        self.check_backend()?;
         if self.no_readdir.load(Ordering::Relaxed) {
             return Err(enosys().into());
         }
+        self.throttle_metadata().await;
         let mut entry_list = Vec::new();
         self.do_readdirplus(parent, fh, offset, &mut entry_list)
             .await?;
@@ -1859,7 +2809,9 @@ impl Filesystem for PassthroughFs {
             info!("fuse: releasedir is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS).into())
         } else {
-            self.do_release(inode, fh).await.map_err(|e| e.into())
+            self.do_release(inode, fh).await?;
+            self.open_dirs.fetch_sub(1, Ordering::AcqRel);
+            Ok(())
         }
     }
 
@@ -1868,6 +2820,7 @@ impl Filesystem for PassthroughFs {
     /// [`opendir`][Filesystem::opendir] method, or will be undefined if the
     /// [`opendir`][Filesystem::opendir] method didn't set any value.
     async fn fsyncdir(&self, req: Request, inode: Inode, fh: u64, datasync: bool) -> Result<()> {
+        self.check_backend()?;
         self.fsync(req, inode, fh, datasync).await
     }
 
@@ -1906,31 +2859,46 @@ impl Filesystem for PassthroughFs {
     /// `default_permissions` mount option is given, this method is not be called. This method is
     /// not called under Linux kernel versions 2.4.x.
     async fn access(&self, req: Request, inode: Inode, mask: u32) -> Result<()> {
+        self.check_backend()?;
         let data = self.inode_map.get(inode).await?;
-        let st = stat_fd(&data.get_file()?, None)?;
+        let file = data.get_file()?;
+        let st = stat_fd(&file, None)?;
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         let uid = self.cfg.mapping.get_uid(req.uid);
-        let gid = self.cfg.mapping.get_gid(req.gid);
 
         if mode == libc::F_OK {
             // The file exists since we were able to call `stat(2)` on it.
             return Ok(());
         }
 
+        // Group permissions apply through the primary as well as the supplementary groups.
+        let in_group = self.in_group(&req, st.st_gid);
+
         if (mode & libc::R_OK) != 0
             && uid != 0
             && (st.st_uid != uid || st.st_mode & 0o400 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o040 == 0)
+            && (!in_group || st.st_mode & 0o040 == 0)
             && st.st_mode & 0o004 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES).into());
         }
 
+        // A read-only file system refuses writes to everyone, but only to the files it stores:
+        // devices and FIFOs can still be written.
+        if (mode & libc::W_OK) != 0
+            && matches!(
+                st.st_mode & libc::S_IFMT,
+                libc::S_IFREG | libc::S_IFDIR | libc::S_IFLNK
+            )
+        {
+            self.check_file_writable(&file)?;
+        }
+
         if (mode & libc::W_OK) != 0
             && uid != 0
             && (st.st_uid != uid || st.st_mode & 0o200 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o020 == 0)
+            && (!in_group || st.st_mode & 0o020 == 0)
             && st.st_mode & 0o002 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES).into());
@@ -1941,7 +2909,7 @@ impl Filesystem for PassthroughFs {
         if (mode & libc::X_OK) != 0
             && (uid != 0 || st.st_mode & 0o111 == 0)
             && (st.st_uid != uid || st.st_mode & 0o100 == 0)
-            && (st.st_gid != gid || st.st_mode & 0o010 == 0)
+            && (!in_group || st.st_mode & 0o010 == 0)
             && st.st_mode & 0o001 == 0
         {
             return Err(io::Error::from_raw_os_error(libc::EACCES).into());
@@ -1974,8 +2942,18 @@ impl Filesystem for PassthroughFs {
         mode: u32,
         flags: u32,
     ) -> Result<ReplyCreated> {
-        self.do_create_inner(req, parent, name, mode, flags, None, None)
-            .await
+        self.check_backend()?;
+        let created = self
+            .do_create_inner(req, parent, name, mode, flags, None, None)
+            .await?;
+        self.mirror(MirrorOp::Create {
+            parent,
+            name,
+            mode,
+            truncate: flags as i32 & libc::O_TRUNC != 0,
+        })
+        .await?;
+        Ok(created)
     }
 
     /// handle interrupt. When a operation is interrupted, an interrupt request will send to fuse
@@ -2004,13 +2982,20 @@ impl Filesystem for PassthroughFs {
         _req: Request,
         inode: Inode,
         fh: u64,
-        _offset: u64,
-        _length: u64,
-        _mode: u32,
+        offset: u64,
+        length: u64,
+        mode: u32,
     ) -> Result<()> {
+        self.check_backend()?;
+        self.check_writable()?;
+        // A hole is only punched within the file, as the kernel insists.
+        if mode & util::FALLOC_FL_PUNCH_HOLE != 0 && mode & util::FALLOC_FL_KEEP_SIZE == 0 {
+            return Err(einval().into());
+        }
+        let _inode_lock = self.lock_inodes(&[inode]).await;
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self.get_data(fh, inode, libc::O_RDWR).await?;
-        let _fd = data.borrow_fd();
+        let fd = data.borrow_fd();
 
         //  if self.seal_size.load().await {
         //      let st = stat_fd(&fd, None)?;
@@ -2023,47 +3008,100 @@ impl Filesystem for PassthroughFs {
         //      )?;
         //  }
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            #[cfg(target_os = "linux")]
-            {
-                libc::fallocate64(
-                    _fd.as_raw_fd(),
-                    _mode as libc::c_int,
-                    _offset as libc::off64_t,
-                    _length as libc::off64_t,
-                )
-            }
-            #[cfg(target_os = "macos")]
-            {
-                // Stub fallocate
-                *libc::__error() = libc::ENOSYS;
-                -1
+        let fallocate =
+            |offset: u64, length: u64| util::do_fallocate(fd.as_raw_fd(), mode, offset, length);
+
+        // Collapsing or inserting a range shifts the data after it, so it can't be split.
+        #[cfg(target_os = "linux")]
+        let splittable =
+            mode as i32 & (libc::FALLOC_FL_COLLAPSE_RANGE | libc::FALLOC_FL_INSERT_RANGE) == 0;
+        #[cfg(target_os = "macos")]
+        let splittable = false;
+        let chunk = match self.cfg.fallocate_chunk_size {
+            Some(chunk) if chunk > 0 && splittable && length > chunk => chunk,
+            _ => {
+                fallocate(offset, length)?;
+                return self
+                    .mirror(MirrorOp::Fallocate {
+                        inode,
+                        mode,
+                        offset,
+                        length,
+                    })
+                    .await;
             }
         };
 
-        if res == 0 {
-            Ok(())
+        // What a failed chunk leaves behind is undone as far as the file's size goes.
+        let size = if mode & util::FALLOC_FL_KEEP_SIZE == 0 {
+            Some(util::stat_fd(&fd, None)?.st_size)
         } else {
-            Err(io::Error::last_os_error().into())
+            None
+        };
+        for (chunk_offset, chunk_length) in util::chunk_range(offset, length, chunk) {
+            if let Err(e) = fallocate(chunk_offset, chunk_length) {
+                if let Some(size) = size
+                    && chunk_offset > offset
+                    && util::stat_fd(&fd, None).is_ok_and(|st| st.st_size > size)
+                {
+                    // Safe because this doesn't modify any memory and we check the return value.
+                    if unsafe { libc::ftruncate(fd.as_raw_fd(), size) } < 0 {
+                        error!(
+                            "fallocate: inode {} failed to truncate back to {}: {}",
+                            inode,
+                            size,
+                            io::Error::last_os_error()
+                        );
+                    } else {
+                        // The mirror got the chunks done so far, it's cut back the same way.
+                        let _ = self
+                            .mirror(MirrorOp::Truncate {
+                                inode,
+                                size: size as u64,
+                            })
+                            .await;
+                    }
+                }
+                return Err(e.into());
+            }
+            self.mirror(MirrorOp::Fallocate {
+                inode,
+                mode,
+                offset: chunk_offset,
+                length: chunk_length,
+            })
+            .await?;
+            debug!(
+                "fallocate: inode {} done {} of {} bytes",
+                inode,
+                chunk_offset + chunk_length - offset,
+                length
+            );
+            tokio::task::yield_now().await;
         }
+        Ok(())
     }
 
     /// rename a file or directory.
     async fn rename(
         &self,
-        _req: Request,
+        req: Request,
         parent: Inode,
         name: &OsStr,
         new_parent: Inode,
         new_name: &OsStr,
     ) -> Result<()> {
+        self.check_backend()?;
+        self.check_writable()?;
         let oldname = osstr_to_cstr(name).unwrap();
         let oldname = oldname.as_ref();
         let newname = osstr_to_cstr(new_name).unwrap();
         let newname = newname.as_ref();
         self.validate_path_component(oldname)?;
         self.validate_path_component(newname)?;
+        let _inode_lock = self.lock_inodes(&[parent, new_parent]).await;
+        self.check_sticky(&req, parent, oldname).await?;
+        self.check_sticky(&req, new_parent, newname).await?;
 
         // Check if new_name exists and is a whiteout file
         let new_parent_data = self.inode_map.get(new_parent).await?;
@@ -2100,73 +3138,104 @@ impl Filesystem for PassthroughFs {
         }
 
         let old_inode = self.inode_map.get(parent).await?;
-        let new_inode = self.inode_map.get(new_parent).await?;
         let old_file = old_inode.get_file()?;
-        let new_file = new_inode.get_file()?;
 
-        //TODO: Switch to libc::renameat2 -> libc::renameat2(olddirfd, oldpath, newdirfd, newpath, flags)
-        let res = unsafe {
-            libc::renameat(
-                old_file.as_raw_fd(),
-                oldname.as_ptr(),
-                new_file.as_raw_fd(),
-                newname.as_ptr(),
-            )
+        let deferred = self.defer_unlink(new_parent, newname, true).await?;
+        let flags = match self.do_rename_replacing(&old_file, oldname, &new_parent_file, newname) {
+            Ok(flags) => flags,
+            Err(e) => {
+                self.undo_defer_unlink(new_parent, deferred).await;
+                return Err(e.into());
+            }
         };
-
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error().into())
-        }
+        self.mirror(MirrorOp::Rename {
+            parent,
+            name,
+            new_parent,
+            new_name,
+            flags,
+        })
+        .await
     }
 
     /// rename a file or directory with flags.
     async fn rename2(
         &self,
-        _req: Request,
+        req: Request,
         parent: Inode,
         name: &OsStr,
         new_parent: Inode,
         new_name: &OsStr,
-        _flags: u32,
+        flags: u32,
     ) -> Result<()> {
+        self.check_backend()?;
+        self.check_writable()?;
         let oldname = osstr_to_cstr(name).unwrap();
         let oldname = oldname.as_ref();
         let newname = osstr_to_cstr(new_name).unwrap();
         let newname = newname.as_ref();
         self.validate_path_component(oldname)?;
         self.validate_path_component(newname)?;
+        let _inode_lock = self.lock_inodes(&[parent, new_parent]).await;
+        self.check_sticky(&req, parent, oldname).await?;
+        self.check_sticky(&req, new_parent, newname).await?;
 
         let old_inode = self.inode_map.get(parent).await?;
         let new_inode = self.inode_map.get(new_parent).await?;
-        let _old_file = old_inode.get_file()?;
-        let _new_file = new_inode.get_file()?;
-        //TODO: Switch to libc::renameat2 -> libc::renameat2(olddirfd, oldpath, newdirfd, newpath, flags)
-        let res = unsafe {
-            #[cfg(target_os = "linux")]
-            {
-                libc::renameat2(
-                    _old_file.as_raw_fd(),
-                    oldname.as_ptr(),
-                    _new_file.as_raw_fd(),
-                    newname.as_ptr(),
-                    _flags,
-                )
-            }
-            #[cfg(target_os = "macos")]
-            {
-                // Stub renameat2 with ENOSYS on Mac
-                *libc::__error() = libc::ENOSYS;
-                -1
+        let old_file = old_inode.get_file()?;
+        let new_file = new_inode.get_file()?;
+
+        // An exchange swaps two existing entries, which may be of different types (e.g. a file
+        // and a directory), so there is nothing to validate or clean up on the destination side.
+        // Both entries keep their inodes; cached handles for them are dropped, as unlink does.
+        #[cfg(target_os = "linux")]
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            let old_st = self.statx(&old_file, Some(oldname)).ok();
+            let new_st = self.statx(&new_file, Some(newname)).ok();
+            self.do_renameat2(&old_file, oldname, &new_file, newname, flags)?;
+            for st in [old_st, new_st].into_iter().flatten() {
+                if let Some(btime) = st.btime
+                    && (btime.tv_sec != 0 || btime.tv_nsec != 0)
+                {
+                    let key = FileUniqueKey(st.st.st_ino, btime);
+                    self.handle_cache.invalidate(&key).await;
+                }
             }
-        };
+            return self
+                .mirror(MirrorOp::Rename {
+                    parent,
+                    name,
+                    new_parent,
+                    new_name,
+                    flags,
+                })
+                .await;
+        }
 
-        if res == 0 {
-            Ok(())
+        // RENAME_WHITEOUT, which overlayfs uses on its upper layer, and RENAME_NOREPLACE are
+        // left to the host. Kernels or filesystems without support fail them with ENOSYS or
+        // EINVAL, which is passed back as is rather than emulated non-atomically.
+        let flags = if flags == 0 {
+            let deferred = self.defer_unlink(new_parent, newname, true).await?;
+            match self.do_rename_replacing(&old_file, oldname, &new_file, newname) {
+                Ok(flags) => flags,
+                Err(e) => {
+                    self.undo_defer_unlink(new_parent, deferred).await;
+                    return Err(e.into());
+                }
+            }
         } else {
-            Err(io::Error::last_os_error().into())
-        }
+            self.do_renameat2(&old_file, oldname, &new_file, newname, flags)?;
+            flags
+        };
+        self.mirror(MirrorOp::Rename {
+            parent,
+            name,
+            new_parent,
+            new_name,
+            flags,
+        })
+        .await
     }
 
     /// find next data or hole after the specified offset.
@@ -2178,6 +3247,7 @@ impl Filesystem for PassthroughFs {
         offset: u64,
         whence: u32,
     ) -> Result<ReplyLSeek> {
+        self.check_backend()?;
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self.handle_map.get(fh, inode).await?;
 
@@ -2276,37 +3346,65 @@ impl Filesystem for PassthroughFs {
             // File seek handling for non-directory files
             // Acquire the lock to get exclusive access, otherwise it may break do_readdir().
             let (_guard, file) = data.get_file_mut().await;
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            // Use 64-bit seek for regular files to match kernel offsets
-            let res = unsafe {
-                #[cfg(target_os = "linux")]
-                {
-                    libc::lseek64(
-                        file.as_raw_fd(),
-                        offset as libc::off64_t,
-                        whence as libc::c_int,
-                    )
+            let fd = file.as_raw_fd();
+
+            // FUSE carries the offset as an unsigned value, but for SEEK_CUR and SEEK_END it is
+            // a signed displacement. Resolve SEEK_SET/SEEK_CUR/SEEK_END to an absolute position
+            // here so a negative or overflowing result is rejected before touching the fd.
+            let whence = match whence {
+                FUSE_SEEK_DATA => libc::SEEK_DATA,
+                FUSE_SEEK_HOLE => libc::SEEK_HOLE,
+                whence => whence as libc::c_int,
+            };
+            let target = match whence {
+                libc::SEEK_SET => i64::try_from(offset).ok(),
+                libc::SEEK_CUR => {
+                    let cur = Self::raw_lseek(fd, 0, libc::SEEK_CUR)?;
+                    cur.checked_add(offset as i64)
                 }
-                #[cfg(target_os = "macos")]
-                {
-                    libc::lseek(
-                        file.as_raw_fd(),
-                        offset as libc::off_t,
-                        whence as libc::c_int,
-                    )
+                libc::SEEK_END => {
+                    let st = stat_fd(file, None)?;
+                    (st.st_size as i64).checked_add(offset as i64)
+                }
+                libc::SEEK_DATA | libc::SEEK_HOLE => {
+                    let offset = i64::try_from(offset).map_err(|_| einval())?;
+                    let res = match Self::raw_lseek(fd, offset, whence) {
+                        // Filesystems without sparse files don't support these on macOS. Treat
+                        // the file as all data, as Linux does for them.
+                        #[cfg(target_os = "macos")]
+                        Err(e)
+                            if matches!(e.raw_os_error(), Some(libc::ENOTSUP | libc::ENOTTY)) =>
+                        {
+                            let size = stat_fd(file, None)?.st_size as i64;
+                            if offset >= size {
+                                return Err(io::Error::from_raw_os_error(libc::ENXIO).into());
+                            }
+                            if whence == libc::SEEK_DATA {
+                                offset
+                            } else {
+                                size
+                            }
+                        }
+                        res => res?,
+                    };
+                    return Ok(ReplyLSeek { offset: res as u64 });
                 }
+                _ => return Err(einval().into()),
             };
-            if res < 0 {
-                Err(io::Error::last_os_error().into())
-            } else {
-                Ok(ReplyLSeek { offset: res as u64 })
+
+            match target {
+                Some(target) if target >= 0 => {
+                    let res = Self::raw_lseek(fd, target, libc::SEEK_SET)?;
+                    Ok(ReplyLSeek { offset: res as u64 })
+                }
+                _ => Err(einval().into()),
             }
         }
     }
 
     /// Copy a range of data from one file to another using the copy_file_range system call.
     /// This can improve performance by reducing data copying between userspace and kernel.
+    /// macOS has no such call, there the data is copied through a buffer in the server.
     #[allow(clippy::too_many_arguments)]
     async fn copy_file_range(
         &self,
@@ -2320,64 +3418,34 @@ impl Filesystem for PassthroughFs {
         length: u64,
         flags: u64,
     ) -> Result<ReplyCopyFileRange> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let _inode_lock = self.lock_inodes(&[inode_out]).await;
         // Get the handle data for both source and destination files
         let data_in = self.handle_map.get(fh_in, inode_in).await?;
         let data_out = self.handle_map.get(fh_out, inode_out).await?;
 
-        // Get file descriptors
-        let _fd_in = data_in.borrow_fd().as_raw_fd();
-        let _fd_out = data_out.borrow_fd().as_raw_fd();
-
         // Validate and reject unsupported flags
         // Linux copy_file_range currently doesn't define any flags (should be 0)
         if flags != 0 {
             return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
         }
-
-        // Convert offsets to i64, checking for overflow (offsets > i64::MAX would wrap to negative)
-        let mut _off_in: i64 = offset_in
-            .try_into()
-            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-        let mut _off_out: i64 = offset_out
-            .try_into()
-            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        // Convert length to usize, checking for overflow on 32-bit systems
-        let _len: usize = length
-            .try_into()
-            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        // SAFETY: copy_file_range reads from fd_in and writes to fd_out. We pass valid
-        // file descriptors and pointers to offset values. The syscall updates the offset
-        // pointers to reflect the new positions after the copy, but doesn't modify the
-        // file descriptor positions themselves (when offsets are non-NULL).
-        let res = unsafe {
-            #[cfg(target_os = "linux")]
-            {
-                libc::copy_file_range(
-                    _fd_in,
-                    &mut _off_in as *mut i64,
-                    _fd_out,
-                    &mut _off_out as *mut i64,
-                    _len,
-                    0,
-                )
-            }
-            #[cfg(target_os = "macos")]
-            {
-                *libc::__error() = libc::ENOSYS;
-                -1
-            }
-        };
-
-        if res < 0 {
-            Err(io::Error::last_os_error().into())
-        } else {
-            // res is guaranteed >= 0 here, safe to cast to usize then u64
-            Ok(ReplyCopyFileRange {
-                copied: res as usize as u64,
-            })
+        #[cfg(target_os = "linux")]
+        if !self.syscalls.copy_file_range {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS).into());
         }
+
+        // The reply carries the size copied in 32 bits, the kernel asks again for the rest.
+        let length = length.min(u32::MAX.into());
+        let copied = util::copy_file_range_all(
+            data_in.borrow_fd().as_raw_fd(),
+            offset_in,
+            data_out.borrow_fd().as_raw_fd(),
+            offset_out,
+            length,
+        )?;
+        self.mirror_range(inode_out, offset_out, copied).await?;
+        Ok(ReplyCopyFileRange { copied })
     }
 }
 
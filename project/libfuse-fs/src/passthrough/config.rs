@@ -1,11 +1,21 @@
 // Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::util::mapping::IdMappings;
+use tracing::level_filters::LevelFilter;
+
+use super::redirect::virtual_components;
+use super::util::Layout;
+use crate::util::mapping::{IdMapEntry, IdMappings};
 
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
@@ -52,6 +62,232 @@ impl FromStr for CachePolicy {
     }
 }
 
+/// How to retry I/O against the backing store which failed with a transient error, see
+/// `Config::io_retry`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The total number of tries, including the first one. `0` is treated as `1`.
+    pub attempts: u32,
+    /// How long to wait before each retry.
+    pub backoff: Duration,
+    /// Also treat `EIO` as transient. Only useful for backing stores known to report flaky
+    /// network errors as `EIO`, since it is a hard error on local disks.
+    pub retry_eio: bool,
+}
+
+impl RetryPolicy {
+    /// Whether `err` is worth retrying under this policy.
+    pub fn is_transient(&self, err: &std::io::Error) -> bool {
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) => true,
+            Some(libc::EIO) => self.retry_eio,
+            _ => false,
+        }
+    }
+}
+
+/// How to watch over a backing store which may become unreachable, see
+/// `Config::backend_health_probe`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HealthProbe {
+    /// How often the root directory is stat'ed. A stat taking longer counts as a failure.
+    pub interval: Duration,
+    /// How long probes have to keep failing before the backing store is considered
+    /// unreachable, so a single blip doesn't fail requests. Zero acts on the first failure.
+    pub grace: Duration,
+    /// The errno requests fail with while the backing store is unreachable, e.g. `EAGAIN` for
+    /// clients to retry later or `EIO`.
+    pub errno: i32,
+}
+
+/// Further directory trees served together with `root_dir`, see `Config::multi_root`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MultiRootConfig {
+    /// The roots searched after `root_dir`, in order.
+    pub roots: Vec<PathBuf>,
+}
+
+/// What `write` does with writes through an `O_DIRECT` handle whose offset or size isn't a
+/// multiple of the direct I/O alignment of the file, see `Config::direct_io_writes`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum DirectIoWrites {
+    /// Fail them with `EINVAL` without writing anything, as the host would.
+    #[default]
+    Strict,
+    /// Read the blocks they partially cover, patch them in memory and write them back whole.
+    Compat,
+}
+
+type XattrPredicate = dyn Fn(&[u8]) -> bool + Send + Sync;
+
+/// Which xattrs clients may see and use, by name, see `Config::xattr_namespace_filter`.
+#[derive(Clone)]
+pub struct XattrFilter(Arc<XattrPredicate>);
+
+impl XattrFilter {
+    /// A filter allowing the names `allow` returns `true` for.
+    pub fn new(allow: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Self {
+        XattrFilter(Arc::new(allow))
+    }
+
+    /// The filter for mounts run without privileges: `trusted.*` and `security.*` are hidden,
+    /// as such a mount can't set them and reading them could leak what the host keeps there.
+    pub fn unprivileged() -> Self {
+        Self::new(|name| !name.starts_with(b"trusted.") && !name.starts_with(b"security."))
+    }
+
+    /// Whether clients may see and use the xattr `name`.
+    pub fn allows(&self, name: &[u8]) -> bool {
+        (self.0)(name)
+    }
+}
+
+impl fmt::Debug for XattrFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("XattrFilter(..)")
+    }
+}
+
+/// Filters are equal if they are clones of each other.
+impl PartialEq for XattrFilter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for XattrFilter {}
+
+type LogLevelSetter = dyn Fn(LevelFilter) -> io::Result<()> + Send + Sync;
+
+/// Applies the level written to the `log_level` control xattr, see `Config::log_level_hook`.
+#[derive(Clone)]
+pub struct LogLevelHook(Arc<LogLevelSetter>);
+
+impl LogLevelHook {
+    /// A hook calling `set` with each new level.
+    pub fn new(set: impl Fn(LevelFilter) -> io::Result<()> + Send + Sync + 'static) -> Self {
+        LogLevelHook(Arc::new(set))
+    }
+
+    /// The hook replacing the filter of a `tracing_subscriber::reload::Layer`, so that the
+    /// subscriber it's part of follows the control channel.
+    pub fn reload<S: 'static>(handle: tracing_subscriber::reload::Handle<LevelFilter, S>) -> Self {
+        Self::new(move |level| {
+            handle
+                .reload(level)
+                .map_err(|e| io::Error::other(e.to_string()))
+        })
+    }
+
+    /// Apply `level`.
+    pub fn set(&self, level: LevelFilter) -> io::Result<()> {
+        (self.0)(level)
+    }
+}
+
+impl fmt::Debug for LogLevelHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogLevelHook(..)")
+    }
+}
+
+/// Hooks are equal if they are clones of each other.
+impl PartialEq for LogLevelHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for LogLevelHook {}
+
+/// Xattr names as clients see them mapped to the names the host stores, by prefix, see
+/// `Config::xattr_name_map`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XattrNameMap {
+    // (client prefix, host prefix)
+    prefixes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl XattrNameMap {
+    /// Store the names starting with each client prefix under the same names starting with the
+    /// paired host prefix instead, e.g. `("trusted.overlay.", "user.overlay.")`.
+    ///
+    /// Fails with `InvalidInput` on an empty prefix, or if one client prefix starts with another
+    /// or one host prefix starts with another, as a name would then map in more than one way.
+    pub fn new<C, H>(prefixes: impl IntoIterator<Item = (C, H)>) -> io::Result<Self>
+    where
+        C: Into<Vec<u8>>,
+        H: Into<Vec<u8>>,
+    {
+        let prefixes: Vec<(Vec<u8>, Vec<u8>)> = prefixes
+            .into_iter()
+            .map(|(client, host)| (client.into(), host.into()))
+            .collect();
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let overlap = |a: &[u8], b: &[u8]| a.starts_with(b) || b.starts_with(a);
+        for (i, (client, host)) in prefixes.iter().enumerate() {
+            if client.is_empty() || host.is_empty() {
+                return Err(invalid("xattr name prefixes must not be empty".to_string()));
+            }
+            for (other_client, other_host) in &prefixes[..i] {
+                if overlap(client, other_client) || overlap(host, other_host) {
+                    return Err(invalid(format!(
+                        "xattr name mappings {:?} -> {:?} and {:?} -> {:?} are ambiguous",
+                        String::from_utf8_lossy(other_client),
+                        String::from_utf8_lossy(other_host),
+                        String::from_utf8_lossy(client),
+                        String::from_utf8_lossy(host),
+                    )));
+                }
+            }
+        }
+        Ok(XattrNameMap { prefixes })
+    }
+
+    /// Whether no names are mapped.
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// The name the host stores the client's xattr `name` under. `None` for the names only
+    /// reachable through the map: those starting with a host prefix but no client prefix.
+    pub fn to_host<'a>(&self, name: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        Self::translate(self.prefixes.iter().map(|(c, h)| (c, h)), name)
+    }
+
+    /// The name clients see the host's xattr `name` as. `None` for the names clients can't
+    /// reach: those starting with a client prefix but no host prefix.
+    pub fn to_client<'a>(&self, name: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        Self::translate(self.prefixes.iter().map(|(c, h)| (h, c)), name)
+    }
+
+    fn translate<'a, 'p>(
+        mut prefixes: impl Iterator<Item = (&'p Vec<u8>, &'p Vec<u8>)> + Clone,
+        name: &'a [u8],
+    ) -> Option<Cow<'a, [u8]>> {
+        if let Some((from, to)) = prefixes.clone().find(|(from, _)| name.starts_with(from)) {
+            return Some(Cow::Owned([to, &name[from.len()..]].concat()));
+        }
+        if prefixes.any(|(_, to)| name.starts_with(to)) {
+            return None;
+        }
+        Some(Cow::Borrowed(name))
+    }
+}
+
+/// A way of telling the files of a host filesystem apart, see `Config::inode_identity`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InodeIdentity {
+    /// A file handle from `name_to_handle_at()`, so no fd has to be kept open per inode. Needs
+    /// filesystem support, which e.g. overlayfs only has with `nfs_export`.
+    FileHandle,
+    /// The inode number and the mount id from `statx()` (Linux 5.8) or `name_to_handle_at()`,
+    /// holding an `O_PATH` fd per inode.
+    MountId,
+    /// The inode number and the device, holding an `O_PATH` fd per inode. Always available.
+    Device,
+}
+
 /// Options that configure the behavior of the passthrough fuse file system.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Config {
@@ -94,9 +330,22 @@ pub struct Config {
     /// system has exclusive access to the directory and 2) the file system has read permissions for
     /// all files in that directory.
     ///
+    /// Files opened with `O_APPEND` bypass the cache, as the end of the file the kernel knows of
+    /// may be stale: each write is appended by the host, atomically with other appenders. The
+    /// session has to be mounted with `MountOptions::write_back` for the cache to be negotiated.
+    ///
     /// The default value for this option is `false`.
     pub writeback: bool,
 
+    /// The granularity of the timestamps the host filesystem stores, in nanoseconds, reported to
+    /// the kernel when the session starts. The kernel rounds the timestamps it generates itself,
+    /// e.g. the mtime of writes cached with `writeback`, down to a multiple of it, so they match
+    /// what the host keeps, e.g. `1_000_000_000` for a host with whole second timestamps. Must
+    /// be from 1 to `1_000_000_000`.
+    ///
+    /// The default value for this option is `1`.
+    pub time_gran: u32,
+
     /// The path of the root directory.
     ///
     /// The default is `/`.
@@ -167,18 +416,315 @@ pub struct Config {
     /// The default value for this option is `false`.
     pub use_host_ino: bool,
 
+    /// The ways of identifying inodes to try, in order of preference. The first one a host
+    /// filesystem supports is used for all of its files, probed on the first lookup in it and
+    /// remembered from then on. `InodeIdentity::Device` is the last resort whether listed or not.
+    /// `InodeIdentity::FileHandle` is skipped on macOS, and on Linux where a seccomp filter blocks
+    /// `statx()`, `name_to_handle_at()` or `open_by_handle_at()` or the process lacks
+    /// `CAP_DAC_READ_SEARCH`.
+    ///
+    /// The default value for this option is `[FileHandle, MountId, Device]`.
+    pub inode_identity: Vec<InodeIdentity>,
+
+    /// Log a warning once this many percent of the virtual inodes are in use. Host inodes too
+    /// big to be passed on as is, above 2^47 - 1, each take one of the 2^47 - 1 virtual inodes
+    /// while the kernel knows them, and looking up more of them fails once all are taken. The
+    /// current usage is in the `stats` control xattr. `None` disables the warning.
+    ///
+    /// The default value for this option is `Some(90)`.
+    pub virtual_inode_warn_percent: Option<u8>,
+
+    /// How the inode numbers handed to the kernel are split between the host filesystem and
+    /// the host inode. `Layout::Wide` tells apart up to 4094 combinations of device and mount
+    /// instead of 254, for hosts with many bind mounts, at the cost of giving a virtual inode to
+    /// every host inode above 2^43 - 1 rather than 2^47 - 1.
+    ///
+    /// The default value for this option is `Layout::Narrow`.
+    pub inode_layout: Layout,
+
     /// Whether the file system should honor the O_DIRECT flag. If this option is disabled,
     /// that flag will be filtered out at `open_inode`.
     ///
     /// The default is `true`.
     pub allow_direct_io: bool,
 
+    /// How writes through a handle opened with `O_DIRECT` which aren't aligned to the direct
+    /// I/O alignment of the file are handled. Such a write has to be turned into aligned I/O
+    /// somewhere: `DirectIoWrites::Compat` does it here, at the cost of a read of up to two
+    /// blocks and a write of whole blocks for each one, serialized with the other writes to the
+    /// inode. The host page cache is still bypassed, but a tiny write costs several times its
+    /// size in I/O, so clients relying on it should rather align their writes or not use
+    /// `O_DIRECT`. The data of all writes through `O_DIRECT` handles is copied to aligned memory
+    /// first. Both only apply where the host reports the alignment, with `STATX_DIOALIGN`;
+    /// elsewhere writes are passed to the host as they are.
+    ///
+    /// The default value for this option is `DirectIoWrites::Strict`.
+    pub direct_io_writes: DirectIoWrites,
+
+    /// Whether `open` may open FIFOs and device files on the host for I/O. On Linux, lookups and
+    /// attributes never open them, they only hold `O_PATH` fds. When this is disabled, `open` of
+    /// such an inode fails with `ENXIO`, as `open` of a socket always does. FIFOs and devices are
+    /// opened non-blocking, so opening a FIFO for writing without a reader fails with `ENXIO`
+    /// rather than blocking, and are switched to blocking afterwards unless `O_NONBLOCK` was
+    /// requested.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_special_file_io: bool,
+
     pub use_mmap: bool,
 
     /// The size of the mmap max usage
     /// The default is `1024 * 1024 * 1024` (1GB).
     pub max_mmap_size: u64,
 
+    /// Reply to reads with a range of the open host file instead of its data, so that the
+    /// session moves the data into the FUSE device with `splice(2)` without copying it through
+    /// userspace. Files which don't support splicing, and reads hitting EOF, fall back to a plain
+    /// read. Takes precedence over `use_mmap`, handles opened with `O_DIRECT` aren't spliced and
+    /// spliced reads aren't retried under `io_retry`. Only supported on Linux.
+    ///
+    /// The default value for this option is `false`.
+    pub use_splice: bool,
+
+    /// The maximum number of entry lookups `readdirplus` keeps in flight while fetching the
+    /// attributes of a directory, so listing a large directory issues its stat calls in bounded
+    /// batches. A value of `0` is treated as `1`.
+    ///
+    /// The default value for this option is `16`.
+    pub readdirplus_concurrency: usize,
+
+    /// Serve `readdirplus` on directories whose size, as `stat` reports it, is above this many
+    /// bytes without the attributes of their entries. The entries are listed as by `readdir`
+    /// and the kernel looks up the ones it needs itself, so listing a large directory just for
+    /// its names doesn't pay a stat call per entry. The union directories of `multi_root`
+    /// always get attributes. `None` serves attributes for every directory.
+    ///
+    /// The default value for this option is `None`.
+    pub readdirplus_max_dir_size: Option<u64>,
+
+    /// Export the tree read-only. Every request which would modify it, including opening a file
+    /// for writing or with `O_TRUNC`, fails with `EROFS`.
+    ///
+    /// The default value for this option is `false`.
+    pub readonly: bool,
+
+    /// The inode number reported for the root directory. The FUSE protocol always addresses the
+    /// root as `FUSE_ROOT_ID` (1), which keeps resolving to the root when a different number is
+    /// configured here, but the root's attributes and the layer's root inode use this value.
+    /// Useful when composing or re-exporting layers which need a specific root inode.
+    ///
+    /// The default value for this option is `1`.
+    pub root_ino: u64,
+
+    /// Serialize conflicting operations on the same inode with a per-inode lock. Data and
+    /// attribute updates (`write`, `setattr`, `fallocate`, ...) lock the target inode, and
+    /// namespace updates (`create`, `unlink`, `rename`, ...) lock the parent directories
+    /// involved. Operations on unrelated inodes still run concurrently.
+    ///
+    /// The default value for this option is `true`.
+    pub serialize_inode_ops: bool,
+
+    /// Retry `open`, `read` and `write` against the backing store when they fail with a
+    /// transient error (`EAGAIN`, and `EIO` if enabled), as networked backing stores may
+    /// report. Other errors fail immediately, as do transient ones once the attempts run out.
+    ///
+    /// The default value for this option is `None`.
+    pub io_retry: Option<RetryPolicy>,
+
+    /// Stat the root of the backing store in the background, and once that has failed for
+    /// `HealthProbe::grace` fail all requests touching it with `HealthProbe::errno` right away,
+    /// rather than with whatever errors a disconnected network filesystem returns or after its
+    /// timeouts. Requests are served again from the first successful probe on. Releases and
+    /// forgets are never held up.
+    ///
+    /// The default value for this option is `None`.
+    pub backend_health_probe: Option<HealthProbe>,
+
+    /// The largest extended attribute value, in bytes, accepted by `setxattr`. Larger values
+    /// fail with `E2BIG` before reaching the host, so clients see the same limit regardless of
+    /// what the backing filesystem supports. `None` leaves the limit to the host.
+    ///
+    /// The default value for this option is `None`.
+    pub max_xattr_size: Option<usize>,
+
+    /// Hide the xattrs whose names the filter rejects: `listxattr` leaves them out, and
+    /// `getxattr`, `setxattr` and `removexattr` fail with `ENOTSUP` for them as if the host
+    /// didn't support them. `XattrFilter::unprivileged()` suits mounts run without privileges.
+    /// `None` passes all names through.
+    ///
+    /// The default value for this option is `None`.
+    pub xattr_namespace_filter: Option<XattrFilter>,
+
+    /// Rename xattrs between clients and the host, e.g. to keep an overlay's `trusted.overlay.*`
+    /// xattrs as `user.overlay.*` on a host it runs on without privileges. The host names of
+    /// mapped xattrs can't be used directly, see `XattrNameMap::to_host()`. The filter of
+    /// `xattr_namespace_filter` sees the names clients use.
+    ///
+    /// The default value for this option is empty.
+    pub xattr_name_map: XattrNameMap,
+
+    /// The largest link count `link` may bring a file to. Links beyond it fail with `EMLINK`
+    /// before reaching the host, so clients see the same limit regardless of the backing
+    /// filesystem. `None` leaves the limit to the host. The host's own `LINK_MAX` isn't queried
+    /// up front since `pathconf(3)` only guesses it for many filesystems, e.g. 127 on tmpfs which
+    /// has no limit at all.
+    ///
+    /// The default value for this option is `None`.
+    pub max_links: Option<u64>,
+
+    /// The most directory streams clients may hold open at once. Each one pins a host directory
+    /// fd, so a client which opens directories without ever releasing them can otherwise run the
+    /// daemon out of descriptors. `opendir` beyond the limit fails with `EMFILE` until one of
+    /// the streams is released. Streams still referenced by the kernel can't be reclaimed behind
+    /// its back, so they're never evicted. `None` leaves the limit to the host.
+    ///
+    /// The default value for this option is `None`.
+    pub max_open_dirs: Option<usize>,
+
+    /// The most `lookup`, `getattr`, `readdir` and `readdirplus` requests served per second,
+    /// with bursts of up to one second's worth. Excess requests wait for their turn instead of
+    /// failing, so a flood of stat calls can't overwhelm a slow backing store. `None` disables
+    /// the limit.
+    ///
+    /// The default value for this option is `None`.
+    pub metadata_ops_per_sec: Option<u32>,
+
+    /// How long a `statfs` result is reused for further `statfs` requests on the same host
+    /// filesystem, as `df` and friends may call it repeatedly and it can be slow on networked
+    /// backing stores. Concurrent requests on a cache miss share a single host call. Writes
+    /// don't invalidate the cache, so the reported usage can be stale by up to this long.
+    ///
+    /// The default value for this option is `None`.
+    pub statfs_cache: Option<Duration>,
+
+    /// Split `fallocate` requests longer than this many bytes into several host calls of at most
+    /// this size, yielding between them so that a huge allocation neither blocks a single syscall
+    /// for long enough to trip the kernel's request timeout nor starves other requests. Only the
+    /// modes acting on a plain byte range are split, `FALLOC_FL_COLLAPSE_RANGE` and
+    /// `FALLOC_FL_INSERT_RANGE` always run as one call. If a call fails partway, a file it grew
+    /// is truncated back to its size before the request, the ranges already allocated within it
+    /// stay allocated. `None` disables the splitting.
+    ///
+    /// The default value for this option is `None`.
+    pub fallocate_chunk_size: Option<u64>,
+
+    /// Rewrite absolute symlink targets returned by `readlink` into relative ones, so that a link
+    /// to `/etc` resolves to `<export>/etc` on the client instead of the client's own `/etc`.
+    /// `..` components of the target can't climb above the export root. Only what clients see is
+    /// changed, the links on the host keep their absolute targets, so host-side users and
+    /// anything that reads the links through another path still follow them to the host root.
+    /// Links reachable through several hard links are rewritten for the path the inode was
+    /// first looked up by.
+    ///
+    /// The default value for this option is `false`.
+    pub confine_symlinks: bool,
+
+    /// Enforce the sticky bit of directories for `unlink`, `rmdir`, `rename` and `rename2`: an
+    /// entry of a sticky directory can only be removed or replaced by the owner of the entry,
+    /// the owner of the directory or root, judged by the (mapped) uid of the request. The host
+    /// only enforces this for the user the server runs as, which usually is root.
+    ///
+    /// The default value for this option is `false`.
+    pub enforce_sticky_bit: bool,
+
+    /// A second host directory to which every successful mutating operation (creating,
+    /// writing, truncating, linking, renaming and removing entries) is applied as well, e.g. to
+    /// keep a live copy for migration or backup. Reads are always served from `root_dir`. Paths
+    /// in the mirror are resolved from the directory opened at start without following
+    /// symlinks, so a symlink in the mirror fails the operation rather than leading out of it.
+    /// The mirror is best effort: ownership, xattrs and anything changed on the host behind our
+    /// back are not carried over, and failures are only logged unless `mirror_strict` is set.
+    ///
+    /// The default value for this option is `None`.
+    pub mirror_dir: Option<PathBuf>,
+
+    /// Fail an operation when applying it to `mirror_dir` fails. The primary directory has been
+    /// modified already by then, the error only tells the client the mirror diverged.
+    ///
+    /// The default value for this option is `false`.
+    pub mirror_strict: bool,
+
+    /// Serve the union of `root_dir` and further roots. A name resolves in the first root that
+    /// has it. A directory found in several roots lists their entries merged, each name once as
+    /// it appears in the first root having it, and its subdirectories are merged in turn. Unlike
+    /// overlayfs there are no whiteouts, opaque directories or copy-up, so this implies
+    /// `readonly`. Directories are merged when they are looked up, roots gaining a directory
+    /// later only show it after the merged one was forgotten.
+    ///
+    /// The default value for this option is `None`.
+    pub multi_root: Option<MultiRootConfig>,
+
+    /// Serve host paths at virtual paths below the root in place of what `root_dir` has there,
+    /// e.g. `("/config", "/etc/myapp")` makes `/config` resolve to `/etc/myapp`. A virtual path
+    /// is relative to the root whether or not it starts with `/`, can't have `..`, and its
+    /// parent directories must exist below `root_dir`. Like `root_dir`, a host path which is a
+    /// symlink is followed. Redirects only apply to lookups, a listing of the parent directory
+    /// shows what `root_dir` has under that name. A redirected directory counts as a root of its
+    /// own: `..` resolves within it, but not from it to its host parent.
+    ///
+    /// The default value for this option is empty.
+    pub path_redirects: Vec<(PathBuf, PathBuf)>,
+
+    /// Let a rename replace a non-empty directory with another directory, which the host fails
+    /// with `ENOTEMPTY` or `EEXIST`: the two are swapped atomically with `RENAME_EXCHANGE`, so
+    /// the target path always shows either of them whole. The old directory is left under the
+    /// source name for the caller to remove, with its own permissions. Where the host can't
+    /// exchange, e.g. on macOS, the rename fails as it would without this.
+    ///
+    /// The default value for this option is `false`.
+    pub atomic_dir_replace: bool,
+
+    /// Serve a control channel as xattrs in the `user.fuse.passthrough.` namespace of the root
+    /// directory, which never reach the host. Reading `version`, `features`, `stats` or `fs_type`
+    /// reports runtime information, writing `log_level` or `flush_cache` changes the running
+    /// file system. Only root and the user running the file system may write them, others get
+    /// `EPERM`. Clients only send xattr requests when `xattr` is enabled as well.
+    ///
+    /// The default value for this option is `false`.
+    pub control_xattr: bool,
+
+    /// Where the level written to the `log_level` control xattr goes, see `control_xattr`, e.g.
+    /// `LogLevelHook::reload()` of the subscriber's level filter. Without a hook writing
+    /// `log_level` fails with `ENOTSUP`.
+    ///
+    /// The default value for this option is `None`.
+    pub log_level_hook: Option<LogLevelHook>,
+
+    /// Drop the client's page cache of a file on open if the file changed since the client last
+    /// had it open, by leaving `FOPEN_KEEP_CACHE` out of the reply. Changes are detected by the
+    /// modification time and size recorded at the previous open and after each write made
+    /// through this file system, so those writes don't count, while changes made on the host
+    /// while the file is open do. Only matters with `CachePolicy::Always`, the only
+    /// policy which keeps the cache of regular files.
+    ///
+    /// The default value for this option is `false`.
+    pub drop_cache_on_change: bool,
+
+    /// Don't unlink files which are still open, for host filesystems which can't serve open
+    /// unlinked files (e.g. some network filesystems). Such a file is renamed to a hidden
+    /// `.fuse_hidden*` entry in the same directory instead, which is unlinked when its last handle
+    /// is released. A file a rename replaces while it is open is linked to such an entry first.
+    /// Until then the hidden entry shows up in directory listings, the same as with libfuse's
+    /// `hard_remove` turned off. Ignored where a seccomp filter blocks `renameat2()`.
+    ///
+    /// The default value for this option is `false`.
+    pub defer_unlink: bool,
+
+    /// What to do when the host file backing an open handle has been replaced, i.e. unlinked
+    /// and recreated at the same path. By default the handle sticks with the inode it opened,
+    /// as POSIX requires, so clients keep seeing the old content. When enabled, the next
+    /// `read`, `write`, `fsync` or `fallocate` on a handle whose inode has no links left reopens
+    /// the path it was opened with, if that still resolves to a regular file.
+    ///
+    /// The default value for this option is `false`.
+    pub reopen_on_replace: bool,
+
+    /// Allow `PassthroughFs::dump_inodes()` to report the resident inodes. The dump locks the
+    /// whole inode store while it runs, so it's meant for diagnosing leaked inodes only.
+    ///
+    /// The default value for this option is `false`.
+    pub debug_inode_dump: bool,
+
     /// UID/GID mapping. Format: `uidmapping=H:T:L[:H2:T2:L2...],gidmapping=H:T:L[:H2:T2:L2...]`
     pub mapping: IdMappings,
 }
@@ -190,6 +736,7 @@ impl Default for Config {
             attr_timeout: Duration::from_secs(5),
             cache_policy: Default::default(),
             writeback: false,
+            time_gran: 1,
             root_dir: PathBuf::from("/"),
             xattr: false,
             do_import: true,
@@ -203,10 +750,375 @@ impl Default for Config {
             dir_entry_timeout: None,
             dir_attr_timeout: None,
             use_host_ino: false,
+            inode_identity: vec![
+                InodeIdentity::FileHandle,
+                InodeIdentity::MountId,
+                InodeIdentity::Device,
+            ],
             allow_direct_io: true,
+            direct_io_writes: DirectIoWrites::default(),
+            virtual_inode_warn_percent: Some(90),
+            inode_layout: Layout::default(),
+            allow_special_file_io: false,
             use_mmap: false,
+            use_splice: false,
             max_mmap_size: 1024 * 1024 * 1024,
+            readdirplus_concurrency: 16,
+            readdirplus_max_dir_size: None,
+            readonly: false,
+            root_ino: 1,
+            serialize_inode_ops: true,
+            debug_inode_dump: false,
+            reopen_on_replace: false,
+            defer_unlink: false,
+            drop_cache_on_change: false,
+            control_xattr: false,
+            log_level_hook: None,
+            max_xattr_size: None,
+            xattr_namespace_filter: None,
+            xattr_name_map: XattrNameMap::default(),
+            max_links: None,
+            max_open_dirs: None,
+            metadata_ops_per_sec: None,
+            statfs_cache: None,
+            io_retry: None,
+            backend_health_probe: None,
+            fallocate_chunk_size: None,
+            mirror_dir: None,
+            mirror_strict: false,
+            multi_root: None,
+            path_redirects: Vec::new(),
+            atomic_dir_replace: false,
+            confine_symlinks: false,
+            enforce_sticky_bit: false,
             mapping: IdMappings::default(),
         }
     }
 }
+
+/// A problem with a `Config`, see `Config::validate()`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The name of the offending option, e.g. `"root_dir"`.
+    pub option: &'static str,
+    /// What is wrong with it.
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(option: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic {
+            option,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.option, self.message)
+    }
+}
+
+impl Config {
+    /// Check the configuration against the host before mounting, returning every problem found
+    /// instead of stopping at the first one. Most of them would otherwise only surface when the
+    /// file system is imported or on first access. An empty result means nothing was found, not
+    /// that mounting is guaranteed to succeed.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+
+        match self.root_dir.metadata() {
+            Ok(md) if !md.is_dir() => diags.push(Diagnostic::new(
+                "root_dir",
+                format!("{:?} is not a directory", self.root_dir),
+            )),
+            Ok(_) => {}
+            Err(e) => diags.push(Diagnostic::new(
+                "root_dir",
+                format!("{:?} is not accessible: {e}", self.root_dir),
+            )),
+        }
+        if self.root_ino == 0 {
+            diags.push(Diagnostic::new("root_ino", "0 is not a valid inode number"));
+        }
+        if !(1..=1_000_000_000).contains(&self.time_gran) {
+            diags.push(Diagnostic::new(
+                "time_gran",
+                "must be from 1 to 1000000000 nanoseconds",
+            ));
+        }
+        if self.readdirplus_concurrency == 0 {
+            diags.push(Diagnostic::new(
+                "readdirplus_concurrency",
+                "must be at least 1",
+            ));
+        }
+        if self.fallocate_chunk_size == Some(0) {
+            diags.push(Diagnostic::new(
+                "fallocate_chunk_size",
+                "must not be 0, use `None` to disable chunking",
+            ));
+        }
+
+        match &self.mirror_dir {
+            Some(mirror_dir) => self.validate_mirror_dir(mirror_dir, &mut diags),
+            None if self.mirror_strict => diags.push(Diagnostic::new(
+                "mirror_strict",
+                "has no effect without `mirror_dir`",
+            )),
+            None => {}
+        }
+
+        for root in self.multi_root.iter().flat_map(|m| &m.roots) {
+            match root.metadata() {
+                Ok(md) if !md.is_dir() => diags.push(Diagnostic::new(
+                    "multi_root",
+                    format!("{root:?} is not a directory"),
+                )),
+                Ok(_) => {}
+                Err(e) => diags.push(Diagnostic::new(
+                    "multi_root",
+                    format!("{root:?} is not accessible: {e}"),
+                )),
+            }
+        }
+
+        for (virtual_path, host) in &self.path_redirects {
+            if virtual_components(virtual_path).is_none() {
+                diags.push(Diagnostic::new(
+                    "path_redirects",
+                    format!("{virtual_path:?} doesn't name an entry below the root"),
+                ));
+            }
+            if let Err(e) = host.metadata() {
+                diags.push(Diagnostic::new(
+                    "path_redirects",
+                    format!("{host:?} is not accessible: {e}"),
+                ));
+            }
+        }
+
+        validate_id_map("uid", &self.mapping.uid_map, &mut diags);
+        validate_id_map("gid", &self.mapping.gid_map, &mut diags);
+
+        diags
+    }
+
+    fn validate_mirror_dir(&self, mirror_dir: &Path, diags: &mut Vec<Diagnostic>) {
+        match mirror_dir.metadata() {
+            Ok(md) if !md.is_dir() => {
+                diags.push(Diagnostic::new(
+                    "mirror_dir",
+                    format!("{mirror_dir:?} is not a directory"),
+                ));
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                diags.push(Diagnostic::new(
+                    "mirror_dir",
+                    format!("{mirror_dir:?} is not accessible: {e}"),
+                ));
+                return;
+            }
+        }
+
+        let writable = CString::new(mirror_dir.as_os_str().as_bytes())
+            // Safe because this doesn't modify any memory and we check the return value.
+            .map(|path| unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) } == 0)
+            .unwrap_or(false);
+        if !writable {
+            diags.push(Diagnostic::new(
+                "mirror_dir",
+                format!("{mirror_dir:?} is not writable"),
+            ));
+        }
+
+        // Mirroring into the export, or the export into the mirror, feeds operations back into
+        // the tree they came from.
+        if let (Ok(root), Ok(mirror)) = (self.root_dir.canonicalize(), mirror_dir.canonicalize())
+            && (root.starts_with(&mirror) || mirror.starts_with(&root))
+        {
+            diags.push(Diagnostic::new(
+                "mirror_dir",
+                format!("{mirror_dir:?} overlaps with root_dir {:?}", self.root_dir),
+            ));
+        }
+    }
+}
+
+/// Check one direction of an id map: empty and overflowing ranges, and ranges which overlap on
+/// either the host or the mapped side, which would make the mapping ambiguous.
+fn validate_id_map(kind: &str, map: &[IdMapEntry], diags: &mut Vec<Diagnostic>) {
+    let end = |start: u32, len: u32| start as u64 + len as u64;
+    for (i, e) in map.iter().enumerate() {
+        if e.len == 0 {
+            diags.push(Diagnostic::new(
+                "mapping",
+                format!("{kind} range {}:{}:0 is empty", e.host, e.to),
+            ));
+            continue;
+        }
+        if end(e.host, e.len) > u32::MAX as u64 + 1 || end(e.to, e.len) > u32::MAX as u64 + 1 {
+            diags.push(Diagnostic::new(
+                "mapping",
+                format!(
+                    "{kind} range {}:{}:{} exceeds the id space",
+                    e.host, e.to, e.len
+                ),
+            ));
+        }
+        for o in map[..i].iter().filter(|o| o.len != 0) {
+            let overlaps =
+                |a: u32, b: u32| (a as u64) < end(b, o.len) && (b as u64) < end(a, e.len);
+            if overlaps(e.host, o.host) || overlaps(e.to, o.to) {
+                diags.push(Diagnostic::new(
+                    "mapping",
+                    format!(
+                        "{kind} ranges {}:{}:{} and {}:{}:{} overlap",
+                        o.host, o.to, o.len, e.host, e.to, e.len
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        assert_eq!(
+            Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                ..Default::default()
+            }
+            .validate(),
+            vec![]
+        );
+
+        let entry = |host, to, len| IdMapEntry { host, to, len };
+        let cfg = Config {
+            root_dir: file.clone(),
+            root_ino: 0,
+            time_gran: 0,
+            readdirplus_concurrency: 0,
+            fallocate_chunk_size: Some(0),
+            mirror_dir: Some(tmp_dir.path().join("missing")),
+            mapping: IdMappings::new(
+                vec![entry(0, 1000, 10), entry(5, 2000, 10), entry(100, 100, 0)],
+                vec![entry(0, 0, 10), entry(100, 5, 10)],
+            ),
+            ..Default::default()
+        };
+        let options: Vec<_> = cfg.validate().into_iter().map(|d| d.option).collect();
+        assert_eq!(
+            options,
+            vec![
+                "root_dir",
+                "root_ino",
+                "time_gran",
+                "readdirplus_concurrency",
+                "fallocate_chunk_size",
+                "mirror_dir",
+                "mapping",
+                "mapping",
+                "mapping",
+            ]
+        );
+        let diags = cfg.validate();
+        assert!(diags[0].message.contains("not a directory"));
+        assert!(diags[5].message.contains("not accessible"));
+        assert_eq!(
+            diags[6].to_string(),
+            "mapping: uid ranges 0:1000:10 and 5:2000:10 overlap"
+        );
+        assert_eq!(
+            diags[7].to_string(),
+            "mapping: uid range 100:100:0 is empty"
+        );
+        assert_eq!(
+            diags[8].to_string(),
+            "mapping: gid ranges 0:0:10 and 100:5:10 overlap"
+        );
+
+        // A mirror inside the export, and a strict mirror without a mirror.
+        let cfg = Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            mirror_dir: Some(tmp_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let diags = cfg.validate();
+        assert_eq!(diags.len(), 1);
+        assert!(diags[0].message.contains("overlaps with root_dir"));
+        let cfg = Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            mirror_strict: true,
+            ..Default::default()
+        };
+        assert_eq!(cfg.validate()[0].option, "mirror_strict");
+
+        // Redirects of the root itself, out of it, and to nowhere.
+        let cfg = Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            path_redirects: vec![
+                ("/".into(), file.clone()),
+                ("a/../..".into(), file.clone()),
+                ("/ok".into(), tmp_dir.path().join("missing")),
+                ("/ok".into(), file.clone()),
+            ],
+            ..Default::default()
+        };
+        let diags = cfg.validate();
+        assert_eq!(diags.len(), 3);
+        assert!(diags.iter().all(|d| d.option == "path_redirects"));
+        assert!(diags[2].message.contains("not accessible"));
+    }
+
+    #[test]
+    fn test_xattr_name_map() {
+        let map = XattrNameMap::new([("trusted.overlay.", "user.overlay.")]).unwrap();
+        let to_host = |name: &str| map.to_host(name.as_bytes()).map(|n| n.into_owned());
+        let to_client = |name: &str| map.to_client(name.as_bytes()).map(|n| n.into_owned());
+        assert_eq!(
+            to_host("trusted.overlay.opaque").as_deref(),
+            Some(&b"user.overlay.opaque"[..])
+        );
+        assert_eq!(
+            to_client("user.overlay.opaque").as_deref(),
+            Some(&b"trusted.overlay.opaque"[..])
+        );
+        assert_eq!(to_host("user.other").as_deref(), Some(&b"user.other"[..]));
+        assert_eq!(to_client("user.other").as_deref(), Some(&b"user.other"[..]));
+        // Each name has one meaning on either side.
+        assert_eq!(to_host("user.overlay.opaque"), None);
+        assert_eq!(to_client("trusted.overlay.opaque"), None);
+
+        // Several client prefixes for one host prefix, or prefixes of one another.
+        for prefixes in [
+            vec![
+                ("trusted.overlay.", "user.overlay."),
+                ("security.overlay.", "user.overlay."),
+            ],
+            vec![
+                ("trusted.", "user.t."),
+                ("trusted.overlay.", "user.overlay."),
+            ],
+            vec![
+                ("trusted.overlay.", "user.overlay."),
+                ("trusted.x.", "user."),
+            ],
+            vec![("", "user.")],
+        ] {
+            let err = XattrNameMap::new(prefixes).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
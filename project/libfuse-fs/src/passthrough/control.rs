@@ -0,0 +1,140 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The synthetic xattrs of `Config::control_xattr`.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::Ordering;
+
+use rfuse3::Inode;
+use rfuse3::raw::Request;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use vm_memory::bitmap::BitmapSlice;
+
+use super::{CachePolicy, PassthroughFs};
+
+/// The namespace of the control xattrs on the root directory.
+pub(super) const CONTROL_XATTR_PREFIX: &[u8] = b"user.fuse.passthrough.";
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Whether the xattr `name` of `inode` is served by the control channel instead of the host.
+    pub(super) fn is_control_xattr(&self, inode: Inode, name: &OsStr) -> bool {
+        self.cfg.control_xattr
+            && self.is_root(inode)
+            && name.as_bytes().starts_with(CONTROL_XATTR_PREFIX)
+    }
+
+    /// The value of the control xattr `name`.
+    ///
+    /// - `version`: the version of this crate.
+    /// - `features`: the enabled options, one per line.
+    /// - `stats`: resident inodes, open handles and the virtual inodes in use out of the ones
+    ///   available, one `name value` pair per line.
+    /// - `log_level`: the level set through the control channel, see `log_level()`.
+    /// - `fs_type`: the `f_type` of the host filesystem, see `host_fs_type()`.
+    pub(super) async fn control_getxattr(&self, name: &OsStr) -> io::Result<Vec<u8>> {
+        let value = match &name.as_bytes()[CONTROL_XATTR_PREFIX.len()..] {
+            b"version" => env!("CARGO_PKG_VERSION").to_string(),
+            b"features" => self.enabled_features().join("\n"),
+            b"stats" => {
+                let (virtual_inodes, virtual_inodes_max) = self.ino_allocator.virtual_inode_usage();
+                format!(
+                    "inodes {}\nhandles {}\nvirtual_inodes {virtual_inodes}\n\
+                     virtual_inodes_max {virtual_inodes_max}\n",
+                    self.inode_map.inodes.read().await.iter().count(),
+                    self.handle_map.handles.read().await.len()
+                )
+            }
+            b"log_level" => self.log_level().to_string(),
+            b"fs_type" => format!("{:#x}", self.host_fs_type(self.root_ino()).await),
+            _ => return Err(io::Error::from_raw_os_error(libc::ENODATA)),
+        };
+        Ok(value.into_bytes())
+    }
+
+    /// Apply the control xattr `name` on behalf of the caller of `req`, which has to be root or
+    /// the user running the file system.
+    ///
+    /// - `log_level`: one of `off`, `error`, `warn`, `info`, `debug` or `trace`, passed on to
+    ///   `Config::log_level_hook`.
+    /// - `flush_cache`: drop the cached file handles and mmaps, the value is ignored.
+    pub(super) async fn control_setxattr(
+        &self,
+        req: &Request,
+        name: &OsStr,
+        value: &[u8],
+    ) -> io::Result<()> {
+        // Safe because this doesn't modify any memory.
+        if req.uid != 0 && req.uid != unsafe { libc::geteuid() } {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        match &name.as_bytes()[CONTROL_XATTR_PREFIX.len()..] {
+            b"log_level" => {
+                let hook = self
+                    .cfg
+                    .log_level_hook
+                    .as_ref()
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOTSUP))?;
+                let level = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<LevelFilter>().ok())
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+                hook.set(level)?;
+                info!("passthrough: log level set to {level} through the control xattr");
+                *self.log_level.write().unwrap() = level;
+            }
+            b"flush_cache" => {
+                self.handle_cache.invalidate_all();
+                self.openable_handles.clear();
+                self.mmap_chunks.invalidate_all();
+            }
+            b"version" | b"features" | b"stats" | b"fs_type" => {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::ENOTSUP)),
+        }
+        Ok(())
+    }
+
+    fn enabled_features(&self) -> Vec<&'static str> {
+        let cfg = &self.cfg;
+        let mut features = vec![match cfg.cache_policy {
+            CachePolicy::Never => "cache=never",
+            CachePolicy::Metadata => "cache=metadata",
+            CachePolicy::Auto => "cache=auto",
+            CachePolicy::Always => "cache=always",
+        }];
+        for (enabled, name) in [
+            (self.writeback.load(Ordering::Relaxed), "writeback"),
+            (cfg.xattr, "xattr"),
+            (cfg.readonly, "readonly"),
+            (cfg.allow_direct_io, "direct_io"),
+            (cfg.allow_special_file_io, "special_file_io"),
+            (cfg.use_mmap, "mmap"),
+            (cfg.use_splice, "splice"),
+            (cfg.use_host_ino, "host_ino"),
+            (cfg.serialize_inode_ops, "serialize_inode_ops"),
+            (cfg.io_retry.is_some(), "io_retry"),
+            (cfg.backend_health_probe.is_some(), "health_probe"),
+            (cfg.metadata_ops_per_sec.is_some(), "metadata_throttle"),
+            (cfg.statfs_cache.is_some(), "statfs_cache"),
+            (cfg.confine_symlinks, "confine_symlinks"),
+            (cfg.enforce_sticky_bit, "enforce_sticky_bit"),
+            (cfg.mirror_dir.is_some(), "mirror"),
+            (cfg.multi_root.is_some(), "multi_root"),
+            (!cfg.path_redirects.is_empty(), "path_redirects"),
+            (cfg.atomic_dir_replace, "atomic_dir_replace"),
+            (cfg.reopen_on_replace, "reopen_on_replace"),
+            (cfg.defer_unlink, "defer_unlink"),
+            (cfg.drop_cache_on_change, "drop_cache_on_change"),
+        ] {
+            if enabled {
+                features.push(name);
+            }
+        }
+        features
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Sharing identical extents between files, as `FIDEDUPERANGE` does.
+//!
+//! The kernel handles `FIDEDUPERANGE` on a FUSE mount in the VFS, without passing it on to the
+//! filesystem, so this is exported as a method for the users of `PassthroughFs` that take such
+//! requests themselves.
+
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+
+use rfuse3::Inode;
+use vm_memory::bitmap::BitmapSlice;
+
+use super::PassthroughFs;
+
+/// A destination of [`PassthroughFs::dedupe_range`]: the range at `offset` in the file open as
+/// `fh` on `inode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupeDest {
+    pub inode: Inode,
+    pub fh: u64,
+    pub offset: u64,
+}
+
+/// The outcome of [`PassthroughFs::dedupe_range`] for one destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeStatus {
+    /// The contents were the same and this many bytes now share the source's extents.
+    Deduped(u64),
+    /// The contents differ, nothing was changed.
+    Differs,
+    /// Deduping failed with this errno.
+    Failed(i32),
+}
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)`, see ioctl_fideduperange(2).
+#[cfg(target_os = "linux")]
+const FIDEDUPERANGE: libc::Ioctl = 0xC018_9436u32 as libc::Ioctl;
+#[cfg(target_os = "linux")]
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+#[cfg(target_os = "linux")]
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+/// `struct file_dedupe_range`, followed by `dest_count` of `FileDedupeRangeInfo`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+}
+
+/// `struct file_dedupe_range_info`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Share the extents of `length` bytes at `offset` in the file open as `fh` on `inode` with
+    /// each of `dests` whose contents are the same, like `FIDEDUPERANGE` on the backing files.
+    /// The destinations must be open for writing. Fails with `ENOTSUP` if the backing
+    /// filesystem can't share extents.
+    pub async fn dedupe_range(
+        &self,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        dests: &[DedupeDest],
+    ) -> io::Result<Vec<DedupeStatus>> {
+        self.check_backend()?;
+        self.check_writable()?;
+        let src = self.handle_map.get(fh, inode).await?;
+        let mut dest_data = Vec::with_capacity(dests.len());
+        for dest in dests {
+            dest_data.push(self.handle_map.get(dest.fh, dest.inode).await?);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let dest_count = u16::try_from(dests.len())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+            let header = std::mem::size_of::<FileDedupeRange>();
+            let info_size = std::mem::size_of::<FileDedupeRangeInfo>();
+            // Both structures are made of 8 byte aligned fields, so a buffer of u64 fits them.
+            let mut buf = vec![0u64; (header + dests.len() * info_size).div_ceil(8)];
+            let base = buf.as_mut_ptr() as *mut u8;
+            // Safe because `buf` is large enough and aligned for the header and the infos.
+            unsafe {
+                std::ptr::write(
+                    base as *mut FileDedupeRange,
+                    FileDedupeRange {
+                        src_offset: offset,
+                        src_length: length,
+                        dest_count,
+                        reserved1: 0,
+                        reserved2: 0,
+                    },
+                );
+                for (i, (dest, data)) in dests.iter().zip(&dest_data).enumerate() {
+                    std::ptr::write(
+                        base.add(header + i * info_size) as *mut FileDedupeRangeInfo,
+                        FileDedupeRangeInfo {
+                            dest_fd: data.borrow_fd().as_raw_fd().into(),
+                            dest_offset: dest.offset,
+                            bytes_deduped: 0,
+                            status: 0,
+                            reserved: 0,
+                        },
+                    );
+                }
+            }
+
+            // Safe because the kernel only accesses `buf`, which is sized for `dest_count`
+            // infos, and we check the return value.
+            let res = unsafe { libc::ioctl(src.borrow_fd().as_raw_fd(), FIDEDUPERANGE, base) };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                return Err(match err.raw_os_error() {
+                    Some(libc::ENOTTY) => io::Error::from_raw_os_error(libc::ENOTSUP),
+                    _ => err,
+                });
+            }
+
+            Ok((0..dests.len())
+                .map(|i| {
+                    // Safe because the infos were written above and filled in by the kernel.
+                    let info = unsafe {
+                        std::ptr::read(
+                            base.add(header + i * info_size) as *const FileDedupeRangeInfo
+                        )
+                    };
+                    match info.status {
+                        FILE_DEDUPE_RANGE_SAME => DedupeStatus::Deduped(info.bytes_deduped),
+                        FILE_DEDUPE_RANGE_DIFFERS => DedupeStatus::Differs,
+                        errno => DedupeStatus::Failed(-errno),
+                    }
+                })
+                .collect())
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let _ = (src, offset, length);
+            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+    }
+}
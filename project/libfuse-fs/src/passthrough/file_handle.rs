@@ -4,15 +4,16 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::CStr;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::os::fd::AsFd;
-#[cfg(target_os = "linux")]
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 #[allow(unused_imports)]
 use tracing::error;
@@ -29,6 +30,173 @@ use super::mount_fd::{MPRResult, MountFd, MountFds};
 /// maximum value of 128 bytes defined in file include/linux/exportfs.h
 pub const MAX_HANDLE_SIZE: usize = 128;
 
+/// The leading byte of `FileHandle::to_bytes()`, to be changed along with the format.
+const FILE_HANDLE_MAGIC_V1: u8 = 0xf1;
+
+/// The size of the magic, `mnt_id`, `handle_type` and `handle_bytes` preceding the handle.
+const FILE_HANDLE_HEADER_SIZE: usize = 1 + 8 + 4 + 4;
+
+/// The `handle_type` of the handles made on macOS, whose payload is an index into `FD_SLAB`
+/// followed by the inode number of the file.
+#[cfg(target_os = "macos")]
+const FD_SLAB_HANDLE_TYPE: libc::c_int = 1;
+
+/// The size of the payload of an `FD_SLAB_HANDLE_TYPE` handle.
+#[cfg(target_os = "macos")]
+const FD_SLAB_HANDLE_SIZE: usize = 4 + 8;
+
+/// The fds behind the handles made on macOS, which has no `name_to_handle_at()`.
+///
+/// A file gets one slot, found by its device and inode number, so looking it up again yields an
+/// equal handle. Every `FileHandle` of a slot holds a reference to it, so the slot is freed
+/// once the inodes and caches using the file are gone, and its index is reused. A handle
+/// decoded by `from_bytes()` after that is stale, and so is any handle whose slot now holds
+/// another file.
+#[cfg(target_os = "macos")]
+static FD_SLAB: Mutex<FdSlab> = Mutex::new(FdSlab {
+    entries: Vec::new(),
+    free: Vec::new(),
+    slots: BTreeMap::new(),
+});
+
+#[cfg(target_os = "macos")]
+struct FdSlab {
+    entries: Vec<Option<FdSlabEntry>>,
+    // Indices of `entries` that are `None`.
+    free: Vec<u32>,
+    slots: BTreeMap<(u64, u64), u32>,
+}
+
+#[cfg(target_os = "macos")]
+struct FdSlabEntry {
+    file: Arc<File>,
+    // Device and inode number of `file`.
+    key: (u64, u64),
+    refs: usize,
+}
+
+#[cfg(target_os = "macos")]
+impl FdSlab {
+    /// Take a reference to the slot of the file with device and inode number `key`, registering
+    /// `file`, which is open on it, if it has none yet.
+    fn insert(&mut self, key: (u64, u64), file: File) -> io::Result<u32> {
+        if let Some(&slot) = self.slots.get(&key) {
+            self.acquire(slot, key);
+            return Ok(slot);
+        }
+        let entry = Some(FdSlabEntry {
+            file: Arc::new(file),
+            key,
+            refs: 1,
+        });
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.entries[slot as usize] = entry;
+                slot
+            }
+            None => {
+                let slot = u32::try_from(self.entries.len())
+                    .map_err(|_| io::Error::from_raw_os_error(libc::ENFILE))?;
+                self.entries.push(entry);
+                slot
+            }
+        };
+        self.slots.insert(key, slot);
+        Ok(slot)
+    }
+
+    /// Take another reference to `slot` if it still holds the file `key`.
+    fn acquire(&mut self, slot: u32, key: (u64, u64)) -> bool {
+        match self.entries.get_mut(slot as usize) {
+            Some(Some(entry)) if entry.key == key => {
+                entry.refs += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop a reference to `slot`, freeing it with the last one.
+    fn release(&mut self, slot: u32) {
+        let Some(Some(entry)) = self.entries.get_mut(slot as usize) else {
+            return;
+        };
+        entry.refs -= 1;
+        if entry.refs == 0 {
+            self.slots.remove(&entry.key);
+            self.entries[slot as usize] = None;
+            self.free.push(slot);
+        }
+    }
+
+    fn get(&self, slot: u32, key: (u64, u64)) -> Option<Arc<File>> {
+        match self.entries.get(slot as usize) {
+            Some(Some(entry)) if entry.key == key => Some(entry.file.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A reference to an `FD_SLAB` slot held by a `FileHandle`. It doesn't take part in comparing
+/// handles, which are identified by their bytes.
+#[cfg(target_os = "macos")]
+struct SlabRef {
+    slot: u32,
+    key: (u64, u64),
+}
+
+#[cfg(target_os = "macos")]
+impl Clone for SlabRef {
+    fn clone(&self) -> Self {
+        // The slot can't be freed while `self` holds a reference to it.
+        FD_SLAB.lock().unwrap().acquire(self.slot, self.key);
+        SlabRef { ..*self }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Drop for SlabRef {
+    fn drop(&mut self) {
+        FD_SLAB.lock().unwrap().release(self.slot);
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl PartialEq for SlabRef {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Eq for SlabRef {}
+
+#[cfg(target_os = "macos")]
+impl PartialOrd for SlabRef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Ord for SlabRef {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Hash for SlabRef {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+#[cfg(target_os = "macos")]
+impl Debug for SlabRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SlabRef({})", self.slot)
+    }
+}
+
 /// Dynamically allocated array.
 #[derive(Default)]
 #[repr(C)]
@@ -91,6 +259,14 @@ impl CFileHandle {
             wrapper: CFileHandleWrapper::new(size).unwrap(),
         }
     }
+
+    /// The used part of `f_handle`.
+    fn bytes(&self) -> &[u8] {
+        let fh = self.wrapper.as_fam_struct_ref();
+        let handle = fh.f_handle.as_slice(fh.handle_bytes as usize);
+        // Safe because `c_char` has the size and alignment of `u8`.
+        unsafe { std::slice::from_raw_parts(handle.as_ptr().cast(), handle.len()) }
+    }
 }
 
 // Safe because f_handle is readonly once FileHandle is initialized.
@@ -101,22 +277,16 @@ impl Ord for CFileHandle {
     fn cmp(&self, other: &Self) -> Ordering {
         let s_fh = self.wrapper.as_fam_struct_ref();
         let o_fh = other.wrapper.as_fam_struct_ref();
-        if s_fh.handle_bytes != o_fh.handle_bytes {
-            return s_fh.handle_bytes.cmp(&o_fh.handle_bytes);
+        // The same allocation, i.e. a handle compared with itself.
+        if std::ptr::eq(s_fh, o_fh) {
+            return Ordering::Equal;
         }
-        let length = s_fh.handle_bytes as usize;
-        if s_fh.handle_type != o_fh.handle_type {
-            return s_fh.handle_type.cmp(&o_fh.handle_type);
-        }
-
-        if s_fh.f_handle.as_ptr() != o_fh.f_handle.as_ptr() {
-            return s_fh
-                .f_handle
-                .as_slice(length)
-                .cmp(o_fh.f_handle.as_slice(length));
-        }
-
-        Ordering::Equal
+        // The headers first, the handles only if they're of the same size and type. Comparing
+        // them as `u8` rather than `c_char` lets the slice comparison use `memcmp()`.
+        s_fh.handle_bytes
+            .cmp(&o_fh.handle_bytes)
+            .then(s_fh.handle_type.cmp(&o_fh.handle_type))
+            .then_with(|| self.bytes().cmp(other.bytes()))
     }
 }
 
@@ -128,12 +298,27 @@ impl PartialOrd for CFileHandle {
 
 impl PartialEq for CFileHandle {
     fn eq(&self, other: &Self) -> bool {
-        self.cmp(other) == Ordering::Equal
+        let s_fh = self.wrapper.as_fam_struct_ref();
+        let o_fh = other.wrapper.as_fam_struct_ref();
+        std::ptr::eq(s_fh, o_fh)
+            || (s_fh.handle_bytes == o_fh.handle_bytes
+                && s_fh.handle_type == o_fh.handle_type
+                && self.bytes() == other.bytes())
     }
 }
 
 impl Eq for CFileHandle {}
 
+impl Hash for CFileHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Only the used part of `f_handle`, as compared by `cmp()`.
+        let fh = self.wrapper.as_fam_struct_ref();
+        fh.handle_bytes.hash(state);
+        fh.handle_type.hash(state);
+        self.bytes().hash(state);
+    }
+}
+
 impl Debug for CFileHandle {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let fh = self.wrapper.as_fam_struct_ref();
@@ -148,10 +333,12 @@ impl Debug for CFileHandle {
 }
 
 /// Struct to maintain information for a file handle.
-#[derive(Clone, PartialOrd, Ord, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
 pub struct FileHandle {
     pub(crate) mnt_id: u64,
     handle: CFileHandle,
+    #[cfg(target_os = "macos")]
+    slab: Option<SlabRef>,
 }
 
 impl Default for FileHandle {
@@ -159,6 +346,8 @@ impl Default for FileHandle {
         Self {
             mnt_id: 0,
             handle: CFileHandle::new(0),
+            #[cfg(target_os = "macos")]
+            slab: None,
         }
     }
 }
@@ -183,6 +372,14 @@ unsafe extern "C" {
     ) -> libc::c_int;
 }
 
+/// Whether `err` from `name_to_handle_at()` means the filesystem doesn't support file handles.
+/// The kernel reports `EOPNOTSUPP`, which is not guaranteed to have the same value as `ENOTSUP`
+/// on every libc, so both are accepted.
+#[cfg(target_os = "linux")]
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(e) if e == libc::EOPNOTSUPP || e == libc::ENOTSUP)
+}
+
 impl FileHandle {
     /// Create a file handle for the given file.
     ///
@@ -223,7 +420,7 @@ impl FileHandle {
                     // Got the needed buffer size.
                     Some(libc::EOVERFLOW) => {}
                     // Filesystem does not support file handles
-                    Some(libc::EOPNOTSUPP) => return Ok(None),
+                    _ if is_unsupported(&err) => return Ok(None),
                     // Other error
                     _ => return Err(err),
                 }
@@ -260,10 +457,62 @@ impl FileHandle {
         }
         #[cfg(target_os = "macos")]
         {
-            Ok(None)
+            // `O_SYMLINK` opens a symlink itself, as `name_to_handle_at()` makes a handle for it.
+            let fd = if path.to_bytes().is_empty() {
+                unsafe { libc::fcntl(dir_fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) }
+            } else {
+                unsafe {
+                    libc::openat(
+                        dir_fd.as_raw_fd(),
+                        path.as_ptr(),
+                        libc::O_RDONLY | libc::O_SYMLINK | libc::O_CLOEXEC,
+                    )
+                }
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safe because we just opened this fd.
+            let file = unsafe { File::from_raw_fd(fd) };
+            let st = super::util::stat_fd(&file, None)?;
+            let key = (st.st_dev as u64, st.st_ino);
+            let slot = FD_SLAB.lock().unwrap().insert(key, file)?;
+            let slab = SlabRef { slot, key };
+
+            let mut payload = [0u8; FD_SLAB_HANDLE_SIZE];
+            payload[..4].copy_from_slice(&slot.to_le_bytes());
+            payload[4..].copy_from_slice(&st.st_ino.to_le_bytes());
+            let mut c_fh = CFileHandle::new(payload.len());
+            // Safe because the wrapper was allocated for `payload.len()` bytes of handle.
+            let fh = unsafe { c_fh.wrapper.as_mut_fam_struct() };
+            fh.handle_type = FD_SLAB_HANDLE_TYPE;
+            for (dst, src) in fh
+                .f_handle
+                .as_mut_slice(payload.len())
+                .iter_mut()
+                .zip(payload)
+            {
+                *dst = src as libc::c_char;
+            }
+            Ok(Some(FileHandle {
+                mnt_id: key.0,
+                handle: c_fh,
+                slab: Some(slab),
+            }))
         }
     }
 
+    /// The `FD_SLAB` slot of a handle made on macOS and the device and inode number of its
+    /// file, `None` for any other handle.
+    #[cfg(target_os = "macos")]
+    fn slab_slot(&self) -> Option<(u32, (u64, u64))> {
+        let fh = self.handle.wrapper.as_fam_struct_ref();
+        let payload: [u8; FD_SLAB_HANDLE_SIZE] = self.handle.bytes().try_into().ok()?;
+        let slot = u32::from_le_bytes(payload[..4].try_into().unwrap());
+        let ino = u64::from_le_bytes(payload[4..].try_into().unwrap());
+        (fh.handle_type == FD_SLAB_HANDLE_TYPE).then_some((slot, (self.mnt_id, ino)))
+    }
+
     /// Create a file handle from a `fd`.
     /// This is a wrapper around `from_name_at()` and so has the same interface.
     pub fn from_fd(fd: &impl AsRawFd) -> io::Result<Option<Self>> {
@@ -272,6 +521,73 @@ impl FileHandle {
         Self::from_name_at(fd, empty_path)
     }
 
+    /// Encode the handle for storing it, e.g. across restarts, to be decoded by `from_bytes()`.
+    ///
+    /// The format is a magic byte identifying its version, `mnt_id`, `handle_type` and
+    /// `handle_bytes` in little endian, then the handle itself.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let fh = self.handle.wrapper.as_fam_struct_ref();
+        let payload = fh.f_handle.as_slice(fh.handle_bytes as usize);
+        let mut buf = Vec::with_capacity(FILE_HANDLE_HEADER_SIZE + payload.len());
+        buf.push(FILE_HANDLE_MAGIC_V1);
+        buf.extend_from_slice(&self.mnt_id.to_le_bytes());
+        buf.extend_from_slice(&fh.handle_type.to_le_bytes());
+        buf.extend_from_slice(&fh.handle_bytes.to_le_bytes());
+        buf.extend(payload.iter().map(|b| *b as u8));
+        buf
+    }
+
+    /// Decode a handle encoded by `to_bytes()`. Fails with `InvalidData` if `buf` is of another
+    /// format version, is truncated or has trailing bytes, or if the handle is larger than
+    /// `MAX_HANDLE_SIZE`.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if buf.len() < FILE_HANDLE_HEADER_SIZE {
+            return Err(invalid("file handle truncated"));
+        }
+        let (header, payload) = buf.split_at(FILE_HANDLE_HEADER_SIZE);
+        if header[0] != FILE_HANDLE_MAGIC_V1 {
+            return Err(invalid("unknown file handle format"));
+        }
+        let mnt_id = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let handle_type = libc::c_int::from_le_bytes(header[9..13].try_into().unwrap());
+        let handle_bytes = libc::c_uint::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+        if handle_bytes > MAX_HANDLE_SIZE {
+            return Err(invalid("file handle too large"));
+        }
+        if payload.len() != handle_bytes {
+            return Err(invalid("file handle length mismatch"));
+        }
+
+        let mut handle = CFileHandle::new(handle_bytes);
+        // Safe because the wrapper was allocated for `handle_bytes` bytes of handle.
+        let fh = unsafe { handle.wrapper.as_mut_fam_struct() };
+        fh.handle_type = handle_type;
+        for (dst, src) in fh
+            .f_handle
+            .as_mut_slice(handle_bytes)
+            .iter_mut()
+            .zip(payload)
+        {
+            *dst = *src as libc::c_char;
+        }
+        #[allow(unused_mut)]
+        let mut fh = FileHandle {
+            mnt_id,
+            handle,
+            #[cfg(target_os = "macos")]
+            slab: None,
+        };
+        // Keep the slot alive along with the handle if it still holds the file.
+        #[cfg(target_os = "macos")]
+        if let Some((slot, key)) = fh.slab_slot() {
+            if FD_SLAB.lock().unwrap().acquire(slot, key) {
+                fh.slab = Some(SlabRef { slot, key });
+            }
+        }
+        Ok(fh)
+    }
+
     /// Return an openable copy of the file handle by ensuring that `mount_fd` contains a valid fd
     /// for the mount the file handle is for.
     ///
@@ -334,7 +650,41 @@ impl OpenableFileHandle {
         }
         #[cfg(target_os = "macos")]
         {
-            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+            // The slab fd is reopened through its path rather than duplicated, so that the new
+            // file gets its own flags and offset.
+            let (slot, key) = self
+                .handle
+                .slab_slot()
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+            let file = FD_SLAB
+                .lock()
+                .unwrap()
+                .get(slot, key)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+            let mut buf = [0u8; libc::MAXPATHLEN as usize];
+            // Safe because `F_GETPATH` writes at most `MAXPATHLEN` bytes to `buf`.
+            let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_bytes_until_nul(&buf)
+                .map_err(|_| io::Error::from_raw_os_error(libc::ENAMETOOLONG))?;
+            let flags = flags & !libc::O_CREAT & !libc::O_DIRECTORY;
+            // Safe because `path` is a valid C string and we check the return value.
+            let ret = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                error!("reopening file handle slot failed error {e:?}");
+                return Err(e);
+            }
+            // Safe because `open()` guarantees this is a valid fd
+            let file = unsafe { File::from_raw_fd(ret) };
+            // The path may have been taken over by another file since.
+            let st = super::util::stat_fd(&file, None)?;
+            if (st.st_dev as u64, st.st_ino) != key {
+                return Err(io::Error::from_raw_os_error(libc::ESTALE));
+            }
+            Ok(file)
         }
     }
 
@@ -343,14 +693,114 @@ impl OpenableFileHandle {
     }
 }
 
+/// A bounded LRU cache of `OpenableFileHandle`s, so a file looked up again reuses the one built
+/// the first time instead of resolving its mount fd anew. Evicted entries drop their reference
+/// to the mount fd, which is closed once no inode uses it either.
+pub struct FileHandleCache {
+    capacity: usize,
+    inner: Mutex<FileHandleCacheInner>,
+}
+
+#[derive(Default)]
+struct FileHandleCacheInner {
+    // The cached handles and the stamp of their last use.
+    entries: HashMap<FileHandle, (u64, Arc<OpenableFileHandle>)>,
+    // The keys of `entries` by the stamp of their last use, least recent first.
+    recency: BTreeMap<u64, FileHandle>,
+    next_stamp: u64,
+}
+
+impl FileHandleCacheInner {
+    fn touch(&mut self, handle: &FileHandle) -> Option<Arc<OpenableFileHandle>> {
+        let stamp = self.next_stamp;
+        let (old_stamp, openable) = self.entries.get_mut(handle)?;
+        let key = self.recency.remove(old_stamp).unwrap();
+        self.recency.insert(stamp, key);
+        *old_stamp = stamp;
+        self.next_stamp += 1;
+        Some(openable.clone())
+    }
+}
+
+impl FileHandleCache {
+    /// Create a cache holding up to `capacity` handles. A capacity of 0 caches nothing.
+    pub fn new(capacity: usize) -> Self {
+        FileHandleCache {
+            capacity,
+            inner: Mutex::new(Default::default()),
+        }
+    }
+
+    /// The cached openable handle for `handle`, or the one built by `f`, which is cached and
+    /// may evict the least recently used handle.
+    ///
+    /// `f` runs without the cache locked. If another caller cached the same handle meanwhile,
+    /// theirs is kept and returned instead.
+    pub fn get_or_insert_with<F>(
+        &self,
+        handle: &FileHandle,
+        f: F,
+    ) -> MPRResult<Arc<OpenableFileHandle>>
+    where
+        F: FnOnce() -> MPRResult<OpenableFileHandle>,
+    {
+        if let Some(openable) = self.inner.lock().unwrap().touch(handle) {
+            return Ok(openable);
+        }
+        let openable = Arc::new(f()?);
+        if self.capacity == 0 {
+            return Ok(openable);
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(openable) = inner.touch(handle) {
+            return Ok(openable);
+        }
+        while inner.entries.len() >= self.capacity {
+            let (_, lru) = inner.recency.pop_first().unwrap();
+            inner.entries.remove(&lru);
+        }
+        let stamp = inner.next_stamp;
+        inner.next_stamp += 1;
+        inner.recency.insert(stamp, handle.clone());
+        inner
+            .entries
+            .insert(handle.clone(), (stamp, openable.clone()));
+        Ok(openable)
+    }
+
+    #[cfg(all(test, target_os = "linux"))]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// Drop all cached handles.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.recency.clear();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[cfg(target_os = "linux")]
+    use crate::passthrough::util::reopen_fd_through_proc;
+    #[cfg(target_os = "linux")]
     use std::ffi::CString;
     #[cfg(target_os = "linux")]
     use std::fs::OpenOptions;
 
+    fn file_handle(mnt_id: u64, handle: CFileHandle) -> FileHandle {
+        FileHandle {
+            mnt_id,
+            handle,
+            #[cfg(target_os = "macos")]
+            slab: None,
+        }
+    }
+
     fn generate_c_file_handle(
         handle_bytes: usize,
         handle_type: libc::c_int,
@@ -370,34 +820,19 @@ mod tests {
     #[test]
     fn test_file_handle_derives() {
         let h1 = generate_c_file_handle(128, 3, vec![0; 128]);
-        let mut fh1 = FileHandle {
-            mnt_id: 0,
-            handle: h1,
-        };
+        let mut fh1 = file_handle(0, h1);
 
         let h2 = generate_c_file_handle(127, 3, vec![0; 127]);
-        let fh2 = FileHandle {
-            mnt_id: 0,
-            handle: h2,
-        };
+        let fh2 = file_handle(0, h2);
 
         let h3 = generate_c_file_handle(128, 4, vec![0; 128]);
-        let fh3 = FileHandle {
-            mnt_id: 0,
-            handle: h3,
-        };
+        let fh3 = file_handle(0, h3);
 
         let h4 = generate_c_file_handle(128, 3, vec![1; 128]);
-        let fh4 = FileHandle {
-            mnt_id: 0,
-            handle: h4,
-        };
+        let fh4 = file_handle(0, h4);
 
         let h5 = generate_c_file_handle(128, 3, vec![0; 128]);
-        let mut fh5 = FileHandle {
-            mnt_id: 0,
-            handle: h5,
-        };
+        let mut fh5 = file_handle(0, h5);
 
         assert!(fh1 > fh2);
         assert_ne!(fh1, fh2);
@@ -425,6 +860,88 @@ mod tests {
         assert_eq!(fh1, fh5);
     }
 
+    #[test]
+    fn test_file_handle_hash() {
+        use std::collections::HashSet;
+        use std::hash::BuildHasher;
+
+        let hasher = std::collections::hash_map::RandomState::new();
+        let handle = |mnt_id: u64, bytes: usize, handle_type: libc::c_int, first: libc::c_char| {
+            let mut buf = vec![0; bytes];
+            if bytes > 0 {
+                buf[0] = first;
+            }
+            file_handle(mnt_id, generate_c_file_handle(bytes, handle_type, buf))
+        };
+
+        // Equal handles hash the same, whatever lies past `handle_bytes` in the allocation.
+        let mut fh1 = handle(0, 8, 1, 1);
+        let fh2 = handle(0, 8, 1, 1);
+        unsafe {
+            let fh = fh1.handle.wrapper.as_mut_fam_struct();
+            fh.handle_bytes = 4;
+        }
+        let mut fh2_short = fh2.clone();
+        unsafe {
+            let fh = fh2_short.handle.wrapper.as_mut_fam_struct();
+            fh.f_handle.as_mut_slice(8)[7] = 9;
+            fh.handle_bytes = 4;
+        }
+        assert_eq!(fh1, fh2_short);
+        assert_eq!(hasher.hash_one(&fh1), hasher.hash_one(&fh2_short));
+        assert_eq!(hasher.hash_one(&fh2), hasher.hash_one(fh2.clone()));
+
+        // Handles differing in any part mostly hash differently.
+        let mut distinct = vec![handle(1, 8, 1, 1), handle(0, 8, 2, 1), handle(0, 7, 1, 1)];
+        distinct.extend((2..102).map(|i| handle(0, 8, 1, i)));
+        let hashes: HashSet<_> = distinct.iter().map(|fh| hasher.hash_one(fh)).collect();
+        assert!(hashes.len() > distinct.len() * 9 / 10);
+        assert!(!hashes.contains(&hasher.hash_one(&fh2)));
+    }
+
+    #[test]
+    fn test_file_handle_bytes() {
+        let buf = (0..=127).collect::<Vec<libc::c_char>>();
+        for fh in [
+            FileHandle::default(),
+            file_handle(
+                0x0102_0304_0506_0708,
+                generate_c_file_handle(8, -2, buf[..8].to_vec()),
+            ),
+            file_handle(1, generate_c_file_handle(MAX_HANDLE_SIZE, 1, buf.clone())),
+        ] {
+            let bytes = fh.to_bytes();
+            let handle_bytes = fh.handle.wrapper.as_fam_struct_ref().handle_bytes as usize;
+            assert_eq!(bytes.len(), FILE_HANDLE_HEADER_SIZE + handle_bytes);
+            assert_eq!(bytes[0], FILE_HANDLE_MAGIC_V1);
+            let decoded = FileHandle::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded, fh);
+            assert_eq!(decoded.mnt_id, fh.mnt_id);
+            assert_eq!(decoded.to_bytes(), bytes);
+        }
+
+        let bytes = file_handle(3, generate_c_file_handle(8, 1, buf[..8].to_vec())).to_bytes();
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = 0;
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        // A header claiming a handle beyond the limit, followed by as many bytes.
+        let mut oversized = bytes[..FILE_HANDLE_HEADER_SIZE].to_vec();
+        oversized[13..17].copy_from_slice(&(MAX_HANDLE_SIZE as u32 + 1).to_le_bytes());
+        oversized.resize(FILE_HANDLE_HEADER_SIZE + MAX_HANDLE_SIZE + 1, 0);
+        for buf in [
+            &[][..],
+            &bytes[..FILE_HANDLE_HEADER_SIZE - 1],
+            &bytes[..bytes.len() - 1],
+            &trailing,
+            &bad_magic,
+            &oversized,
+        ] {
+            let err = FileHandle::from_bytes(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
     #[test]
     fn test_c_file_handle_wrapper() {
         let buf = (0..=127).collect::<Vec<libc::c_char>>();
@@ -466,8 +983,162 @@ mod tests {
             file_handle.handle.wrapper.as_fam_struct_ref().handle_bytes,
             0
         );
+        let decoded = FileHandle::from_bytes(&file_handle.to_bytes()).unwrap();
+        assert_eq!(decoded, file_handle);
 
         // Clean up the temporary file
         std::fs::remove_file(tmp_file_path).unwrap();
     }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_file_handle_unsupported_errno() {
+        for errno in [libc::EOPNOTSUPP, libc::ENOTSUP] {
+            assert!(is_unsupported(&io::Error::from_raw_os_error(errno)));
+        }
+        for errno in [libc::EOVERFLOW, libc::EPERM, libc::EIO] {
+            assert!(!is_unsupported(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(!is_unsupported(&io::Error::from(
+            io::ErrorKind::InvalidData
+        )));
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_file_handle_from_name_at_macos() {
+        use std::ffi::CString;
+        use std::io::Read;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"hello").unwrap();
+        let dir = File::open(tmp_dir.path()).unwrap();
+        let name = CString::new("file").unwrap();
+
+        let handle = FileHandle::from_name_at(&dir, &name).unwrap().unwrap();
+        assert_eq!(
+            FileHandle::from_name_at(&dir, &name).unwrap().unwrap(),
+            handle
+        );
+        let file = File::open(tmp_dir.path().join("file")).unwrap();
+        assert_eq!(FileHandle::from_fd(&file).unwrap().unwrap(), handle);
+        assert_ne!(FileHandle::from_fd(&dir).unwrap().unwrap(), handle);
+
+        let mount_fds = MountFds::with_mount_info_file(File::open("/dev/null").unwrap(), None);
+        let openable = FileHandle::from_bytes(&handle.to_bytes())
+            .unwrap()
+            .into_openable(&mount_fds, |fd, flags, _mode| {
+                let path = CString::new(format!("/dev/fd/{fd}")).unwrap();
+                let ret = unsafe { libc::open(path.as_ptr(), flags) };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(unsafe { File::from_raw_fd(ret) })
+            })
+            .unwrap();
+        // Each open gets its own offset.
+        for _ in 0..2 {
+            let mut content = String::new();
+            openable
+                .open(libc::O_RDONLY)
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(content, "hello");
+        }
+
+        // A slot that was never handed out is stale.
+        let mut buf = handle.to_bytes();
+        buf[FILE_HANDLE_HEADER_SIZE..FILE_HANDLE_HEADER_SIZE + 4]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        let stale = FileHandle::from_bytes(&buf)
+            .unwrap()
+            .into_openable(&mount_fds, |_, _, _| unreachable!())
+            .unwrap();
+        let err = stale.open(libc::O_RDONLY).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+
+        // The slot is freed with the last handle of the file, a handle decoded later is stale
+        // even when the slot is reused.
+        let bytes = handle.to_bytes();
+        drop((handle, openable, file));
+        std::fs::write(tmp_dir.path().join("other"), b"").unwrap();
+        let other = FileHandle::from_name_at(&dir, &CString::new("other").unwrap())
+            .unwrap()
+            .unwrap();
+        let stale = FileHandle::from_bytes(&bytes)
+            .unwrap()
+            .into_openable(&mount_fds, |_, _, _| unreachable!())
+            .unwrap();
+        let err = stale.open(libc::O_RDONLY).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+        drop(other);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_file_handle_cache() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = File::open(tmp_dir.path()).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                std::fs::write(tmp_dir.path().join(i.to_string()), b"").unwrap();
+                let name = CString::new(i.to_string()).unwrap();
+                FileHandle::from_name_at(&dir, &name).unwrap()
+            })
+            .collect::<Option<_>>()
+            .unwrap_or_default();
+        if handles.is_empty() {
+            eprintln!("skip test_file_handle_cache: no file handles on the host filesystem");
+            return;
+        }
+        let mount_fds = MountFds::new(None).unwrap();
+        let proc_self_fd = File::open("/proc/self/fd").unwrap();
+        let openable = |handle: &FileHandle| {
+            handle
+                .clone()
+                .into_openable(&mount_fds, |fd, flags, _mode| {
+                    reopen_fd_through_proc(&fd, flags, &proc_self_fd)
+                })
+        };
+
+        let cache = FileHandleCache::new(2);
+        let builds = std::cell::Cell::new(0);
+        let get = |i: usize| {
+            cache
+                .get_or_insert_with(&handles[i], || {
+                    builds.set(builds.get() + 1);
+                    openable(&handles[i])
+                })
+                .unwrap()
+        };
+
+        // Identical handles collapse to one entry.
+        let first = get(0);
+        let again = cache
+            .get_or_insert_with(&handles[0].clone(), || panic!("rebuilt a cached handle"))
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!((cache.len(), builds.get()), (1, 1));
+
+        // Using 0 makes 1 the least recently used, so 2 evicts it.
+        get(1);
+        get(0);
+        get(2);
+        assert_eq!((cache.len(), builds.get()), (2, 3));
+        get(0);
+        get(2);
+        assert_eq!(builds.get(), 3);
+        get(1);
+        assert_eq!(builds.get(), 4);
+
+        // 0 was evicted, so it goes away with the last outside reference, and with it its
+        // reference to the mount fd.
+        let evicted = Arc::downgrade(&first);
+        let mount_fd = first.mount_fd.clone();
+        let refs = Arc::strong_count(&mount_fd);
+        drop((first, again));
+        assert!(evicted.upgrade().is_none());
+        assert_eq!(Arc::strong_count(&mount_fd), refs - 1);
+    }
 }
@@ -0,0 +1,127 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use super::config::HealthProbe;
+
+type Probe = dyn Fn() -> io::Result<()> + Send + Sync;
+
+/// Whether the backing store answers, as found by probing its root periodically, see
+/// `Config::backend_health_probe`.
+pub(crate) struct BackendHealth {
+    probe: HealthProbe,
+    root: PathBuf,
+    down: AtomicBool,
+    // When the probes that have failed since the last successful one started failing.
+    failing_since: Mutex<Option<Instant>>,
+    started: AtomicBool,
+    // Whether a probe is still running, possibly stuck past its timeout.
+    in_flight: Arc<AtomicBool>,
+}
+
+impl BackendHealth {
+    pub(crate) fn new(probe: HealthProbe, root: PathBuf) -> Self {
+        BackendHealth {
+            probe,
+            root,
+            down: AtomicBool::new(false),
+            failing_since: Mutex::new(None),
+            started: AtomicBool::new(false),
+            in_flight: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start calling `check` in the background, unless already started. It's meant to touch the
+    /// root the file system holds open rather than whatever is at its path now. The task ends
+    /// once `self` is dropped.
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        check: impl Fn() -> io::Result<()> + Send + Sync + 'static,
+    ) {
+        if self.started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let check: Arc<Probe> = Arc::new(check);
+        let health = Arc::downgrade(self);
+        let mut ticker = tokio::time::interval(self.probe.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::spawn(async move {
+            loop {
+                ticker.tick().await;
+                let Some(health) = health.upgrade() else {
+                    break;
+                };
+                health.probe_once(&check).await;
+            }
+        });
+    }
+
+    async fn probe_once(&self, check: &Arc<Probe>) {
+        // A probe stuck on a hung store keeps its blocking thread. Don't pile more up behind it,
+        // the store stays down until it returns.
+        if self.in_flight.swap(true, Ordering::Acquire) {
+            self.set_down(Some(io::Error::from(io::ErrorKind::TimedOut)));
+            return;
+        }
+        let check = check.clone();
+        let in_flight = self.in_flight.clone();
+        let res = tokio::time::timeout(
+            self.probe.interval,
+            tokio::task::spawn_blocking(move || {
+                let res = check();
+                in_flight.store(false, Ordering::Release);
+                res
+            }),
+        )
+        .await;
+        let err = match res {
+            Ok(Ok(Ok(()))) => None,
+            Ok(Ok(Err(e))) => Some(e),
+            Ok(Err(e)) => Some(io::Error::other(e)),
+            Err(_) => Some(io::Error::from(io::ErrorKind::TimedOut)),
+        };
+        self.set_down(err);
+    }
+
+    /// Record the outcome of a probe, `err` if it failed. The store is only considered down
+    /// once probes have kept failing for the grace period.
+    fn set_down(&self, err: Option<io::Error>) {
+        {
+            let mut failing_since = self.failing_since.lock().unwrap();
+            if err.is_none() {
+                *failing_since = None;
+            } else if failing_since.get_or_insert_with(Instant::now).elapsed() < self.probe.grace {
+                return;
+            }
+        }
+        let was_down = self.down.swap(err.is_some(), Ordering::Relaxed);
+        match err {
+            Some(e) if !was_down => warn!(
+                "passthrough: backing store {} is unreachable ({e}), failing requests with {}",
+                self.root.display(),
+                io::Error::from_raw_os_error(self.probe.errno)
+            ),
+            None if was_down => {
+                info!("passthrough: backing store {} is back", self.root.display())
+            }
+            _ => {}
+        }
+    }
+
+    /// Fail with the configured errno if the backing store was unreachable when last probed.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.down.load(Ordering::Relaxed) {
+            Err(io::Error::from_raw_os_error(self.probe.errno))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::config::InodeIdentity;
+
+/// The `InodeIdentity` chosen for each host filesystem from `Config::inode_identity`.
+pub(crate) struct IdentityChain {
+    chain: Vec<InodeIdentity>,
+    chosen: RwLock<HashMap<libc::dev_t, InodeIdentity>>,
+}
+
+impl IdentityChain {
+    pub(crate) fn new(chain: &[InodeIdentity]) -> Self {
+        IdentityChain {
+            chain: chain.to_vec(),
+            chosen: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The identity of the files on the filesystem `dev`. On its first use, it's the first one
+    /// in the chain `probe` accepts, falling back to `InodeIdentity::Device`.
+    pub(crate) fn choose(
+        &self,
+        dev: libc::dev_t,
+        mut probe: impl FnMut(InodeIdentity) -> bool,
+    ) -> InodeIdentity {
+        if let Some(identity) = self.chosen.read().unwrap().get(&dev) {
+            return *identity;
+        }
+        let identity = self
+            .chain
+            .iter()
+            .copied()
+            .find(|identity| *identity == InodeIdentity::Device || probe(*identity))
+            .unwrap_or(InodeIdentity::Device);
+        // A racing lookup may have probed too, keep whichever got there first.
+        *self.chosen.write().unwrap().entry(dev).or_insert(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use InodeIdentity::*;
+
+    const ALL: [InodeIdentity; 3] = [FileHandle, MountId, Device];
+
+    #[test]
+    fn test_fall_through() {
+        // Everything works: the first one wins.
+        let chain = IdentityChain::new(&ALL);
+        assert_eq!(chain.choose(1, |_| true), FileHandle);
+
+        // No file handles.
+        let chain = IdentityChain::new(&ALL);
+        assert_eq!(chain.choose(1, |identity| identity != FileHandle), MountId);
+
+        // Neither file handles nor mount ids.
+        let chain = IdentityChain::new(&ALL);
+        let mut probed = Vec::new();
+        let identity = chain.choose(1, |identity| {
+            probed.push(identity);
+            false
+        });
+        assert_eq!(identity, Device);
+        assert_eq!(probed, [FileHandle, MountId]);
+
+        // Device is the last resort even when left out.
+        let chain = IdentityChain::new(&[FileHandle]);
+        assert_eq!(chain.choose(1, |_| false), Device);
+
+        // Disabled primitives aren't probed.
+        let chain = IdentityChain::new(&[MountId, Device]);
+        assert_eq!(
+            chain.choose(1, |identity| {
+                assert_ne!(identity, FileHandle);
+                true
+            }),
+            MountId
+        );
+    }
+
+    #[test]
+    fn test_remembered_per_filesystem() {
+        let chain = IdentityChain::new(&ALL);
+        assert_eq!(chain.choose(1, |identity| identity == MountId), MountId);
+        assert_eq!(chain.choose(2, |_| true), FileHandle);
+
+        // Later lookups don't probe again, even if the answer would change.
+        assert_eq!(chain.choose(1, |_| panic!("probed again")), MountId);
+        assert_eq!(chain.choose(2, |_| panic!("probed again")), FileHandle);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rfuse3::Inode;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// A map of per-inode async locks used to serialize conflicting operations.
+///
+/// Operations on the same inode (e.g. `write` vs `setattr`, or `rename` vs `unlink` on the same
+/// parent directory) take the lock of that inode, while operations on different inodes never
+/// contend with each other. Entries are created lazily and dropped as soon as the last guard
+/// referring to them goes away, so the map only ever holds inodes with operations in flight.
+#[derive(Default)]
+pub(crate) struct InodeLockMap {
+    locks: Mutex<HashMap<Inode, Arc<AsyncMutex<()>>>>,
+}
+
+/// Guard holding the locks of one or more inodes, released on drop.
+pub(crate) struct InodeLockGuard<'a> {
+    map: Option<&'a InodeLockMap>,
+    inodes: Vec<Inode>,
+    guards: Vec<OwnedMutexGuard<()>>,
+}
+
+impl InodeLockMap {
+    /// Lock a set of inodes.
+    ///
+    /// Inodes are deduplicated and locked in ascending order so that concurrent callers locking
+    /// overlapping sets (such as two cross-directory renames) can't deadlock.
+    pub(crate) async fn lock_many(&self, inodes: &[Inode]) -> InodeLockGuard<'_> {
+        let mut inodes = inodes.to_vec();
+        inodes.sort_unstable();
+        inodes.dedup();
+
+        let mut guards = Vec::with_capacity(inodes.len());
+        for inode in inodes.iter() {
+            let lock = self
+                .locks
+                .lock()
+                .unwrap()
+                .entry(*inode)
+                .or_default()
+                .clone();
+            guards.push(lock.lock_owned().await);
+        }
+
+        InodeLockGuard {
+            map: Some(self),
+            inodes,
+            guards,
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl InodeLockGuard<'_> {
+    /// A guard which holds nothing, used when per-inode locking is disabled.
+    pub(crate) fn empty() -> Self {
+        InodeLockGuard {
+            map: None,
+            inodes: Vec::new(),
+            guards: Vec::new(),
+        }
+    }
+}
+
+impl Drop for InodeLockGuard<'_> {
+    fn drop(&mut self) {
+        // Release the locks before pruning, otherwise our own guards keep the entries alive.
+        self.guards.clear();
+        if let Some(map) = self.map {
+            let mut locks = map.locks.lock().unwrap();
+            for inode in self.inodes.iter() {
+                if locks
+                    .get(inode)
+                    .is_some_and(|lock| Arc::strong_count(lock) == 1)
+                {
+                    locks.remove(inode);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_inode_lock_map() {
+        let map = Arc::new(InodeLockMap::default());
+
+        let guard = map.lock_many(&[3, 2, 3]).await;
+        assert_eq!(guard.inodes, vec![2, 3]);
+        assert_eq!(map.len(), 2);
+
+        // A different inode is not blocked.
+        let other = tokio::time::timeout(Duration::from_millis(100), map.lock_many(&[4]))
+            .await
+            .expect("unrelated inode must not block");
+        drop(other);
+
+        // The same inode is blocked until the first guard is released.
+        let m = map.clone();
+        let waiter = tokio::spawn(async move {
+            let _g = m.lock_many(&[3]).await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+        drop(guard);
+        waiter.await.unwrap();
+
+        assert_eq!(map.len(), 0);
+    }
+}
@@ -93,6 +93,16 @@ impl InodeStore {
         // );
     }
 
+    /// Make `alias` resolve to the data of the already inserted `inode`.
+    ///
+    /// Only the data mapping is added, so the alias is never returned by `inode_by_id()` or
+    /// `inode_by_handle()`.
+    pub fn insert_alias(&mut self, alias: Inode, inode: &Inode) {
+        if let Some(data) = self.data.get(inode).cloned() {
+            self.data.insert(alias, data);
+        }
+    }
+
     /// Remove an inode from the manager, keeping the (key, ino) mapping if `remove_data_only` is true.
     #[allow(unused)]
     pub fn remove(&mut self, inode: &Inode, remove_data_only: bool) -> Option<Arc<InodeData>> {
@@ -148,6 +158,11 @@ impl InodeStore {
     pub fn inode_by_handle(&self, handle: &FileHandle) -> Option<&Inode> {
         self.by_handle.get(handle)
     }
+
+    /// Iterate over all resident inodes, including aliases, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Inode, &Arc<InodeData>)> {
+        self.data.iter()
+    }
 }
 
 #[cfg(test)]
@@ -175,6 +190,7 @@ mod test {
             inode1,
             file_or_handle1,
             2,
+            1,
             id1,
             inode_stat1.st.st_mode.into(),
             inode_stat1.btime.unwrap(),
@@ -183,6 +199,7 @@ mod test {
             inode2,
             file_or_handle2,
             2,
+            2,
             id2,
             inode_stat2.st.st_mode.into(),
             inode_stat2.btime.unwrap(),
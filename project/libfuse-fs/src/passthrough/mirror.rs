@@ -0,0 +1,359 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Best-effort mirroring of mutating operations to `Config::mirror_dir`.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+use rfuse3::{Errno, Inode};
+use tracing::warn;
+use vm_memory::bitmap::BitmapSlice;
+
+use super::util::{do_fallocate, openat};
+use super::{PassthroughFs, ROOT_ID};
+
+/// A mutating operation which already succeeded on the primary directory.
+pub(super) enum MirrorOp<'a> {
+    Create {
+        parent: Inode,
+        name: &'a OsStr,
+        mode: u32,
+        /// Whether an existing file is truncated, as by `O_TRUNC`.
+        truncate: bool,
+    },
+    Mkdir {
+        parent: Inode,
+        name: &'a OsStr,
+        mode: u32,
+    },
+    Symlink {
+        parent: Inode,
+        name: &'a OsStr,
+        target: &'a OsStr,
+    },
+    Link {
+        inode: Inode,
+        new_parent: Inode,
+        new_name: &'a OsStr,
+    },
+    Unlink {
+        parent: Inode,
+        name: &'a OsStr,
+    },
+    Rmdir {
+        parent: Inode,
+        name: &'a OsStr,
+    },
+    Rename {
+        parent: Inode,
+        name: &'a OsStr,
+        new_parent: Inode,
+        new_name: &'a OsStr,
+        /// The `renameat2` flags, e.g. `RENAME_EXCHANGE`.
+        flags: u32,
+    },
+    Write {
+        inode: Inode,
+        offset: u64,
+        data: &'a [u8],
+    },
+    Truncate {
+        inode: Inode,
+        size: u64,
+    },
+    Fallocate {
+        inode: Inode,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    },
+}
+
+/// The most data read back from the primary directory at once by `mirror_range`.
+const MIRROR_RANGE_CHUNK: u64 = 1 << 20;
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Apply `op` to the mirror directory, if one is configured.
+    ///
+    /// Failures are logged and swallowed unless `Config::mirror_strict` is set, in which case
+    /// they're returned even though the primary directory has already been modified.
+    pub(super) async fn mirror(&self, op: MirrorOp<'_>) -> rfuse3::Result<()> {
+        if self.cfg.mirror_dir.is_none() {
+            return Ok(());
+        }
+        let res = self.do_mirror(op).await;
+        self.mirror_result(res)
+    }
+
+    /// Mirror the `length` bytes at `offset` of the file `inode` which changed other than by a
+    /// write, e.g. by `copy_file_range`, as writes of the data read back from the primary.
+    pub(super) async fn mirror_range(
+        &self,
+        inode: Inode,
+        offset: u64,
+        length: u64,
+    ) -> rfuse3::Result<()> {
+        if self.cfg.mirror_dir.is_none() || length == 0 {
+            return Ok(());
+        }
+        let file = match self.open_inode(inode, libc::O_RDONLY).await {
+            Ok(file) => file,
+            Err(e) => return self.mirror_result(Err(e)),
+        };
+        let mut buf = vec![0; length.min(MIRROR_RANGE_CHUNK) as usize];
+        let mut done = 0;
+        while done < length {
+            let len = (length - done).min(buf.len() as u64) as usize;
+            if let Err(e) = file.read_exact_at(&mut buf[..len], offset + done) {
+                return self.mirror_result(Err(e));
+            }
+            self.mirror(MirrorOp::Write {
+                inode,
+                offset: offset + done,
+                data: &buf[..len],
+            })
+            .await?;
+            done += len as u64;
+        }
+        Ok(())
+    }
+
+    fn mirror_result(&self, res: io::Result<()>) -> rfuse3::Result<()> {
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) if self.cfg.mirror_strict => {
+                Err(Errno::from(e.raw_os_error().unwrap_or(libc::EIO)))
+            }
+            Err(e) => {
+                warn!("passthrough: failed to mirror operation: {e}");
+                Ok(())
+            }
+        }
+    }
+
+    async fn do_mirror(&self, op: MirrorOp<'_>) -> io::Result<()> {
+        match op {
+            MirrorOp::Create {
+                parent,
+                name,
+                mode,
+                truncate,
+            } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                let trunc = if truncate { libc::O_TRUNC } else { 0 };
+                openat(
+                    &dir,
+                    &name,
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW | trunc,
+                    mode & 0o7777,
+                )
+                .map(drop)
+            }
+            MirrorOp::Mkdir { parent, name, mode } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                check(unsafe {
+                    libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), (mode & 0o7777) as _)
+                })
+            }
+            MirrorOp::Symlink {
+                parent,
+                name,
+                target,
+            } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                let target = cstring(target)?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                check(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })
+            }
+            MirrorOp::Link {
+                inode,
+                new_parent,
+                new_name,
+            } => {
+                let (dir, name) = self.mirror_entry(inode, None).await?;
+                let (new_dir, new_name) = self.mirror_entry(new_parent, Some(new_name)).await?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                check(unsafe {
+                    libc::linkat(
+                        dir.as_raw_fd(),
+                        name.as_ptr(),
+                        new_dir.as_raw_fd(),
+                        new_name.as_ptr(),
+                        0,
+                    )
+                })
+            }
+            MirrorOp::Unlink { parent, name } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+            }
+            MirrorOp::Rmdir { parent, name } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                // Safe because this doesn't modify any memory and we check the return value.
+                check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) })
+            }
+            MirrorOp::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                flags,
+            } => {
+                let (dir, name) = self.mirror_entry(parent, Some(name)).await?;
+                let (new_dir, new_name) = self.mirror_entry(new_parent, Some(new_name)).await?;
+                mirror_rename(&dir, &name, &new_dir, &new_name, flags)
+            }
+            MirrorOp::Write {
+                inode,
+                offset,
+                data,
+            } => {
+                let Some(file) = self.mirror_file(inode).await? else {
+                    return Ok(());
+                };
+                file.write_all_at(data, offset)
+            }
+            MirrorOp::Truncate { inode, size } => {
+                let Some(file) = self.mirror_file(inode).await? else {
+                    return Ok(());
+                };
+                file.set_len(size)
+            }
+            MirrorOp::Fallocate {
+                inode,
+                mode,
+                offset,
+                length,
+            } => {
+                let Some(file) = self.mirror_file(inode).await? else {
+                    return Ok(());
+                };
+                do_fallocate(file.as_raw_fd(), mode, offset, length)
+            }
+        }
+    }
+
+    /// The file in the mirror directory corresponding to the file `inode`, opened for writing,
+    /// or `None` if it was unlinked while open, as its data changes then have nowhere to go.
+    /// Files created before the mirror was set up get created on their first change.
+    async fn mirror_file(&self, inode: Inode) -> io::Result<Option<File>> {
+        let (dir, name) = match self.mirror_entry(inode, None).await {
+            Ok(entry) => entry,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        openat(
+            &dir,
+            &name,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW,
+            0o666,
+        )
+        .map(Some)
+    }
+
+    /// The directory in the mirror corresponding to `inode` and the entry `name` in it, or
+    /// without a name the directory holding `inode` itself and its name there.
+    async fn mirror_entry(
+        &self,
+        inode: Inode,
+        name: Option<&OsStr>,
+    ) -> io::Result<(File, CString)> {
+        let rel = self.mirror_rel_path(inode).await?;
+        let (dir, name) = match name {
+            Some(name) => (rel.as_path(), name),
+            None => match (rel.parent(), rel.file_name()) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            },
+        };
+        Ok((self.open_mirror_dir(dir)?, cstring(name)?))
+    }
+
+    /// Open the directory `rel` below the mirror root one component at a time, never following
+    /// a symlink, so nothing placed in the mirror can redirect an operation out of it.
+    fn open_mirror_dir(&self, rel: &Path) -> io::Result<File> {
+        #[cfg(target_os = "linux")]
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+        #[cfg(target_os = "macos")]
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+
+        let root = self
+            .mirror_root
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let mut dir = openat(root, c".", flags, 0)?;
+        for component in rel.components() {
+            let Component::Normal(name) = component else {
+                return Err(io::Error::from_raw_os_error(libc::EXDEV));
+            };
+            dir = openat(&dir, &cstring(name)?, flags, 0)?;
+        }
+        Ok(dir)
+    }
+
+    /// The path of `inode` relative to the root of the primary directory.
+    async fn mirror_rel_path(&self, inode: Inode) -> io::Result<PathBuf> {
+        let root = self.readlinkat_proc_file(ROOT_ID).await?;
+        let path = self.readlinkat_proc_file(inode).await?;
+        // An inode whose last link is gone has no counterpart left in the mirror.
+        if path.as_os_str().as_bytes().ends_with(b" (deleted)") {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        path.strip_prefix(&root)
+            .map(Path::to_path_buf)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EXDEV))
+    }
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn mirror_rename(
+    dir: &File,
+    name: &CStr,
+    new_dir: &File,
+    new_name: &CStr,
+    flags: u32,
+) -> io::Result<()> {
+    if flags == 0 {
+        // Safe because this doesn't modify any memory and we check the return value.
+        return check(unsafe {
+            libc::renameat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                new_dir.as_raw_fd(),
+                new_name.as_ptr(),
+            )
+        });
+    }
+    // Safe because this doesn't modify any memory and we check the return value.
+    #[cfg(target_os = "linux")]
+    return check(unsafe {
+        libc::renameat2(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            new_dir.as_raw_fd(),
+            new_name.as_ptr(),
+            flags,
+        )
+    });
+    #[cfg(target_os = "macos")]
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
@@ -1,31 +1,49 @@
 #![allow(clippy::useless_conversion)]
-use config::{CachePolicy, Config};
-use file_handle::{FileHandle, OpenableFileHandle};
+pub use config::{
+    CachePolicy, Config, Diagnostic, DirectIoWrites, HealthProbe, InodeIdentity, LogLevelHook,
+    MultiRootConfig, RetryPolicy, XattrFilter, XattrNameMap,
+};
+pub use dedupe::{DedupeDest, DedupeStatus};
+pub use file_handle::FileHandle;
+use file_handle::{FileHandleCache, OpenableFileHandle};
 
 #[cfg(target_os = "macos")]
 use self::statx::statx_timestamp;
 use futures::executor::block_on;
+use health::BackendHealth;
+use identity::IdentityChain;
+use inode_lock::{InodeLockGuard, InodeLockMap};
 use inode_store::{InodeId, InodeStore};
 #[cfg(target_os = "linux")]
 use libc::{self, statx_timestamp};
 
 use moka::future::Cache;
-use rfuse3::{Errno, raw::reply::ReplyEntry};
+use rfuse3::{
+    Errno,
+    raw::{
+        Request,
+        reply::{ReplyEntry, ReplyStatFs},
+    },
+};
 use uuid::Uuid;
 
 use crate::passthrough::mmap::{MmapCachedValue, MmapChunkKey};
 use crate::util::convert_stat64_to_file_attr;
 use mount_fd::MountFds;
+use redirect::Redirect;
 use statx::StatExt;
 use std::cmp;
 use std::io::Result;
 use std::ops::DerefMut;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use syscalls::SyscallSupport;
+use throttle::TokenBucket;
 use tracing::error;
+use tracing::level_filters::LevelFilter;
 use tracing::{debug, warn};
 
-use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
 use std::{
     collections::{BTreeMap, btree_map},
     ffi::{CStr, CString, OsString},
@@ -52,12 +70,22 @@ use nix::sys::resource::{Resource, getrlimit};
 
 pub mod async_io;
 mod config;
+mod control;
+mod dedupe;
 mod file_handle;
+mod health;
+mod identity;
+pub(crate) mod inode_lock;
 mod inode_store;
+mod mirror;
 mod mmap;
 mod mount_fd;
+mod multi_root;
 mod os_compat;
+mod redirect;
 mod statx;
+mod syscalls;
+mod throttle;
 pub mod util;
 
 /// Current directory
@@ -123,9 +151,6 @@ pub async fn new_passthroughfs_layer<P: AsRef<Path>, M: AsRef<str>>(
 type Inode = u64;
 type Handle = u64;
 
-/// Maximum host inode number supported by passthroughfs
-const MAX_HOST_INO: u64 = 0x7fff_ffff_ffff;
-
 /**
  * Represents the file associated with an inode (`InodeData`).
  *
@@ -238,9 +263,23 @@ pub struct InodeData {
     handle: InodeHandle,
     id: InodeId,
     refcount: AtomicU64,
+    // Distinguishes this file from earlier ones that had the same inode number, reported with
+    // `inode` wherever an entry is returned. Never 0, which is reserved for "unknown".
+    generation: u64,
     // File type and mode
     mode: u32,
     btime: statx_timestamp,
+    // Modification time and size at the last open or release, see
+    // `Config::drop_cache_on_change`.
+    change_stamp: std::sync::Mutex<Option<(i64, i64, i64)>>,
+    // For a directory, the directories of the same path in the roots after the one it was found
+    // in, see `Config::multi_root`.
+    union_dirs: Vec<File>,
+    // For a directory with `Config::multi_root`, the directory it was looked up in, which `..`
+    // leads back to even if this one was found in a later root.
+    union_parent: Option<Inode>,
+    // For a directory, the redirects of `Config::path_redirects` below it.
+    redirects: Vec<Redirect>,
 }
 
 impl InodeData {
@@ -248,6 +287,7 @@ impl InodeData {
         inode: Inode,
         f: InodeHandle,
         refcount: u64,
+        generation: u64,
         id: InodeId,
         mode: u32,
         btime: statx_timestamp,
@@ -257,11 +297,40 @@ impl InodeData {
             handle: f,
             id,
             refcount: AtomicU64::new(refcount),
+            generation,
             mode,
             btime,
+            change_stamp: std::sync::Mutex::new(None),
+            union_dirs: Vec::new(),
+            union_parent: None,
+            redirects: Vec::new(),
         }
     }
 
+    fn with_union_dirs(mut self, union_dirs: Vec<File>) -> Self {
+        self.union_dirs = union_dirs;
+        self
+    }
+
+    fn with_union_parent(mut self, union_parent: Option<Inode>) -> Self {
+        self.union_parent = union_parent;
+        self
+    }
+
+    fn with_redirects(mut self, redirects: Vec<Redirect>) -> Self {
+        self.redirects = redirects;
+        self
+    }
+
+    /// Record the modification time and size of `file`, an open file of this inode, and return
+    /// whether they differ from the previous record. The first record counts as unchanged.
+    fn update_change_stamp(&self, file: &File) -> io::Result<bool> {
+        let st = stat_fd(file, None)?;
+        let stamp = (st.st_mtime, st.st_mtime_nsec, st.st_size);
+        let old = self.change_stamp.lock().unwrap().replace(stamp);
+        Ok(old.is_some_and(|old| old != stamp))
+    }
+
     fn get_file(&self) -> Result<InodeFile<'_>> {
         self.handle.get_file()
     }
@@ -271,6 +340,23 @@ impl InodeData {
     }
 }
 
+/// Diagnostic snapshot of a resident inode, see `PassthroughFs::dump_inodes()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InodeDebugInfo {
+    /// The inode number handed out to the kernel.
+    pub inode: Inode,
+    /// The lookup count, i.e. how many `forget`s the kernel still owes.
+    pub refcount: u64,
+    /// Device of the backing host inode.
+    pub dev: u64,
+    /// Inode number of the backing host inode.
+    pub ino: u64,
+    /// Whether the inode keeps an `O_PATH` fd open on the backing inode.
+    pub has_fd: bool,
+    /// Whether the inode is tracked by a file handle instead of an fd.
+    pub has_handle: bool,
+}
+
 /// Data structures to manage accessed inodes.
 struct InodeMap {
     pub inodes: RwLock<InodeStore>,
@@ -357,6 +443,12 @@ struct HandleData {
     file: File,
     lock: Mutex<()>,
     open_flags: AtomicU32,
+    // Parent directory and name the file was opened at, only tracked with
+    // `Config::reopen_on_replace`.
+    origin: Option<Arc<(File, CString)>>,
+    // The merged listing of a union directory, see `Config::multi_root`, taken when reading it
+    // starts from the beginning.
+    union_listing: std::sync::Mutex<Option<Arc<Vec<OsString>>>>,
 }
 
 impl HandleData {
@@ -366,9 +458,16 @@ impl HandleData {
             file,
             lock: Mutex::new(()),
             open_flags: AtomicU32::new(flags),
+            origin: None,
+            union_listing: std::sync::Mutex::new(None),
         }
     }
 
+    fn with_origin(mut self, origin: Option<Arc<(File, CString)>>) -> Self {
+        self.origin = origin;
+        self
+    }
+
     fn get_file(&self) -> &File {
         &self.file
     }
@@ -390,25 +489,52 @@ impl HandleData {
     }
 }
 
+impl AsFd for HandleData {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
 struct HandleMap {
     handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    // How many handles are open on each inode, only changed together with `handles`.
+    open_counts: std::sync::Mutex<BTreeMap<Inode, usize>>,
 }
 
 impl HandleMap {
     fn new() -> Self {
         HandleMap {
             handles: RwLock::new(BTreeMap::new()),
+            open_counts: std::sync::Mutex::new(BTreeMap::new()),
         }
     }
 
     async fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().await.clear();
+        let mut handles = self.handles.write().await;
+        handles.clear();
+        self.open_counts.lock().unwrap().clear();
     }
 
     async fn insert(&self, handle: Handle, data: HandleData) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().await.insert(handle, Arc::new(data));
+        let mut handles = self.handles.write().await;
+        let inode = data.inode;
+        if let Some(old) = handles.insert(handle, Arc::new(data)) {
+            self.close_one(old.inode);
+        }
+        *self.open_counts.lock().unwrap().entry(inode).or_default() += 1;
+    }
+
+    /// Count one handle less as open on `inode`.
+    fn close_one(&self, inode: Inode) {
+        let mut open_counts = self.open_counts.lock().unwrap();
+        if let btree_map::Entry::Occupied(mut e) = open_counts.entry(inode) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
     }
 
     async fn release(&self, handle: Handle, inode: Inode) -> Result<()> {
@@ -421,6 +547,7 @@ impl HandleMap {
             // We don't need to close the file here because that will happen automatically when
             // the last `Arc` is dropped.
             e.remove();
+            self.close_one(inode);
 
             return Ok(());
         }
@@ -428,6 +555,30 @@ impl HandleMap {
         Err(ebadf())
     }
 
+    /// Whether any handle is open on `inode`.
+    async fn has_inode(&self, inode: Inode) -> bool {
+        let _handles = self.handles.read().await;
+        self.open_counts.lock().unwrap().contains_key(&inode)
+    }
+
+    /// Replace the data of `handle`, unless it was released or replaced since `old` was fetched.
+    async fn replace(
+        &self,
+        handle: Handle,
+        old: &Arc<HandleData>,
+        data: HandleData,
+    ) -> Arc<HandleData> {
+        let mut handles = self.handles.write().await;
+        match handles.get_mut(&handle) {
+            Some(hd) if Arc::ptr_eq(hd, old) => {
+                *hd = Arc::new(data);
+                hd.clone()
+            }
+            Some(hd) if hd.inode == old.inode => hd.clone(),
+            _ => old.clone(),
+        }
+    }
+
     async fn get(&self, handle: Handle, inode: Inode) -> Result<Arc<HandleData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
         self.handles
@@ -440,6 +591,10 @@ impl HandleMap {
     }
 }
 
+/// The parent directory and hidden name of an entry whose unlink was deferred, see
+/// `Config::defer_unlink`.
+type DeferredUnlink = (Arc<InodeData>, CString);
+
 #[derive(Debug, Hash, Eq, PartialEq)]
 struct FileUniqueKey(u64, statx_timestamp);
 
@@ -450,6 +605,15 @@ struct FileUniqueKey(u64, statx_timestamp);
 /// that wish to serve only a specific directory should set up the environment so that that
 /// directory ends up as the root of the file system process. One way to accomplish this is via a
 /// combination of mount namespaces and the pivot_root system call.
+///
+/// Requests can't reach outside `Config::root_dir`. Every name is resolved with `openat()`
+/// relative to the fd held by its parent inode, never through an absolute path, symlinks are
+/// never followed, lookup names containing `/` are rejected and `..` of the root is the root
+/// itself. The host can still move a directory out of the export while a client holds it, so a
+/// `..` lookup is checked to land at or below the root and fails with `ENOENT` otherwise. As
+/// with a chroot, what was moved out stays reachable through the inodes the client already
+/// holds, until it forgets them, and through the directories handles were opened in for
+/// `Config::reopen_on_replace`.
 pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // File descriptors for various points in the file system tree. These fds are always opened with
     // the `O_PATH` option so they cannot be used for reading or writing any data. See the
@@ -457,6 +621,13 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // do with an fd opened with this flag.
     inode_map: InodeMap,
     next_inode: AtomicU64,
+    // How the files of each host filesystem are identified, see `Config::inode_identity`.
+    identity: IdentityChain,
+    // The optional syscalls that aren't blocked, probed once on creation.
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    syscalls: SyscallSupport,
+    // The generation of the next inode inserted into `inode_map`, see `InodeData::generation`.
+    next_generation: AtomicU64,
 
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
@@ -474,6 +645,10 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // to be serving doesn't have access to `/proc/self/fd`.
     proc_self_fd: File,
 
+    // The mirror directory of `Config::mirror_dir`, which mirrored operations resolve their
+    // paths from.
+    mirror_root: Option<File>,
+
     // Whether writeback caching is enabled for this directory. This will only be true when
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
@@ -507,7 +682,35 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
 
     handle_cache: Cache<FileUniqueKey, Arc<FileHandle>>,
 
+    // Openable handles recently built from `handle_cache` entries.
+    openable_handles: FileHandleCache,
+
     mmap_chunks: Cache<MmapChunkKey, Arc<RwLock<mmap::MmapCachedValue>>>,
+
+    // Per-inode locks serializing conflicting operations, see `Config::serialize_inode_ops`.
+    inode_locks: InodeLockMap,
+
+    // Hidden entries of open files to unlink on their last release, see `Config::defer_unlink`.
+    deferred_unlinks: Mutex<BTreeMap<Inode, Vec<DeferredUnlink>>>,
+
+    // The log level set through the control xattr, see `Config::control_xattr`.
+    log_level: std::sync::RwLock<LevelFilter>,
+
+    // The number of directory streams currently open, see `Config::max_open_dirs`.
+    open_dirs: AtomicUsize,
+
+    // Rate limit of lookups, getattrs and readdirs, see `Config::metadata_ops_per_sec`.
+    metadata_throttle: Option<TokenBucket>,
+
+    // Recent `statfs` replies by host device, see `Config::statfs_cache`.
+    statfs_cache: Option<Cache<libc::dev_t, ReplyStatFs>>,
+
+    // Whether the backing store answers, see `Config::backend_health_probe`.
+    health: Option<Arc<BackendHealth>>,
+
+    // The number of `fstatvfs()` calls on the host.
+    #[cfg(test)]
+    statfs_calls: AtomicU64,
 }
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
@@ -524,6 +727,21 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             cfg.writeback = false;
         }
 
+        if cfg.multi_root.is_some() && !cfg.readonly {
+            warn!("passthroughfs: multi_root is read-only, reset to readonly");
+            cfg.readonly = true;
+        }
+
+        let syscalls = SyscallSupport::probe();
+        if !syscalls.file_handles() {
+            cfg.inode_identity
+                .retain(|identity| *identity != InodeIdentity::FileHandle);
+        }
+        #[cfg(target_os = "linux")]
+        if !syscalls.renameat2 {
+            cfg.defer_unlink = false;
+        }
+
         // Safe because this is a constant value and a valid C string.
         let proc_self_fd_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_SELF_FD_CSTR) };
 
@@ -533,6 +751,15 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
 
         let proc_self_fd = Self::open_file(&libc::AT_FDCWD, proc_self_fd_cstr, flags, 0)?;
+        let mirror_root = match &cfg.mirror_dir {
+            Some(mirror_dir) => {
+                let mirror_dir = CString::new(mirror_dir.as_os_str().as_bytes())
+                    .map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))?;
+                let flags = (flags & !libc::O_NOFOLLOW) | libc::O_DIRECTORY;
+                Some(Self::open_file(&libc::AT_FDCWD, &mirror_dir, flags, 0)?)
+            }
+            None => None,
+        };
 
         let (dir_entry_timeout, dir_attr_timeout) =
             match (cfg.dir_entry_timeout, cfg.dir_attr_timeout) {
@@ -550,6 +777,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         };
 
         let max_mmap_size = if cfg.use_mmap { cfg.max_mmap_size } else { 0 };
+        let metadata_throttle = cfg.metadata_ops_per_sec.map(TokenBucket::new);
+        let statfs_cache = cfg
+            .statfs_cache
+            .map(|ttl| Cache::builder().max_capacity(64).time_to_live(ttl).build());
+        let health = cfg
+            .backend_health_probe
+            .clone()
+            .map(|probe| Arc::new(BackendHealth::new(probe, cfg.root_dir.clone())));
 
         let mmap_cache_builder = Cache::builder()
             .max_capacity(max_mmap_size)
@@ -567,13 +802,18 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(PassthroughFs {
             inode_map: InodeMap::new(),
             next_inode: AtomicU64::new(ROOT_ID + 1),
-            ino_allocator: UniqueInodeGenerator::new(),
+            identity: IdentityChain::new(&cfg.inode_identity),
+            syscalls,
+            next_generation: AtomicU64::new(1),
+            ino_allocator: UniqueInodeGenerator::with_layout(cfg.inode_layout)
+                .with_usage_warning(cfg.virtual_inode_warn_percent),
 
             handle_map: HandleMap::new(),
             next_handle: AtomicU64::new(1),
 
             mount_fds,
             proc_self_fd,
+            mirror_root,
 
             writeback: AtomicBool::new(false),
             no_open: AtomicBool::new(false),
@@ -591,15 +831,31 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             phantom: PhantomData,
 
             handle_cache: moka::future::Cache::new(fd_limit),
+            openable_handles: FileHandleCache::new(fd_limit as usize),
 
             mmap_chunks: mmap_cache_builder.build(),
+
+            inode_locks: InodeLockMap::default(),
+
+            deferred_unlinks: Mutex::new(BTreeMap::new()),
+
+            log_level: std::sync::RwLock::new(LevelFilter::current()),
+            open_dirs: AtomicUsize::new(0),
+            metadata_throttle,
+            statfs_cache,
+            health,
+            #[cfg(test)]
+            statfs_calls: AtomicU64::new(0),
         })
     }
 
     /// Initialize the Passthrough file system.
     pub async fn import(&self) -> Result<()> {
-        let root =
-            CString::new(self.cfg.root_dir.as_os_str().as_bytes()).expect("Invalid root_dir");
+        // The root of a mount must be a directory, so unlike any other entry, which is opened
+        // with O_NOFOLLOW and reported as a symlink, a symlinked `root_dir` is followed.
+        let root_dir =
+            std::fs::canonicalize(&self.cfg.root_dir).unwrap_or_else(|_| self.cfg.root_dir.clone());
+        let root = CString::new(root_dir.as_os_str().as_bytes()).expect("Invalid root_dir");
 
         let (handle, st) = Self::open_file_and_handle(self, &libc::AT_FDCWD, &root)
             .await
@@ -617,21 +873,223 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         unsafe { libc::umask(0o000) };
 
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
+        let root_ino = self.root_ino();
         self.inode_map
-            .insert(Arc::new(InodeData::new(
-                ROOT_ID,
-                handle,
-                2,
-                id,
-                st.st.st_mode.into(),
-                st.btime
-                    .ok_or_else(|| io::Error::other("birth time not available"))?,
-            )))
+            .insert(Arc::new(
+                InodeData::new(
+                    root_ino,
+                    handle,
+                    2,
+                    self.next_generation.fetch_add(1, Ordering::Relaxed),
+                    id,
+                    st.st.st_mode.into(),
+                    st.btime
+                        .ok_or_else(|| io::Error::other("birth time not available"))?,
+                )
+                .with_union_dirs(self.open_union_roots()?)
+                .with_redirects(self.root_redirects()?),
+            ))
             .await;
+        // The kernel always addresses the root of the mount as FUSE_ROOT_ID.
+        if root_ino != ROOT_ID {
+            self.inode_map
+                .inodes
+                .write()
+                .await
+                .insert_alias(ROOT_ID, &root_ino);
+        }
+        if let Some(health) = &self.health {
+            #[cfg(target_os = "linux")]
+            let flags = libc::O_PATH | libc::O_DIRECTORY;
+            #[cfg(target_os = "macos")]
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+            let root = self.inode_map.get(root_ino).await?;
+            let root = openat(&root.get_file()?, c".", flags, 0)?;
+            health.start(move || stat_fd(&root, None).map(drop));
+        }
+
+        Ok(())
+    }
+
+    /// The log level last set through the `log_level` control xattr, see
+    /// `Config::log_level_hook`, or the global maximum level at creation.
+    pub fn log_level(&self) -> LevelFilter {
+        *self.log_level.read().unwrap()
+    }
+
+    /// The inode number of the root directory, see `Config::root_ino`.
+    pub fn root_ino(&self) -> Inode {
+        self.cfg.root_ino
+    }
+
+    /// Wait for the metadata rate limit, see `Config::metadata_ops_per_sec`.
+    async fn throttle_metadata(&self) {
+        if let Some(throttle) = &self.metadata_throttle {
+            throttle.acquire().await;
+        }
+    }
+
+    /// Whether `inode` refers to the root directory, either by `FUSE_ROOT_ID` or `root_ino`.
+    fn is_root(&self, inode: Inode) -> bool {
+        inode == ROOT_ID || inode == self.cfg.root_ino
+    }
+
+    /// Return a snapshot of all inodes currently held in the inode store, for spotting inodes
+    /// which are unexpectedly retained (e.g. because of missing `forget`s).
+    ///
+    /// This locks the whole store, so it returns an empty list unless
+    /// `Config::debug_inode_dump` is enabled.
+    #[allow(clippy::unnecessary_cast)]
+    pub async fn dump_inodes(&self) -> Vec<InodeDebugInfo> {
+        if !self.cfg.debug_inode_dump {
+            warn!("dump_inodes: Config::debug_inode_dump is disabled");
+            return Vec::new();
+        }
+
+        let inodes = self.inode_map.inodes.read().await;
+        inodes
+            .iter()
+            .map(|(inode, data)| InodeDebugInfo {
+                inode: *inode,
+                refcount: data.refcount.load(Ordering::Relaxed),
+                dev: data.id.dev as u64,
+                ino: data.id.ino as u64,
+                has_fd: matches!(data.handle, InodeHandle::File(_)),
+                has_handle: matches!(data.handle, InodeHandle::Handle(_)),
+            })
+            .collect()
+    }
+
+    /// Whether the caller of `req` belongs to the host group `gid`, through either its primary
+    /// group or one of its supplementary groups. The latter are read from the host, so they are
+    /// host gids already.
+    fn in_group(&self, req: &Request, gid: u32) -> bool {
+        self.cfg.mapping.get_gid(req.gid) == gid
+            || util::supplementary_groups(req.pid, req.uid, req.gid).contains(&gid)
+    }
+
+    /// Check that the caller of `req` may set the times of a file with attributes `st`, following
+    /// the POSIX utime() rules: explicit times are reserved to the owner, while setting the
+    /// current time also works with write permission.
+    fn check_set_times(
+        &self,
+        req: &Request,
+        st: &util::stat64,
+        explicit_times: bool,
+    ) -> io::Result<()> {
+        let uid = self.cfg.mapping.get_uid(req.uid);
+        if st.st_uid == uid {
+            return Ok(());
+        }
+        if explicit_times {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        // Check group (including supplementary groups) and other permissions
+        let has_group_write = st.st_mode & 0o020 != 0 && self.in_group(req, st.st_gid);
+        let has_other_write = st.st_mode & 0o002 != 0;
+        if !has_group_write && !has_other_write {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(())
+    }
+
+    /// Fail with `HealthProbe::errno` while the backing store is unreachable, see
+    /// `Config::backend_health_probe`.
+    fn check_backend(&self) -> io::Result<()> {
+        match &self.health {
+            Some(health) => health.check(),
+            None => Ok(()),
+        }
+    }
 
+    /// Fail with `EROFS` if the export is read-only, see `Config::readonly`.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.cfg.readonly {
+            Err(io::Error::from_raw_os_error(libc::EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fail with `EROFS` if `file` can't be written whatever the permissions: the export is
+    /// read-only, see `Config::readonly`, or `file` is on a read-only host mount. Permission
+    /// denials are reported as `EACCES` by their own checks, or by the host.
+    fn check_file_writable(&self, file: &impl AsRawFd) -> io::Result<()> {
+        self.check_writable()?;
+        let mut st = std::mem::MaybeUninit::<libc::statvfs>::zeroed();
+        // Safe because the kernel only writes to `st` and we check the return value.
+        if unsafe { libc::fstatvfs(file.as_raw_fd(), st.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `fstatvfs()` filled in `st`.
+        let st = unsafe { st.assume_init() };
+        if st.f_flag & libc::ST_RDONLY != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
         Ok(())
     }
 
+    /// The name the host stores the client's xattr `name` under, failing with `ENOTSUP` if
+    /// clients can't use it, see `Config::xattr_namespace_filter` and `Config::xattr_name_map`.
+    fn host_xattr_name(&self, name: &std::ffi::OsStr) -> io::Result<CString> {
+        let unsupported = || io::Error::from_raw_os_error(libc::ENOTSUP);
+        if let Some(filter) = &self.cfg.xattr_namespace_filter
+            && !filter.allows(name.as_bytes())
+        {
+            return Err(unsupported());
+        }
+        let name = self
+            .cfg
+            .xattr_name_map
+            .to_host(name.as_bytes())
+            .ok_or_else(unsupported)?;
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The name clients see the host's xattr `name` as, `None` if it's hidden from them, see
+    /// `Config::xattr_name_map` and `Config::xattr_namespace_filter`.
+    fn client_xattr_name<'a>(&self, name: &'a [u8]) -> Option<std::borrow::Cow<'a, [u8]>> {
+        let name = self.cfg.xattr_name_map.to_client(name)?;
+        match &self.cfg.xattr_namespace_filter {
+            Some(filter) if !filter.allows(&name) => None,
+            _ => Some(name),
+        }
+    }
+
+    /// Fail with `EPERM` if `parent` is sticky and the caller of `req` owns neither it nor its
+    /// entry `name`, see `Config::enforce_sticky_bit`. A missing entry passes the check.
+    async fn check_sticky(&self, req: &Request, parent: Inode, name: &CStr) -> io::Result<()> {
+        if !self.cfg.enforce_sticky_bit {
+            return Ok(());
+        }
+        let uid = self.cfg.mapping.get_uid(req.uid);
+        if uid == 0 {
+            return Ok(());
+        }
+        let data = self.inode_map.get(parent).await?;
+        let file = data.get_file()?;
+        let dir_st = stat_fd(&file, None)?;
+        if dir_st.st_mode & libc::S_ISVTX == 0 || dir_st.st_uid == uid {
+            return Ok(());
+        }
+        match stat_fd(&file, Some(name)) {
+            Ok(st) if st.st_uid != uid => Err(io::Error::from_raw_os_error(libc::EPERM)),
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lock the given inodes against conflicting operations, see `Config::serialize_inode_ops`.
+    async fn lock_inodes(&self, inodes: &[Inode]) -> InodeLockGuard<'_> {
+        if self.cfg.serialize_inode_ops {
+            self.inode_locks.lock_many(inodes).await
+        } else {
+            InodeLockGuard::empty()
+        }
+    }
+
     /// Get the list of file descriptors which should be reserved across live upgrade.
     pub fn keep_fds(&self) -> Vec<RawFd> {
         vec![self.proc_self_fd.as_raw_fd()]
@@ -717,6 +1175,15 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         //}
     }
 
+    /// `statx()`, or `fstatat()` where it's blocked.
+    fn statx(&self, dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
+        #[cfg(target_os = "linux")]
+        if !self.syscalls.statx {
+            return statx::fstatat(dir, path);
+        }
+        statx::statx(dir, path)
+    }
+
     /// Create a File or File Handle for `name` under directory `dir_fd` to support `lookup()`.
     async fn open_file_and_handle(
         &self,
@@ -725,9 +1192,29 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     ) -> io::Result<(InodeHandle, StatExt)> {
         #[cfg(target_os = "linux")]
         let path_file = self.open_file_restricted(dir, name, libc::O_PATH, 0)?;
+        // macOS has no `O_PATH`, `O_NONBLOCK` keeps the open of a FIFO from waiting for a writer.
         #[cfg(target_os = "macos")]
-        let path_file = self.open_file_restricted(dir, name, libc::O_RDONLY, 0)?;
-        let st = statx::statx(&path_file, None)?;
+        let path_file =
+            self.open_file_restricted(dir, name, libc::O_RDONLY | libc::O_NONBLOCK, 0)?;
+        let mut st = self.statx(&path_file, None)?;
+
+        let identity = self
+            .identity
+            .choose(st.st.st_dev, |identity| match identity {
+                InodeIdentity::FileHandle => {
+                    FileHandle::from_fd(&path_file).is_ok_and(|h| h.is_some())
+                }
+                InodeIdentity::MountId => st.mnt_id != 0,
+                InodeIdentity::Device => true,
+            });
+        match identity {
+            InodeIdentity::FileHandle => {}
+            InodeIdentity::MountId => return Ok((InodeHandle::File(path_file), st)),
+            InodeIdentity::Device => {
+                st.mnt_id = 0;
+                return Ok((InodeHandle::File(path_file), st));
+            }
+        }
 
         let btime_is_valid = match st.btime {
             Some(ts) => ts.tv_sec != 0 || ts.tv_nsec != 0,
@@ -764,12 +1251,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     }
 
     fn to_openable_handle(&self, fh: Arc<FileHandle>) -> io::Result<Arc<OpenableFileHandle>> {
-        (*Arc::as_ref(&fh))
-            .clone()
-            .into_openable(&self.mount_fds, |fd, flags, _mode| {
-                reopen_fd_through_proc(&fd, flags, &self.proc_self_fd)
+        self.openable_handles
+            .get_or_insert_with(&fh, || {
+                (*Arc::as_ref(&fh))
+                    .clone()
+                    .into_openable(&self.mount_fds, |fd, flags, _mode| {
+                        reopen_fd_through_proc(&fd, flags, &self.proc_self_fd)
+                    })
             })
-            .map(Arc::new)
             .map_err(|e| {
                 if !e.silent() {
                     error!("{e}");
@@ -789,10 +1278,16 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             // ensuring that the same file is always the same inode
             match InodeMap::get_inode_locked(inodes, handle) {
                 Some(a) => Ok(a),
-                None => Ok(self.next_inode.fetch_add(1, Ordering::Relaxed)),
+                None => {
+                    let mut inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                    if inode == self.cfg.root_ino {
+                        inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(inode)
+                }
             }
         } else {
-            let inode = if id.ino > MAX_HOST_INO {
+            let inode = if id.ino > self.ino_allocator.max_host_ino() {
                 // Prefer looking for previous mappings from memory
                 match InodeMap::get_inode_locked(inodes, handle) {
                     Some(ino) => ino,
@@ -811,7 +1306,8 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         parent: Inode,
         name: &CStr,
     ) -> std::result::Result<ReplyEntry, Errno> {
-        let name = if parent == ROOT_ID && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR) {
+        let name = if self.is_root(parent) && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR)
+        {
             // Safe as this is a constant value and a valid C string.
             CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap()
         } else {
@@ -820,7 +1316,33 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
 
         let dir = self.inode_map.get(parent).await?;
         let dir_file = dir.get_file()?;
-        let (inode_handle, st) = self.open_file_and_handle(&dir_file, name).await?;
+        let (redirected, redirects) = self.lookup_redirect(&dir.redirects, name).await?;
+        let union_parent = match dir.union_parent {
+            Some(up) if name.to_bytes_with_nul() == PARENT_DIR_CSTR => {
+                self.inode_map.get(up).await.ok()
+            }
+            _ => None,
+        };
+        let (inode_handle, st, union_dirs) = if let Some((inode_handle, st)) = redirected {
+            (inode_handle, st, Vec::new())
+        } else if let Some(up) = union_parent {
+            // The host's `..` of a directory found in a later root is that root's directory,
+            // not the union directory it belongs to.
+            // Safe as this is a constant value and a valid C string.
+            let current = CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap();
+            let (inode_handle, st) = self.open_file_and_handle(&up.get_file()?, current).await?;
+            (inode_handle, st, Vec::new())
+        } else if dir.union_dirs.is_empty() {
+            let (inode_handle, st) = self.open_file_and_handle(&dir_file, name).await?;
+            (inode_handle, st, Vec::new())
+        } else {
+            self.lookup_union(&dir, &dir_file, name).await?
+        };
+        if name.to_bytes_with_nul() == PARENT_DIR_CSTR
+            && !self.is_beneath_root(&inode_handle.get_file()?).await?
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT).into());
+        }
         let id = InodeId::from_stat(&st);
         debug!(
             "do_lookup: parent: {}, name: {}, handle: {:?}, id: {:?}",
@@ -851,14 +1373,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
                         .is_ok()
                     {
-                        found = Some(data.inode);
+                        found = Some((data.inode, data.generation));
                         break;
                     }
                 }
             }
         }
 
-        let inode = if let Some(v) = found {
+        let (inode, generation) = if let Some(v) = found {
             v
         } else {
             // Write guard get_alt_locked() and insert_lock() to avoid race conditions.
@@ -875,7 +1397,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     // `self.inodes_map`, so we use that instead. `handle` will be dropped.
                     // trace!("FS {} found existing inode: {}", self.uuid, data.inode);
                     data.refcount.fetch_add(1, Ordering::Relaxed);
-                    data.inode
+                    (data.inode, data.generation)
                 }
                 None => {
                     let inode = self.allocate_inode(&inodes, &id, &inode_handle).await?;
@@ -889,20 +1411,32 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         .into());
                     }
 
+                    let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
                     InodeMap::insert_locked(
                         inodes.deref_mut(),
-                        Arc::new(InodeData::new(
-                            inode,
-                            inode_handle,
-                            1,
-                            id,
-                            st.st.st_mode.into(),
-                            st.btime
-                                .ok_or_else(|| io::Error::other("birth time not available"))?,
-                        )),
+                        Arc::new(
+                            InodeData::new(
+                                inode,
+                                inode_handle,
+                                1,
+                                generation,
+                                id,
+                                st.st.st_mode.into(),
+                                st.btime
+                                    .ok_or_else(|| io::Error::other("birth time not available"))?,
+                            )
+                            .with_union_dirs(union_dirs)
+                            .with_union_parent(
+                                (self.cfg.multi_root.is_some()
+                                    && is_dir(st.st.st_mode.into())
+                                    && name.to_bytes_with_nul() != PARENT_DIR_CSTR)
+                                    .then_some(parent),
+                            )
+                            .with_redirects(redirects),
+                        ),
                     );
 
-                    inode
+                    (inode, generation)
                 }
             }
         };
@@ -931,13 +1465,14 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(ReplyEntry {
             ttl: entry_timeout,
             attr: attr_temp,
-            generation: 0,
+            generation,
         })
     }
 
     async fn forget_one(&self, inodes: &mut InodeStore, inode: Inode, count: u64) {
-        // ROOT_ID should not be forgotten, or we're not able to access to files any more.
-        if inode == ROOT_ID {
+        // ROOT_ID should not be forgotten, or we're not able to access to files any more. Its
+        // refcount is never decremented, so no number of forgets can drop its fd.
+        if self.is_root(inode) {
             return;
         }
 
@@ -969,9 +1504,15 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         }
                         // We just removed the last refcount for this inode.
                         // The allocated inode number should be kept in the map when use_host_ino
-                        // is false or host inode(don't use the virtual 56bit inode) is bigger than MAX_HOST_INO.
-                        let keep_mapping = !self.cfg.use_host_ino || data.id.ino > MAX_HOST_INO;
+                        // is false. Virtual inodes (for host inodes bigger than max_host_ino()) are
+                        // given back to the allocator instead, so they don't run out.
+                        let keep_mapping = !self.cfg.use_host_ino;
+                        let is_virtual = self.cfg.use_host_ino
+                            && data.id.ino > self.ino_allocator.max_host_ino();
                         inodes.remove(&inode, keep_mapping);
+                        if is_virtual {
+                            self.ino_allocator.release_inode(inode);
+                        }
                     }
                     break;
                 }
@@ -980,7 +1521,61 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     }
 
     async fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        self.handle_map.release(handle, inode).await
+        self.handle_map.release(handle, inode).await?;
+        if self.cfg.defer_unlink && !self.handle_map.has_inode(inode).await {
+            let entries = self.deferred_unlinks.lock().await.remove(&inode);
+            for (parent, name) in entries.into_iter().flatten() {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res =
+                    unsafe { libc::unlinkat(parent.get_file()?.as_raw_fd(), name.as_ptr(), 0) };
+                if res < 0 {
+                    warn!(
+                        "passthrough: failed to unlink deferred entry {name:?}: {}",
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the directory `dir` is the root, one of the roots of `Config::multi_root` or a
+    /// target of `Config::path_redirects`, or below it, found by walking up its `..` entries to
+    /// the host root. Names are only ever resolved relative to the fd of their parent inode, so
+    /// this can only fail for `..` of a directory the host moved out of the export, or of a
+    /// redirected directory.
+    async fn is_beneath_root(&self, dir: &impl AsRawFd) -> io::Result<bool> {
+        #[cfg(target_os = "linux")]
+        let flags = libc::O_PATH | libc::O_DIRECTORY;
+        #[cfg(target_os = "macos")]
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+        // Safe because this is a constant value and a valid C string.
+        let parent = CStr::from_bytes_with_nul(PARENT_DIR_CSTR).unwrap();
+
+        let root = self.inode_map.get(ROOT_ID).await?;
+        let mut roots = vec![stat_fd(&root.get_file()?, None)?];
+        for dir in &root.union_dirs {
+            roots.push(stat_fd(dir, None)?);
+        }
+        for redirect in &root.redirects {
+            roots.push(stat_fd(&libc::AT_FDCWD, Some(&redirect.host))?);
+        }
+        let mut st = stat_fd(dir, None)?;
+        let mut dir = self.open_file_restricted(dir, parent, flags, 0)?;
+        loop {
+            if roots
+                .iter()
+                .any(|root| (st.st_dev, st.st_ino) == (root.st_dev, root.st_ino))
+            {
+                return Ok(true);
+            }
+            let up = stat_fd(&dir, None)?;
+            if (up.st_dev, up.st_ino) == (st.st_dev, st.st_ino) {
+                return Ok(false);
+            }
+            st = up;
+            dir = self.open_file_restricted(&dir, parent, flags, 0)?;
+        }
     }
 
     // Validate a path component, same as the one in vfs layer, but only do the validation if this
@@ -1056,16 +1651,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             new_flags |= libc::O_RDWR;
         }
 
-        // When writeback caching is enabled the kernel is responsible for handling `O_APPEND`.
-        // However, this breaks atomicity as the file may have changed on disk, invalidating the
-        // cached copy of the data in the kernel and the offset that the kernel thinks is the end of
-        // the file. Just allow this for now as it is the user's responsibility to enable writeback
-        // caching only for directories that are not shared. It also means that we need to clear the
-        // `O_APPEND` flag.
-        if writeback && flags & libc::O_APPEND != 0 {
-            new_flags &= !libc::O_APPEND;
-        }
-
+        // `O_APPEND` is kept even with writeback caching: the kernel's idea of the end of the
+        // file may be stale if others append to it too. Such files are opened with `DIRECT_IO`
+        // instead, so their writes bypass the cache and are appended by the host, see `write()`.
         new_flags
     }
 
@@ -1208,13 +1796,33 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let file_size = file.metadata()?.len();
         let len = data.len();
 
-        // If the file needs to be extended, do so
-        if offset + len as u64 > file_size {
-            let raw_fd = file.as_raw_fd();
-            let res = unsafe { libc::ftruncate(raw_fd, (offset + len as u64) as i64) };
-
+        // Storing into a mapped page which has no backing block raises SIGBUS instead of
+        // returning ENOSPC/EDQUOT, so reserve the blocks up front. On failure the caller falls
+        // back to pwrite(), which reports short writes and the errno properly.
+        #[cfg(target_os = "linux")]
+        {
+            let res = unsafe {
+                libc::fallocate64(
+                    file.as_raw_fd(),
+                    0,
+                    offset as libc::off64_t,
+                    len as libc::off64_t,
+                )
+            };
             if res < 0 {
-                return Err(std::io::Error::other("error to ftruncate"));
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // If the file needs to be extended, do so. On Linux fallocate() above already did it.
+        if offset + len as u64 > file_size {
+            #[cfg(target_os = "macos")]
+            {
+                let res =
+                    unsafe { libc::ftruncate(file.as_raw_fd(), (offset + len as u64) as i64) };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
             }
 
             self.invalidate_mmap_cache(inode, file_size).await;
@@ -1357,6 +1965,4660 @@ mod tests {
         let _ = std::fs::remove_dir_all(&temp_dir);
     }
 
+    #[test]
+    fn test_current_thread_session() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+        std::fs::write(source_dir.join("file"), b"hello").unwrap();
+
+        let is_mounted = |dir: &std::path::Path| {
+            std::fs::read_to_string("/proc/self/mountinfo")
+                .unwrap()
+                .lines()
+                .any(|line| line.split(' ').nth(4) == Some(dir.to_str().unwrap()))
+        };
+        let mount = |dir: std::path::PathBuf| {
+            let source_dir = source_dir.clone();
+            async move {
+                let args = PassthroughArgs {
+                    root_dir: source_dir,
+                    mapping: None::<&str>,
+                };
+                let fs = new_passthroughfs_layer(args).await?;
+                let mut mount_options = MountOptions::default();
+                mount_options
+                    .uid(unsafe { libc::getuid() })
+                    .gid(unsafe { libc::getgid() });
+                Session::new(mount_options).mount(fs, dir).await
+            }
+        };
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let served = rt.block_on(async {
+            let handle = match mount(mount_dir.clone()).await {
+                Ok(handle) => handle,
+                Err(e) => {
+                    eprintln!("skip test_current_thread_session: mount failed: {e:?}");
+                    return false;
+                }
+            };
+            // The read has to come from another thread, the runtime's only thread serves it.
+            let path = mount_dir.join("file");
+            let data = tokio::task::spawn_blocking(move || std::fs::read(path))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(data, b"hello");
+            handle.unmount().await.unwrap();
+            true
+        });
+        if !served {
+            return;
+        }
+        assert!(!is_mounted(&mount_dir));
+
+        // Dropping the handle while the runtime shuts down must not leave the mount behind. It
+        // is detached from a thread of its own, so give that a moment.
+        rt.block_on(async {
+            let _handle = mount(mount_dir.clone()).await.unwrap();
+            assert!(is_mounted(&mount_dir));
+        });
+        drop(rt);
+        for _ in 0..100 {
+            if !is_mounted(&mount_dir) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(!is_mounted(&mount_dir));
+    }
+
+    /// Counts the `setattr` requests reaching a passthrough fs.
+    struct SetattrCounter {
+        inner: PassthroughFs,
+        setattrs: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Filesystem for SetattrCounter {
+        async fn init(&self, req: Request) -> rfuse3::Result<rfuse3::raw::reply::ReplyInit> {
+            self.inner.init(req).await
+        }
+
+        async fn destroy(&self, req: Request) {
+            self.inner.destroy(req).await
+        }
+
+        async fn lookup(
+            &self,
+            req: Request,
+            parent: rfuse3::Inode,
+            name: &OsStr,
+        ) -> rfuse3::Result<rfuse3::raw::reply::ReplyEntry> {
+            self.inner.lookup(req, parent, name).await
+        }
+
+        async fn forget(&self, req: Request, inode: rfuse3::Inode, nlookup: u64) {
+            self.inner.forget(req, inode, nlookup).await
+        }
+
+        async fn getattr(
+            &self,
+            req: Request,
+            inode: rfuse3::Inode,
+            fh: Option<u64>,
+            flags: u32,
+        ) -> rfuse3::Result<rfuse3::raw::reply::ReplyAttr> {
+            self.inner.getattr(req, inode, fh, flags).await
+        }
+
+        async fn setattr(
+            &self,
+            req: Request,
+            inode: rfuse3::Inode,
+            fh: Option<u64>,
+            set_attr: rfuse3::SetAttr,
+        ) -> rfuse3::Result<rfuse3::raw::reply::ReplyAttr> {
+            self.setattrs
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.setattr(req, inode, fh, set_attr).await
+        }
+
+        async fn open(
+            &self,
+            req: Request,
+            inode: rfuse3::Inode,
+            flags: u32,
+        ) -> rfuse3::Result<rfuse3::raw::reply::ReplyOpen> {
+            self.inner.open(req, inode, flags).await
+        }
+
+        async fn flush(
+            &self,
+            req: Request,
+            inode: rfuse3::Inode,
+            fh: u64,
+            lock_owner: u64,
+        ) -> rfuse3::Result<()> {
+            self.inner.flush(req, inode, fh, lock_owner).await
+        }
+
+        async fn release(
+            &self,
+            req: Request,
+            inode: rfuse3::Inode,
+            fh: u64,
+            flags: u32,
+            lock_owner: u64,
+            flush: bool,
+        ) -> rfuse3::Result<()> {
+            self.inner
+                .release(req, inode, fh, flags, lock_owner, flush)
+                .await
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        async fn getlk(
+            &self,
+            _req: Request,
+            _inode: rfuse3::Inode,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+        ) -> rfuse3::Result<rfuse3::raw::reply::ReplyLock> {
+            Err(libc::ENOSYS.into())
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        async fn setlk(
+            &self,
+            _req: Request,
+            _inode: rfuse3::Inode,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+            _block: bool,
+        ) -> rfuse3::Result<()> {
+            Err(libc::ENOSYS.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_atomic_o_trunc() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+
+        // Without the capability the kernel truncates with a separate setattr first.
+        for atomic in [true, false] {
+            std::fs::write(source_dir.join("file"), b"data").unwrap();
+            let args = PassthroughArgs {
+                root_dir: source_dir.clone(),
+                mapping: None::<&str>,
+            };
+            let setattrs = std::sync::Arc::new(AtomicUsize::new(0));
+            let fs = SetattrCounter {
+                inner: new_passthroughfs_layer(args).await.unwrap(),
+                setattrs: setattrs.clone(),
+            };
+            let mut mount_options = MountOptions::default();
+            mount_options
+                .uid(unsafe { libc::getuid() })
+                .gid(unsafe { libc::getgid() })
+                .atomic_o_trunc(atomic);
+            let handle = unwrap_or_skip_eperm!(
+                Session::new(mount_options)
+                    .mount(fs, mount_dir.clone())
+                    .await,
+                "mount passthrough fs"
+            );
+
+            // Closing sends a flush, so the file must be dropped off the runtime's thread too.
+            let path = mount_dir.join("file");
+            tokio::task::spawn_blocking(move || {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(path)
+                    .map(drop)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+            handle.unmount().await.unwrap();
+
+            assert_eq!(std::fs::metadata(source_dir.join("file")).unwrap().len(), 0);
+            let expected = if atomic { 0 } else { 1 };
+            assert_eq!(
+                setattrs.load(Ordering::Relaxed),
+                expected,
+                "atomic={atomic}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lseek_sparse_through_mount() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::FileExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+        // A block of data at either end, a hole in between.
+        const MIB: i64 = 1 << 20;
+        let file = std::fs::File::create(source_dir.join("sparse")).unwrap();
+        file.write_all_at(&[b'a'; 4096], 0).unwrap();
+        file.write_all_at(&[b'b'; 4096], (4 * MIB - 4096) as u64)
+            .unwrap();
+        drop(file);
+
+        let args = PassthroughArgs {
+            root_dir: source_dir.clone(),
+            mapping: None::<&str>,
+        };
+        let fs = new_passthroughfs_layer(args).await.unwrap();
+        let mut mount_options = MountOptions::default();
+        mount_options
+            .uid(unsafe { libc::getuid() })
+            .gid(unsafe { libc::getgid() });
+        let handle = unwrap_or_skip_eperm!(
+            Session::new(mount_options)
+                .mount(fs, mount_dir.clone())
+                .await,
+            "mount passthrough fs"
+        );
+
+        let path = mount_dir.join("sparse");
+        let seeks = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(path).unwrap();
+            let lseek = |offset: i64, whence: libc::c_int| {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+                if res < 0 {
+                    Err(std::io::Error::last_os_error().raw_os_error())
+                } else {
+                    Ok(res)
+                }
+            };
+            [
+                lseek(0, libc::SEEK_HOLE),
+                lseek(4096, libc::SEEK_DATA),
+                lseek(4 * MIB - 4096, libc::SEEK_HOLE),
+                lseek(4 * MIB, libc::SEEK_DATA),
+            ]
+        })
+        .await
+        .unwrap();
+        handle.unmount().await.unwrap();
+
+        let [hole, data, end, past_end] = seeks;
+        // A filesystem without holes reports the whole file as data.
+        if hole == Ok(4 * MIB) {
+            assert_eq!(data, Ok(4096));
+        } else {
+            assert_eq!(hole, Ok(4096));
+            assert_eq!(data, Ok(4 * MIB - 4096));
+        }
+        assert_eq!(end, Ok(4 * MIB));
+        assert_eq!(past_end, Err(Some(libc::ENXIO)));
+    }
+
+    #[tokio::test]
+    async fn test_writeback_through_mount() {
+        use std::io::Write;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+        // Owned by someone else, so the cached times are flushed by a task that isn't the owner.
+        std::fs::write(source_dir.join("file"), b"").unwrap();
+        std::os::unix::fs::chown(source_dir.join("file"), Some(1000), Some(1000)).unwrap();
+
+        let cfg = super::Config {
+            root_dir: source_dir.clone(),
+            writeback: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let mut mount_options = MountOptions::default();
+        mount_options
+            .uid(unsafe { libc::getuid() })
+            .gid(unsafe { libc::getgid() })
+            .write_back(true);
+        let handle = unwrap_or_skip_eperm!(
+            Session::new(mount_options)
+                .mount(fs, mount_dir.clone())
+                .await,
+            "mount passthrough fs"
+        );
+
+        let path = mount_dir.join("file");
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let res = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+            let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+            for chunk in data.chunks(1000) {
+                file.write_all(chunk)?;
+            }
+            let cached_len = file.metadata()?.len();
+            file.sync_all()?;
+            drop(file);
+            Ok((
+                cached_len,
+                std::fs::metadata(&path)?.len(),
+                std::fs::read(&path)?,
+            ))
+        })
+        .await
+        .unwrap();
+        handle.unmount().await.unwrap();
+
+        let (cached_len, len, content) = res.unwrap();
+        assert_eq!(cached_len, expected.len() as u64);
+        assert_eq!(len, expected.len() as u64);
+        assert!(content == expected);
+        assert!(std::fs::read(source_dir.join("file")).unwrap() == expected);
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_xattr_through_mount() {
+        use std::ffi::CString;
+
+        for xattr in [true, false] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let source_dir = tmp_dir.path().join("src");
+            let mount_dir = tmp_dir.path().join("mnt");
+            std::fs::create_dir(&source_dir).unwrap();
+            std::fs::create_dir(&mount_dir).unwrap();
+            std::fs::write(source_dir.join("file"), b"").unwrap();
+
+            let cfg = super::Config {
+                root_dir: source_dir.clone(),
+                xattr,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let mut mount_options = MountOptions::default();
+            mount_options
+                .uid(unsafe { libc::getuid() })
+                .gid(unsafe { libc::getgid() });
+            let handle = unwrap_or_skip_eperm!(
+                Session::new(mount_options)
+                    .mount(fs, mount_dir.clone())
+                    .await,
+                "mount passthrough fs"
+            );
+
+            let path = CString::new(format!("{}/file", mount_dir.display())).unwrap();
+            let res = tokio::task::spawn_blocking(move || {
+                let check = |res: isize| {
+                    if res < 0 {
+                        Err(std::io::Error::last_os_error().raw_os_error())
+                    } else {
+                        Ok(res as usize)
+                    }
+                };
+                let name = c"user.test";
+                let mut buf = vec![0u8; 64];
+                // Safe because the arguments are valid C strings and `buf` is large enough for
+                // the sizes passed.
+                unsafe {
+                    let set = check(libc::setxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        b"value".as_ptr() as *const libc::c_void,
+                        5,
+                        0,
+                    ) as isize);
+                    if set.is_err() {
+                        return (set, None);
+                    }
+                    let list_size = check(libc::listxattr(path.as_ptr(), std::ptr::null_mut(), 0));
+                    let list_len = check(libc::listxattr(
+                        path.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_char,
+                        buf.len(),
+                    ));
+                    let list = buf[..list_len.unwrap()].to_vec();
+                    let size = check(libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        std::ptr::null_mut(),
+                        0,
+                    ));
+                    let len = check(libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    ));
+                    let value = buf[..len.unwrap()].to_vec();
+                    let too_small = check(libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        2,
+                    ));
+                    let removed = check(libc::removexattr(path.as_ptr(), name.as_ptr()) as isize);
+                    let gone = check(libc::getxattr(
+                        path.as_ptr(),
+                        name.as_ptr(),
+                        std::ptr::null_mut(),
+                        0,
+                    ));
+                    (
+                        set,
+                        Some((list_size, list, size, value, too_small, removed, gone)),
+                    )
+                }
+            })
+            .await
+            .unwrap();
+            handle.unmount().await.unwrap();
+
+            match res {
+                (Ok(0), Some((list_size, list, size, value, too_small, removed, gone))) => {
+                    assert!(xattr);
+                    assert_eq!(list_size, Ok(list.len()));
+                    assert!(list.split(|b| *b == 0).any(|n| n == b"user.test"));
+                    assert_eq!(size, Ok(5));
+                    assert_eq!(value, b"value");
+                    assert_eq!(too_small, Err(Some(libc::ERANGE)));
+                    assert_eq!(removed, Ok(0));
+                    assert_eq!(gone, Err(Some(libc::ENODATA)));
+                    let source = CString::new(format!("{}/file", source_dir.display())).unwrap();
+                    // Safe because the arguments are valid C strings and no buffer is passed.
+                    let res = unsafe {
+                        libc::getxattr(
+                            source.as_ptr(),
+                            c"user.test".as_ptr(),
+                            std::ptr::null_mut(),
+                            0,
+                        )
+                    };
+                    assert_eq!(res, -1);
+                    assert_eq!(
+                        std::io::Error::last_os_error().raw_os_error(),
+                        Some(libc::ENODATA)
+                    );
+                }
+                // Without `xattr` the kernel tells callers xattrs aren't supported.
+                (set, _) => {
+                    assert!(!xattr);
+                    assert_eq!(set, Err(Some(libc::EOPNOTSUPP)));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_time_gran() {
+        use std::io::Write;
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+        std::fs::write(source_dir.join("file"), b"").unwrap();
+        let cfg = |time_gran| super::Config {
+            root_dir: source_dir.clone(),
+            writeback: true,
+            time_gran,
+            ..Default::default()
+        };
+
+        let fs = PassthroughFs::<()>::new(cfg(1_000_000_000)).unwrap();
+        let reply = unwrap_or_skip_eperm!(fs.init(Request::default()).await, "init");
+        assert_eq!(reply.time_gran.get(), 1_000_000_000);
+
+        // The kernel stamps the writes it caches with whole seconds, or with the nanoseconds of
+        // its clock given the default granularity.
+        for time_gran in [1_000_000_000, 1] {
+            let fs = PassthroughFs::<()>::new(cfg(time_gran)).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let mut mount_options = MountOptions::default();
+            mount_options
+                .uid(unsafe { libc::getuid() })
+                .gid(unsafe { libc::getgid() })
+                .write_back(true);
+            let handle = unwrap_or_skip_eperm!(
+                Session::new(mount_options)
+                    .mount(fs, mount_dir.clone())
+                    .await,
+                "mount passthrough fs"
+            );
+            let path = mount_dir.join("file");
+            let nsecs = tokio::task::spawn_blocking(move || {
+                let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+                let mut nsecs = Vec::new();
+                for _ in 0..3 {
+                    file.write_all(b"data")?;
+                    nsecs.push(file.metadata()?.mtime_nsec());
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                std::io::Result::Ok(nsecs)
+            })
+            .await
+            .unwrap();
+            handle.unmount().await.unwrap();
+            let nsecs = nsecs.unwrap();
+            if time_gran == 1 {
+                assert!(nsecs.iter().any(|&nsec| nsec != 0), "{nsecs:?}");
+            } else {
+                assert_eq!(nsecs, vec![0; 3]);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_atomic_dir_replace() {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::MetadataExt;
+        use std::path::PathBuf;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = tmp_dir.path().join("config");
+        std::fs::create_dir(&config).unwrap();
+        std::fs::write(config.join("app.conf"), b"0").unwrap();
+        let deploy = |version: usize| {
+            let staged = tmp_dir.path().join("config.new");
+            std::fs::create_dir(&staged).unwrap();
+            std::fs::write(staged.join("app.conf"), version.to_string()).unwrap();
+            std::fs::write(staged.join(format!("extra{version}")), b"").unwrap();
+        };
+
+        for atomic_dir_replace in [false, true] {
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                atomic_dir_replace,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let rename = || {
+                fs.rename(
+                    Request::default(),
+                    ROOT_ID,
+                    OsStr::new("config.new"),
+                    ROOT_ID,
+                    OsStr::new("config"),
+                )
+            };
+
+            if !atomic_dir_replace {
+                deploy(1);
+                let err: std::io::Error = rename().await.unwrap_err().into();
+                assert!(matches!(
+                    err.raw_os_error(),
+                    Some(libc::ENOTEMPTY | libc::EEXIST)
+                ));
+                std::fs::remove_dir_all(tmp_dir.path().join("config.new")).unwrap();
+                continue;
+            }
+
+            // A reader never finds the config missing, and finds each version whole unless it
+            // was already replaced, and so is being removed.
+            let done = std::sync::Arc::new(AtomicBool::new(false));
+            let reader = std::thread::spawn({
+                let config = config.clone();
+                let done = done.clone();
+                move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let dir = std::fs::File::open(&config).unwrap();
+                        let at = |name: &str| {
+                            PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd())).join(name)
+                        };
+                        let whole = match std::fs::read_to_string(at("app.conf")) {
+                            Ok(version) => {
+                                version == "0" || at(&format!("extra{version}")).exists()
+                            }
+                            Err(e) => {
+                                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+                                false
+                            }
+                        };
+                        if !whole {
+                            let replaced = std::fs::metadata(&config).unwrap().ino();
+                            assert_ne!(dir.metadata().unwrap().ino(), replaced);
+                        }
+                        reads += 1;
+                    }
+                    reads
+                }
+            });
+            for version in 1..=20 {
+                deploy(version);
+                rename().await.unwrap();
+                // The previous version is left for the deployment to remove.
+                let old = tmp_dir.path().join("config.new");
+                let previous = std::fs::read(old.join("app.conf")).unwrap();
+                assert_eq!(previous, (version - 1).to_string().as_bytes());
+                std::fs::remove_dir_all(old).unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+            assert!(reader.join().unwrap() > 0);
+
+            assert_eq!(std::fs::read(config.join("app.conf")).unwrap(), b"20");
+            let mut names: Vec<_> = std::fs::read_dir(tmp_dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            assert_eq!(names, vec![OsString::from("config")]);
+            let entry = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("config"))
+                .await
+                .unwrap();
+            fs.lookup(Request::default(), entry.attr.ino, OsStr::new("extra20"))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mapped_chown_round_trip() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            mapping: "uidmapping=2000:1000:10,gidmapping=3000:1000:10"
+                .parse()
+                .unwrap(),
+            ..Default::default()
+        };
+        let overflow_uid = cfg.mapping.find_mapping(0, true, true);
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let chown = |uid, gid| {
+            let set_attr = rfuse3::SetAttr {
+                uid,
+                gid,
+                ..Default::default()
+            };
+            fs.setattr(Request::default(), ino, None, set_attr)
+        };
+
+        // The host stores the mapped ids, clients see their own.
+        let reply = unwrap_or_skip_eperm!(chown(Some(1005), Some(1009)).await, "chown");
+        assert_eq!((reply.attr.uid, reply.attr.gid), (1005, 1009));
+        let md = std::fs::metadata(&file).unwrap();
+        assert_eq!((md.uid(), md.gid()), (2005, 3009));
+
+        // Changing only the owner leaves the group as it is.
+        let reply = chown(Some(1000), None).await.unwrap();
+        assert_eq!((reply.attr.uid, reply.attr.gid), (1000, 1009));
+        let md = std::fs::metadata(&file).unwrap();
+        assert_eq!((md.uid(), md.gid()), (2000, 3009));
+
+        // Host owners outside the mapped range show as the overflow id.
+        std::os::unix::fs::chown(&file, Some(4000), None).unwrap();
+        let attr = fs
+            .getattr(Request::default(), ino, None, 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!((attr.uid, attr.gid), (overflow_uid, 1009));
+    }
+
+    /// Build a passthrough fs over a fresh temporary directory.
+    async fn prepare_fs() -> (PassthroughFs, tempfile::TempDir) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let args = PassthroughArgs {
+            root_dir: tmp_dir.path().to_path_buf(),
+            mapping: None::<&str>,
+        };
+        let fs = new_passthroughfs_layer(args).await.unwrap();
+        (fs, tmp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_lseek_whence() {
+        use super::async_io::{FUSE_SEEK_DATA, FUSE_SEEK_HOLE};
+
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::write(tmp_dir.path().join("file"), b"0123456789").unwrap();
+
+        let entry = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        );
+        let ino = entry.attr.ino;
+        let fh = fs
+            .open(Request::default(), ino, libc::O_RDWR as u32)
+            .await
+            .unwrap()
+            .fh;
+        let lseek = |offset: i64, whence: i32| {
+            fs.lseek(Request::default(), ino, fh, offset as u64, whence as u32)
+        };
+
+        assert_eq!(lseek(4, libc::SEEK_SET).await.unwrap().offset, 4);
+        // SEEK_CUR is relative to the position left by the previous seek.
+        assert_eq!(lseek(2, libc::SEEK_CUR).await.unwrap().offset, 6);
+        assert_eq!(lseek(-3, libc::SEEK_CUR).await.unwrap().offset, 3);
+        assert_eq!(lseek(-2, libc::SEEK_END).await.unwrap().offset, 8);
+        assert_eq!(lseek(5, libc::SEEK_END).await.unwrap().offset, 15);
+        assert_eq!(lseek(0, FUSE_SEEK_DATA as i32).await.unwrap().offset, 0);
+        assert_eq!(lseek(0, FUSE_SEEK_HOLE as i32).await.unwrap().offset, 10);
+
+        let einval = |r: rfuse3::Result<_>| {
+            let e: std::io::Error = r.unwrap_err().into();
+            assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        };
+        einval(lseek(0, 42).await);
+        einval(lseek(-1, libc::SEEK_SET).await);
+        einval(lseek(-20, libc::SEEK_END).await);
+        lseek(2, libc::SEEK_SET).await.unwrap();
+        einval(lseek(-3, libc::SEEK_CUR).await);
+        einval(lseek(i64::MAX, libc::SEEK_END).await);
+        // A rejected seek leaves the position untouched.
+        assert_eq!(lseek(0, libc::SEEK_CUR).await.unwrap().offset, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_write_and_truncate() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+
+        let entry = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        );
+        let ino = entry.attr.ino;
+        let fh = fs
+            .open(Request::default(), ino, libc::O_RDWR as u32)
+            .await
+            .unwrap()
+            .fh;
+        let fs = std::sync::Arc::new(fs);
+        let payload = vec![b'a'; 64 * 1024];
+
+        for _ in 0..32 {
+            let writer = {
+                let fs = fs.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move {
+                    fs.write(Request::default(), ino, fh, 0, &payload, 0, 0)
+                        .await
+                        .unwrap()
+                        .written
+                })
+            };
+            let truncater = {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    let set_attr = rfuse3::SetAttr {
+                        size: Some(0),
+                        ..Default::default()
+                    };
+                    fs.setattr(Request::default(), ino, None, set_attr)
+                        .await
+                        .unwrap()
+                        .attr
+                        .size
+                })
+            };
+            assert_eq!(writer.await.unwrap() as usize, payload.len());
+            // The truncate either fully precedes or fully follows the write.
+            let size = truncater.await.unwrap();
+            assert_eq!(size, 0);
+
+            let content = std::fs::read(&path).unwrap();
+            let attr = fs.getattr(Request::default(), ino, None, 0).await.unwrap();
+            assert_eq!(attr.attr.size as usize, content.len());
+            assert!(content.is_empty() || content == payload);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_write_enospc() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().join("tmpfs");
+        std::fs::create_dir(&root).unwrap();
+        let target = std::ffi::CString::new(root.to_str().unwrap()).unwrap();
+        let res = unsafe {
+            libc::mount(
+                c"tmpfs".as_ptr(),
+                target.as_ptr(),
+                c"tmpfs".as_ptr(),
+                0,
+                c"size=64k".as_ptr() as *const libc::c_void,
+            )
+        };
+        unwrap_or_skip_eperm!(
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            },
+            "mount tmpfs"
+        );
+
+        let args = PassthroughArgs {
+            root_dir: root.clone(),
+            mapping: None::<&str>,
+        };
+        let fs = new_passthroughfs_layer(args).await.unwrap();
+        let created = fs
+            .create(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("file"),
+                libc::S_IFREG | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+        let (ino, fh) = (created.attr.ino, created.fh);
+
+        // The first write fills the fs and is cut short.
+        let payload = vec![b'a'; 1024 * 1024];
+        let written = fs
+            .write(Request::default(), ino, fh, 0, &payload, 0, 0)
+            .await
+            .unwrap()
+            .written as u64;
+        assert!(written > 0 && written < payload.len() as u64);
+
+        // The next one reports ENOSPC.
+        let err: std::io::Error = fs
+            .write(Request::default(), ino, fh, written, &payload, 0, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+        // The reported size only accounts for what was actually written.
+        let attr = fs.getattr(Request::default(), ino, None, 0).await.unwrap();
+        assert_eq!(attr.attr.size, written);
+
+        fs.release(Request::default(), ino, fh, 0, 0, false)
+            .await
+            .unwrap();
+        drop(fs);
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+    }
+
+    #[tokio::test]
+    async fn test_custom_root_ino() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            root_ino: 42,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        assert_eq!(fs.root_ino(), 42);
+
+        // The FUSE root id keeps working and reports the configured inode.
+        for ino in [ROOT_ID, 42] {
+            let attr = fs.getattr(Request::default(), ino, None, 0).await.unwrap();
+            assert_eq!(attr.attr.ino, 42);
+        }
+
+        let dir = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+        assert_ne!(dir.attr.ino, 42);
+        let parent = fs
+            .lookup(Request::default(), dir.attr.ino, OsStr::new(".."))
+            .await
+            .unwrap();
+        assert_eq!(parent.attr.ino, 42);
+
+        // Forgetting the root by either number must not drop it.
+        fs.forget(Request::default(), ROOT_ID, 10).await;
+        fs.forget(Request::default(), 42, 10).await;
+        let attr = fs
+            .getattr(Request::default(), ROOT_ID, None, 0)
+            .await
+            .unwrap();
+        assert_eq!(attr.attr.ino, 42);
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_readdir_resume_from_offset() {
+        use futures_util::StreamExt;
+
+        let (fs, tmp_dir) = prepare_fs().await;
+        // Enough entries to span several getdents64() buffers.
+        for i in 0..512 {
+            std::fs::write(tmp_dir.path().join(format!("file-{i:03}")), b"").unwrap();
+        }
+
+        let fh = unwrap_or_skip_eperm!(
+            fs.opendir(Request::default(), ROOT_ID, libc::O_RDONLY as u32)
+                .await,
+            "opendir"
+        )
+        .fh;
+        let list = |offset: i64| {
+            let fs = &fs;
+            async move {
+                let reply = fs
+                    .readdir(Request::default(), ROOT_ID, fh, offset)
+                    .await
+                    .unwrap();
+                reply
+                    .entries
+                    .map(|e| e.unwrap())
+                    .map(|e| (e.name, e.offset))
+                    .collect::<Vec<_>>()
+                    .await
+            }
+        };
+
+        let all = list(0).await;
+        assert!(all.len() >= 512);
+
+        // Resuming from the cookie of any entry continues right after it, like seekdir().
+        for idx in [0, 10, 255, 400, all.len() - 2] {
+            let (_, cookie) = &all[idx];
+            let rest = list(*cookie).await;
+            assert_eq!(rest, all[idx + 1..]);
+        }
+        assert!(list(all.last().unwrap().1).await.is_empty());
+
+        fs.releasedir(Request::default(), ROOT_ID, fh, 0)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_trunc() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"some content").unwrap();
+
+        let entry = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        );
+        assert_eq!(entry.attr.size, 12);
+
+        // A reader holding the file open sees it empty as soon as the O_TRUNC open returns.
+        let reader = std::fs::File::open(&path).unwrap();
+        let fh = fs
+            .open(
+                Request::default(),
+                entry.attr.ino,
+                (libc::O_WRONLY | libc::O_TRUNC) as u32,
+            )
+            .await
+            .unwrap()
+            .fh;
+        assert_eq!(reader.metadata().unwrap().len(), 0);
+        let attr = fs
+            .getattr(Request::default(), entry.attr.ino, Some(fh), 0)
+            .await
+            .unwrap();
+        assert_eq!(attr.attr.size, 0);
+
+        // create() on an existing file reports the truncated size, not the looked up one.
+        std::fs::write(&path, b"some content").unwrap();
+        let created = fs
+            .create(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("file"),
+                u32::from(libc::S_IFREG) | 0o644,
+                (libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32,
+            )
+            .await
+            .unwrap();
+        assert_eq!(created.attr.size, 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_readonly_open_trunc() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"some content").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            readonly: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        for flags in [
+            libc::O_RDONLY | libc::O_TRUNC,
+            libc::O_WRONLY,
+            libc::O_RDWR | libc::O_TRUNC,
+        ] {
+            let err: std::io::Error = fs
+                .open(Request::default(), ino, flags as u32)
+                .await
+                .unwrap_err()
+                .into();
+            assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"some content");
+
+        // Plain read-only opens keep working.
+        fs.open(Request::default(), ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap();
+        let err: std::io::Error = fs
+            .unlink(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_write_denial_errno() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source = tmp_dir.path().join("src");
+        let target = tmp_dir.path().join("ro");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::create_dir(&target).unwrap();
+        std::fs::write(source.join("file"), b"").unwrap();
+        // Other tests clear the umask.
+        for (path, mode) in [(&source, 0o755), (&source.join("file"), 0o644)] {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let user = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: std::process::id(),
+        };
+        let errno = |res: rfuse3::Result<()>| {
+            res.map_err(std::io::Error::from)
+                .unwrap_err()
+                .raw_os_error()
+        };
+
+        // `readonly` and a read-only host mount refuse everyone, root included.
+        let source_c = CString::new(source.as_os_str().as_bytes()).unwrap();
+        let target_c = CString::new(target.as_os_str().as_bytes()).unwrap();
+        // Safe because the arguments are valid C strings or null.
+        let bound = unsafe {
+            libc::mount(
+                source_c.as_ptr(),
+                target_c.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND,
+                std::ptr::null(),
+            ) == 0
+                && libc::mount(
+                    std::ptr::null(),
+                    target_c.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                    std::ptr::null(),
+                ) == 0
+        };
+        // Unmounts the read-only mount even if an assertion fails.
+        struct Unmount(CString);
+        impl Drop for Unmount {
+            fn drop(&mut self) {
+                // Safe because the argument is a valid C string.
+                unsafe { libc::umount2(self.0.as_ptr(), libc::MNT_DETACH) };
+            }
+        }
+        let _unmount = bound.then(|| Unmount(target_c.clone()));
+        let mut read_only = vec![(source.clone(), true)];
+        if bound {
+            read_only.push((target.clone(), false));
+        } else {
+            eprintln!(
+                "skip read-only mount part of test_write_denial_errno: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        for (root_dir, readonly) in read_only {
+            let cfg = super::Config {
+                root_dir,
+                readonly,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let open = fs
+                .open(Request::default(), ino, libc::O_WRONLY as u32)
+                .await;
+            assert_eq!(errno(open.map(drop)), Some(libc::EROFS));
+            let mkdir = fs.mkdir(Request::default(), ROOT_ID, OsStr::new("dir"), 0o755, 0);
+            assert_eq!(errno(mkdir.await.map(drop)), Some(libc::EROFS));
+            for req in [Request::default(), user] {
+                let access = fs.access(req, ino, libc::W_OK as u32).await;
+                assert_eq!(errno(access), Some(libc::EROFS), "readonly={readonly}");
+            }
+        }
+
+        // Lacking permission on a writable export is a matter of access rights.
+        let cfg = super::Config {
+            root_dir: source.clone(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let access = fs.access(user, ino, libc::W_OK as u32).await;
+        assert_eq!(errno(access), Some(libc::EACCES));
+        let mkdir = fs.mkdir(user, ROOT_ID, OsStr::new("dir"), 0o755, 0);
+        assert_eq!(errno(mkdir.await.map(drop)), Some(libc::EACCES));
+        fs.access(Request::default(), ino, libc::W_OK as u32)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_readdirplus_bounded_concurrency() {
+        use futures_util::StreamExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for i in 0..300 {
+            std::fs::write(tmp_dir.path().join(format!("file-{i:03}")), b"").unwrap();
+        }
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            readdirplus_concurrency: 4,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let fh = fs
+            .opendir(Request::default(), ROOT_ID, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let entries = fs
+            .readdirplus(Request::default(), ROOT_ID, fh, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(entries.len(), 300);
+
+        // Entries keep the directory order and carry the attributes of their own file.
+        let offsets: Vec<_> = entries.iter().map(|e| e.offset).collect();
+        let all = fs
+            .readdir(Request::default(), ROOT_ID, fh, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap())
+            .filter(|e| std::future::ready(e.name != "." && e.name != ".."))
+            .map(|e| e.offset)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(offsets, all);
+        for entry in entries {
+            let looked_up = fs
+                .lookup(Request::default(), ROOT_ID, &entry.name)
+                .await
+                .unwrap();
+            assert_eq!(looked_up.attr.ino, entry.attr.ino);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dir_nlink() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::create_dir(tmp_dir.path().join("parent")).unwrap();
+        std::fs::write(tmp_dir.path().join("parent/file"), b"").unwrap();
+
+        let parent = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("parent"))
+                .await,
+            "lookup parent"
+        );
+        let host_nlink = |fs_nlink: u32| {
+            use std::os::unix::fs::MetadataExt;
+            let nlink = std::fs::metadata(tmp_dir.path().join("parent"))
+                .unwrap()
+                .nlink();
+            assert_eq!(fs_nlink as u64, nlink);
+            nlink
+        };
+        let nlink = || async {
+            fs.getattr(Request::default(), parent.attr.ino, None, 0)
+                .await
+                .unwrap()
+                .attr
+                .nlink
+        };
+
+        // Regular files don't count, only the `..` of subdirectories does.
+        let base = host_nlink(parent.attr.nlink);
+        for i in 1..=3 {
+            fs.mkdir(
+                Request::default(),
+                parent.attr.ino,
+                OsStr::new(&format!("sub{i}")),
+                0o755,
+                0,
+            )
+            .await
+            .unwrap();
+            assert_eq!(host_nlink(nlink().await), base + i);
+        }
+        fs.rmdir(Request::default(), parent.attr.ino, OsStr::new("sub2"))
+            .await
+            .unwrap();
+        assert_eq!(host_nlink(nlink().await), base + 2);
+
+        // The lookup reply of the directory agrees with getattr.
+        let again = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("parent"))
+            .await
+            .unwrap();
+        assert_eq!(again.attr.nlink as u64, base + 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_rename_exchange_file_and_dir() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::write(tmp_dir.path().join("file"), b"data").unwrap();
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        std::fs::write(tmp_dir.path().join("dir/child"), b"").unwrap();
+
+        let file = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        );
+        let dir = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+
+        fs.rename2(
+            Request::default(),
+            ROOT_ID,
+            OsStr::new("file"),
+            ROOT_ID,
+            OsStr::new("dir"),
+            libc::RENAME_EXCHANGE,
+        )
+        .await
+        .unwrap();
+
+        // The names are swapped and both entries survive.
+        assert!(tmp_dir.path().join("file").is_dir());
+        assert!(tmp_dir.path().join("file/child").exists());
+        assert_eq!(std::fs::read(tmp_dir.path().join("dir")).unwrap(), b"data");
+
+        // The inodes follow their objects to the new names.
+        let swapped_file = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+        let swapped_dir = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        assert_eq!(swapped_file.attr.ino, file.attr.ino);
+        assert_eq!(swapped_dir.attr.ino, dir.attr.ino);
+        assert_eq!(swapped_dir.attr.kind, rfuse3::FileType::Directory);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_rename_whiteout() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::write(tmp_dir.path().join("file"), b"data").unwrap();
+        let file = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        );
+
+        let res = fs
+            .rename2(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("file"),
+                ROOT_ID,
+                OsStr::new("moved"),
+                libc::RENAME_WHITEOUT,
+            )
+            .await;
+        if let Err(e) = res {
+            let e: std::io::Error = e.into();
+            // Needs CAP_MKNOD and a host filesystem which supports whiteouts.
+            if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) {
+                eprintln!("skip test_rename_whiteout: {e}");
+                return;
+            }
+            panic!("rename2: {e}");
+        }
+
+        assert_eq!(
+            std::fs::read(tmp_dir.path().join("moved")).unwrap(),
+            b"data"
+        );
+        let md = std::fs::symlink_metadata(tmp_dir.path().join("file")).unwrap();
+        assert!(md.file_type().is_char_device());
+        assert_eq!(md.rdev(), 0);
+
+        let moved = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("moved"))
+            .await
+            .unwrap();
+        assert_eq!(moved.attr.ino, file.attr.ino);
+        let whiteout = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        assert_ne!(whiteout.attr.ino, file.attr.ino);
+        assert_eq!(whiteout.attr.kind, rfuse3::FileType::CharDevice);
+        assert_eq!(whiteout.attr.rdev, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dump_inodes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(tmp_dir.path().join(name), b"").unwrap();
+        }
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            debug_inode_dump: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let mut inos = Vec::new();
+        for name in ["a", "b", "c"] {
+            let entry = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new(name))
+                .await
+                .unwrap();
+            inos.push(entry.attr.ino);
+        }
+        // Look "b" up a second time so it holds two references.
+        fs.lookup(Request::default(), ROOT_ID, OsStr::new("b"))
+            .await
+            .unwrap();
+
+        // Drop "a" entirely and one of the references of "b".
+        fs.forget(Request::default(), inos[0], 1).await;
+        fs.forget(Request::default(), inos[1], 1).await;
+
+        let dump = fs.dump_inodes().await;
+        let resident: Vec<_> = dump.iter().map(|i| i.inode).collect();
+        assert_eq!(resident, vec![ROOT_ID, inos[1], inos[2]]);
+        for (info, name) in dump[1..].iter().zip(["b", "c"]) {
+            let md = std::fs::metadata(tmp_dir.path().join(name)).unwrap();
+            assert_eq!(info.refcount, 1);
+            assert_eq!((info.dev, info.ino), (md.dev(), md.ino()));
+            assert!(info.has_fd || info.has_handle);
+        }
+
+        // The dump is only available when enabled.
+        let (fs, _tmp_dir) = prepare_fs().await;
+        assert!(fs.dump_inodes().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reopen_on_replace() {
+        for reopen_on_replace in [false, true] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let path = tmp_dir.path().join("file");
+            std::fs::write(&path, b"old").unwrap();
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                reopen_on_replace,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+
+            let entry = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap();
+            let fh = fs
+                .open(Request::default(), entry.attr.ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+
+            // Replace the file behind the open handle.
+            std::fs::remove_file(&path).unwrap();
+            std::fs::write(&path, b"new").unwrap();
+
+            let data = fs
+                .read(Request::default(), entry.attr.ino, fh, 0, 16)
+                .await
+                .unwrap()
+                .data;
+            let expected: &[u8] = if reopen_on_replace { b"new" } else { b"old" };
+            assert_eq!(&data[..], expected, "reopen_on_replace={reopen_on_replace}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen_on_replace_confined() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("file"), b"outside").unwrap();
+        let dir = tmp_dir.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"old").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            reopen_on_replace: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let dir_entry = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+        let entry = fs
+            .lookup(Request::default(), dir_entry.attr.ino, OsStr::new("file"))
+            .await
+            .unwrap();
+        let fh = fs
+            .open(Request::default(), entry.attr.ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+
+        // Swap the directory for a symlink to one outside the export holding the same name.
+        std::fs::remove_file(dir.join("file")).unwrap();
+        std::fs::rename(&dir, tmp_dir.path().join("dir.old")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), &dir).unwrap();
+
+        let data = fs
+            .read(Request::default(), entry.attr.ino, fh, 0, 16)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(&data[..], b"old");
+    }
+
+    #[tokio::test]
+    async fn test_max_xattr_size() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            xattr: true,
+            max_xattr_size: Some(16),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        let name = OsStr::new("user.test");
+        let err: std::io::Error = fs
+            .setxattr(Request::default(), ino, name, &[0u8; 17], 0, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::E2BIG));
+
+        // Values up to the limit are passed on to the host, which may not support user xattrs.
+        if let Err(e) = fs
+            .setxattr(Request::default(), ino, name, &[0u8; 16], 0, 0)
+            .await
+        {
+            let e: std::io::Error = e.into();
+            assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_xattr_namespace_filter() {
+        use rfuse3::raw::reply::ReplyXAttr;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let path = CString::new(file.as_os_str().as_bytes()).unwrap();
+        for name in [c"user.test", c"trusted.test"] {
+            let res =
+                unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), b"v".as_ptr().cast(), 1, 0) };
+            if res < 0 {
+                eprintln!(
+                    "skip test_xattr_namespace_filter: setxattr {name:?}: {}",
+                    std::io::Error::last_os_error()
+                );
+                return;
+            }
+        }
+
+        let names = |list: ReplyXAttr| match list {
+            ReplyXAttr::Data(d) => d
+                .split(|b| *b == 0)
+                .filter(|n| !n.is_empty())
+                .map(|n| String::from_utf8(n.to_vec()).unwrap())
+                .collect::<Vec<_>>(),
+            ReplyXAttr::Size(_) => panic!("expected data"),
+        };
+        let errno = |r: rfuse3::Errno| std::io::Error::from(r).raw_os_error();
+        for (filter, visible) in [
+            (None, vec!["trusted.test", "user.test"]),
+            (Some(super::XattrFilter::unprivileged()), vec!["user.test"]),
+            (
+                Some(super::XattrFilter::new(|name| {
+                    name.starts_with(b"trusted.")
+                })),
+                vec!["trusted.test"],
+            ),
+        ] {
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                xattr: true,
+                xattr_namespace_filter: filter,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+
+            let list = fs.listxattr(Request::default(), ino, 256).await.unwrap();
+            let mut listed = names(list);
+            listed.sort();
+            assert_eq!(listed, visible);
+            let size: usize = visible.iter().map(|n| n.len() + 1).sum();
+            let probe = fs.listxattr(Request::default(), ino, 0).await.unwrap();
+            assert!(matches!(probe, ReplyXAttr::Size(n) if n as usize == size));
+            if size > 1 {
+                let err = fs
+                    .listxattr(Request::default(), ino, size as u32 - 1)
+                    .await
+                    .unwrap_err();
+                assert_eq!(errno(err), Some(libc::ERANGE));
+            }
+
+            for name in ["trusted.test", "user.test"] {
+                let name = OsStr::new(name);
+                let got = fs.getxattr(Request::default(), ino, name, 16).await;
+                let set = fs.setxattr(Request::default(), ino, name, b"w", 0, 0).await;
+                if visible.iter().any(|v| OsStr::new(v) == name) {
+                    assert!(matches!(got, Ok(ReplyXAttr::Data(_))));
+                    set.unwrap();
+                } else {
+                    assert_eq!(errno(got.unwrap_err()), Some(libc::ENOTSUP));
+                    assert_eq!(errno(set.unwrap_err()), Some(libc::ENOTSUP));
+                    let err = fs
+                        .removexattr(Request::default(), ino, name)
+                        .await
+                        .unwrap_err();
+                    assert_eq!(errno(err), Some(libc::ENOTSUP));
+                }
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_xattr_name_map() {
+        use rfuse3::raw::reply::ReplyXAttr;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            xattr: true,
+            xattr_name_map: super::XattrNameMap::new([("trusted.overlay.", "user.overlay.")])
+                .unwrap(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        let mapped = OsStr::new("trusted.overlay.opaque");
+        if let Err(e) = fs
+            .setxattr(Request::default(), ino, mapped, b"y", 0, 0)
+            .await
+        {
+            let e: std::io::Error = e.into();
+            assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+            eprintln!("skip test_xattr_name_map: host has no user xattrs");
+            return;
+        }
+        fs.setxattr(
+            Request::default(),
+            ino,
+            OsStr::new("user.other"),
+            b"z",
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+
+        // The host keeps it under the host name only.
+        let path = CString::new(file.as_os_str().as_bytes()).unwrap();
+        let host_size = |name: &CStr| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0)
+        };
+        assert_eq!(host_size(c"user.overlay.opaque"), 1);
+        assert_eq!(host_size(c"trusted.overlay.opaque"), -1);
+
+        let value = fs
+            .getxattr(Request::default(), ino, mapped, 16)
+            .await
+            .unwrap();
+        assert!(matches!(value, ReplyXAttr::Data(d) if d == "y"));
+        let list = fs.listxattr(Request::default(), ino, 256).await.unwrap();
+        let ReplyXAttr::Data(list) = list else {
+            panic!("expected data");
+        };
+        let mut names: Vec<_> = list.split(|b| *b == 0).filter(|n| !n.is_empty()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![&b"trusted.overlay.opaque"[..], &b"user.other"[..]]
+        );
+        let probe = fs.listxattr(Request::default(), ino, 0).await.unwrap();
+        assert!(matches!(probe, ReplyXAttr::Size(n) if n as usize == list.len()));
+
+        // The host name is only reachable through the mapped one.
+        let err: std::io::Error = fs
+            .getxattr(
+                Request::default(),
+                ino,
+                OsStr::new("user.overlay.opaque"),
+                16,
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTSUP));
+
+        fs.removexattr(Request::default(), ino, mapped)
+            .await
+            .unwrap();
+        assert_eq!(host_size(c"user.overlay.opaque"), -1);
+    }
+
+    #[tokio::test]
+    async fn test_symlink_xattr() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Linux only allows trusted xattrs on symlinks, which take CAP_SYS_ADMIN.
+        #[cfg(target_os = "linux")]
+        const NAME: &str = "trusted.test";
+        #[cfg(target_os = "macos")]
+        const NAME: &str = "user.test";
+        #[cfg(target_os = "linux")]
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skip test_symlink_xattr: not root");
+            return;
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("target"), b"data").unwrap();
+        std::os::unix::fs::symlink("target", tmp_dir.path().join("link")).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            xattr: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("link"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        // The size of the attribute of the file at `name` itself, not following symlinks.
+        let host_xattr_size = |name: &str| {
+            let path = CString::new(tmp_dir.path().join(name).as_os_str().as_bytes()).unwrap();
+            let attr = CString::new(NAME).unwrap();
+            // Safe because the size query doesn't write to memory.
+            #[cfg(target_os = "linux")]
+            let res =
+                unsafe { libc::lgetxattr(path.as_ptr(), attr.as_ptr(), std::ptr::null_mut(), 0) };
+            #[cfg(target_os = "macos")]
+            let res = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    attr.as_ptr(),
+                    std::ptr::null_mut(),
+                    0,
+                    0,
+                    libc::XATTR_NOFOLLOW,
+                )
+            };
+            (res >= 0).then_some(res)
+        };
+
+        fs.setxattr(Request::default(), ino, OsStr::new(NAME), b"link", 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(host_xattr_size("link"), Some(4));
+        assert_eq!(host_xattr_size("target"), None);
+
+        let value = fs
+            .getxattr(Request::default(), ino, OsStr::new(NAME), 64)
+            .await
+            .unwrap();
+        assert!(matches!(value, rfuse3::raw::reply::ReplyXAttr::Data(d) if d == "link"));
+        let list = fs.listxattr(Request::default(), ino, 256).await.unwrap();
+        assert!(matches!(
+            list,
+            rfuse3::raw::reply::ReplyXAttr::Data(d) if d.split(|b| *b == 0).any(|n| n == NAME.as_bytes())
+        ));
+
+        fs.removexattr(Request::default(), ino, OsStr::new(NAME))
+            .await
+            .unwrap();
+        assert_eq!(host_xattr_size("link"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_access_supplementary_groups() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::process::CommandExt;
+
+        const GROUP: u32 = 4242;
+        let (fs, tmp_dir) = prepare_fs().await;
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        if std::os::unix::fs::chown(&path, Some(0), Some(GROUP)).is_err() {
+            eprintln!("skip test_access_supplementary_groups: chown not permitted");
+            return;
+        }
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o060)).unwrap();
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        // A caller whose primary group doesn't match, but which has GROUP as a supplementary
+        // group.
+        let mut cmd = std::process::Command::new("sleep");
+        cmd.arg("10");
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setgroups(1, [GROUP].as_ptr()) != 0
+                    || libc::setgid(1000) != 0
+                    || libc::setuid(1000) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("skip test_access_supplementary_groups: {e}");
+                return;
+            }
+        };
+        let req = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: child.id(),
+        };
+        let res = fs.access(req, ino, (libc::R_OK | libc::W_OK) as u32).await;
+        let _ = child.kill();
+        let _ = child.wait();
+        res.unwrap();
+
+        // Without the supplementary group the same caller is denied.
+        let req = Request {
+            pid: std::process::id(),
+            ..req
+        };
+        let err: std::io::Error = fs
+            .access(req, ino, libc::R_OK as u32)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_preferred_io_size() {
+        use std::os::unix::fs::MetadataExt;
+
+        // A tmpfs has a known block size, fall back to the temp dir's if it can't be mounted.
+        let (fs, tmp_dir) = prepare_fs().await;
+        let mnt = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&mnt).unwrap();
+        let mounted = std::process::Command::new("mount")
+            .args(["-t", "tmpfs", "-o", "size=1m", "tmpfs"])
+            .arg(&mnt)
+            .status()
+            .is_ok_and(|s| s.success());
+        let dir = if mounted {
+            mnt.clone()
+        } else {
+            tmp_dir.path().to_path_buf()
+        };
+        std::fs::write(dir.join("file"), b"data").unwrap();
+
+        let mut parent = ROOT_ID;
+        if mounted {
+            parent = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("mnt"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+        }
+        let entry = fs
+            .lookup(Request::default(), parent, OsStr::new("file"))
+            .await
+            .unwrap();
+        let md = std::fs::metadata(dir.join("file")).unwrap();
+        let mut host = std::mem::MaybeUninit::<libc::statvfs>::zeroed();
+        let cpath = std::ffi::CString::new(dir.as_os_str().as_encoded_bytes()).unwrap();
+        assert_eq!(
+            unsafe { libc::statvfs(cpath.as_ptr(), host.as_mut_ptr()) },
+            0
+        );
+        let host = unsafe { host.assume_init() };
+
+        // The preferred I/O size is reported in the attributes, the block and fragment sizes,
+        // which back `pathconf`-style alignment queries, in statfs.
+        assert_eq!(entry.attr.blksize as u64, md.blksize());
+        let attr = fs
+            .getattr(Request::default(), entry.attr.ino, None, 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.blksize as u64, md.blksize());
+        let statfs = fs.statfs(Request::default(), entry.attr.ino).await.unwrap();
+        assert_eq!(statfs.bsize as u64, host.f_bsize);
+        assert_eq!(statfs.frsize as u64, host.f_frsize);
+        if mounted {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+            assert_eq!(md.blksize(), page_size);
+        }
+
+        drop(fs);
+        if mounted {
+            unsafe { libc::umount2(cpath.as_ptr(), libc::MNT_DETACH) };
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_fallocate_chunked() {
+        use std::os::unix::fs::MetadataExt;
+        use std::time::Duration;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            fallocate_chunk_size: Some(1024 * 1024),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let fh = fs
+            .open(Request::default(), ino, libc::O_RDWR as u32)
+            .await
+            .unwrap()
+            .fh;
+
+        // 8 host calls of 1 MiB each.
+        const LEN: u64 = 8 * 1024 * 1024;
+        let res = tokio::time::timeout(
+            Duration::from_secs(60),
+            fs.fallocate(Request::default(), ino, fh, 0, LEN, 0),
+        )
+        .await
+        .expect("chunked fallocate timed out");
+        if let Err(e) = res {
+            let e: std::io::Error = e.into();
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                eprintln!("skip test_fallocate_chunked: fallocate unsupported");
+                return;
+            }
+            panic!("fallocate failed: {e}");
+        }
+
+        let md = std::fs::metadata(tmp_dir.path().join("file")).unwrap();
+        assert_eq!(md.len(), LEN);
+        assert!(md.blocks() * 512 >= LEN);
+
+        // Punching a hole is split the same way and keeps the size.
+        fs.fallocate(
+            Request::default(),
+            ino,
+            fh,
+            0,
+            LEN,
+            (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+        )
+        .await
+        .unwrap();
+        let md = std::fs::metadata(tmp_dir.path().join("file")).unwrap();
+        assert_eq!(md.len(), LEN);
+        assert!(md.blocks() * 512 < LEN);
+    }
+
+    #[tokio::test]
+    async fn test_fallocate_modes() {
+        use super::util::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+        use std::os::unix::fs::MetadataExt;
+
+        let (fs, tmp_dir) = prepare_fs().await;
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        let ino = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .map_err(std::io::Error::from),
+            "lookup"
+        )
+        .attr
+        .ino;
+        let fh = fs
+            .open(Request::default(), ino, libc::O_RDWR as u32)
+            .await
+            .unwrap()
+            .fh;
+        let fs = &fs;
+        let fallocate = |offset: u64, length: u64, mode: u32| async move {
+            fs.fallocate(Request::default(), ino, fh, offset, length, mode)
+                .await
+                .map_err(std::io::Error::from)
+        };
+
+        const LEN: u64 = 4 * 1024 * 1024;
+        if let Err(e) = fallocate(0, LEN, 0).await {
+            assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+            eprintln!("skip test_fallocate_modes: fallocate unsupported");
+            return;
+        }
+        // Plain preallocation grows the file.
+        let md = std::fs::metadata(&path).unwrap();
+        assert_eq!(md.len(), LEN);
+        assert!(md.blocks() * 512 >= LEN);
+
+        // Preallocating past the end while keeping the size only adds blocks.
+        fallocate(LEN, LEN, FALLOC_FL_KEEP_SIZE).await.unwrap();
+        let md = std::fs::metadata(&path).unwrap();
+        assert_eq!(md.len(), LEN);
+        assert!(md.blocks() * 512 >= 2 * LEN);
+
+        // Punching a hole frees the blocks and keeps the size.
+        let blocks = md.blocks();
+        fallocate(0, LEN, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE)
+            .await
+            .unwrap();
+        let md = std::fs::metadata(&path).unwrap();
+        assert_eq!(md.len(), LEN);
+        assert!(md.blocks() * 512 <= blocks * 512 - LEN);
+
+        // Without keeping the size it's rejected, as by the kernel.
+        let err = fallocate(0, LEN, FALLOC_FL_PUNCH_HOLE).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), LEN);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let primary = tmp_dir.path().join("primary");
+        let mirror = tmp_dir.path().join("mirror");
+        std::fs::create_dir(&primary).unwrap();
+        std::fs::create_dir(&mirror).unwrap();
+        let cfg = super::Config {
+            root_dir: primary.clone(),
+            mirror_dir: Some(mirror.clone()),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let dir = fs
+            .mkdir(Request::default(), ROOT_ID, OsStr::new("dir"), 0o755, 0)
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let created = fs
+            .create(
+                Request::default(),
+                dir,
+                OsStr::new("file"),
+                u32::from(libc::S_IFREG) | 0o644,
+                libc::O_RDWR as u32,
+            )
+            .await
+            .unwrap();
+        let written = fs
+            .write(
+                Request::default(),
+                created.attr.ino,
+                created.fh,
+                0,
+                b"hello",
+                0,
+                0,
+            )
+            .await
+            .unwrap()
+            .written;
+        assert_eq!(written, 5);
+        for root in [&primary, &mirror] {
+            assert_eq!(std::fs::read(root.join("dir/file")).unwrap(), b"hello");
+        }
+
+        fs.rename(
+            Request::default(),
+            dir,
+            OsStr::new("file"),
+            ROOT_ID,
+            OsStr::new("moved"),
+        )
+        .await
+        .unwrap();
+        // Writes after the rename follow the file to its new name.
+        fs.write(
+            Request::default(),
+            created.attr.ino,
+            created.fh,
+            5,
+            b"!",
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+        for root in [&primary, &mirror] {
+            assert!(!root.join("dir/file").exists());
+            assert_eq!(std::fs::read(root.join("moved")).unwrap(), b"hello!");
+        }
+
+        // Data copied within the host and space allocated there reach the mirror as well.
+        let copied = fs
+            .copy_file_range(
+                Request::default(),
+                created.attr.ino,
+                created.fh,
+                0,
+                created.attr.ino,
+                created.fh,
+                6,
+                5,
+                0,
+            )
+            .await
+            .unwrap()
+            .copied;
+        assert_eq!(copied, 5);
+        fs.fallocate(Request::default(), created.attr.ino, created.fh, 0, 16, 0)
+            .await
+            .unwrap();
+        for root in [&primary, &mirror] {
+            assert_eq!(
+                std::fs::read(root.join("moved")).unwrap(),
+                b"hello!hello\0\0\0\0\0"
+            );
+        }
+
+        fs.unlink(Request::default(), ROOT_ID, OsStr::new("moved"))
+            .await
+            .unwrap();
+        fs.rmdir(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+        for root in [&primary, &mirror] {
+            assert_eq!(std::fs::read_dir(root).unwrap().count(), 0);
+        }
+
+        // A mirror failure only fails the operation in strict mode.
+        std::fs::write(primary.join("unmirrored"), b"").unwrap();
+        fs.unlink(Request::default(), ROOT_ID, OsStr::new("unmirrored"))
+            .await
+            .unwrap();
+        let cfg = super::Config {
+            root_dir: primary.clone(),
+            mirror_dir: Some(mirror.clone()),
+            mirror_strict: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        std::fs::write(primary.join("unmirrored"), b"").unwrap();
+        let err: std::io::Error = fs
+            .unlink(Request::default(), ROOT_ID, OsStr::new("unmirrored"))
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert!(!primary.join("unmirrored").exists());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_dir_confined() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let primary = tmp_dir.path().join("primary");
+        let mirror = tmp_dir.path().join("mirror");
+        let outside = tmp_dir.path().join("outside");
+        for dir in [&primary, &mirror, &outside] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        std::fs::create_dir(primary.join("b")).unwrap();
+        std::os::unix::fs::symlink(&outside, mirror.join("b")).unwrap();
+        std::fs::write(outside.join("passwd"), b"secret").unwrap();
+        for root in [&primary, &mirror] {
+            std::fs::write(root.join("keep"), b"data").unwrap();
+        }
+        let cfg = super::Config {
+            root_dir: primary.clone(),
+            mirror_dir: Some(mirror.clone()),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        // A symlink in the mirror doesn't lead mirrored operations out of it.
+        let b = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("b"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        fs.create(
+            Request::default(),
+            b,
+            OsStr::new("passwd"),
+            u32::from(libc::S_IFREG) | 0o644,
+            (libc::O_RDWR | libc::O_TRUNC) as u32,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(outside.join("passwd")).unwrap(), b"secret");
+
+        // Only O_TRUNC truncates the mirrored file.
+        fs.create(
+            Request::default(),
+            ROOT_ID,
+            OsStr::new("keep"),
+            u32::from(libc::S_IFREG) | 0o644,
+            libc::O_RDWR as u32,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(mirror.join("keep")).unwrap(), b"data");
+
+        // Exchanges are mirrored.
+        #[cfg(target_os = "linux")]
+        {
+            fs.rename2(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("b"),
+                ROOT_ID,
+                OsStr::new("keep"),
+                libc::RENAME_EXCHANGE,
+            )
+            .await
+            .unwrap();
+            for root in [&primary, &mirror] {
+                assert_eq!(std::fs::read(root.join("b")).unwrap(), b"data");
+            }
+            assert!(
+                std::fs::symlink_metadata(mirror.join("keep"))
+                    .unwrap()
+                    .is_symlink()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_confine_symlinks() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp_dir.path().join("a/b")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", tmp_dir.path().join("a/b/abs")).unwrap();
+        std::os::unix::fs::symlink("../rel", tmp_dir.path().join("a/b/rel")).unwrap();
+
+        for confine in [false, true] {
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                confine_symlinks: confine,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let mut parent = ROOT_ID;
+            for name in ["a", "b"] {
+                parent = fs
+                    .lookup(Request::default(), parent, OsStr::new(name))
+                    .await
+                    .unwrap()
+                    .attr
+                    .ino;
+            }
+            let readlink = async |name: &str| {
+                let ino = fs
+                    .lookup(Request::default(), parent, OsStr::new(name))
+                    .await
+                    .unwrap()
+                    .attr
+                    .ino;
+                fs.readlink(Request::default(), ino).await.unwrap().data
+            };
+
+            let expected: &[u8] = if confine {
+                b"../../etc/passwd"
+            } else {
+                b"/etc/passwd"
+            };
+            assert_eq!(&readlink("abs").await[..], expected);
+            assert_eq!(&readlink("rel").await[..], b"../rel");
+        }
+        // The host link is left alone.
+        assert_eq!(
+            std::fs::read_link(tmp_dir.path().join("a/b/abs")).unwrap(),
+            std::path::Path::new("/etc/passwd")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_mkdir_over_existing() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"data").unwrap();
+
+        let errno = |r: rfuse3::Errno| std::io::Error::from(r).raw_os_error();
+        for flags in [libc::O_RDONLY, libc::O_WRONLY, libc::O_RDWR | libc::O_TRUNC] {
+            let err = fs
+                .create(
+                    Request::default(),
+                    ROOT_ID,
+                    OsStr::new("dir"),
+                    u32::from(libc::S_IFREG) | 0o644,
+                    flags as u32,
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(errno(err), Some(libc::EISDIR), "flags={flags:#o}");
+        }
+        let err = fs
+            .create(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("dir"),
+                u32::from(libc::S_IFREG) | 0o644,
+                (libc::O_RDWR | libc::O_EXCL) as u32,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(errno(err), Some(libc::EEXIST));
+        assert!(tmp_dir.path().join("dir").is_dir());
+
+        let err = fs
+            .mkdir(Request::default(), ROOT_ID, OsStr::new("file"), 0o755, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(errno(err), Some(libc::EEXIST));
+        assert_eq!(std::fs::read(tmp_dir.path().join("file")).unwrap(), b"data");
+    }
+
+    #[tokio::test]
+    async fn test_symlinked_root_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let real = tmp_dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::fs::write(real.join("file"), b"data").unwrap();
+        std::os::unix::fs::symlink("file", real.join("link")).unwrap();
+        let root_link = tmp_dir.path().join("root");
+        std::os::unix::fs::symlink(&real, &root_link).unwrap();
+
+        let args = PassthroughArgs {
+            root_dir: root_link,
+            mapping: None::<&str>,
+        };
+        let fs = unwrap_or_skip_eperm!(new_passthroughfs_layer(args).await, "import");
+
+        let attr = fs
+            .getattr(Request::default(), ROOT_ID, None, 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.kind, rfuse3::FileType::Directory);
+
+        let file = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        assert_eq!(file.attr.kind, rfuse3::FileType::RegularFile);
+        // Symlinks below the root are still reported as such.
+        let link = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("link"))
+            .await
+            .unwrap();
+        assert_eq!(link.attr.kind, rfuse3::FileType::Symlink);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path().join("tmp");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o1777)).unwrap();
+        for (name, uid) in [("theirs", 1000), ("mine", 2000), ("other", 1000)] {
+            std::fs::write(dir.join(name), b"").unwrap();
+            if let Err(e) = std::os::unix::fs::chown(dir.join(name), Some(uid), Some(uid)) {
+                eprintln!("skip test_sticky_dir: {e}");
+                return;
+            }
+        }
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            enforce_sticky_bit: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let dir_ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("tmp"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let req = Request {
+            uid: 2000,
+            gid: 2000,
+            ..Request::default()
+        };
+
+        // Entries owned by someone else can be neither removed nor renamed over.
+        let err: std::io::Error = fs
+            .unlink(req, dir_ino, OsStr::new("theirs"))
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err: std::io::Error = fs
+            .rename(req, dir_ino, OsStr::new("theirs"), dir_ino, OsStr::new("x"))
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err: std::io::Error = fs
+            .rename(
+                req,
+                dir_ino,
+                OsStr::new("mine"),
+                dir_ino,
+                OsStr::new("theirs"),
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert!(dir.join("theirs").exists());
+
+        // The owner of an entry and root may remove it.
+        fs.rename(
+            req,
+            dir_ino,
+            OsStr::new("mine"),
+            dir_ino,
+            OsStr::new("renamed"),
+        )
+        .await
+        .unwrap();
+        fs.unlink(req, dir_ino, OsStr::new("renamed"))
+            .await
+            .unwrap();
+        fs.unlink(Request::default(), dir_ino, OsStr::new("theirs"))
+            .await
+            .unwrap();
+
+        // Without enforcement the host decides, which lets everything through for root.
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        let dir_ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("tmp"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        fs.unlink(req, dir_ino, OsStr::new("other")).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_getattr_direct_io_blksize() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, vec![0u8; 8192]).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let fh = match fs
+            .open(
+                Request::default(),
+                ino,
+                (libc::O_RDONLY | libc::O_DIRECT) as u32,
+            )
+            .await
+        {
+            Ok(reply) => reply.fh,
+            Err(e) => {
+                eprintln!("skip test_getattr_direct_io_blksize: {e:?}");
+                return;
+            }
+        };
+
+        let host_blksize = std::fs::metadata(&path).unwrap().blksize() as u32;
+        let attr = fs
+            .getattr(Request::default(), ino, None, 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.blksize, host_blksize);
+
+        let attr = fs
+            .getattr(Request::default(), ino, Some(fh), 1)
+            .await
+            .unwrap()
+            .attr;
+        match super::statx::dio_offset_align(&std::fs::File::open(&path).unwrap()) {
+            Some(align) => {
+                assert!(attr.blksize >= align);
+                assert_eq!(attr.blksize % align, 0);
+                assert!(attr.blksize >= host_blksize);
+            }
+            None => assert_eq!(attr.blksize, host_blksize),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unlink_open_file() {
+        for defer_unlink in [false, true] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            std::fs::write(tmp_dir.path().join("file"), b"still here").unwrap();
+            std::fs::write(tmp_dir.path().join("closed"), b"").unwrap();
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                defer_unlink,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let fh = fs
+                .open(Request::default(), ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            fs.unlink(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap();
+            fs.unlink(Request::default(), ROOT_ID, OsStr::new("closed"))
+                .await
+                .unwrap();
+            let host_entries = || -> Vec<String> {
+                std::fs::read_dir(tmp_dir.path())
+                    .unwrap()
+                    .map(|e| e.unwrap().file_name().into_string().unwrap())
+                    .collect()
+            };
+            let entries = host_entries();
+            if defer_unlink {
+                assert_eq!(entries.len(), 1, "{entries:?}");
+                assert!(entries[0].starts_with(".fuse_hidden"), "{entries:?}");
+            } else {
+                assert!(entries.is_empty(), "{entries:?}");
+            }
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap_err();
+
+            let data = fs
+                .read(Request::default(), ino, fh, 0, 64)
+                .await
+                .unwrap()
+                .data;
+            assert_eq!(&data[..], b"still here");
+            fs.release(Request::default(), ino, fh, 0, 0, false)
+                .await
+                .unwrap();
+            assert!(host_entries().is_empty());
+
+            // A rename over an open file is deferred the same way.
+            std::fs::write(tmp_dir.path().join("target"), b"old target").unwrap();
+            std::fs::write(tmp_dir.path().join("new"), b"new target").unwrap();
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("target"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let fh = fs
+                .open(Request::default(), ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            fs.rename(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("new"),
+                ROOT_ID,
+                OsStr::new("target"),
+            )
+            .await
+            .unwrap();
+            let mut entries = host_entries();
+            entries.sort();
+            if defer_unlink {
+                assert_eq!(entries.len(), 2, "{entries:?}");
+                assert!(entries[0].starts_with(".fuse_hidden"), "{entries:?}");
+            } else {
+                assert_eq!(entries, ["target"]);
+            }
+            assert_eq!(
+                std::fs::read(tmp_dir.path().join("target")).unwrap(),
+                b"new target"
+            );
+            let data = fs
+                .read(Request::default(), ino, fh, 0, 64)
+                .await
+                .unwrap()
+                .data;
+            assert_eq!(&data[..], b"old target");
+            fs.release(Request::default(), ino, fh, 0, 0, false)
+                .await
+                .unwrap();
+            assert_eq!(host_entries(), ["target"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_cache_on_change() {
+        use crate::util::open_options::OpenOptions;
+
+        for drop_cache_on_change in [false, true] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let path = tmp_dir.path().join("file");
+            std::fs::write(&path, b"v1").unwrap();
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                cache_policy: super::CachePolicy::Always,
+                drop_cache_on_change,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+
+            let open_keeps_cache = || async {
+                let reply = fs
+                    .open(Request::default(), ino, libc::O_RDWR as u32)
+                    .await
+                    .unwrap();
+                (reply.fh, reply.flags & OpenOptions::KEEP_CACHE.bits() != 0)
+            };
+            let release = |fh| fs.release(Request::default(), ino, fh, 0, 0, false);
+
+            let (fh, keep) = open_keeps_cache().await;
+            assert!(keep);
+            // Writes through the file system leave the cache valid.
+            fs.write(Request::default(), ino, fh, 0, b"v2, longer", 0, 0)
+                .await
+                .unwrap();
+            release(fh).await.unwrap();
+            let (fh, keep) = open_keeps_cache().await;
+            assert!(keep);
+            release(fh).await.unwrap();
+
+            // A change behind the client's back drops it, once.
+            std::fs::write(&path, b"v3, out of band").unwrap();
+            let (fh, keep) = open_keeps_cache().await;
+            assert_eq!(keep, !drop_cache_on_change);
+            release(fh).await.unwrap();
+            let (fh, keep) = open_keeps_cache().await;
+            assert!(keep);
+
+            // So does one made while the client has the file open.
+            std::fs::write(&path, b"v4").unwrap();
+            release(fh).await.unwrap();
+            let (fh, keep) = open_keeps_cache().await;
+            assert_eq!(keep, !drop_cache_on_change);
+            release(fh).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_release_one_of_two_handles() {
+        use futures_util::StreamExt;
+
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::write(tmp_dir.path().join("file"), b"hello").unwrap();
+        let ino = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await,
+            "lookup file"
+        )
+        .attr
+        .ino;
+
+        // Each open has a descriptor of its own, closed by its release.
+        let open = || fs.open(Request::default(), ino, libc::O_RDWR as u32);
+        let first = open().await.unwrap().fh;
+        let second = open().await.unwrap().fh;
+        assert_ne!(first, second);
+        fs.flush(Request::default(), ino, first, 0).await.unwrap();
+        fs.release(Request::default(), ino, first, 0, 0, true)
+            .await
+            .unwrap();
+        let err: std::io::Error = fs
+            .read(Request::default(), ino, first, 0, 5)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        // Flushing doesn't close the descriptor either.
+        fs.flush(Request::default(), ino, second, 0).await.unwrap();
+        fs.write(Request::default(), ino, second, 5, b", world", 0, 0)
+            .await
+            .unwrap();
+        let data = fs
+            .read(Request::default(), ino, second, 0, 64)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(&data[..], b"hello, world");
+        fs.release(Request::default(), ino, second, 0, 0, true)
+            .await
+            .unwrap();
+
+        let opendir = || fs.opendir(Request::default(), ROOT_ID, 0);
+        let first = opendir().await.unwrap().fh;
+        let second = opendir().await.unwrap().fh;
+        fs.releasedir(Request::default(), ROOT_ID, first, 0)
+            .await
+            .unwrap();
+        let entries = fs
+            .readdir(Request::default(), ROOT_ID, second, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap().name)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(entries.iter().any(|name| name == "file"));
+        fs.releasedir(Request::default(), ROOT_ID, second, 0)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_links() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            max_links: Some(3),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        for (i, name) in ["link1", "link2"].into_iter().enumerate() {
+            let entry = fs
+                .link(Request::default(), ino, ROOT_ID, OsStr::new(name))
+                .await
+                .unwrap();
+            assert_eq!(entry.attr.nlink, i as u32 + 2);
+        }
+        let err: std::io::Error = fs
+            .link(Request::default(), ino, ROOT_ID, OsStr::new("link3"))
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EMLINK));
+        assert!(!tmp_dir.path().join("link3").exists());
+
+        // Dropping a link makes room for another one.
+        fs.unlink(Request::default(), ROOT_ID, OsStr::new("link1"))
+            .await
+            .unwrap();
+        fs.link(Request::default(), ino, ROOT_ID, OsStr::new("link3"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_control_xattr() {
+        use rfuse3::raw::reply::ReplyXAttr;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use tracing::level_filters::LevelFilter;
+        use tracing_subscriber::{Registry, reload};
+
+        let (_filter, handle) = reload::Layer::<LevelFilter, Registry>::new(LevelFilter::INFO);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            xattr: true,
+            readonly: true,
+            control_xattr: true,
+            log_level_hook: Some(super::LogLevelHook::reload(handle.clone())),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let getxattr = |name: &'static str| {
+            let fs = &fs;
+            async move {
+                let name = OsStr::new(name);
+                let ReplyXAttr::Size(size) = fs
+                    .getxattr(Request::default(), ROOT_ID, name, 0)
+                    .await
+                    .unwrap()
+                else {
+                    panic!("expected a size");
+                };
+                match fs.getxattr(Request::default(), ROOT_ID, name, size).await {
+                    Ok(ReplyXAttr::Data(data)) => String::from_utf8(data.to_vec()).unwrap(),
+                    _ => panic!("expected data"),
+                }
+            }
+        };
+        let features = getxattr("user.fuse.passthrough.features").await;
+        assert!(features.lines().any(|f| f == "readonly"), "{features}");
+        assert!(features.lines().any(|f| f == "xattr"), "{features}");
+        assert!(!features.lines().any(|f| f == "mmap"), "{features}");
+        assert_eq!(
+            getxattr("user.fuse.passthrough.version").await,
+            env!("CARGO_PKG_VERSION")
+        );
+        let stats = getxattr("user.fuse.passthrough.stats").await;
+        assert!(stats.starts_with("inodes "));
+        assert!(
+            stats.ends_with("\nvirtual_inodes 0\nvirtual_inodes_max 140737488355327\n"),
+            "{stats}"
+        );
+
+        // Setting a control xattr works on a read-only export and never reaches the host.
+        fs.setxattr(
+            Request::default(),
+            ROOT_ID,
+            OsStr::new("user.fuse.passthrough.log_level"),
+            b"trace",
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs.log_level(), LevelFilter::TRACE);
+        assert_eq!(handle.clone_current(), Some(LevelFilter::TRACE));
+        assert_eq!(getxattr("user.fuse.passthrough.log_level").await, "trace");
+        let path = CString::new(tmp_dir.path().as_os_str().as_bytes()).unwrap();
+        let name = CString::new("user.fuse.passthrough.log_level").unwrap();
+        // Safe because this doesn't modify any memory.
+        let res = match () {
+            #[cfg(target_os = "linux")]
+            () => unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) },
+            #[cfg(target_os = "macos")]
+            () => unsafe {
+                libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0, 0, 0)
+            },
+        };
+        assert!(res < 0);
+        let err: std::io::Error = fs
+            .setxattr(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("user.fuse.passthrough.log_level"),
+                b"loud",
+                0,
+                0,
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(fs.log_level(), LevelFilter::TRACE);
+
+        // Only root and the user running the file system may write control xattrs.
+        let user = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: 0,
+        };
+        let err: std::io::Error = fs
+            .setxattr(
+                user,
+                ROOT_ID,
+                OsStr::new("user.fuse.passthrough.log_level"),
+                b"off",
+                0,
+                0,
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert_eq!(handle.clone_current(), Some(LevelFilter::TRACE));
+
+        fs.setxattr(
+            Request::default(),
+            ROOT_ID,
+            OsStr::new("user.fuse.passthrough.flush_cache"),
+            b"",
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let err: std::io::Error = fs
+            .getxattr(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("user.fuse.passthrough.nope"),
+                0,
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_host_fs_type() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&root).unwrap();
+        let root_c = std::ffi::CString::new(root.as_os_str().as_bytes()).unwrap();
+        let fstype = std::ffi::CString::new("tmpfs").unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let tmpfs = unsafe {
+            libc::mount(
+                fstype.as_ptr(),
+                root_c.as_ptr(),
+                fstype.as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        } == 0;
+
+        let cfg = super::Config {
+            root_dir: root.clone(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let fs_type = fs.host_fs_type(ROOT_ID).await;
+
+        let mut st = std::mem::MaybeUninit::<libc::statfs>::zeroed();
+        // Safe because this only writes to `st` and we check the return value.
+        assert_eq!(unsafe { libc::statfs(root_c.as_ptr(), st.as_mut_ptr()) }, 0);
+        let host_type = unsafe { st.assume_init() }.f_type;
+        drop(fs);
+        if tmpfs {
+            // Safe because this doesn't modify any memory.
+            unsafe { libc::umount2(root_c.as_ptr(), libc::MNT_DETACH) };
+            assert_eq!(host_type, libc::TMPFS_MAGIC);
+        }
+        assert_eq!(fs_type, host_type);
+        assert_ne!(fs_type, super::async_io::FUSE_SUPER_MAGIC);
+    }
+
+    #[tokio::test]
+    async fn test_max_open_dirs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir(tmp_dir.path().join(name)).unwrap();
+        }
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            max_open_dirs: Some(2),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let mut inodes = Vec::new();
+        for name in ["a", "b", "c"] {
+            let entry = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new(name))
+                .await
+                .unwrap();
+            inodes.push(entry.attr.ino);
+        }
+        let a = fs.opendir(Request::default(), inodes[0], 0).await.unwrap();
+        fs.opendir(Request::default(), inodes[1], 0).await.unwrap();
+        let err: std::io::Error = fs
+            .opendir(Request::default(), inodes[2], 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+
+        fs.releasedir(Request::default(), inodes[0], a.fh, 0)
+            .await
+            .unwrap();
+        // A failed open gives its slot back.
+        let err: std::io::Error = fs
+            .opendir(Request::default(), inodes[2] + 1000, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_ne!(err.raw_os_error(), Some(libc::EMFILE));
+        fs.opendir(Request::default(), inodes[2], 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forget_root() {
+        use std::os::fd::AsRawFd;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let root = fs.inode_map.get(ROOT_ID).await.unwrap();
+        // Roots opened by file handle hold no fd, only the `Arc` check below applies to them.
+        let fd = match &root.handle {
+            super::InodeHandle::File(f) => Some(f.as_raw_fd()),
+            super::InodeHandle::Handle(_) => None,
+        };
+
+        // More forgets than lookups, through both paths, as a confused kernel might send.
+        fs.forget(Request::default(), ROOT_ID, u64::MAX).await;
+        fs.batch_forget(Request::default(), &[(ROOT_ID, u64::MAX), (ROOT_ID, 1)])
+            .await;
+        let data = fs.inode_map.get(ROOT_ID).await.unwrap();
+        assert!(std::sync::Arc::ptr_eq(&root, &data));
+        drop((root, data));
+
+        // The root's fd is still open and usable for lookups.
+        if let Some(fd) = fd {
+            // Safe because this doesn't modify any memory and we check the return value.
+            assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        }
+        fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        fs.getattr(Request::default(), ROOT_ID, None, 0)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_splice() {
+        use rfuse3::raw::reply::ReplyRead;
+
+        // Bytes this thread read through read(2) and friends, splice(2) doesn't count.
+        fn thread_rchar() -> Option<u64> {
+            std::fs::read_to_string("/proc/thread-self/io")
+                .ok()?
+                .lines()
+                .find_map(|line| line.strip_prefix("rchar: "))?
+                .parse()
+                .ok()
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source_dir = tmp_dir.path().join("src");
+        let mount_dir = tmp_dir.path().join("mnt");
+        std::fs::create_dir(&source_dir).unwrap();
+        std::fs::create_dir(&mount_dir).unwrap();
+        let content: Vec<u8> = (0..4 * 1024 * 1024 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(source_dir.join("file"), &content).unwrap();
+
+        let cfg = super::Config {
+            root_dir: source_dir.clone(),
+            use_splice: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let fh = fs
+            .open(Request::default(), ino, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        match fs
+            .read_splice(Request::default(), ino, fh, 4096, 8192)
+            .await
+            .unwrap()
+        {
+            ReplyRead::Splice { offset, len, .. } => assert_eq!((offset, len), (4096, 8192)),
+            reply => panic!("expected a splice reply, got {reply:?}"),
+        }
+        fs.release(Request::default(), ino, fh, 0, 0, false)
+            .await
+            .unwrap();
+
+        // Served on this thread, so its read counter shows what the daemon copied.
+        for use_splice in [false, true] {
+            let cfg = super::Config {
+                root_dir: source_dir.clone(),
+                use_splice,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().await.unwrap();
+            let mut mount_options = MountOptions::default();
+            mount_options
+                .uid(unsafe { libc::getuid() })
+                .gid(unsafe { libc::getgid() });
+            let handle = unwrap_or_skip_eperm!(
+                Session::new(mount_options)
+                    .mount(fs, mount_dir.clone())
+                    .await,
+                "mount passthrough fs"
+            );
+
+            let before = thread_rchar();
+            let path = mount_dir.join("file");
+            let data = tokio::task::spawn_blocking(move || std::fs::read(path))
+                .await
+                .unwrap()
+                .unwrap();
+            let copied = thread_rchar()
+                .zip(before)
+                .map(|(after, before)| after - before);
+            handle.unmount().await.unwrap();
+
+            assert!(data == content, "use_splice={use_splice}");
+            if let Some(copied) = copied {
+                if use_splice {
+                    assert!(copied < content.len() as u64 / 2, "copied {copied} bytes");
+                } else {
+                    assert!(copied >= content.len() as u64, "copied {copied} bytes");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_ops_per_sec() {
+        use std::time::Duration;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            metadata_ops_per_sec: Some(100),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        // The first second's worth goes through as a burst, the rest at the configured rate.
+        let start = std::time::Instant::now();
+        let lookups = futures::future::join_all(
+            (0..150).map(|_| fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))),
+        )
+        .await;
+        let elapsed = start.elapsed();
+        assert!(lookups.iter().all(|entry| entry.is_ok()));
+        assert!(elapsed >= Duration::from_millis(450), "took {elapsed:?}");
+
+        // Once drained, a second burst is throttled from the start.
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            fs.getattr(Request::default(), ROOT_ID, None, 0)
+                .await
+                .unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_generation_consistent() {
+        use futures_util::StreamExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let file = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        let dir = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap();
+        assert_ne!(file.generation, 0);
+        assert_ne!(dir.generation, 0);
+
+        let fh = fs
+            .opendir(Request::default(), ROOT_ID, libc::O_RDONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        let entries = fs
+            .readdirplus(Request::default(), ROOT_ID, fh, 0, 0)
+            .await
+            .unwrap()
+            .entries
+            .map(|e| e.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        for (name, looked_up) in [("file", &file), ("dir", &dir)] {
+            let entry = entries.iter().find(|e| e.name == name).unwrap();
+            assert_eq!(
+                (entry.inode, entry.generation),
+                (looked_up.attr.ino, looked_up.generation)
+            );
+            assert_eq!(entry.attr.ino, entry.inode);
+        }
+
+        // A file replacing a forgotten one gets a new generation.
+        fs.forget(Request::default(), file.attr.ino, 3).await;
+        std::fs::remove_file(tmp_dir.path().join("file")).unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let replaced = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        assert_ne!(replaced.generation, file.generation);
+    }
+
+    #[tokio::test]
+    async fn test_inode_identity() {
+        use super::{FileHandle, InodeHandle, InodeIdentity};
+        use rfuse3::Errno;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let lookup_with = async |chain: Vec<InodeIdentity>| {
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                inode_identity: chain,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().await?;
+            let entry = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap();
+            let data = fs.inode_map.get(entry.attr.ino).await.unwrap();
+            Ok::<_, Errno>((matches!(data.handle, InodeHandle::Handle(_)), data.id.mnt))
+        };
+
+        // Without file handles, the mount id is used if known.
+        let (is_handle, mnt) =
+            unwrap_or_skip_eperm!(lookup_with(vec![InodeIdentity::MountId]).await, "import");
+        assert!(!is_handle);
+        let has_mnt_id = mnt != 0;
+
+        // Without mount ids either, only the device and inode number are left.
+        let (is_handle, mnt) = lookup_with(vec![]).await.unwrap();
+        assert!(!is_handle);
+        assert_eq!(mnt, 0);
+
+        // The full chain only uses file handles where the host filesystem has them.
+        let has_handles = FileHandle::from_fd(&std::fs::File::open(tmp_dir.path()).unwrap())
+            .unwrap()
+            .is_some();
+        let (is_handle, mnt) = lookup_with(super::Config::default().inode_identity)
+            .await
+            .unwrap();
+        assert_eq!(is_handle, has_handles);
+        assert_eq!(mnt != 0, has_handles || has_mnt_id);
+    }
+
+    #[tokio::test]
+    async fn test_statfs_cache() {
+        use std::sync::atomic::Ordering;
+        use std::time::Duration;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            statfs_cache: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let dir = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("dir"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        // Rapid and concurrent calls on the same host filesystem share one host call.
+        let first = fs.statfs(Request::default(), ROOT_ID).await.unwrap();
+        let replies = futures::future::join_all(
+            (0..50).map(|i| fs.statfs(Request::default(), if i % 2 == 0 { ROOT_ID } else { dir })),
+        )
+        .await;
+        assert!(replies.into_iter().all(|reply| reply.unwrap() == first));
+        assert_eq!(fs.statfs_calls.load(Ordering::Relaxed), 1);
+
+        // Once the entry expires, the host is asked again.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        for _ in 0..10 {
+            fs.statfs(Request::default(), dir).await.unwrap();
+        }
+        assert_eq!(fs.statfs_calls.load(Ordering::Relaxed), 2);
+
+        // Without the cache, every call goes to the host.
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().await.unwrap();
+        for _ in 0..10 {
+            fs.statfs(Request::default(), ROOT_ID).await.unwrap();
+        }
+        assert_eq!(fs.statfs_calls.load(Ordering::Relaxed), 10);
+    }
+
+    #[tokio::test]
+    async fn test_unlinked_open_file() {
+        use rfuse3::{SetAttr, Timestamp};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mirror_dir = tempfile::tempdir().unwrap();
+        // A strict mirror fails requests whose change can't be mirrored, which mustn't include
+        // changes to unlinked files.
+        for mirror in [false, true] {
+            std::fs::write(tmp_dir.path().join("file"), b"hello").unwrap();
+            std::fs::write(mirror_dir.path().join("file"), b"hello").unwrap();
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                mirror_dir: mirror.then(|| mirror_dir.path().to_path_buf()),
+                mirror_strict: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let fh = fs
+                .open(Request::default(), ino, libc::O_RDWR as u32)
+                .await
+                .unwrap()
+                .fh;
+            fs.unlink(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap();
+            assert!(!tmp_dir.path().join("file").exists());
+
+            // Everything given the handle keeps working on the open file.
+            let written = fs
+                .write(Request::default(), ino, fh, 5, b" world", 0, 0)
+                .await
+                .unwrap()
+                .written;
+            assert_eq!(written, 6);
+            let data = fs.read(Request::default(), ino, fh, 0, 64).await.unwrap();
+            assert_eq!(&data.data[..], b"hello world");
+            fs.fsync(Request::default(), ino, fh, false).await.unwrap();
+            fs.fsync(Request::default(), ino, fh, true).await.unwrap();
+            fs.flush(Request::default(), ino, fh, 0).await.unwrap();
+            fs.fallocate(Request::default(), ino, fh, 0, 4096, 0)
+                .await
+                .unwrap();
+            let seek = fs
+                .lseek(Request::default(), ino, fh, 0, libc::SEEK_END as u32)
+                .await
+                .unwrap();
+            assert_eq!(seek.offset, 4096);
+
+            let attr = fs
+                .getattr(Request::default(), ino, Some(fh), 0)
+                .await
+                .unwrap();
+            assert_eq!(attr.attr.size, 4096);
+            assert_eq!(attr.attr.nlink, 0);
+            let set_attr = SetAttr {
+                size: Some(5),
+                mode: Some(0o600),
+                mtime: Some(Timestamp::new(1_000_000, 0)),
+                ..Default::default()
+            };
+            let attr = fs
+                .setattr(Request::default(), ino, Some(fh), set_attr)
+                .await
+                .unwrap()
+                .attr;
+            assert_eq!(attr.size, 5);
+            assert_eq!(attr.perm & 0o777, 0o600);
+            assert_eq!(attr.mtime.sec, 1_000_000);
+            let data = fs.read(Request::default(), ino, fh, 0, 64).await.unwrap();
+            assert_eq!(&data.data[..], b"hello");
+
+            fs.release(Request::default(), ino, fh, 0, 0, true)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writeback_flushed_times() {
+        use rfuse3::{SetAttr, Timestamp};
+        use std::os::unix::fs::PermissionsExt;
+        use std::sync::atomic::Ordering;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            writeback: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        fs.writeback.store(true, Ordering::Relaxed);
+
+        let user = Request {
+            unique: 0,
+            uid: 1000,
+            gid: 1000,
+            pid: std::process::id(),
+        };
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let fh = fs.open(user, ino, libc::O_WRONLY as u32).await.unwrap().fh;
+        let now = Timestamp::from(std::time::SystemTime::now());
+        let set_times = || SetAttr {
+            mtime: Some(now),
+            ctime: Some(now),
+            ..Default::default()
+        };
+
+        // A flush of the mtime of cached writes, sent with the written handle, only needs write
+        // permission ...
+        fs.setattr(user, ino, Some(fh), set_times()).await.unwrap();
+
+        // ... but an explicit time is still reserved to the owner.
+        let err: std::io::Error = fs
+            .setattr(user, ino, None, set_times())
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        fs.release(user, ino, fh, 0, 0, false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_utimensat_owner_only() {
+        use rfuse3::Timestamp;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+        let owner = Request {
+            unique: 0,
+            uid: std::fs::metadata(&path).unwrap().uid(),
+            gid: 0,
+            pid: std::process::id(),
+        };
+        let other = Request {
+            uid: owner.uid + 1,
+            ..owner
+        };
+        let time = Timestamp::new(1_000_000_000, 123_456_789);
+
+        // Write permission isn't enough to set explicit times.
+        let err = fs.do_utimensat(other, ino, time, time).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        fs.do_utimensat(owner, ino, time, time).await.unwrap();
+        let md = std::fs::metadata(&path).unwrap();
+        assert_eq!((md.mtime(), md.mtime_nsec()), (1_000_000_000, 123_456_789));
+    }
+
+    #[tokio::test]
+    async fn test_inode_layout() {
+        use super::util::Layout;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for (layout, max_host_ino) in [
+            (Layout::Narrow, (1 << 47) - 1),
+            (Layout::Wide, (1 << 43) - 1),
+        ] {
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                inode_layout: layout,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            assert_eq!(fs.ino_allocator.max_host_ino(), max_host_ino);
+            assert_eq!(fs.ino_allocator.virtual_inode_usage().1, max_host_ino);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_blocked_syscalls() {
+        use super::InodeIdentity;
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"data").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            defer_unlink: true,
+            ..Default::default()
+        };
+        super::syscalls::SIMULATE_BLOCKED.set(&["statx", "renameat2"]);
+        let fs = PassthroughFs::<()>::new(cfg);
+        super::syscalls::SIMULATE_BLOCKED.set(&[]);
+        let fs = fs.unwrap();
+        assert!(!fs.syscalls.statx);
+        assert!(!fs.syscalls.renameat2);
+        assert!(fs.syscalls.copy_file_range);
+        // The features depending on them are turned off.
+        assert!(!fs.cfg.defer_unlink);
+        assert!(!fs.cfg.inode_identity.contains(&InodeIdentity::FileHandle));
+        // Handles are given up on if they can be made but not opened, or the other way round.
+        for blocked in [&["name_to_handle_at"], &["open_by_handle_at"]] {
+            super::syscalls::SIMULATE_BLOCKED.set(blocked);
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg);
+            super::syscalls::SIMULATE_BLOCKED.set(&[]);
+            let fs = fs.unwrap();
+            assert!(fs.syscalls.statx);
+            assert!(!fs.cfg.inode_identity.contains(&InodeIdentity::FileHandle));
+        }
+
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let entry = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+            .await
+            .unwrap();
+        let host = std::fs::metadata(tmp_dir.path().join("file")).unwrap();
+        assert_eq!(entry.attr.size, 4);
+        assert_eq!(entry.attr.mtime.sec, host.mtime());
+        let attr = fs
+            .getattr(Request::default(), entry.attr.ino, None, 0)
+            .await
+            .unwrap();
+        assert_eq!(attr.attr.size, 4);
+
+        // Plain renames fall back to renameat(), those with flags are refused.
+        fs.rename(
+            Request::default(),
+            ROOT_ID,
+            OsStr::new("file"),
+            ROOT_ID,
+            OsStr::new("renamed"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(tmp_dir.path().join("renamed")).unwrap(),
+            b"data"
+        );
+        let err: std::io::Error = fs
+            .rename2(
+                Request::default(),
+                ROOT_ID,
+                OsStr::new("renamed"),
+                ROOT_ID,
+                OsStr::new("file"),
+                libc::RENAME_NOREPLACE,
+            )
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        let renamed = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("renamed"))
+            .await
+            .unwrap();
+        assert_eq!(renamed.attr.ino, entry.attr.ino);
+    }
+
+    #[tokio::test]
+    async fn test_append_with_writeback() {
+        use crate::util::open_options::OpenOptions;
+        use rfuse3::raw::flags::FUSE_WRITE_CACHE;
+        use std::sync::atomic::Ordering;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("log"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            writeback: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.init(Request::default()).await, "init");
+        assert!(fs.writeback.load(Ordering::Relaxed));
+        let ino = fs
+            .lookup(Request::default(), ROOT_ID, OsStr::new("log"))
+            .await
+            .unwrap()
+            .attr
+            .ino;
+
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let mut fhs = Vec::new();
+        for _ in 0..2 {
+            let reply = fs.open(Request::default(), ino, flags).await.unwrap();
+            assert_ne!(reply.flags & OpenOptions::DIRECT_IO.bits(), 0);
+            fhs.push(reply.fh);
+        }
+        // Both writers pass the same stale offset, as a kernel caching the empty file would.
+        let writer = |fh: u64, tag: u8| {
+            let fs = &fs;
+            async move {
+                for _ in 0..100 {
+                    let written = fs
+                        .write(Request::default(), ino, fh, 0, &[tag; 64], 0, flags)
+                        .await
+                        .unwrap()
+                        .written;
+                    assert_eq!(written, 64);
+                }
+            }
+        };
+        futures::join!(writer(fhs[0], b'a'), writer(fhs[1], b'b'));
+
+        let data = std::fs::read(tmp_dir.path().join("log")).unwrap();
+        assert_eq!(data.len(), 2 * 100 * 64);
+        for record in data.chunks(64) {
+            assert!(record.iter().all(|b| *b == record[0]));
+        }
+        assert_eq!(data.iter().filter(|b| **b == b'a').count(), 100 * 64);
+
+        // Cached pages written back through an append handle still go to their offset.
+        fs.write(
+            Request::default(),
+            ino,
+            fhs[0],
+            0,
+            b"c",
+            FUSE_WRITE_CACHE,
+            flags,
+        )
+        .await
+        .unwrap();
+        let data = std::fs::read(tmp_dir.path().join("log")).unwrap();
+        assert_eq!(data.len(), 2 * 100 * 64);
+        assert_eq!(data[0], b'c');
+
+        // Several cached pages are written from one buffer per page, all of them in one go.
+        let pages: Vec<u8> = (0..3 * 4096 + 50).map(|i| i as u8).collect();
+        let written = fs
+            .write(
+                Request::default(),
+                ino,
+                fhs[0],
+                100,
+                &pages,
+                FUSE_WRITE_CACHE,
+                flags,
+            )
+            .await
+            .unwrap()
+            .written;
+        assert_eq!(written as usize, pages.len());
+        let data = std::fs::read(tmp_dir.path().join("log")).unwrap();
+        assert_eq!(&data[100..100 + pages.len()], &pages[..]);
+
+        for fh in fhs {
+            fs.release(Request::default(), ino, fh, flags, 0, false)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_health_probe() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            backend_health_probe: Some(super::HealthProbe {
+                interval: Duration::from_millis(10),
+                grace: Duration::ZERO,
+                errno: libc::EAGAIN,
+            }),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        // Stand in for the probe of the root, which a local store can't fail, before `import()`
+        // starts the real one. It fails while `down` is set and hangs while `hang` is.
+        let down = Arc::new(AtomicBool::new(false));
+        let hang = Arc::new(AtomicBool::new(false));
+        let probes = Arc::new(AtomicUsize::new(0));
+        fs.health.as_ref().unwrap().start({
+            let (down, hang, probes) = (down.clone(), hang.clone(), probes.clone());
+            move || {
+                probes.fetch_add(1, Ordering::Relaxed);
+                while hang.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                if down.load(Ordering::Relaxed) {
+                    Err(std::io::Error::from_raw_os_error(libc::EIO))
+                } else {
+                    Ok(())
+                }
+            }
+        });
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let lookup = || fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"));
+        let ino = lookup().await.unwrap().attr.ino;
+
+        // Wait for the probe to notice a change, polling `lookup` until it gives `errno`.
+        let wait_for = |errno: Option<i32>| async move {
+            for _ in 0..500 {
+                let res = lookup().await.map_err(std::io::Error::from);
+                if res.as_ref().err().and_then(|e| e.raw_os_error()) == errno {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("the backing store isn't reported as {errno:?}");
+        };
+
+        // The backing store goes away, as a disconnected network mount would.
+        down.store(true, Ordering::Relaxed);
+        wait_for(Some(libc::EAGAIN)).await;
+        let err: std::io::Error = fs
+            .getattr(Request::default(), ino, None, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        // Forgets still go through, as they don't touch the backing store.
+        fs.forget(Request::default(), ino, 1).await;
+
+        // Requests are served again once it's back.
+        down.store(false, Ordering::Relaxed);
+        wait_for(None).await;
+        fs.getattr(Request::default(), ROOT_ID, None, 0)
+            .await
+            .unwrap();
+
+        // A hung probe is reported as such and not started again while it hangs.
+        hang.store(true, Ordering::Relaxed);
+        wait_for(Some(libc::EAGAIN)).await;
+        let started = probes.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(probes.load(Ordering::Relaxed), started);
+        hang.store(false, Ordering::Relaxed);
+        wait_for(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_backend_health_probe_grace() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::time::{Duration, Instant};
+
+        const GRACE: Duration = Duration::from_millis(300);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(tmp_dir.path().join("file"), b"").unwrap();
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            backend_health_probe: Some(super::HealthProbe {
+                interval: Duration::from_millis(10),
+                grace: GRACE,
+                errno: libc::EAGAIN,
+            }),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        let down = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicUsize::new(0));
+        fs.health.as_ref().unwrap().start({
+            let (down, failed) = (down.clone(), failed.clone());
+            move || {
+                if down.load(Ordering::Relaxed) {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    Err(std::io::Error::from_raw_os_error(libc::EIO))
+                } else {
+                    Ok(())
+                }
+            }
+        });
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let lookup = || fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"));
+
+        // A blip within the grace period doesn't fail requests.
+        let start = Instant::now();
+        down.store(true, Ordering::Relaxed);
+        while failed.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        down.store(false, Ordering::Relaxed);
+        lookup().await.unwrap();
+        assert!(start.elapsed() < GRACE);
+
+        // Failures lasting past it do, counted from the first one after a successful probe.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        down.store(true, Ordering::Relaxed);
+        loop {
+            let res = lookup().await.map_err(std::io::Error::from);
+            if let Err(e) = res {
+                assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(start.elapsed() >= GRACE);
+    }
+
+    #[tokio::test]
+    async fn test_confinement() {
+        use std::os::unix::fs::symlink;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root_dir = tmp_dir.path().join("export");
+        std::fs::create_dir_all(root_dir.join("dir/sub")).unwrap();
+        std::fs::create_dir(tmp_dir.path().join("outside")).unwrap();
+        std::fs::write(root_dir.join("file"), b"").unwrap();
+        symlink("/", root_dir.join("abs")).unwrap();
+        symlink("../..", root_dir.join("rel")).unwrap();
+        let cfg = super::Config {
+            root_dir: root_dir.clone(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let lookup = |parent, name: &'static str| {
+            let fs = &fs;
+            async move {
+                fs.lookup(Request::default(), parent, OsStr::new(name))
+                    .await
+                    .map_err(std::io::Error::from)
+            }
+        };
+        let root = lookup(ROOT_ID, ".").await.unwrap().attr.ino;
+
+        // `..` of the root is the root.
+        assert_eq!(lookup(ROOT_ID, "..").await.unwrap().attr.ino, root);
+
+        // Names with a slash never resolve.
+        for name in ["../file", "/etc", "dir/.."] {
+            let err = lookup(ROOT_ID, name).await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL), "{name}");
+        }
+
+        // Symlinks resolve to the links themselves, which have no entries.
+        for name in ["abs", "rel"] {
+            let link = lookup(ROOT_ID, name).await.unwrap();
+            assert_eq!(link.attr.kind, rfuse3::FileType::Symlink);
+            assert!(lookup(link.attr.ino, "etc").await.is_err());
+            assert!(lookup(link.attr.ino, "..").await.is_err());
+        }
+
+        // An inode the client forgot can't be used anymore.
+        let file = lookup(ROOT_ID, "file").await.unwrap().attr.ino;
+        fs.forget(Request::default(), file, 1).await;
+        let err: std::io::Error = fs
+            .getattr(Request::default(), file, None, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        // `..` of a directory moved out of the export doesn't follow it outside.
+        let dir = lookup(ROOT_ID, "dir").await.unwrap().attr.ino;
+        let sub = lookup(dir, "sub").await.unwrap().attr.ino;
+        assert_eq!(lookup(sub, "..").await.unwrap().attr.ino, dir);
+        assert_eq!(lookup(dir, "..").await.unwrap().attr.ino, root);
+        std::fs::rename(root_dir.join("dir"), tmp_dir.path().join("outside/dir")).unwrap();
+        for ino in [sub, dir] {
+            let err = lookup(ino, "..").await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_special_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root_dir = tmp_dir.path().to_path_buf();
+        nix::unistd::mkfifo(&root_dir.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(root_dir.join("socket")).unwrap();
+        // Creating a device needs CAP_MKNOD, go on without one.
+        let null = libc::makedev(1, 3);
+        let has_dev = nix::sys::stat::mknod(
+            &root_dir.join("null"),
+            nix::sys::stat::SFlag::S_IFCHR,
+            nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR,
+            null,
+        )
+        .is_ok();
+
+        for allow in [false, true] {
+            let cfg = super::Config {
+                root_dir: root_dir.clone(),
+                allow_special_file_io: allow,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let open = |ino, flags: i32| {
+                let fs = &fs;
+                async move {
+                    fs.open(Request::default(), ino, flags as u32)
+                        .await
+                        .map_err(std::io::Error::from)
+                }
+            };
+            let enxio = |res: std::io::Result<_>| {
+                res.map(|_| ()).unwrap_err().raw_os_error() == Some(libc::ENXIO)
+            };
+
+            // The types and device numbers are reported without opening anything.
+            let mut special = vec![
+                ("fifo", rfuse3::FileType::NamedPipe),
+                ("socket", rfuse3::FileType::Socket),
+            ];
+            if has_dev {
+                special.push(("null", rfuse3::FileType::CharDevice));
+            }
+            for (name, kind) in special {
+                let entry = fs
+                    .lookup(Request::default(), ROOT_ID, OsStr::new(name))
+                    .await
+                    .unwrap();
+                let attr = fs
+                    .getattr(Request::default(), entry.attr.ino, None, 0)
+                    .await
+                    .unwrap()
+                    .attr;
+                assert_eq!((entry.attr.kind, attr.kind), (kind, kind), "{name}");
+                let host = std::fs::symlink_metadata(root_dir.join(name)).unwrap();
+                assert_eq!(
+                    attr.rdev as u64,
+                    std::os::unix::fs::MetadataExt::rdev(&host)
+                );
+
+                // Sockets can't be opened at all, the others only where allowed.
+                if !allow || name == "socket" {
+                    assert!(enxio(open(entry.attr.ino, libc::O_RDONLY).await), "{name}");
+                    continue;
+                }
+                match name {
+                    "fifo" => {
+                        // With no reader, opening for writing fails instead of blocking.
+                        assert!(enxio(open(entry.attr.ino, libc::O_WRONLY).await));
+                        open(entry.attr.ino, libc::O_RDONLY).await.unwrap();
+                        open(entry.attr.ino, libc::O_WRONLY).await.unwrap();
+                    }
+                    _ => {
+                        let reply = open(entry.attr.ino, libc::O_RDONLY).await.unwrap();
+                        let written = fs
+                            .write(Request::default(), entry.attr.ino, reply.fh, 0, b"x", 0, 0)
+                            .await;
+                        assert!(written.is_err(), "opened read-only");
+                        let rw = open(entry.attr.ino, libc::O_RDWR).await.unwrap();
+                        let written = fs
+                            .write(Request::default(), entry.attr.ino, rw.fh, 0, b"x", 0, 0)
+                            .await
+                            .unwrap();
+                        assert_eq!(written.written, 1);
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_root() {
+        use futures_util::StreamExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (first, second) = (tmp_dir.path().join("first"), tmp_dir.path().join("second"));
+        for (root, files) in [
+            (&first, &["a", "shared", "dir/x", "dir/common"][..]),
+            (
+                &second,
+                &[
+                    "a/hidden",
+                    "shared",
+                    "b",
+                    "dir/y",
+                    "dir/common",
+                    "dir/sub/w",
+                    "only/z",
+                ][..],
+            ),
+        ] {
+            for file in files {
+                let path = root.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, root.to_str().unwrap()).unwrap();
+            }
+        }
+        let cfg = super::Config {
+            root_dir: first.clone(),
+            multi_root: Some(super::MultiRootConfig {
+                roots: vec![second.clone()],
+            }),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let lookup = |parent, name: &'static str| {
+            let fs = &fs;
+            async move {
+                fs.lookup(Request::default(), parent, OsStr::new(name))
+                    .await
+                    .map_err(std::io::Error::from)
+            }
+        };
+        let list = |ino, offset| {
+            let fs = &fs;
+            async move {
+                let fh = fs
+                    .opendir(Request::default(), ino, libc::O_RDONLY as u32)
+                    .await
+                    .unwrap()
+                    .fh;
+                let mut names = fs
+                    .readdir(Request::default(), ino, fh, offset)
+                    .await
+                    .unwrap()
+                    .entries
+                    .map(|e| e.unwrap().name.into_string().unwrap())
+                    .filter(|name| std::future::ready(name != "." && name != ".."))
+                    .collect::<Vec<_>>()
+                    .await;
+                let plus = fs
+                    .readdirplus(Request::default(), ino, fh, offset as u64, 0)
+                    .await
+                    .unwrap()
+                    .entries
+                    .map(|e| e.unwrap().name.into_string().unwrap())
+                    .collect::<Vec<_>>()
+                    .await;
+                assert_eq!(names, plus);
+                fs.releasedir(Request::default(), ino, fh, 0).await.unwrap();
+                names.sort();
+                names
+            }
+        };
+        // The files of the first root are the length of its path, those of the second longer.
+        let from_first = first.to_str().unwrap().len() as u64;
+
+        // The first root having a name wins, whatever the later ones have.
+        let shared = lookup(ROOT_ID, "shared").await.unwrap();
+        assert_eq!(shared.attr.size, from_first);
+        let a = lookup(ROOT_ID, "a").await.unwrap();
+        assert_eq!(a.attr.kind, rfuse3::FileType::RegularFile);
+        assert!(lookup(ROOT_ID, "b").await.unwrap().attr.size > from_first);
+        assert!(lookup(ROOT_ID, "missing").await.is_err());
+
+        // Directories are merged, each name listed once.
+        assert_eq!(list(ROOT_ID, 0).await, ["a", "b", "dir", "only", "shared"]);
+        let dir = lookup(ROOT_ID, "dir").await.unwrap().attr.ino;
+        assert_eq!(list(dir, 0).await, ["common", "sub", "x", "y"]);
+        assert_eq!(lookup(dir, "common").await.unwrap().attr.size, from_first);
+        assert!(lookup(dir, "y").await.unwrap().attr.size > from_first);
+        let only = lookup(ROOT_ID, "only").await.unwrap().attr.ino;
+        assert_eq!(list(only, 0).await, ["z"]);
+        assert_eq!(list(ROOT_ID, 3).await.len(), 2);
+        assert_eq!(lookup(dir, "..").await.unwrap().attr.ino, ROOT_ID);
+        // `..` leads back to the union directory, not to the later root's own directory.
+        assert_eq!(lookup(only, "..").await.unwrap().attr.ino, ROOT_ID);
+        let sub = lookup(dir, "sub").await.unwrap().attr.ino;
+        assert_eq!(lookup(sub, "..").await.unwrap().attr.ino, dir);
+        assert_eq!(list(sub, 0).await, ["w"]);
+
+        // Nothing can be changed.
+        let err: std::io::Error = fs
+            .mkdir(Request::default(), ROOT_ID, OsStr::new("new"), 0o755, 0)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_dedupe_range() {
+        use super::{DedupeDest, DedupeStatus};
+        use std::os::fd::AsRawFd;
+
+        /// Whether the first extent of `path` is shared with another file, see
+        /// Documentation/filesystems/fiemap.rst.
+        fn first_extent_shared(path: &std::path::Path) -> bool {
+            const FS_IOC_FIEMAP: libc::Ioctl = 0xC020_660Bu32 as libc::Ioctl;
+            const FIEMAP_FLAG_SYNC: u32 = 1;
+            const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+            // `struct fiemap` with room for one `struct fiemap_extent`, in u64 words.
+            let mut buf = [0u64; 4 + 7];
+            buf[1] = u64::MAX;
+            buf[2] = u64::from(FIEMAP_FLAG_SYNC);
+            buf[3] = 1 << 32;
+            let file = std::fs::File::open(path).unwrap();
+            // Safe because the kernel only writes the one extent `buf` has room for.
+            let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, buf.as_mut_ptr()) };
+            assert_eq!(res, 0, "{}", std::io::Error::last_os_error());
+            assert_eq!(buf[2] >> 32, 1, "mapped extents");
+            (buf[4 + 5] as u32) & FIEMAP_EXTENT_SHARED != 0
+        }
+
+        const LEN: usize = 128 * 1024;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root_dir = tmp_dir.path().to_path_buf();
+        let data = (0..LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(root_dir.join("a"), &data).unwrap();
+        std::fs::write(root_dir.join("b"), &data).unwrap();
+        std::fs::write(root_dir.join("c"), vec![0u8; LEN]).unwrap();
+
+        let cfg = super::Config {
+            root_dir: root_dir.clone(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let open = |name: &'static str, flags: i32| {
+            let fs = &fs;
+            async move {
+                let ino = fs
+                    .lookup(Request::default(), ROOT_ID, OsStr::new(name))
+                    .await
+                    .unwrap()
+                    .attr
+                    .ino;
+                let fh = fs
+                    .open(Request::default(), ino, flags as u32)
+                    .await
+                    .unwrap()
+                    .fh;
+                (ino, fh)
+            }
+        };
+        let (src_ino, src_fh) = open("a", libc::O_RDONLY).await;
+        let mut dests = Vec::new();
+        for name in ["b", "c"] {
+            let (inode, fh) = open(name, libc::O_RDWR).await;
+            dests.push(DedupeDest {
+                inode,
+                fh,
+                offset: 0,
+            });
+        }
+
+        let res = fs
+            .dedupe_range(src_ino, src_fh, 0, LEN as u64, &dests)
+            .await;
+        if fs.host_fs_type(ROOT_ID).await != libc::BTRFS_SUPER_MAGIC {
+            // Most other filesystems, ext4 and tmpfs among them, can't share extents.
+            if let Err(e) = res {
+                assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP));
+            }
+            return;
+        }
+        assert_eq!(
+            res.unwrap(),
+            [DedupeStatus::Deduped(LEN as u64), DedupeStatus::Differs]
+        );
+        assert!(first_extent_shared(&root_dir.join("a")));
+        assert!(first_extent_shared(&root_dir.join("b")));
+        assert!(!first_extent_shared(&root_dir.join("c")));
+        assert_eq!(std::fs::read(root_dir.join("b")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_direct_io_writes() {
+        use super::DirectIoWrites;
+
+        for mode in [DirectIoWrites::Strict, DirectIoWrites::Compat] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let path = tmp_dir.path().join("file");
+            std::fs::write(&path, vec![b'a'; 8192]).unwrap();
+            // Without a reported alignment writes go to the host as they are.
+            if super::statx::dio_offset_align(&std::fs::File::open(&path).unwrap()).is_none() {
+                eprintln!("skip test_direct_io_writes: no STATX_DIOALIGN");
+                return;
+            }
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                direct_io_writes: mode,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let flags = (libc::O_RDWR | libc::O_DIRECT) as u32;
+            let fh = match fs.open(Request::default(), ino, flags).await {
+                Ok(reply) => reply.fh,
+                Err(e) => {
+                    eprintln!("skip test_direct_io_writes: {e:?}");
+                    return;
+                }
+            };
+            let write = |offset: u64, data: Vec<u8>| {
+                let fs = &fs;
+                async move {
+                    fs.write(Request::default(), ino, fh, offset, &data, 0, flags)
+                        .await
+                        .map(|reply| reply.written)
+                        .map_err(std::io::Error::from)
+                }
+            };
+
+            assert_eq!(write(4096, vec![b'b'; 4096]).await.unwrap(), 4096);
+            let mut expected = [vec![b'a'; 4096], vec![b'b'; 4096]].concat();
+            assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+            let res = write(100, vec![b'c'; 10]).await;
+            let extend = write(8190, vec![b'd'; 10]).await;
+            if mode == DirectIoWrites::Strict {
+                assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+                assert_eq!(extend.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+            } else {
+                assert_eq!(res.unwrap(), 10);
+                assert_eq!(extend.unwrap(), 10);
+                expected[100..110].fill(b'c');
+                expected.truncate(8190);
+                expected.extend_from_slice(&[b'd'; 10]);
+            }
+            // Past the end of the file, only what was written shows up.
+            assert_eq!(std::fs::read(&path).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_create_same_name() {
+        use std::os::unix::fs::MetadataExt;
+
+        for serialize_inode_ops in [true, false] {
+            let tmp_dir = tempfile::tempdir().unwrap();
+            let cfg = super::Config {
+                root_dir: tmp_dir.path().to_path_buf(),
+                serialize_inode_ops,
+                debug_inode_dump: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            unwrap_or_skip_eperm!(fs.import().await, "import");
+            let fs = std::sync::Arc::new(fs);
+
+            for i in 0..32 {
+                let name = format!("file{i}");
+                let create = || {
+                    let fs = fs.clone();
+                    let name = name.clone();
+                    tokio::spawn(async move {
+                        fs.create(
+                            Request::default(),
+                            ROOT_ID,
+                            OsStr::new(&name),
+                            u32::from(libc::S_IFREG) | 0o644,
+                            libc::O_RDWR as u32,
+                        )
+                        .await
+                        .map_err(std::io::Error::from)
+                    })
+                };
+                let (first, second) = (create(), create());
+                let first = first.await.unwrap().unwrap();
+                let second = second.await.unwrap().unwrap();
+                assert_eq!(first.attr.ino, second.attr.ino, "{name}");
+                assert_ne!(first.fh, second.fh);
+
+                // Both creates took a lookup reference on the one inode of the file.
+                let host_ino = std::fs::metadata(tmp_dir.path().join(&name)).unwrap().ino();
+                let inodes: Vec<_> = fs
+                    .dump_inodes()
+                    .await
+                    .into_iter()
+                    .filter(|info| info.ino == host_ino)
+                    .collect();
+                assert_eq!(inodes.len(), 1, "{name}: {inodes:?}");
+                assert_eq!(inodes[0].inode, first.attr.ino);
+                assert_eq!(inodes[0].refcount, 2);
+
+                for fh in [first.fh, second.fh] {
+                    fs.release(Request::default(), first.attr.ino, fh, 0, 0, false)
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_range() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        let src: Vec<u8> = (0..5u32 << 20).map(|i| (i % 251) as u8).collect();
+        std::fs::write(tmp_dir.path().join("src"), &src).unwrap();
+        std::fs::write(tmp_dir.path().join("dst"), b"head").unwrap();
+
+        let open = |name: &'static str, flags: i32| {
+            let fs = &fs;
+            async move {
+                let ino = fs
+                    .lookup(Request::default(), ROOT_ID, OsStr::new(name))
+                    .await
+                    .map_err(std::io::Error::from)?
+                    .attr
+                    .ino;
+                let fh = fs
+                    .open(Request::default(), ino, flags as u32)
+                    .await
+                    .map_err(std::io::Error::from)?
+                    .fh;
+                Ok::<_, std::io::Error>((ino, fh))
+            }
+        };
+        let (src_ino, src_fh) = unwrap_or_skip_eperm!(open("src", libc::O_RDONLY).await, "open");
+        let (dst_ino, dst_fh) = open("dst", libc::O_RDWR).await.unwrap();
+        let copy = |off_in: u64, off_out: u64, length: u64| {
+            fs.copy_file_range(
+                Request::default(),
+                src_ino,
+                src_fh,
+                off_in,
+                dst_ino,
+                dst_fh,
+                off_out,
+                length,
+                0,
+            )
+        };
+
+        // A multi-megabyte range at unaligned offsets.
+        let (off_in, len) = ((1 << 20) + 7, (3 << 20) + 123);
+        assert_eq!(copy(off_in, 4, len).await.unwrap().copied, len);
+        let dst = std::fs::read(tmp_dir.path().join("dst")).unwrap();
+        assert_eq!(&dst[..4], b"head");
+        assert!(dst[4..] == src[off_in as usize..(off_in + len) as usize]);
+
+        // A range past the end of the source copies what there is.
+        let tail = 1000;
+        let off_in = src.len() as u64 - tail;
+        assert_eq!(copy(off_in, 0, 1 << 20).await.unwrap().copied, tail);
+        assert_eq!(copy(src.len() as u64, 0, 10).await.unwrap().copied, 0);
+        let dst = std::fs::read(tmp_dir.path().join("dst")).unwrap();
+        assert!(dst[..tail as usize] == src[off_in as usize..]);
+
+        // No flags are defined.
+        let err = fs
+            .copy_file_range(
+                Request::default(),
+                src_ino,
+                src_fh,
+                0,
+                dst_ino,
+                dst_fh,
+                0,
+                10,
+                1,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[tokio::test]
+    async fn test_getattr_crtime() {
+        let (fs, tmp_dir) = prepare_fs().await;
+        std::fs::write(tmp_dir.path().join("file"), b"data").unwrap();
+
+        let attr = unwrap_or_skip_eperm!(
+            fs.lookup(Request::default(), ROOT_ID, OsStr::new("file"))
+                .await
+                .map_err(std::io::Error::from),
+            "lookup"
+        )
+        .attr;
+        let created = std::fs::metadata(tmp_dir.path().join("file"))
+            .unwrap()
+            .created()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(attr.crtime.sec, created.as_secs() as i64);
+        assert_eq!(attr.crtime.nsec, created.subsec_nanos());
+    }
+
+    #[tokio::test]
+    async fn test_readdirplus_max_dir_size() {
+        use futures_util::StreamExt;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for (dir, count) in [("small", 3), ("large", 2000)] {
+            std::fs::create_dir(tmp_dir.path().join(dir)).unwrap();
+            for i in 0..count {
+                let path = tmp_dir.path().join(format!("{dir}/file-{i:04}"));
+                std::fs::write(path, b"data").unwrap();
+            }
+        }
+        let cfg = super::Config {
+            root_dir: tmp_dir.path().to_path_buf(),
+            readdirplus_max_dir_size: Some(16384),
+            debug_inode_dump: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+
+        for (dir, count, plus) in [("small", 3, true), ("large", 2000, false)] {
+            let ino = fs
+                .lookup(Request::default(), ROOT_ID, OsStr::new(dir))
+                .await
+                .unwrap()
+                .attr
+                .ino;
+            let fh = fs
+                .opendir(Request::default(), ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let inodes = fs.dump_inodes().await.len();
+            let entries = fs
+                .readdirplus(Request::default(), ino, fh, 0, 0)
+                .await
+                .unwrap()
+                .entries
+                .map(|e| e.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(entries.len(), count, "{dir}");
+
+            // Only the small directory's entries were looked up and carry attributes.
+            let looked_up = fs.dump_inodes().await.len() - inodes;
+            assert_eq!(looked_up, if plus { count } else { 0 }, "{dir}");
+            for entry in entries {
+                assert_eq!(entry.kind, rfuse3::FileType::RegularFile);
+                if plus {
+                    assert_ne!(entry.attr.ino, 0);
+                    assert_eq!(entry.attr.size, 4);
+                } else {
+                    assert_eq!(entry.attr.ino, 0);
+                    assert_ne!(entry.inode, 0);
+                }
+            }
+            fs.releasedir(Request::default(), ino, fh, 0).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_path_redirects() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().join("root");
+        let host = tmp_dir.path().join("host");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("file"), b"root").unwrap();
+        std::fs::create_dir_all(host.join("myapp/dir")).unwrap();
+        std::fs::write(host.join("myapp/app.conf"), b"redirected").unwrap();
+        std::fs::write(host.join("other"), b"nested").unwrap();
+        let cfg = super::Config {
+            root_dir: root.clone(),
+            path_redirects: vec![
+                ("/config".into(), host.join("myapp")),
+                ("sub/other".into(), host.join("other")),
+            ],
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        unwrap_or_skip_eperm!(fs.import().await, "import");
+        let fs = &fs;
+
+        let lookup = |parent, name: &'static str| async move {
+            fs.lookup(Request::default(), parent, OsStr::new(name))
+                .await
+                .map(|entry| entry.attr.ino)
+                .map_err(std::io::Error::from)
+        };
+        let read = |ino| async move {
+            let fh = fs
+                .open(Request::default(), ino, libc::O_RDONLY as u32)
+                .await
+                .unwrap()
+                .fh;
+            let data = fs
+                .read(Request::default(), ino, fh, 0, 64)
+                .await
+                .unwrap()
+                .data;
+            fs.release(Request::default(), ino, fh, 0, 0, false)
+                .await
+                .unwrap();
+            data
+        };
+
+        let config = lookup(ROOT_ID, "config").await.unwrap();
+        let conf = lookup(config, "app.conf").await.unwrap();
+        assert_eq!(&read(conf).await[..], b"redirected");
+        let sub = lookup(ROOT_ID, "sub").await.unwrap();
+        let other = lookup(sub, "other").await.unwrap();
+        assert_eq!(&read(other).await[..], b"nested");
+        // The rest of the tree comes from `root_dir`.
+        let file = lookup(ROOT_ID, "file").await.unwrap();
+        assert_eq!(&read(file).await[..], b"root");
+        assert_eq!(
+            lookup(ROOT_ID, "other").await.unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        // `..` stays within the redirected directory.
+        let dir = lookup(config, "dir").await.unwrap();
+        assert_eq!(lookup(dir, "..").await.unwrap(), config);
+        assert_eq!(
+            lookup(config, "..").await.unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+    }
+
     // // Test for uid/gid mapping
     // async fn setup(
     //     mapping: Option<&str>,
@@ -0,0 +1,181 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The union of the roots of `Config::multi_root`.
+
+use std::collections::HashSet;
+use std::ffi::{CStr, CString, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::Arc;
+
+use nix::errno::Errno;
+use rfuse3::FileType;
+use rfuse3::raw::reply::DirectoryEntry;
+use vm_memory::bitmap::BitmapSlice;
+
+use super::statx::StatExt;
+use super::util::{einval, is_dir, openat, reopen_fd_through_proc};
+use super::{HandleData, InodeData, InodeHandle, PassthroughFs};
+
+/// How the directories of a union directory are held, like inodes.
+#[cfg(target_os = "linux")]
+const UNION_DIR_FLAGS: libc::c_int = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+#[cfg(target_os = "macos")]
+const UNION_DIR_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Open the roots after `root_dir`, which are merged into the root directory.
+    pub(super) fn open_union_roots(&self) -> io::Result<Vec<File>> {
+        let Some(multi_root) = &self.cfg.multi_root else {
+            return Ok(Vec::new());
+        };
+        multi_root
+            .roots
+            .iter()
+            .map(|root| {
+                let root = CString::new(root.as_os_str().as_bytes()).map_err(|_| einval())?;
+                openat(&libc::AT_FDCWD, &root, UNION_DIR_FLAGS, 0)
+            })
+            .collect()
+    }
+
+    /// Look `name` up in the union directory `dir`, open at `dir_file`. The first of its
+    /// directories that has the entry wins. If the entry is a directory, the directories of that
+    /// name in the later ones are returned as well, to be merged into it.
+    pub(super) async fn lookup_union(
+        &self,
+        dir: &InodeData,
+        dir_file: &impl AsRawFd,
+        name: &CStr,
+    ) -> io::Result<(InodeHandle, StatExt, Vec<File>)> {
+        let mut dirs = std::iter::once(dir_file.as_raw_fd())
+            .chain(dir.union_dirs.iter().map(AsRawFd::as_raw_fd));
+        let mut found = None;
+        for candidate in dirs.by_ref() {
+            match self.open_file_and_handle(&candidate, name).await {
+                Ok(entry) => {
+                    found = Some(entry);
+                    break;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let (handle, st) = found.ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        if !is_dir(st.st.st_mode.into()) {
+            return Ok((handle, st, Vec::new()));
+        }
+
+        let mut union_dirs = Vec::new();
+        for candidate in dirs {
+            match self.open_file_restricted(&candidate, name, UNION_DIR_FLAGS, 0) {
+                Ok(file) => union_dirs.push(file),
+                // Missing, or not a directory, which a symlink isn't either.
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENOENT | libc::ENOTDIR | libc::ELOOP)
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((handle, st, union_dirs))
+    }
+
+    /// The entries from `offset` on of the directory open at `handle` if it is a union
+    /// directory, `None` otherwise. An entry's offset is its position in the listing of
+    /// `union_listing()` plus one, which is taken again whenever reading starts at offset 0.
+    /// The inode numbers and types are left for the caller to fill in with a lookup.
+    pub(super) async fn union_entries(
+        &self,
+        handle: &HandleData,
+        offset: u64,
+    ) -> io::Result<Option<Vec<DirectoryEntry>>> {
+        let data = self.inode_map.get(handle.inode).await?;
+        if data.union_dirs.is_empty() {
+            return Ok(None);
+        }
+        let cached = handle.union_listing.lock().unwrap().clone();
+        let names = match cached {
+            Some(names) if offset != 0 => names,
+            _ => {
+                let names = Arc::new(self.union_listing(&data)?);
+                *handle.union_listing.lock().unwrap() = Some(names.clone());
+                names
+            }
+        };
+        Ok(Some(
+            names
+                .iter()
+                .enumerate()
+                .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+                .map(|(i, name)| DirectoryEntry {
+                    inode: 0,
+                    kind: FileType::RegularFile,
+                    name: name.clone(),
+                    offset: i as i64 + 1,
+                })
+                .collect(),
+        ))
+    }
+
+    /// The names in the union directory `data`. The entries of its directories are listed in
+    /// turn, skipping the names listed already.
+    fn union_listing(&self, data: &InodeData) -> io::Result<Vec<OsString>> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        let mut dirs = vec![data.open_file(flags, &self.proc_self_fd)?];
+        for dir in &data.union_dirs {
+            dirs.push(reopen_fd_through_proc(dir, flags, &self.proc_self_fd)?);
+        }
+
+        let mut seen = HashSet::new();
+        let mut names = Vec::new();
+        for dir in dirs {
+            for name in list_dir(dir)? {
+                if seen.insert(name.clone()) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// The names in the directory `dir`, without `.` and `..`.
+fn list_dir(dir: File) -> io::Result<Vec<OsString>> {
+    let fd = dir.into_raw_fd();
+    // Safe because we own `fd`, which the stream takes over if this succeeds.
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let err = io::Error::last_os_error();
+        // Safe because `fd` is still ours.
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+
+    let mut names = Vec::new();
+    let res = loop {
+        Errno::clear();
+        // Safe because `stream` is a valid directory stream.
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            let err = io::Error::last_os_error();
+            break if err.raw_os_error() == Some(0) {
+                Ok(names)
+            } else {
+                Err(err)
+            };
+        }
+        // Safe because `readdir()` returned an entry with a NUL terminated name.
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsString::from_vec(name.to_vec()));
+        }
+    };
+    // Safe because `stream` is valid and not used afterwards.
+    unsafe { libc::closedir(stream) };
+    res
+}
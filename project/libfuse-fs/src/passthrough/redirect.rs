@@ -0,0 +1,85 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The redirects of `Config::path_redirects`.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+use vm_memory::bitmap::BitmapSlice;
+
+use super::statx::StatExt;
+use super::util::einval;
+use super::{InodeHandle, PassthroughFs};
+
+/// A redirect of `Config::path_redirects` as seen from a directory on its virtual path: the
+/// names still to resolve below that directory, and the host path the last of them leads to.
+#[derive(Debug, Clone)]
+pub(super) struct Redirect {
+    components: Vec<CString>,
+    pub(super) host: CString,
+}
+
+/// The names making up `virtual_path` below the root, `None` if it has `..` or no names at all.
+pub(super) fn virtual_components(virtual_path: &Path) -> Option<Vec<CString>> {
+    let mut components = Vec::new();
+    for component in virtual_path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => components.push(CString::new(name.as_bytes()).ok()?),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!components.is_empty()).then_some(components)
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// The redirects of `Config::path_redirects` as seen from the root directory. Like
+    /// `root_dir`, a host path which is a symlink is followed.
+    pub(super) fn root_redirects(&self) -> io::Result<Vec<Redirect>> {
+        self.cfg
+            .path_redirects
+            .iter()
+            .map(|(virtual_path, host)| {
+                let components = virtual_components(virtual_path).ok_or_else(einval)?;
+                let host = std::fs::canonicalize(host)?;
+                let host = CString::new(host.as_os_str().as_bytes()).map_err(|_| einval())?;
+                Ok(Redirect { components, host })
+            })
+            .collect()
+    }
+
+    /// Look `name` up in `redirects`, those of its parent directory. Returns the host file
+    /// `name` is redirected to, if it is, and the redirects which apply below `name`. The first
+    /// of several redirects of the same path wins.
+    pub(super) async fn lookup_redirect(
+        &self,
+        redirects: &[Redirect],
+        name: &CStr,
+    ) -> io::Result<(Option<(InodeHandle, StatExt)>, Vec<Redirect>)> {
+        let mut target = None;
+        let mut below = Vec::new();
+        for redirect in redirects {
+            match redirect.components.split_first() {
+                Some((first, rest)) if first.as_c_str() == name => {
+                    if rest.is_empty() {
+                        target.get_or_insert(&redirect.host);
+                    } else {
+                        below.push(Redirect {
+                            components: rest.to_vec(),
+                            host: redirect.host.clone(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        let found = match target {
+            Some(host) => Some(self.open_file_and_handle(&libc::AT_FDCWD, host).await?),
+            None => None,
+        };
+        Ok((found, below))
+    }
+}
@@ -28,6 +28,8 @@ use super::{
 };
 #[cfg(target_os = "linux")]
 use crate::passthrough::file_handle::FileHandle;
+#[cfg(target_os = "linux")]
+use crate::passthrough::util::stat_fd;
 
 pub type MountId = u64;
 
@@ -55,6 +57,8 @@ trait SafeStatXAccess {
     #[cfg(target_os = "linux")]
     fn stat64(&self) -> Option<libc::stat64>;
     fn mount_id(&self) -> Option<MountId>;
+    #[cfg(target_os = "linux")]
+    fn btime(&self) -> Option<statx_timestamp>;
 }
 
 #[cfg(target_os = "linux")]
@@ -78,7 +82,13 @@ impl SafeStatXAccess for statx_st {
             st.st_dev = makedev(self.stx_dev_major, self.stx_dev_minor);
             st.st_ino = self.stx_ino;
             st.st_mode = self.stx_mode as _;
-            st.st_nlink = self.stx_nlink as _;
+            // Some filesystems don't report a link count. Claim a single link rather than zero,
+            // which tools like find(1) take as "unknown" instead of a leaf directory.
+            st.st_nlink = if self.stx_mask & libc::STATX_NLINK != 0 {
+                self.stx_nlink as _
+            } else {
+                1
+            };
             st.st_uid = self.stx_uid;
             st.st_gid = self.stx_gid;
             st.st_rdev = makedev(self.stx_rdev_major, self.stx_rdev_minor);
@@ -105,6 +115,14 @@ impl SafeStatXAccess for statx_st {
             None
         }
     }
+
+    fn btime(&self) -> Option<statx_timestamp> {
+        if self.stx_mask & STATX_BTIME != 0 {
+            Some(self.stx_btime)
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -170,7 +188,13 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
             let st = stx
                 .stat64()
                 .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
-            let btime = Some(stx.stx_btime);
+            // Filesystems that don't record a birth time leave it out of the mask, report zero
+            // for them as `fstatat()` does.
+            // Safe because statx_timestamp is plain old data, for which zero is a valid value.
+            let btime = Some(
+                stx.btime()
+                    .unwrap_or(unsafe { MaybeUninit::<statx_timestamp>::zeroed().assume_init() }),
+            );
             Ok(StatExt { st, mnt_id, btime })
         } else {
             Err(io::Error::last_os_error())
@@ -203,11 +227,9 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
         if res == 0 {
             let st = unsafe { st.assume_init() };
             let mnt_id = 0; // Dummy mount id
-            // btime on macos is st_birthtimespec, but referencing it fails for some reason.
-            // We'll trust the error and just use st_mtimespec as fallback or 0.
             let btime = statx_timestamp {
-                tv_sec: st.st_mtime,
-                tv_nsec: st.st_mtime_nsec as u32,
+                tv_sec: st.st_birthtime,
+                tv_nsec: st.st_birthtime_nsec as u32,
                 #[cfg(target_os = "macos")]
                 __reserved: 0,
             };
@@ -222,6 +244,54 @@ pub fn statx(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
     }
 }
 
+/// The `statx()` replacement where it's blocked, e.g. by a seccomp filter: `fstatat()`, with the
+/// mount id from `name_to_handle_at()` if available and a zero birth time, as `statx()` reports
+/// on filesystems that don't record one.
+#[cfg(target_os = "linux")]
+pub fn fstatat(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<StatExt> {
+    let st = stat_fd(dir, path)?;
+    // Safe because this is a constant value and a valid C string.
+    let path = path.unwrap_or_else(|| unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) });
+    let mnt_id = get_mount_id(dir, path).unwrap_or(0);
+    // Safe because statx_timestamp is plain old data, for which zero is a valid value.
+    let btime = unsafe { MaybeUninit::<statx_timestamp>::zeroed().assume_init() };
+    Ok(StatExt {
+        st,
+        mnt_id,
+        btime: Some(btime),
+    })
+}
+
+/// The file offset alignment `file` requires for direct I/O, as reported by `statx()` with
+/// `STATX_DIOALIGN` (Linux 6.1). `None` if unknown or if the file doesn't support direct I/O.
+pub fn dio_offset_align(file: &impl AsRawFd) -> Option<u32> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        let mut stx_ui = MaybeUninit::<statx_st>::zeroed();
+        // Safe because this is a constant value and a valid C string.
+        let path = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        // Safe because the kernel will only write data in `stx_ui` and we check the return value.
+        let res = do_statx(
+            file.as_raw_fd(),
+            path.as_ptr(),
+            libc::AT_EMPTY_PATH,
+            libc::STATX_DIOALIGN,
+            stx_ui.as_mut_ptr(),
+        );
+        if res < 0 {
+            return None;
+        }
+        let stx = unsafe { stx_ui.assume_init() };
+        (stx.stx_mask & libc::STATX_DIOALIGN != 0 && stx.stx_dio_offset_align != 0)
+            .then_some(stx.stx_dio_offset_align)
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        let _ = file;
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -243,4 +313,97 @@ mod tests {
             assert_eq!(_st1.mnt_id, mnt_id);
         }
     }
+
+    /// The id and major:minor of the mount `path` is on, the deepest mount point above it in
+    /// `/proc/self/mountinfo`, of which the last one if several are stacked.
+    #[cfg(target_os = "linux")]
+    fn mountinfo_mount(path: &std::path::Path) -> (MountId, String) {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+        let mut best: Option<(usize, MountId, String)> = None;
+        for line in mountinfo.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let mount_point = std::path::Path::new(fields[4]);
+            if !path.starts_with(mount_point) {
+                continue;
+            }
+            let depth = mount_point.components().count();
+            if best.as_ref().is_none_or(|(d, _, _)| depth >= *d) {
+                best = Some((depth, fields[0].parse().unwrap(), fields[2].to_string()));
+            }
+        }
+        let (_, id, dev) = best.unwrap();
+        (id, dev)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_statx_mnt_id() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("a")).unwrap();
+        std::fs::create_dir(root.join("b")).unwrap();
+        std::fs::write(root.join("a/file"), b"data").unwrap();
+        let dir = File::open(&root).unwrap();
+
+        let st = statx(&dir, Some(c"a/file")).unwrap();
+        let (mnt_id, dev) = mountinfo_mount(&root.join("a/file"));
+        assert_eq!(st.mnt_id, mnt_id);
+        assert_eq!(
+            dev,
+            format!(
+                "{}:{}",
+                libc::major(st.st.st_dev),
+                libc::minor(st.st.st_dev)
+            )
+        );
+
+        // A bind mount of the same device has a mount id of its own.
+        let source = CString::new(root.join("a").as_os_str().as_encoded_bytes()).unwrap();
+        let target = CString::new(root.join("b").as_os_str().as_encoded_bytes()).unwrap();
+        // Safe because the arguments are valid C strings and we check the return value.
+        let res = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND,
+                std::ptr::null(),
+            )
+        };
+        if res != 0 {
+            eprintln!(
+                "skip bind mount part of test_statx_mnt_id: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        let bound = statx(&dir, Some(c"b/file"));
+        // Safe because the argument is a valid C string.
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        let bound = bound.unwrap();
+        assert_eq!(bound.st.st_dev, st.st.st_dev);
+        assert_eq!(bound.st.st_ino, st.st.st_ino);
+        assert_ne!(bound.mnt_id, st.mnt_id);
+    }
+
+    #[test]
+    fn test_statx_btime() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), b"data").unwrap();
+        let dir_file = File::open(dir.path()).unwrap();
+        let btime = statx(&dir_file, Some(c"file")).unwrap().btime.unwrap();
+
+        // The birth time, or zero where the filesystem doesn't record one.
+        match std::fs::metadata(dir.path().join("file"))
+            .unwrap()
+            .created()
+        {
+            Ok(created) => {
+                let created = created.duration_since(std::time::UNIX_EPOCH).unwrap();
+                assert_eq!(btime.tv_sec, created.as_secs() as i64);
+                assert_eq!(btime.tv_nsec, created.subsec_nanos());
+            }
+            Err(_) => assert_eq!((btime.tv_sec, btime.tv_nsec), (0, 0)),
+        }
+    }
 }
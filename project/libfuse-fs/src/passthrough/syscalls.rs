@@ -0,0 +1,159 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Detection of syscalls blocked by a seccomp filter, as container runtimes install.
+
+#[cfg(target_os = "linux")]
+use tracing::warn;
+
+#[cfg(test)]
+thread_local! {
+    /// Syscalls `probe()` reports as blocked on this thread, standing in for a seccomp filter.
+    pub(crate) static SIMULATE_BLOCKED: std::cell::Cell<&'static [&'static str]> =
+        const { std::cell::Cell::new(&[]) };
+}
+
+/// The optional syscalls the passthrough may use, each `false` if it's blocked. Only the Linux
+/// code paths consult them.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+pub(crate) struct SyscallSupport {
+    /// Without it, attributes come from `fstatat()`, with a zero birth time, and inodes aren't
+    /// identified by file handle as checking the mount of a handle needs `statx()`.
+    pub(crate) statx: bool,
+    /// Without it, plain renames use `renameat()` and renames with flags fail with `ENOSYS`.
+    pub(crate) renameat2: bool,
+    /// Without it, `copy_file_range` fails with `ENOSYS`, which makes the kernel copy itself.
+    pub(crate) copy_file_range: bool,
+    /// Without it, inodes aren't identified by file handle.
+    pub(crate) name_to_handle_at: bool,
+    /// Without it, inodes aren't identified by file handle either, as their handles couldn't be
+    /// opened. Lacking `CAP_DAC_READ_SEARCH` has the same effect as a filter blocking it.
+    pub(crate) open_by_handle_at: bool,
+    /// Nothing here uses io_uring, it's probed so that the log tells embedders whether they can.
+    pub(crate) io_uring_setup: bool,
+}
+
+impl SyscallSupport {
+    /// Issue each syscall once, with arguments it rejects before doing anything, and log the
+    /// ones that turn out blocked.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn probe() -> Self {
+        const EMPTY: &std::ffi::CStr = c"";
+        let support = SyscallSupport {
+            // Safe because the empty path fails the lookup before the buffer is written.
+            statx: available("statx", || unsafe {
+                libc::syscall(
+                    libc::SYS_statx,
+                    libc::AT_FDCWD,
+                    EMPTY.as_ptr(),
+                    0,
+                    0,
+                    std::ptr::null_mut::<libc::c_void>(),
+                )
+            }),
+            // Safe because this doesn't modify any memory, the empty paths fail the lookup.
+            renameat2: available("renameat2", || unsafe {
+                libc::renameat2(
+                    libc::AT_FDCWD,
+                    EMPTY.as_ptr(),
+                    libc::AT_FDCWD,
+                    EMPTY.as_ptr(),
+                    0,
+                ) as libc::c_long
+            }),
+            // Safe because this doesn't modify any memory, the invalid fds are rejected.
+            copy_file_range: available("copy_file_range", || unsafe {
+                libc::copy_file_range(-1, std::ptr::null_mut(), -1, std::ptr::null_mut(), 0, 0)
+                    as libc::c_long
+            }),
+            // Safe because the empty path fails the lookup before the handle is written.
+            name_to_handle_at: available("name_to_handle_at", || unsafe {
+                libc::syscall(
+                    libc::SYS_name_to_handle_at,
+                    libc::AT_FDCWD,
+                    EMPTY.as_ptr(),
+                    std::ptr::null_mut::<libc::c_void>(),
+                    std::ptr::null_mut::<libc::c_int>(),
+                    0,
+                )
+            }),
+            // Safe because the null handle fails with `EFAULT` once the permission check passed.
+            open_by_handle_at: available("open_by_handle_at", || unsafe {
+                libc::syscall(
+                    libc::SYS_open_by_handle_at,
+                    libc::AT_FDCWD,
+                    std::ptr::null_mut::<libc::c_void>(),
+                    libc::O_RDONLY,
+                )
+            }),
+            // Safe because the null parameters fail with `EFAULT` before anything is set up.
+            io_uring_setup: available("io_uring_setup", || unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_setup,
+                    1,
+                    std::ptr::null_mut::<libc::c_void>(),
+                )
+            }),
+        };
+        if !support.statx {
+            warn!("passthrough: statx() is blocked, using fstatat() and disabling file handles");
+        }
+        for (available, name) in [
+            (support.name_to_handle_at, "name_to_handle_at"),
+            (support.open_by_handle_at, "open_by_handle_at"),
+        ] {
+            if !available {
+                warn!("passthrough: {name}() is not permitted, disabling file handles");
+            }
+        }
+        if !support.renameat2 {
+            warn!("passthrough: renameat2() is blocked, disabling rename flags and defer_unlink");
+        }
+        if !support.copy_file_range {
+            warn!("passthrough: copy_file_range() is blocked, disabling copy_file_range");
+        }
+        if !support.io_uring_setup {
+            warn!("passthrough: io_uring_setup() is blocked, io_uring is unavailable");
+        }
+        support
+    }
+
+    /// None of these exist on macOS, where the passthrough has always done without them.
+    #[cfg(target_os = "macos")]
+    pub(crate) fn probe() -> Self {
+        SyscallSupport {
+            statx: false,
+            renameat2: false,
+            copy_file_range: false,
+            name_to_handle_at: false,
+            open_by_handle_at: false,
+            io_uring_setup: false,
+        }
+    }
+
+    /// Whether inodes may be identified by file handle.
+    pub(crate) fn file_handles(&self) -> bool {
+        self.statx && self.name_to_handle_at && self.open_by_handle_at
+    }
+}
+
+/// Whether the syscall `name`, made by `call`, got past the seccomp filter. Filters fail the
+/// syscalls they block with `ENOSYS` or `EPERM`, which the probes otherwise only cause for a
+/// caller not permitted to use the syscall at all.
+#[cfg(target_os = "linux")]
+fn available(name: &str, call: impl FnOnce() -> libc::c_long) -> bool {
+    #[cfg(test)]
+    if SIMULATE_BLOCKED.with(|blocked| blocked.get().contains(&name)) {
+        return false;
+    }
+    #[cfg(not(test))]
+    let _ = name;
+    if call() >= 0 {
+        return true;
+    }
+    !matches!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENOSYS | libc::EPERM)
+    )
+}
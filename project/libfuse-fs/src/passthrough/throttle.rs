@@ -0,0 +1,52 @@
+// Copyright (C) 2024 rk8s authors
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// A token bucket admitting up to `rate` operations per second, with bursts of up to `rate`.
+///
+/// Callers over the limit reserve a future token and sleep until it is due, so they're admitted
+/// in arrival order and the lock is never held while waiting.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    // Negative while callers are waiting for tokens they already reserved.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token, waiting for one to become available if the bucket is empty.
+    pub(crate) async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.last = now;
+            state.tokens -= 1.0;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
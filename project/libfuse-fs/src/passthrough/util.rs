@@ -7,15 +7,16 @@
 use std::collections::{BTreeMap, btree_map};
 use std::ffi::{CStr, CString, OsStr};
 use std::fs::File;
-use std::io;
+use std::io::{self, IoSlice};
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 
-use rfuse3::{FileType, Timestamp, raw::reply::FileAttr};
-use tracing::error;
+use futures::{StreamExt, stream};
+use rfuse3::{FileType, raw::reply::FileAttr};
+use tracing::{debug, error, trace, warn};
 
 #[cfg(target_os = "macos")]
 #[allow(non_camel_case_types)]
@@ -27,8 +28,11 @@ pub const AT_EMPTY_PATH: i32 = 0;
 #[cfg(target_os = "linux")]
 pub use libc::{AT_EMPTY_PATH, stat64};
 
+use crate::util::timestamp_from_stat;
+
+use super::config::{DirectIoWrites, RetryPolicy};
 use super::inode_store::InodeId;
-use super::{CURRENT_DIR_CSTR, EMPTY_CSTR, MAX_HOST_INO, PARENT_DIR_CSTR};
+use super::{CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR};
 
 /// the 56th bit used to set the inode to 1 indicates virtual inode
 const VIRTUAL_INODE_FLAG: u64 = 1 << 55;
@@ -37,17 +41,64 @@ const VIRTUAL_INODE_FLAG: u64 = 1 << 55;
 #[derive(Clone, Copy, Default, PartialOrd, Ord, PartialEq, Eq, Debug)]
 struct DevMntIDPair(libc::dev_t, u64);
 
+/// How `UniqueInodeGenerator` splits the 55 bits below the virtual inode flag between the
+/// unique ID of the (dev, mntid) combination and the inode number.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Layout {
+    /// 8 bits for up to 254 combinations of dev and mntid, 47 bits for inodes.
+    #[default]
+    Narrow,
+    /// 12 bits for up to 4094 combinations of dev and mntid, 43 bits for inodes. This keeps
+    /// hosts with many bind mounts working, but every host inode above 2^43 - 1 takes a virtual
+    /// inode (and a map entry to remember it), and 16 times fewer virtual inodes are available.
+    Wide,
+}
+
+impl Layout {
+    fn id_bits(self) -> u32 {
+        match self {
+            Layout::Narrow => 8,
+            Layout::Wide => 12,
+        }
+    }
+
+    fn ino_bits(self) -> u32 {
+        55 - self.id_bits()
+    }
+
+    /// The largest host inode used as is, bigger ones are given a virtual inode.
+    pub fn max_host_ino(self) -> u64 {
+        (1 << self.ino_bits()) - 1
+    }
+
+    // The all ones unique ID is never handed out.
+    fn max_unique_id(self) -> u16 {
+        (1 << self.id_bits()) - 1
+    }
+}
+
 // Used to generate a unique inode with a maximum of 56 bits. the format is
-// |1bit|8bit|47bit
-// when the highest bit is equal to 0, it means the host inode format, and the lower 47 bits normally store no more than 47-bit inode
+// |1bit|8bit|47bit, or |1bit|12bit|43bit with `Layout::Wide`
+// when the highest bit is equal to 0, it means the host inode format, and the lower bits normally store the host inode
 // When the highest bit is equal to 1, it indicates the virtual inode format,
-// which is used to store more than 47 bits of inodes
-// the middle 8bit is used to store the unique ID produced by the combination of dev+mntid
+// which is used to store inodes too big for the lower bits
+// the middle bits are used to store the unique ID produced by the combination of dev+mntid
 pub struct UniqueInodeGenerator {
-    // Mapping (dev, mnt_id) pair to another small unique id
-    dev_mntid_map: Mutex<BTreeMap<DevMntIDPair, u8>>,
-    next_unique_id: AtomicU8,
+    layout: Layout,
+    state: Mutex<GeneratorState>,
+    next_unique_id: AtomicU16,
     next_virtual_inode: AtomicU64,
+    // The number of virtual inodes in use at which to warn, and whether that happened
+    warn_at: u64,
+    warned: AtomicBool,
+}
+
+#[derive(Default)]
+struct GeneratorState {
+    // Mapping (dev, mnt_id) pair to another small unique id
+    dev_mntid_map: BTreeMap<DevMntIDPair, u16>,
+    // Released virtual inodes (without the flag), reused before `next_virtual_inode`
+    free_virtual_inodes: Vec<u64>,
 }
 
 impl Default for UniqueInodeGenerator {
@@ -58,13 +109,47 @@ impl Default for UniqueInodeGenerator {
 
 impl UniqueInodeGenerator {
     pub fn new() -> Self {
+        Self::with_layout(Layout::Narrow)
+    }
+
+    pub fn with_layout(layout: Layout) -> Self {
         UniqueInodeGenerator {
-            dev_mntid_map: Mutex::new(Default::default()),
-            next_unique_id: AtomicU8::new(1),
+            layout,
+            state: Mutex::new(Default::default()),
+            next_unique_id: AtomicU16::new(1),
             next_virtual_inode: AtomicU64::new(1),
+            warn_at: u64::MAX,
+            warned: AtomicBool::new(false),
         }
     }
 
+    /// Log a warning, once, when `percent` percent of the virtual inodes are in use.
+    pub fn with_usage_warning(mut self, percent: Option<u8>) -> Self {
+        self.warn_at = match percent {
+            Some(percent) => {
+                (u128::from(self.max_host_ino()) * u128::from(percent)).div_ceil(100) as u64
+            }
+            None => u64::MAX,
+        };
+        self
+    }
+
+    /// The number of virtual inodes in use and the number available in total.
+    pub fn virtual_inode_usage(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (self.virtual_inodes_in_use(&state), self.max_host_ino())
+    }
+
+    fn virtual_inodes_in_use(&self, state: &GeneratorState) -> u64 {
+        let allocated = self.next_virtual_inode.load(Ordering::Relaxed) - 1;
+        allocated - state.free_virtual_inodes.len() as u64
+    }
+
+    /// The largest host inode used as is, see `Layout::max_host_ino()`.
+    pub fn max_host_ino(&self) -> u64 {
+        self.layout.max_host_ino()
+    }
+
     #[cfg(target_os = "linux")]
     pub fn get_unique_inode(&self, id: &InodeId) -> io::Result<libc::ino64_t> {
         self.get_unique_inode_impl(id)
@@ -74,16 +159,17 @@ impl UniqueInodeGenerator {
         self.get_unique_inode_impl(id)
     }
     fn get_unique_inode_impl(&self, id: &InodeId) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
         let unique_id = {
             let id: DevMntIDPair = DevMntIDPair(id.dev, id.mnt);
-            let mut id_map_guard = self.dev_mntid_map.lock().unwrap();
-            match id_map_guard.entry(id) {
+            match state.dev_mntid_map.entry(id) {
                 btree_map::Entry::Occupied(v) => *v.get(),
                 btree_map::Entry::Vacant(v) => {
-                    if self.next_unique_id.load(Ordering::Relaxed) == u8::MAX {
-                        return Err(io::Error::other(
-                            "the number of combinations of dev and mntid exceeds 255",
-                        ));
+                    let max_id = self.layout.max_unique_id();
+                    if self.next_unique_id.load(Ordering::Relaxed) == max_id {
+                        return Err(io::Error::other(format!(
+                            "the number of combinations of dev and mntid exceeds {max_id}"
+                        )));
                     }
                     let next_id = self.next_unique_id.fetch_add(1, Ordering::Relaxed);
                     v.insert(next_id);
@@ -92,18 +178,47 @@ impl UniqueInodeGenerator {
             }
         };
 
-        let inode = if id.ino <= MAX_HOST_INO {
+        let max_host_ino = self.max_host_ino();
+        let inode = if id.ino <= max_host_ino {
             id.ino
+        } else if let Some(inode) = state.free_virtual_inodes.pop() {
+            inode | VIRTUAL_INODE_FLAG
         } else {
-            if self.next_virtual_inode.load(Ordering::Relaxed) > MAX_HOST_INO {
+            if self.next_virtual_inode.load(Ordering::Relaxed) > max_host_ino {
                 return Err(io::Error::other(format!(
-                    "the virtual inode excess {MAX_HOST_INO}"
+                    "the virtual inode excess {max_host_ino}"
                 )));
             }
             self.next_virtual_inode.fetch_add(1, Ordering::Relaxed) | VIRTUAL_INODE_FLAG
         };
+        if inode & VIRTUAL_INODE_FLAG != 0 {
+            let in_use = self.virtual_inodes_in_use(&state);
+            if in_use >= self.warn_at && !self.warned.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "{in_use} of {max_host_ino} virtual inodes are in use, inodes above \
+                     {max_host_ino} can't be looked up once they run out"
+                );
+            }
+        }
 
-        Ok(((unique_id as u64) << 47) | inode)
+        Ok(((unique_id as u64) << self.layout.ino_bits()) | inode)
+    }
+
+    /// Make the virtual inode of `inode`, returned by `get_unique_inode()` and no longer in use,
+    /// available for reuse. Host inodes are derived from the host and are ignored.
+    #[cfg(target_os = "linux")]
+    pub fn release_inode(&self, inode: libc::ino64_t) {
+        self.release_inode_impl(inode)
+    }
+    #[cfg(target_os = "macos")]
+    pub fn release_inode(&self, inode: libc::ino_t) {
+        self.release_inode_impl(inode)
+    }
+    fn release_inode_impl(&self, inode: u64) {
+        if inode & VIRTUAL_INODE_FLAG != 0 {
+            let mut state = self.state.lock().unwrap();
+            state.free_virtual_inodes.push(inode & self.max_host_ino());
+        }
     }
 
     #[cfg(test)]
@@ -117,11 +232,12 @@ impl UniqueInodeGenerator {
             ));
         }
 
-        let dev_mntid = (inode >> 47) as u8;
-        if dev_mntid == u8::MAX {
+        let max_id = self.layout.max_unique_id();
+        let dev_mntid = (inode >> self.layout.ino_bits()) as u16 & max_id;
+        if dev_mntid == max_id {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!("invalid dev and mntid {dev_mntid} excess 255"),
+                format!("invalid dev and mntid {dev_mntid} excess {max_id}"),
             ));
         }
 
@@ -129,8 +245,8 @@ impl UniqueInodeGenerator {
         let mut mnt: u64 = 0;
 
         let mut found = false;
-        let id_map_guard = self.dev_mntid_map.lock().unwrap();
-        for (k, v) in id_map_guard.iter() {
+        let state = self.state.lock().unwrap();
+        for (k, v) in state.dev_mntid_map.iter() {
             if *v == dev_mntid {
                 found = true;
                 dev = k.0;
@@ -146,19 +262,30 @@ impl UniqueInodeGenerator {
             ));
         }
         Ok(InodeId {
-            ino: inode & MAX_HOST_INO,
+            ino: inode & self.max_host_ino(),
             dev,
             mnt,
         })
     }
 }
 
-/// Safe wrapper around libc::openat().
+/// Safe wrapper around libc::openat(), always adding `O_CLOEXEC` so the fd doesn't leak into
+/// processes spawned by the file system.
 pub fn openat(
     dir_fd: &impl AsRawFd,
     path: &CStr,
     flags: libc::c_int,
     mode: u32,
+) -> io::Result<File> {
+    openat_no_cloexec(dir_fd, path, flags | libc::O_CLOEXEC, mode)
+}
+
+/// Same as `openat()`, but `flags` are passed as is, for fds meant to be inherited across exec.
+pub fn openat_no_cloexec(
+    dir_fd: &impl AsRawFd,
+    path: &CStr,
+    flags: libc::c_int,
+    mode: u32,
 ) -> io::Result<File> {
     // Safe because:
     // - CString::new() has returned success and thus guarantees `path_cstr` is a valid
@@ -174,15 +301,20 @@ pub fn openat(
         unsafe { libc::openat(dir_fd.as_raw_fd(), path.as_ptr(), flags) }
     };
     if fd >= 0 {
+        trace!("openat: {path:?} flags={flags:#o} -> fd {fd}");
         // Safe because we just opened this fd
         Ok(unsafe { File::from_raw_fd(fd) })
     } else {
-        Err(io::Error::last_os_error())
+        // Taken before logging, which may clobber errno.
+        let err = io::Error::last_os_error();
+        debug!("openat: {path:?} flags={flags:#o} failed: {err}");
+        Err(err)
     }
 }
 
 /// Open `/proc/self/fd/{fd}` with the given flags to effectively duplicate the given `fd` with new
-/// flags (e.g. to turn an `O_PATH` file descriptor into one that can be used for I/O).
+/// flags (e.g. to turn an `O_PATH` file descriptor into one that can be used for I/O). Like
+/// `openat()`, the new fd is `O_CLOEXEC`.
 pub fn reopen_fd_through_proc(
     fd: &impl AsRawFd,
     flags: libc::c_int,
@@ -250,6 +382,14 @@ pub fn stat_fd(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<stat64> {
     }
 }
 
+/// Returns true if the mode is for a FIFO, a device file or a socket.
+pub fn is_special_inode(mode: u32) -> bool {
+    let kind = mode & (libc::S_IFMT as u32);
+    [libc::S_IFIFO, libc::S_IFCHR, libc::S_IFBLK, libc::S_IFSOCK]
+        .iter()
+        .any(|special| kind == *special as u32)
+}
+
 /// Returns true if it's safe to open this inode without O_PATH.
 pub fn is_safe_inode(mode: u32) -> bool {
     // Only regular files and directories are considered safe to be opened from the file
@@ -263,6 +403,27 @@ pub fn is_dir(mode: u32) -> bool {
     (mode & (libc::S_IFMT as u32)) == (libc::S_IFDIR as u32)
 }
 
+/// The options of the macOS xattr calls on an inode of `mode`, for the FUSE `flags` of
+/// `setxattr`. FUSE passes on Linux's `XATTR_CREATE` and `XATTR_REPLACE`, whose values differ on
+/// macOS. Symlinks get `XATTR_NOFOLLOW`, so the attributes are those of the link itself.
+#[cfg(target_os = "macos")]
+pub fn xattr_options(mode: u32, flags: u32) -> libc::c_int {
+    // XATTR_CREATE and XATTR_REPLACE on Linux.
+    const CREATE: u32 = 0x1;
+    const REPLACE: u32 = 0x2;
+    let mut options = 0;
+    if flags & CREATE != 0 {
+        options |= libc::XATTR_CREATE;
+    }
+    if flags & REPLACE != 0 {
+        options |= libc::XATTR_REPLACE;
+    }
+    if mode & libc::S_IFMT as u32 == libc::S_IFLNK as u32 {
+        options |= libc::XATTR_NOFOLLOW;
+    }
+    options
+}
+
 pub fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
@@ -284,11 +445,11 @@ pub fn convert_stat64_to_file_attr(stat: stat64) -> FileAttr {
         ino: stat.st_ino,
         size: stat.st_size as u64,
         blocks: stat.st_blocks as u64,
-        atime: Timestamp::new(stat.st_atime, stat.st_atime_nsec.try_into().unwrap()),
-        mtime: Timestamp::new(stat.st_mtime, stat.st_mtime_nsec.try_into().unwrap()),
-        ctime: Timestamp::new(stat.st_ctime, stat.st_ctime_nsec.try_into().unwrap()),
+        atime: timestamp_from_stat(stat.st_atime, stat.st_atime_nsec),
+        mtime: timestamp_from_stat(stat.st_mtime, stat.st_mtime_nsec),
+        ctime: timestamp_from_stat(stat.st_ctime, stat.st_ctime_nsec),
         #[cfg(target_os = "macos")]
-        crtime: Timestamp::new(0, 0), // Set crtime to 0 for non-macOS platforms
+        crtime: timestamp_from_stat(stat.st_birthtime, stat.st_birthtime_nsec),
         kind: filetype_from_mode(stat.st_mode.into()),
         perm: (stat.st_mode & 0o7777) as u16,
         nlink: stat.st_nlink as u32,
@@ -361,6 +522,24 @@ pub fn osstr_to_cstr(os_str: &OsStr) -> Result<CString, std::ffi::NulError> {
     Ok(c_string)
 }
 
+/// Run `f` over `items` with at most `limit` futures in flight, returning the outputs in the
+/// order of `items`. A `limit` of `0` is treated as `1`.
+pub(crate) async fn bounded_map_ordered<T, F, Fut>(
+    items: Vec<T>,
+    limit: usize,
+    f: F,
+) -> Vec<Fut::Output>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future,
+{
+    stream::iter(items)
+        .map(f)
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
 #[cfg(target_os = "linux")]
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
@@ -444,10 +623,463 @@ pub fn set_creds(
     ScopedGid::new(gid).and_then(|gid| Ok((ScopedUid::new(uid)?, gid)))
 }
 
+/// Run the backing store operation `f`, retrying it as long as it fails with an error `policy`
+/// considers transient and attempts are left. Without a policy `f` runs exactly once.
+pub(crate) async fn retry_io<T, F>(policy: Option<&RetryPolicy>, mut f: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let Some(policy) = policy else {
+        return f();
+    };
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if attempt < policy.attempts && policy.is_transient(&e) => {
+                debug!("retry_io: attempt {attempt} failed with {e}, retrying");
+                attempt += 1;
+                tokio::time::sleep(policy.backoff).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Rewrite the absolute symlink `target` of a link `depth` directories below the export root into
+/// a relative one, so that it resolves against the export root rather than the host root. `..`
+/// components can't climb above the root, the same as in `/`. Relative targets are returned
+/// unchanged.
+pub fn confine_symlink_target(target: &[u8], depth: usize) -> Vec<u8> {
+    if !target.starts_with(b"/") {
+        return target.to_vec();
+    }
+    let mut parts: Vec<&[u8]> = Vec::new();
+    for part in target.split(|b| *b == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut confined = b"../".repeat(depth);
+    if depth == 0 {
+        confined.extend_from_slice(b"./");
+    }
+    confined.extend_from_slice(&parts.join(&b'/'));
+    if target.ends_with(b"/") && !parts.is_empty() {
+        confined.push(b'/');
+    }
+    confined
+}
+
+/// Split the byte range `[offset, offset + length)` into consecutive `(offset, length)` pieces of
+/// at most `chunk` bytes. A `chunk` of zero yields the whole range at once.
+pub(crate) fn chunk_range(
+    offset: u64,
+    length: u64,
+    chunk: u64,
+) -> impl Iterator<Item = (u64, u64)> {
+    let chunk = if chunk == 0 { length.max(1) } else { chunk };
+    let end = offset.saturating_add(length);
+    (offset..end)
+        .step_by(usize::try_from(chunk).unwrap_or(usize::MAX))
+        .map(move |start| (start, chunk.min(end - start)))
+}
+
+/// The most buffers one `writev(2)` or `pwritev(2)` takes, `IOV_MAX` of `<limits.h>`.
+#[cfg(target_os = "linux")]
+const IOV_MAX: usize = libc::UIO_MAXIOV as usize;
+#[cfg(target_os = "macos")]
+const IOV_MAX: usize = 1024;
+
+/// Write `bufs` to `fd` at `offset` with one `pwritev(2)`, without gathering them into one
+/// buffer first, and return how much the host took, which may be short.
+pub(crate) fn pwritev(fd: RawFd, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    // Safe because `IoSlice` is ABI compatible with `iovec`, this doesn't modify any memory and
+    // we check the return value.
+    let ret = unsafe {
+        libc::pwritev(
+            fd,
+            bufs.as_ptr() as *const libc::iovec,
+            bufs.len().min(IOV_MAX) as libc::c_int,
+            offset as libc::off_t,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    // Safe because this doesn't modify any memory and we check the return value.
+    let ret = match bufs.first() {
+        Some(buf) => unsafe {
+            libc::pwrite(
+                fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                offset as libc::off_t,
+            )
+        },
+        None => 0,
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Like `pwritev()`, but writing at the file position of `fd`, which for an `O_APPEND` fd is the
+/// end of the file at the time of the write. A short count isn't continued from: for an append,
+/// another appender's data could land between the two parts.
+pub(crate) fn writev(fd: RawFd, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+    // Safe because `IoSlice` is ABI compatible with `iovec`, this doesn't modify any memory and
+    // we check the return value.
+    let ret = unsafe {
+        libc::writev(
+            fd,
+            bufs.as_ptr() as *const libc::iovec,
+            bufs.len().min(IOV_MAX) as libc::c_int,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Write all of `bufs` to `fd` at `offset` with `pwritev()`. A write which stops short in the
+/// middle of the buffers continues from where it stopped. An error after some data has been
+/// written is dropped in favor of the short count, the caller gets it again on its next write.
+pub(crate) fn pwritev_all(fd: RawFd, bufs: &mut [IoSlice<'_>], offset: u64) -> io::Result<usize> {
+    write_vectored_all(bufs, offset, |bufs, offset| pwritev(fd, bufs, offset))
+}
+
+/// Split the data of a write at `offset` at the page boundaries of the file, into the pages
+/// the kernel put one after the other when it sent them from its cache.
+pub(crate) fn page_segments(data: &[u8], offset: u64, page_size: usize) -> Vec<IoSlice<'_>> {
+    let first = page_size - (offset % page_size as u64) as usize;
+    let (head, tail) = data.split_at(first.min(data.len()));
+    std::iter::once(head)
+        .chain(tail.chunks(page_size))
+        .filter(|page| !page.is_empty())
+        .map(IoSlice::new)
+        .collect()
+}
+
+/// Copy `len` bytes at `off_in` of `fd_in` to `off_out` of `fd_out`, without touching the file
+/// positions, and return how many were copied. That is short only if the source ends first, or
+/// an error stops the copy after some data was copied, which the caller gets again when it asks
+/// for the rest. Linux copies within the kernel with `copy_file_range(2)`, which may copy less
+/// than asked each call; macOS has no such syscall and copies through a buffer.
+pub(crate) fn copy_file_range_all(
+    fd_in: RawFd,
+    off_in: u64,
+    fd_out: RawFd,
+    off_out: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let mut off_in = i64::try_from(off_in).map_err(|_| einval())?;
+    let mut off_out = i64::try_from(off_out).map_err(|_| einval())?;
+    // Copy at most `chunk` bytes, advance both offsets past them and return their number.
+    #[cfg(target_os = "linux")]
+    let copy_chunk = |off_in: &mut i64, off_out: &mut i64, chunk: usize| {
+        // Safe because this only accesses the two offsets, which the kernel advances by the
+        // amount copied, and we check the return value.
+        let ret = unsafe { libc::copy_file_range(fd_in, off_in, fd_out, off_out, chunk, 0) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    };
+    #[cfg(target_os = "macos")]
+    let mut buf = vec![0u8; len.min(1 << 20) as usize];
+    #[cfg(target_os = "macos")]
+    let mut copy_chunk = |off_in: &mut i64, off_out: &mut i64, chunk: usize| {
+        let chunk = chunk.min(buf.len());
+        // Safe because the kernel only writes to `buf`, at most its length, and we check the
+        // return value.
+        let ret =
+            unsafe { libc::pread(fd_in, buf.as_mut_ptr() as *mut libc::c_void, chunk, *off_in) };
+        if ret <= 0 {
+            return if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(0)
+            };
+        }
+        let data = &buf[..ret as usize];
+        let n = pwritev_all(fd_out, &mut [IoSlice::new(data)], *off_out as u64)?;
+        *off_in += n as i64;
+        *off_out += n as i64;
+        Ok(n)
+    };
+
+    let mut copied = 0u64;
+    while copied < len {
+        let chunk = usize::try_from(len - copied).unwrap_or(usize::MAX);
+        match copy_chunk(&mut off_in, &mut off_out, chunk) {
+            Ok(0) => break,
+            Ok(n) => copied += n as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if copied > 0 => {
+                debug!("copy_file_range_all: stopped after {copied} bytes: {e}");
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(copied)
+}
+
+/// The `fallocate` mode bits to keep the file size and to punch a hole. FUSE passes on the
+/// values of Linux.
+pub(crate) const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub(crate) const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+
+/// `fallocate(2)` `length` bytes at `offset` of `fd` with `mode`. macOS has no such syscall:
+/// there plain allocations, with or without `FALLOC_FL_KEEP_SIZE`, preallocate with
+/// `F_PREALLOCATE` and extend the file unless asked to keep its size, holes are punched with
+/// `F_PUNCHHOLE` and the other modes fail with `EOPNOTSUPP`.
+pub(crate) fn do_fallocate(fd: RawFd, mode: u32, offset: u64, length: u64) -> io::Result<()> {
+    let offset = i64::try_from(offset).map_err(|_| einval())?;
+    let length = i64::try_from(length).map_err(|_| einval())?;
+    #[cfg(target_os = "linux")]
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fallocate64(fd, mode as libc::c_int, offset, length) };
+    #[cfg(target_os = "macos")]
+    let res = match mode {
+        0 | FALLOC_FL_KEEP_SIZE => {
+            let end = offset
+                .checked_add(length)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+            let st = stat_fd(&fd, None)?;
+            // Preallocation extends what is allocated past the end of the file's blocks.
+            let allocated = st.st_blocks * 512;
+            if end > allocated {
+                let mut store = libc::fstore_t {
+                    fst_flags: libc::F_ALLOCATECONTIG,
+                    fst_posmode: libc::F_PEOFPOSMODE,
+                    fst_offset: 0,
+                    fst_length: end - allocated,
+                    fst_bytesalloc: 0,
+                };
+                // Safe because the kernel only accesses `store` and we check the return value.
+                // A contiguous allocation is only a preference, retry without it.
+                if unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store) } < 0 {
+                    store.fst_flags = libc::F_ALLOCATEALL;
+                    // Safe for the same reasons.
+                    if unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store) } < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            if mode & FALLOC_FL_KEEP_SIZE == 0 && end > st.st_size {
+                // Safe because this doesn't modify any memory and we check the return value.
+                unsafe { libc::ftruncate(fd, end) }
+            } else {
+                0
+            }
+        }
+        m if m == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => {
+            let hole = libc::fpunchhole_t {
+                fp_flags: 0,
+                reserved: 0,
+                fp_offset: offset,
+                fp_length: length,
+            };
+            // Safe because the kernel only reads `hole` and we check the return value.
+            unsafe { libc::fcntl(fd, libc::F_PUNCHHOLE, &hole) }
+        }
+        _ => return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The alignment of the memory `pwrite_direct()` passes to the host.
+pub const DIRECT_IO_MEM_ALIGN: usize = 4096;
+
+/// The most `pwrite_direct()` copies to aligned memory at a time, so a large write doesn't
+/// take a second buffer of its full size.
+pub const DIRECT_IO_CHUNK: usize = 1024 * 1024;
+
+/// Write `data` at `offset` of the `O_DIRECT` fd `fd`, whose I/O offsets and sizes have to be
+/// multiples of `align`, from copies in aligned memory of at most `DIRECT_IO_CHUNK` bytes. A
+/// write which isn't aligned fails with `EINVAL` under `DirectIoWrites::Strict`. Under
+/// `DirectIoWrites::Compat` the blocks it covers are written whole, after reading the first and
+/// the last one, which it only partially covers, and the file is truncated back if that went
+/// past its end.
+pub fn pwrite_direct(
+    fd: RawFd,
+    data: &[u8],
+    offset: u64,
+    align: u64,
+    mode: DirectIoWrites,
+) -> io::Result<usize> {
+    let end = offset.checked_add(data.len() as u64).ok_or_else(einval)?;
+    let start_block = offset / align * align;
+    let end_block = end.div_ceil(align) * align;
+    let aligned = start_block == offset && end_block == end;
+    if !aligned && mode == DirectIoWrites::Strict {
+        return Err(einval());
+    }
+    let size = if aligned {
+        0
+    } else {
+        stat_fd(&fd, None)?.st_size as u64
+    };
+
+    let chunk = (DIRECT_IO_CHUNK as u64 / align).max(1) * align;
+    let len = usize::try_from(chunk.min(end_block - start_block)).map_err(|_| einval())?;
+    let mut mem = vec![0u8; len + DIRECT_IO_MEM_ALIGN];
+    let skip = mem.as_ptr().align_offset(DIRECT_IO_MEM_ALIGN);
+    let mem = &mut mem[skip..skip + len];
+
+    let mut written = 0u64;
+    for (chunk_start, chunk_len) in chunk_range(start_block, end_block - start_block, chunk) {
+        let chunk_end = chunk_start + chunk_len;
+        let buf = &mut mem[..chunk_len as usize];
+        // The blocks the write only partially covers, in this chunk.
+        for block in [start_block, end_block - align] {
+            let partial = (block == start_block && offset != start_block)
+                || (block == end_block - align && end != end_block);
+            if !partial || block < chunk_start || block >= chunk_end {
+                continue;
+            }
+            let at = (block - chunk_start) as usize;
+            let block_buf = &mut buf[at..at + align as usize];
+            // What is past the end of the file stays zero.
+            block_buf.fill(0);
+            // Safe because this only writes to `block_buf` and we check the return value.
+            let ret = unsafe {
+                libc::pread(
+                    fd,
+                    block_buf.as_mut_ptr() as *mut libc::c_void,
+                    block_buf.len(),
+                    block as libc::off_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let from = offset.max(chunk_start);
+        let to = end.min(chunk_end);
+        buf[(from - chunk_start) as usize..(to - chunk_start) as usize]
+            .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+
+        let n = match pwritev_all(fd, &mut [IoSlice::new(buf)], chunk_start) {
+            Ok(n) => n as u64,
+            Err(_) if written > 0 => break,
+            Err(e) => return Err(e),
+        };
+        written += n;
+        if n < chunk_len {
+            break;
+        }
+    }
+
+    if aligned {
+        return Ok(written as usize);
+    }
+    let done = (start_block + written).min(end).saturating_sub(offset);
+    let new_size = size.max(offset + done);
+    if start_block + written > new_size {
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::ftruncate(fd, new_size as libc::off_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(done as usize)
+}
+
+/// The cursor logic of `pwritev_all()`, with the syscall passed in as `pwritev`.
+fn write_vectored_all(
+    mut bufs: &mut [IoSlice<'_>],
+    mut offset: u64,
+    mut pwritev: impl FnMut(&[IoSlice<'_>], u64) -> io::Result<usize>,
+) -> io::Result<usize> {
+    let mut written = 0;
+    // Leading empty buffers would make an exhausted write look like a zero-length one.
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match pwritev(bufs, offset) {
+            Ok(0) => break,
+            Ok(n) => {
+                written += n;
+                offset += n as u64;
+                IoSlice::advance_slices(&mut bufs, n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) if written > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(written)
+}
+
+/// The supplementary groups of process `pid`, as listed by `/proc/<pid>/status`, if it runs
+/// with the filesystem ids `uid` and `gid`.
+///
+/// FUSE requests only carry the caller's primary gid, so this is how group permission checks
+/// learn about the other groups. The ids are in the daemon's user namespace, as those of the
+/// request are, so neither needs mapping. Request pids are those of the pid namespace the file
+/// system was mounted in, which has to be the one of `/proc` for this to find the caller, and
+/// by the time the status is read the caller may have exited and its pid been reused: the ids
+/// have to match so that only a process of the same user can stand in for it. An empty list is
+/// returned if `/proc` is of another pid namespace, the process is gone or runs with other ids,
+/// or the groups can't be determined.
+#[cfg(target_os = "linux")]
+pub fn supplementary_groups(pid: u32, uid: u32, gid: u32) -> Vec<u32> {
+    static PROC_OF_OWN_PID_NS: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    let own_ns = *PROC_OF_OWN_PID_NS.get_or_init(|| {
+        std::fs::read_link("/proc/self")
+            .is_ok_and(|pid| pid.as_os_str() == std::process::id().to_string().as_str())
+    });
+    if pid == 0 || !own_ns {
+        return Vec::new();
+    }
+    std::fs::read_to_string(format!("/proc/{pid}/status"))
+        .ok()
+        .and_then(|status| parse_status_groups(&status, uid, gid))
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "macos")]
+pub fn supplementary_groups(_pid: u32, _uid: u32, _gid: u32) -> Vec<u32> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn parse_status_groups(status: &str, uid: u32, gid: u32) -> Option<Vec<u32>> {
+    // The real, effective, saved and filesystem ids.
+    let fs_id = |key: &str| {
+        let ids = status.lines().find_map(|l| l.strip_prefix(key))?;
+        ids.split_whitespace().nth(3)?.parse::<u32>().ok()
+    };
+    if fs_id("Uid:")? != uid || fs_id("Gid:")? != gid {
+        return None;
+    }
+    let groups = status.lines().find_map(|l| l.strip_prefix("Groups:"))?;
+    Some(
+        groups
+            .split_whitespace()
+            .filter_map(|g| g.parse().ok())
+            .collect(),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const MAX_HOST_INO: u64 = 0x7fff_ffff_ffff;
+
     #[test]
     fn test_is_safe_inode() {
         let mut mode = (libc::S_IFDIR as u32) | 0o755;
@@ -471,6 +1103,13 @@ mod tests {
         mode = (libc::S_IFSOCK as u32) | 0o755;
         assert!(!is_safe_inode(mode));
 
+        for kind in [libc::S_IFCHR, libc::S_IFBLK, libc::S_IFIFO, libc::S_IFSOCK] {
+            assert!(is_special_inode(kind as u32 | 0o755));
+        }
+        for kind in [libc::S_IFDIR, libc::S_IFREG, libc::S_IFLNK] {
+            assert!(!is_special_inode(kind as u32 | 0o755));
+        }
+
         assert_eq!(
             filetype_from_mode((libc::S_IFIFO as u32) | 0o755),
             FileType::NamedPipe
@@ -618,6 +1257,175 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_unique_inode_layouts() {
+        for (layout, max_host_ino, max_ids) in [
+            (Layout::Narrow, MAX_HOST_INO, 254),
+            (Layout::Wide, 0x7ff_ffff_ffff, 4094),
+        ] {
+            let generator = UniqueInodeGenerator::with_layout(layout);
+            assert_eq!(generator.max_host_ino(), max_host_ino);
+
+            // Host inodes up to the limit round-trip on every combination of dev and mntid.
+            let mut last = 0;
+            for mnt in 0..max_ids {
+                for ino in [1, max_host_ino] {
+                    let id = InodeId { ino, dev: 0, mnt };
+                    let unique_inode = generator.get_unique_inode(&id).unwrap();
+                    assert!(unique_inode <= super::super::VFS_MAX_INO);
+                    assert_eq!(unique_inode & VIRTUAL_INODE_FLAG, 0);
+                    assert!(unique_inode > last);
+                    last = unique_inode;
+                    assert_eq!(generator.decode_unique_inode(unique_inode).unwrap(), id);
+                }
+            }
+            let id = InodeId {
+                ino: 1,
+                dev: 0,
+                mnt: max_ids,
+            };
+            assert!(generator.get_unique_inode(&id).is_err());
+
+            // Bigger ones are virtual, the unique ID still decodes to the same combination.
+            let id = InodeId {
+                ino: max_host_ino + 1,
+                dev: 0,
+                mnt: max_ids - 1,
+            };
+            let unique_inode = generator.get_unique_inode(&id).unwrap();
+            assert_ne!(unique_inode & VIRTUAL_INODE_FLAG, 0);
+            let decoded = generator.decode_unique_inode(unique_inode).unwrap();
+            assert_eq!((decoded.ino, decoded.dev, decoded.mnt), (1, 0, max_ids - 1));
+        }
+    }
+
+    #[test]
+    fn test_virtual_inode_usage_warning() {
+        #[derive(Clone, Default)]
+        struct Log(std::sync::Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let generator = UniqueInodeGenerator::new().with_usage_warning(Some(90));
+        let warn_at = (u128::from(MAX_HOST_INO) * 90).div_ceil(100) as u64;
+        let large_ino = |ino: u64| InodeId {
+            ino: MAX_HOST_INO + ino,
+            dev: 0,
+            mnt: 0,
+        };
+        generator
+            .next_virtual_inode
+            .store(warn_at - 2, Ordering::Relaxed);
+        let warnings = || {
+            let log = log.0.lock().unwrap();
+            String::from_utf8_lossy(&log)
+                .matches("virtual inodes are in use")
+                .count()
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let below = generator.get_unique_inode(&large_ino(1)).unwrap();
+            assert_eq!(generator.virtual_inode_usage(), (warn_at - 2, MAX_HOST_INO));
+            assert_eq!(warnings(), 0);
+            // Host inodes don't count.
+            generator.get_unique_inode(&large_ino(0)).unwrap();
+            assert_eq!(warnings(), 0);
+
+            generator.get_unique_inode(&large_ino(2)).unwrap();
+            assert_eq!(warnings(), 0);
+            generator.get_unique_inode(&large_ino(3)).unwrap();
+            assert_eq!(generator.virtual_inode_usage().0, warn_at);
+            assert_eq!(warnings(), 1);
+            for ino in 4..10 {
+                generator.get_unique_inode(&large_ino(ino)).unwrap();
+            }
+            // Releasing and reusing doesn't warn again either.
+            generator.release_inode(below);
+            assert_eq!(generator.virtual_inode_usage().0, warn_at + 5);
+            generator.get_unique_inode(&large_ino(10)).unwrap();
+            assert_eq!(warnings(), 1);
+        });
+    }
+
+    #[test]
+    fn test_release_unique_inode() {
+        let generator = UniqueInodeGenerator::new();
+        let large_ino = |ino: u64| InodeId {
+            ino: MAX_HOST_INO + ino,
+            dev: 0,
+            mnt: 0,
+        };
+        // Leave room for only two virtual inodes.
+        generator
+            .next_virtual_inode
+            .store(MAX_HOST_INO - 1, Ordering::Relaxed);
+        let first = generator.get_unique_inode(&large_ino(1)).unwrap();
+        let second = generator.get_unique_inode(&large_ino(2)).unwrap();
+        assert_eq!(first, 0x80fffffffffffe);
+        assert_eq!(second, 0x80ffffffffffff);
+        assert!(generator.get_unique_inode(&large_ino(3)).is_err());
+
+        // Released virtual inodes are handed out again instead of failing.
+        generator.release_inode(second);
+        generator.release_inode(first);
+        assert_eq!(generator.get_unique_inode(&large_ino(3)).unwrap(), first);
+        assert_eq!(generator.get_unique_inode(&large_ino(4)).unwrap(), second);
+        assert!(generator.get_unique_inode(&large_ino(5)).is_err());
+
+        // Host inodes aren't allocated, so releasing one has no effect.
+        let host = generator.get_unique_inode(&InodeId::default()).unwrap();
+        generator.release_inode(host);
+        assert!(generator.get_unique_inode(&large_ino(6)).is_err());
+    }
+
+    #[test]
+    fn test_openat_cloexec() {
+        let is_cloexec = |file: &File| {
+            // Safe because this only reads the fd flags of an open fd.
+            let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) };
+            assert!(flags >= 0);
+            flags & libc::FD_CLOEXEC != 0
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let dir = File::open(dir.path()).unwrap();
+        let path = CString::new("file").unwrap();
+
+        let file = openat(&dir, &path, libc::O_RDONLY, 0).unwrap();
+        assert!(is_cloexec(&file));
+        let proc_self_fd = File::open("/proc/self/fd").unwrap();
+        let reopened = reopen_fd_through_proc(&file, libc::O_RDWR, &proc_self_fd).unwrap();
+        assert!(is_cloexec(&reopened));
+
+        let file = openat_no_cloexec(&dir, &path, libc::O_RDONLY, 0).unwrap();
+        assert!(!is_cloexec(&file));
+    }
+
+    #[test]
+    fn test_openat_enoent() {
+        let dir = File::open(std::env::temp_dir()).unwrap();
+        let path = CString::new("libfuse-fs-openat-does-not-exist").unwrap();
+        let err = openat(&dir, &path, libc::O_RDONLY, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        let path = CString::new("does-not-exist/file").unwrap();
+        let err = openat(&dir, &path, libc::O_CREAT | libc::O_RDWR, 0o644).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
     #[test]
     fn test_stat_fd() {
         let topdir = std::env::current_dir().unwrap();
@@ -630,4 +1438,206 @@ mod tests {
         assert_eq!(st1.st_dev, st2.st_dev);
         assert_ne!(st1.st_ino, st2.st_ino);
     }
+
+    #[tokio::test]
+    async fn test_bounded_map_ordered() {
+        use std::sync::atomic::AtomicUsize;
+        use std::time::Duration;
+
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        // A slow stat shim which records how many calls overlap.
+        let slow_stat = |i: usize| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis((i % 3) as u64)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        };
+
+        let out = bounded_map_ordered((0..200).collect(), 8, slow_stat).await;
+        assert_eq!(out, (0..200).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 8);
+        assert!(max_in_flight.load(Ordering::SeqCst) > 1);
+
+        max_in_flight.store(0, Ordering::SeqCst);
+        let out = bounded_map_ordered((0..20).collect(), 0, slow_stat).await;
+        assert_eq!(out.len(), 20);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_status_groups() {
+        let status = "Name:\tbash\nUid:\t1\t1\t1\t10\nGid:\t100\t100\t100\t100\n\
+                      Groups:\t4 24 27 \nNgid:\t0\n";
+        assert_eq!(parse_status_groups(status, 10, 100), Some(vec![4, 24, 27]));
+        // A process running as someone else, e.g. one that took over the pid of the caller.
+        assert_eq!(parse_status_groups(status, 1, 100), None);
+        assert_eq!(parse_status_groups(status, 10, 101), None);
+        let status = "Uid:\t0\t0\t0\t0\nGid:\t0\t0\t0\t0\nGroups:\n";
+        assert_eq!(parse_status_groups(status, 0, 0), Some(vec![]));
+        assert_eq!(parse_status_groups("Name:\tbash\n", 0, 0), None);
+    }
+
+    #[test]
+    fn test_confine_symlink_target() {
+        let confine = |target: &str, depth| {
+            String::from_utf8(confine_symlink_target(target.as_bytes(), depth)).unwrap()
+        };
+        assert_eq!(confine("/etc/passwd", 0), "./etc/passwd");
+        assert_eq!(confine("/etc/passwd", 2), "../../etc/passwd");
+        assert_eq!(confine("/etc/", 1), "../etc/");
+        assert_eq!(confine("//etc/./x/../passwd", 1), "../etc/passwd");
+        assert_eq!(confine("/../../etc", 1), "../etc");
+        assert_eq!(confine("/", 1), "../");
+        assert_eq!(confine("etc/passwd", 3), "etc/passwd");
+        assert_eq!(confine("../x", 3), "../x");
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        let chunks = |offset, length, chunk| chunk_range(offset, length, chunk).collect::<Vec<_>>();
+        assert_eq!(chunks(10, 25, 10), vec![(10, 10), (20, 10), (30, 5)]);
+        assert_eq!(chunks(0, 20, 10), vec![(0, 10), (10, 10)]);
+        assert_eq!(chunks(7, 3, 10), vec![(7, 3)]);
+        assert_eq!(chunks(7, 3, 0), vec![(7, 3)]);
+        assert_eq!(chunks(u64::MAX - 4, 10, 3).last(), Some(&(u64::MAX - 1, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_io() {
+        // Fails with `errno` the first `failures` times, then succeeds.
+        let shim = |errno: i32, failures: u32| {
+            let mut calls = 0;
+            move || {
+                calls += 1;
+                if calls <= failures {
+                    Err(io::Error::from_raw_os_error(errno))
+                } else {
+                    Ok(calls)
+                }
+            }
+        };
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: std::time::Duration::from_millis(1),
+            retry_eio: false,
+        };
+
+        // EAGAIN twice, then success within the budget of three attempts.
+        assert_eq!(
+            retry_io(Some(&policy), shim(libc::EAGAIN, 2))
+                .await
+                .unwrap(),
+            3
+        );
+        // The budget runs out.
+        let err = retry_io(Some(&policy), shim(libc::EAGAIN, 3))
+            .await
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+        // Non-transient errors and EIO (unless enabled) fail right away.
+        for errno in [libc::ENOENT, libc::EACCES, libc::EIO] {
+            let err = retry_io(Some(&policy), shim(errno, 1)).await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(errno));
+        }
+        let eio_policy = RetryPolicy {
+            retry_eio: true,
+            ..policy
+        };
+        assert_eq!(
+            retry_io(Some(&eio_policy), shim(libc::EIO, 1))
+                .await
+                .unwrap(),
+            2
+        );
+        // Without a policy nothing is retried.
+        assert!(retry_io(None, shim(libc::EAGAIN, 1)).await.is_err());
+    }
+
+    #[test]
+    fn test_pwritev_all() {
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        let parts: [&[u8]; 5] = [b"", b"hello", b" ", b"", b"scattered world"];
+        let expected = parts.concat();
+
+        // A shim which lets the host take at most 3 bytes per call, so every write ends in the
+        // middle of a buffer or exactly on a boundary.
+        let mut calls = 0;
+        let written = write_vectored_all(&mut parts.map(IoSlice::new), 10, |bufs, offset| {
+            calls += 1;
+            assert!(!bufs[0].is_empty());
+            let mut limited = Vec::new();
+            let mut left = 3;
+            for buf in bufs.iter() {
+                if left == 0 {
+                    break;
+                }
+                let n = buf.len().min(left);
+                limited.push(IoSlice::new(&buf[..n]));
+                left -= n;
+            }
+            pwritev_all(fd, &mut limited, offset)
+        })
+        .unwrap();
+        assert_eq!(written, expected.len());
+        assert_eq!(calls, expected.len().div_ceil(3));
+        let mut content = vec![0u8; 10 + expected.len()];
+        nix::sys::uio::pread(&file, &mut content, 0).unwrap();
+        assert_eq!(&content[10..], &expected[..]);
+
+        // An error after a partial write turns into a short count, an immediate one is returned.
+        let mut calls = 0;
+        let res = write_vectored_all(&mut parts.map(IoSlice::new), 0, |_, _| {
+            calls += 1;
+            if calls == 1 {
+                Ok(4)
+            } else {
+                Err(io::Error::from_raw_os_error(libc::ENOSPC))
+            }
+        });
+        assert_eq!(res.unwrap(), 4);
+        let err = write_vectored_all(&mut parts.map(IoSlice::new), 0, |_, _| {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        })
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+
+        // The real syscall writes everything in one go.
+        assert_eq!(
+            pwritev_all(fd, &mut parts.map(IoSlice::new), 0).unwrap(),
+            expected.len()
+        );
+        nix::sys::uio::pread(&file, &mut content[..expected.len()], 0).unwrap();
+        assert_eq!(&content[..expected.len()], &expected[..]);
+    }
+
+    #[test]
+    fn test_page_segments() {
+        let data: Vec<u8> = (0..=255).collect();
+        let lens = |offset| {
+            page_segments(&data[..100], offset, 32)
+                .iter()
+                .map(|s| s.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lens(0), [32, 32, 32, 4]);
+        assert_eq!(lens(20), [12, 32, 32, 24]);
+        assert_eq!(lens(96), [32, 32, 32, 4]);
+        assert!(page_segments(&data[..0], 5, 32).is_empty());
+        let pages = page_segments(&data, 7, 32);
+        assert_eq!(
+            pages
+                .iter()
+                .flat_map(|s| s.iter())
+                .copied()
+                .collect::<Vec<_>>(),
+            data
+        );
+    }
 }
@@ -46,6 +46,7 @@ impl Filesystem for OverlayFs {
 
         Ok(ReplyInit {
             max_write: NonZeroU32::new(128 * 1024).unwrap(),
+            ..Default::default()
         })
     }
 
@@ -17,6 +17,11 @@ pub struct Config {
     pub no_readdir: bool,
     pub perfile_dax: bool,
     pub cache_policy: CachePolicy,
+    // Give copied-up files fresh timestamps instead of those of the lower file.
+    pub update_times_on_copy: bool,
+    // Fail copy-up of entries nested deeper than this below the root with ENAMETOOLONG
+    // instead of recursing through the whole ancestry. `None` means no limit.
+    pub max_path_depth: Option<usize>,
 }
 
 impl Clone for CachePolicy {
@@ -2,7 +2,7 @@
 use async_trait::async_trait;
 use rfuse3::raw::reply::{FileAttr, ReplyCreated, ReplyXAttr};
 use rfuse3::raw::{ObjectSafeFilesystem, Request, reply::ReplyEntry};
-use rfuse3::{Inode, Result};
+use rfuse3::{Inode, Result, Timestamp};
 use std::ffi::OsStr;
 use std::io::Error;
 use std::time::Duration;
@@ -67,7 +67,21 @@ pub trait Layer: ObjectSafeFilesystem {
         // Try to create whiteout char device with 0/0 device number.
         let dev = libc::makedev(0, 0);
         let mode = (libc::S_IFCHR as u32) | 0o777;
-        self.mknod(ctx, ino, name, mode, dev as u32).await
+        let res = self.mknod(ctx, ino, name, mode, dev as u32).await;
+        match res {
+            // Someone else created the whiteout since the lookup above.
+            Err(e) if e.is_exist() => {
+                let v = self.lookup(ctx, ino, name).await?;
+                if is_whiteout(&v.attr) {
+                    return Ok(v);
+                }
+                if v.attr.ino != 0 {
+                    self.forget(ctx, v.attr.ino, 1).await;
+                }
+                Err(e)
+            }
+            res => res,
+        }
     }
 
     /// Delete whiteout file with name <name>.
@@ -236,12 +250,24 @@ pub trait Layer: ObjectSafeFilesystem {
     ) -> std::io::Result<(Stat64, Duration)> {
         Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
     }
+
+    /// Set access and modification times on behalf of `ctx`, which must own the file.
+    /// Used by copy-up to preserve the timestamps of the lower file.
+    async fn set_times(
+        &self,
+        _ctx: Request,
+        _inode: Inode,
+        _atime: Timestamp,
+        _mtime: Timestamp,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
 }
 
 #[async_trait]
 impl Layer for PassthroughFs {
     fn root_inode(&self) -> Inode {
-        1
+        self.root_ino()
     }
 
     async fn create_with_context(
@@ -313,6 +339,16 @@ impl Layer for PassthroughFs {
     ) -> std::io::Result<(Stat64, Duration)> {
         PassthroughFs::do_getattr_inner(self, inode, handle, mapping).await
     }
+
+    async fn set_times(
+        &self,
+        ctx: Request,
+        inode: Inode,
+        atime: Timestamp,
+        mtime: Timestamp,
+    ) -> std::io::Result<()> {
+        PassthroughFs::do_utimensat(self, ctx, inode, atime, mtime).await
+    }
 }
 pub(crate) fn is_dir(st: &FileAttr) -> bool {
     st.kind.const_into_mode_t() & libc::S_IFMT == libc::S_IFDIR
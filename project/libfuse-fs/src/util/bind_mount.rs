@@ -2,31 +2,63 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 //! Bind mount utilities for container volume management
 
-use std::io::{Error, Result};
-use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
-use tracing::{debug, error, info};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
 /// Represents a single bind mount
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BindMount {
     /// Source path on host
     pub source: PathBuf,
     /// Target path relative to mount point
     pub target: PathBuf,
+    /// Propagation of mount events between the bind mount and its source
+    #[serde(default)]
+    pub propagation: Propagation,
+    /// Whether the mounts below the source are bound as well (`MS_REC`)
+    #[serde(default)]
+    pub recursive: bool,
+    /// Whether the bind mount is remounted read-only right after mounting it
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+/// Mount propagation of a bind mount, applied recursively to the mounts below it, see
+/// mount_namespaces(7).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Propagation {
+    /// Mounts under the source show up under the target, but not the other way around.
+    #[default]
+    Slave,
+    /// Mounts under either side stay on that side.
+    Private,
+    /// Mounts under either side show up under the other one.
+    Shared,
 }
 
 impl BindMount {
-    /// Parse a bind mount specification like "proc:/proc" or "/host/path:/container/path"
+    /// Parse a bind mount specification like "proc:/proc" or "/host/path:/container/path",
+    /// optionally followed by ":rec" to bind the mounts below the source as well
     pub fn parse(spec: &str) -> Result<Self> {
         let parts: Vec<&str> = spec.split(':').collect();
-        if parts.len() != 2 {
-            return Err(Error::other(format!(
-                "Invalid bind mount spec: '{}'. Expected format: 'source:target'",
-                spec
-            )));
-        }
+        let recursive = match parts[..] {
+            [_, _] => false,
+            [_, _, "rec"] => true,
+            _ => {
+                return Err(Error::other(format!(
+                    "Invalid bind mount spec: '{}'. Expected format: 'source:target[:rec]'",
+                    spec
+                )));
+            }
+        };
 
         let source = PathBuf::from(parts[0]);
         let target = PathBuf::from(parts[1]);
@@ -38,7 +70,68 @@ impl BindMount {
             source
         };
 
-        Ok(BindMount { source, target })
+        Ok(BindMount {
+            source,
+            target,
+            propagation: Propagation::default(),
+            recursive,
+            readonly: false,
+        })
+    }
+
+    /// Load bind mounts from the JSON file at `path`, an array of objects with the fields of
+    /// [`BindMount`], of which `propagation` (`"slave"`, `"private"` or `"shared"`), `recursive`
+    /// and `readonly` are optional:
+    ///
+    /// ```json
+    /// [{"source": "/host/data", "target": "/data", "readonly": true}]
+    /// ```
+    ///
+    /// Relative sources are taken from `/`, as in [`parse`](Self::parse). Fails with
+    /// `InvalidData` if the file doesn't parse, if a source or target has a `..` component, or
+    /// if two bind mounts have the same target.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        let invalid =
+            |msg: String| Error::new(ErrorKind::InvalidData, format!("{:?}: {msg}", path));
+        let mut binds: Vec<BindMount> =
+            serde_json::from_slice(&std::fs::read(path)?).map_err(|e| invalid(e.to_string()))?;
+
+        let mut targets = HashSet::new();
+        for bind in &mut binds {
+            for p in [&bind.source, &bind.target] {
+                if p.components().any(|c| c == Component::ParentDir) {
+                    return Err(invalid(format!("{:?} must not contain `..`", p)));
+                }
+            }
+            if bind.source.is_relative() {
+                bind.source = PathBuf::from("/").join(&bind.source);
+            }
+            let target = bind.target.strip_prefix("/").unwrap_or(&bind.target);
+            if !targets.insert(target.to_path_buf()) {
+                return Err(invalid(format!("duplicate target {:?}", bind.target)));
+            }
+        }
+        Ok(binds)
+    }
+
+    /// Save `binds` to the JSON file at `path`, in the format read by
+    /// [`load_from_file`](Self::load_from_file).
+    pub fn save_to_file<P: AsRef<Path>>(path: P, binds: &[BindMount]) -> Result<()> {
+        let json = serde_json::to_vec_pretty(binds).map_err(Error::other)?;
+        std::fs::write(path, json)
+    }
+
+    /// Set the propagation of the bind mount.
+    pub fn with_propagation(mut self, propagation: Propagation) -> Self {
+        self.propagation = propagation;
+        self
+    }
+
+    /// Set whether the mounts below the source are bound as well.
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
     }
 }
 
@@ -46,12 +139,17 @@ impl BindMount {
 pub struct BindMountManager {
     mounts: Arc<Mutex<Vec<MountPoint>>>,
     mountpoint: PathBuf,
+    verify_readonly: bool,
 }
 
 #[derive(Debug)]
 struct MountPoint {
+    source: PathBuf,
     target: PathBuf,
+    propagation: Propagation,
+    recursive: bool,
     mounted: bool,
+    readonly: bool,
 }
 
 impl BindMountManager {
@@ -60,44 +158,56 @@ impl BindMountManager {
         Self {
             mounts: Arc::new(Mutex::new(Vec::new())),
             mountpoint: mountpoint.as_ref().to_path_buf(),
+            verify_readonly: true,
         }
     }
 
+    /// Set whether switching a bind mount between read-only and read-write is checked to have
+    /// taken effect, by reading the flags of the mount back. This is on by default.
+    pub fn with_verify_readonly(mut self, verify: bool) -> Self {
+        self.verify_readonly = verify;
+        self
+    }
+
     /// Mount all bind mounts
+    ///
+    /// Either all of them are mounted or none: if one fails, the ones mounted by this call are
+    /// unmounted again in reverse order, and the targets it created are removed, before the
+    /// error is returned.
     pub async fn mount_all(&self, bind_specs: &[BindMount]) -> Result<()> {
         let mut mounts = self.mounts.lock().await;
+        let mounted_before = mounts.len();
+        let mut created = Vec::new();
 
         for bind in bind_specs {
-            let target_path = self
-                .mountpoint
-                .join(bind.target.strip_prefix("/").unwrap_or(&bind.target));
-
-            // Check if source is a file or directory
-            let source_metadata = std::fs::metadata(&bind.source)?;
-
-            if !target_path.exists() {
-                if source_metadata.is_file() {
-                    // For file bind mounts, create parent directory and an empty file
-                    if let Some(parent) = target_path.parent() {
-                        std::fs::create_dir_all(parent)?;
-                        debug!("Created parent directory: {:?}", parent);
-                    }
-                    std::fs::File::create(&target_path)?;
-                    debug!("Created target file: {:?}", target_path);
-                } else {
-                    // For directory bind mounts, create the directory
-                    std::fs::create_dir_all(&target_path)?;
-                    debug!("Created target directory: {:?}", target_path);
-                }
-            }
+            let target_path = self.target_path(&bind.target);
 
-            // Perform the bind mount
-            self.do_mount(&bind.source, &target_path)?;
+            if let Err(e) = self.mount_one(
+                &bind.source,
+                &target_path,
+                bind.propagation,
+                bind.recursive,
+                &mut created,
+            ) {
+                self.roll_back(&mut mounts, mounted_before, &created);
+                return Err(e);
+            }
 
             mounts.push(MountPoint {
+                source: bind.source.clone(),
                 target: target_path.clone(),
+                propagation: bind.propagation,
+                recursive: bind.recursive,
                 mounted: true,
+                readonly: false,
             });
+            if bind.readonly {
+                if let Err(e) = self.do_remount(&target_path, true) {
+                    self.roll_back(&mut mounts, mounted_before, &created);
+                    return Err(e);
+                }
+                mounts.last_mut().unwrap().readonly = true;
+            }
 
             info!("Bind mounted {:?} -> {:?}", bind.source, target_path);
         }
@@ -105,9 +215,244 @@ impl BindMountManager {
         Ok(())
     }
 
+    /// Undo a failed `mount_all`: unmount what it added to `mounts` past `mounted_before`, then
+    /// remove the paths it `created`, both in reverse order. Paths are removed one by one rather
+    /// than recursively, so nothing under a target that failed to unmount is touched.
+    fn roll_back(&self, mounts: &mut Vec<MountPoint>, mounted_before: usize, created: &[PathBuf]) {
+        for mount in mounts.drain(mounted_before..).rev() {
+            match self.do_unmount(&mount.target) {
+                Ok(()) => info!("Rolled back bind mount {:?}", mount.target),
+                Err(e) => error!("Failed to roll back bind mount {:?}: {}", mount.target, e),
+            }
+        }
+        for path in created.iter().rev() {
+            let res = if path.is_dir() {
+                std::fs::remove_dir(path)
+            } else {
+                std::fs::remove_file(path)
+            };
+            if let Err(e) = res {
+                error!(
+                    "Failed to remove {:?} created for a bind mount: {}",
+                    path, e
+                );
+            }
+        }
+    }
+
+    /// Re-establish all managed bind mounts onto the current mount point.
+    ///
+    /// This is meant for warm restarts: when the filesystem at the mount point is torn down and
+    /// mounted again, the bind mounts stacked on top of it are gone. Targets which are still a
+    /// mount point according to `/proc/self/mountinfo` are skipped, so calling this repeatedly
+    /// is harmless.
+    pub async fn reapply_bind_mounts(&self) -> Result<()> {
+        let mut mounts = self.mounts.lock().await;
+
+        for mount in mounts.iter_mut() {
+            if is_mount_point(&mount.target)? {
+                debug!("Bind mount {:?} is still active", mount.target);
+                mount.mounted = true;
+                continue;
+            }
+
+            self.mount_one(
+                &mount.source,
+                &mount.target,
+                mount.propagation,
+                mount.recursive,
+                &mut Vec::new(),
+            )?;
+            mount.mounted = true;
+            if mount.readonly {
+                self.do_remount(&mount.target, true)?;
+            }
+
+            info!("Re-bind mounted {:?} -> {:?}", mount.source, mount.target);
+        }
+
+        Ok(())
+    }
+
+    /// Switch the managed bind mount at `target` between read-only and read-write.
+    ///
+    /// `target` is either the path given in the [`BindMount`] spec, e.g. `/volume`, or the
+    /// resulting path under the mount point. The new state is kept and restored by
+    /// [`reapply_bind_mounts`](Self::reapply_bind_mounts). Fails with `NotFound` if no managed
+    /// bind mount has this target.
+    pub async fn set_target_readonly<P: AsRef<Path>>(
+        &self,
+        target: P,
+        readonly: bool,
+    ) -> Result<()> {
+        let target = target.as_ref();
+        let target_path = self.target_path(target);
+        let mut mounts = self.mounts.lock().await;
+        let mount = mounts
+            .iter_mut()
+            .find(|m| m.target == target || m.target == target_path)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("{:?} is not a managed bind mount", target),
+                )
+            })?;
+
+        if mount.mounted {
+            self.do_remount(&mount.target, readonly)?;
+        }
+        mount.readonly = readonly;
+
+        info!(
+            "Bind mount {:?} is now {}",
+            mount.target,
+            if readonly { "read-only" } else { "read-write" }
+        );
+        Ok(())
+    }
+
+    /// Whether the managed bind mount at `target` is read-only, or `None` if it isn't managed.
+    pub async fn target_readonly<P: AsRef<Path>>(&self, target: P) -> Option<bool> {
+        let target = target.as_ref();
+        let target_path = self.target_path(target);
+        let mounts = self.mounts.lock().await;
+        mounts
+            .iter()
+            .find(|m| m.target == target || m.target == target_path)
+            .map(|m| m.readonly)
+    }
+
+    /// The targets of the managed bind mounts which are mounted, as paths under the mount point,
+    /// in the order they were mounted.
+    pub async fn mounted_targets(&self) -> Vec<PathBuf> {
+        let mounts = self.mounts.lock().await;
+        mounts
+            .iter()
+            .filter(|m| m.mounted)
+            .map(|m| m.target.clone())
+            .collect()
+    }
+
+    /// Whether the managed bind mount at `target` is mounted, `false` if it isn't managed.
+    /// `target` is the path given in the [`BindMount`] spec or the path under the mount point.
+    pub async fn is_mounted<P: AsRef<Path>>(&self, target: P) -> bool {
+        let target = target.as_ref();
+        let target_path = self.target_path(target);
+        let mounts = self.mounts.lock().await;
+        mounts
+            .iter()
+            .any(|m| m.mounted && (m.target == target || m.target == target_path))
+    }
+
+    /// Unmount the managed bind mount at `target` and stop managing it. `target` is the path
+    /// given in the [`BindMount`] spec or the path under the mount point. Fails with `NotFound`
+    /// if no managed bind mount has this target.
+    pub async fn unmount<P: AsRef<Path>>(&self, target: P) -> Result<()> {
+        let target = target.as_ref();
+        let target_path = self.target_path(target);
+        let mut mounts = self.mounts.lock().await;
+        let index = mounts
+            .iter()
+            .position(|m| m.target == target || m.target == target_path)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("{:?} is not a managed bind mount", target),
+                )
+            })?;
+
+        if mounts[index].mounted {
+            self.do_unmount(&mounts[index].target)?;
+        }
+        let mount = mounts.remove(index);
+        info!("Unmounted {:?}", mount.target);
+        Ok(())
+    }
+
+    /// The path under the mount point a bind mount `target` ends up at.
+    fn target_path(&self, target: &Path) -> PathBuf {
+        self.mountpoint
+            .join(target.strip_prefix("/").unwrap_or(target))
+    }
+
+    /// Create the target if needed and bind mount `source` onto it. The paths created for the
+    /// target, parents first, are appended to `created`.
+    fn mount_one(
+        &self,
+        source: &Path,
+        target_path: &Path,
+        propagation: Propagation,
+        recursive: bool,
+        created: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        // Check if source is a file or directory
+        let source_metadata = std::fs::metadata(source)?;
+        self.validate_mount_target(target_path)?;
+
+        if let Ok(target_metadata) = std::fs::metadata(target_path) {
+            if target_metadata.is_dir() != source_metadata.is_dir() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Cannot bind mount {:?} onto {:?}: one is a directory, the other is not",
+                        source, target_path
+                    ),
+                ));
+            }
+        } else {
+            let missing = target_path
+                .ancestors()
+                .take_while(|path| !path.exists())
+                .collect::<Vec<_>>();
+            created.extend(missing.into_iter().rev().map(Path::to_path_buf));
+            if source_metadata.is_file() {
+                // For file bind mounts, create parent directory and an empty file
+                if let Some(parent) = target_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                    debug!("Created parent directory: {:?}", parent);
+                }
+                std::fs::File::create(target_path)?;
+                debug!("Created target file: {:?}", target_path);
+            } else {
+                // For directory bind mounts, create the directory
+                std::fs::create_dir_all(target_path)?;
+                debug!("Created target directory: {:?}", target_path);
+            }
+        }
+
+        // Perform the bind mount
+        self.do_mount(source, target_path, propagation, recursive)
+    }
+
+    /// Check that `target_path` stays under the mount point, so that neither `..` in the target
+    /// nor a symlink under the mount point gets a target created or mounted over elsewhere.
+    fn validate_mount_target(&self, target_path: &Path) -> Result<()> {
+        let escapes = target_path
+            .strip_prefix(&self.mountpoint)
+            .map_or(true, |rel| {
+                rel.components().any(|c| !matches!(c, Component::Normal(_)))
+            });
+        if escapes || !resolve_path(target_path)?.starts_with(resolve_path(&self.mountpoint)?) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Bind mount target {:?} is outside of the mount point {:?}",
+                    target_path, self.mountpoint
+                ),
+            ));
+        }
+        Ok(())
+    }
+
     /// Perform the actual bind mount using mount(2) syscall
     #[cfg(target_os = "linux")]
-    fn do_mount(&self, source: &Path, target: &Path) -> Result<()> {
+    fn do_mount(
+        &self,
+        source: &Path,
+        target: &Path,
+        propagation: Propagation,
+        recursive: bool,
+    ) -> Result<()> {
         use std::ffi::CString;
 
         let source_cstr = CString::new(
@@ -125,13 +470,17 @@ impl BindMountManager {
         .map_err(|e| Error::other(format!("CString error: {}", e)))?;
 
         let fstype = CString::new("none").unwrap();
+        let mut flags = libc::MS_BIND;
+        if recursive {
+            flags |= libc::MS_REC;
+        }
 
         let ret = unsafe {
             libc::mount(
                 source_cstr.as_ptr(),
                 target_cstr.as_ptr(),
                 fstype.as_ptr(),
-                libc::MS_BIND | libc::MS_REC,
+                flags,
                 std::ptr::null(),
             )
         };
@@ -142,15 +491,20 @@ impl BindMountManager {
             return Err(err);
         }
 
-        // Prevent mount propagation issues by making the mount point a slave.
-        // This ensures that unmounting the target doesn't propagate back to the host/source
-        // if they are part of a shared subtree (which is common on modern Linux).
+        // Set the propagation, slave by default. This ensures that unmounting the target doesn't
+        // propagate back to the host/source if they are part of a shared subtree (which is
+        // common on modern Linux).
+        let propagation = match propagation {
+            Propagation::Slave => libc::MS_SLAVE,
+            Propagation::Private => libc::MS_PRIVATE,
+            Propagation::Shared => libc::MS_SHARED,
+        };
         let ret = unsafe {
             libc::mount(
                 std::ptr::null(),
                 target_cstr.as_ptr(),
                 std::ptr::null(),
-                libc::MS_SLAVE | libc::MS_REC,
+                propagation | libc::MS_REC,
                 std::ptr::null(),
             )
         };
@@ -166,8 +520,79 @@ impl BindMountManager {
         Ok(())
     }
 
+    /// Change the read-only flag of the existing bind mount at `target`.
+    #[cfg(target_os = "linux")]
+    fn do_remount(&self, target: &Path, readonly: bool) -> Result<()> {
+        use std::ffi::CString;
+
+        let target_cstr = CString::new(
+            target
+                .to_str()
+                .ok_or_else(|| Error::other(format!("Invalid target path: {:?}", target)))?,
+        )
+        .map_err(|e| Error::other(format!("CString error: {}", e)))?;
+
+        // Keep the other per-mount flags. They can't be cleared in a user namespace if they
+        // were set outside of it, which would fail the remount.
+        let mut flags = libc::MS_REMOUNT | libc::MS_BIND;
+        let current = mount_flags(&target_cstr)?;
+        for (st, ms) in [
+            (libc::ST_NOSUID, libc::MS_NOSUID),
+            (libc::ST_NODEV, libc::MS_NODEV),
+            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+            (libc::ST_NOATIME, libc::MS_NOATIME),
+            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+            (libc::ST_RELATIME, libc::MS_RELATIME),
+        ] {
+            if current & st != 0 {
+                flags |= ms;
+            }
+        }
+        if readonly {
+            flags |= libc::MS_RDONLY;
+        }
+
+        let ret = unsafe {
+            libc::mount(
+                std::ptr::null(),
+                target_cstr.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+
+        if ret != 0 {
+            let err = Error::last_os_error();
+            error!("Failed to remount {:?}: {}", target, err);
+            return Err(err);
+        }
+
+        if self.verify_readonly && (mount_flags(&target_cstr)? & libc::ST_RDONLY != 0) != readonly {
+            error!("Remount of {:?} didn't change its read-only flag", target);
+            return Err(Error::other(format!(
+                "Bind mount {:?} is still {} after remounting it",
+                target,
+                if readonly { "read-write" } else { "read-only" }
+            )));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn do_remount(&self, _target: &Path, _readonly: bool) -> Result<()> {
+        Err(Error::other("Bind mounts are not supported on macOS"))
+    }
+
     #[cfg(target_os = "macos")]
-    fn do_mount(&self, _source: &Path, _target: &Path) -> Result<()> {
+    fn do_mount(
+        &self,
+        _source: &Path,
+        _target: &Path,
+        _propagation: Propagation,
+        _recursive: bool,
+    ) -> Result<()> {
         // Bind mounts are not supported on non-Linux platforms yet
         Err(Error::other("Bind mounts are not supported on macOS"))
     }
@@ -200,9 +625,71 @@ impl BindMountManager {
         Ok(())
     }
 
+    /// Unmount all bind mounts in reverse order, giving each one until `timeout` after the call
+    /// to stop being busy.
+    ///
+    /// Unlike [`unmount_all`](Self::unmount_all), which detaches the mounts lazily right away,
+    /// a plain unmount is tried first and retried with exponential backoff while it fails with
+    /// `EBUSY`, so that short-lived users of a mount get a chance to close it. Mounts still busy
+    /// at the deadline are detached lazily.
+    pub async fn unmount_all_with_timeout(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut mounts = self.mounts.lock().await;
+        let mut errors = Vec::new();
+
+        while let Some(mut mount) = mounts.pop() {
+            if !mount.mounted {
+                continue;
+            }
+            let mut backoff = Duration::from_millis(10);
+            let res = loop {
+                match self.do_unmount_flags(&mount.target, 0) {
+                    Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            warn!("{:?} is still busy, detaching it", mount.target);
+                            break self.do_unmount(&mount.target);
+                        }
+                        debug!("{:?} is busy, retrying in {:?}", mount.target, backoff);
+                        tokio::time::sleep(backoff.min(deadline - now)).await;
+                        backoff *= 2;
+                    }
+                    res => break res,
+                }
+            };
+            if let Err(e) = res {
+                error!("Failed to unmount {:?}: {}", mount.target, e);
+                errors.push(e);
+            } else {
+                mount.mounted = false;
+                info!("Unmounted {:?}", mount.target);
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::other(format!(
+                "Failed to unmount {} bind mounts",
+                errors.len()
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Perform the actual unmount using umount(2) syscall
     #[cfg(target_os = "linux")]
     fn do_unmount(&self, target: &Path) -> Result<()> {
+        self.do_unmount_flags(target, libc::MNT_DETACH)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn do_unmount(&self, _target: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Unmount `target` with the umount2(2) `flags`.
+    #[cfg(target_os = "linux")]
+    fn do_unmount_flags(&self, target: &Path, flags: libc::c_int) -> Result<()> {
         use std::ffi::CString;
 
         let target_cstr = CString::new(
@@ -212,7 +699,7 @@ impl BindMountManager {
         )
         .map_err(|e| Error::other(format!("CString error: {}", e)))?;
 
-        let ret = unsafe { libc::umount2(target_cstr.as_ptr(), libc::MNT_DETACH) };
+        let ret = unsafe { libc::umount2(target_cstr.as_ptr(), flags) };
 
         if ret != 0 {
             let err = Error::last_os_error();
@@ -227,11 +714,72 @@ impl BindMountManager {
     }
 
     #[cfg(target_os = "macos")]
-    fn do_unmount(&self, _target: &Path) -> Result<()> {
+    fn do_unmount_flags(&self, _target: &Path, _flags: libc::c_int) -> Result<()> {
         Ok(())
     }
 }
 
+/// Check whether `path` is a mount point according to `/proc/self/mountinfo`.
+#[cfg(target_os = "linux")]
+fn is_mount_point(path: &Path) -> Result<bool> {
+    // mountinfo lists resolved paths.
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| Path::new(&unescape_mountinfo(mount_point)) == path))
+}
+
+#[cfg(target_os = "macos")]
+fn is_mount_point(_path: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// The `ST_*` flags of the mount at `path`.
+#[cfg(target_os = "linux")]
+fn mount_flags(path: &std::ffi::CStr) -> Result<libc::c_ulong> {
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::zeroed();
+    // Safe because this only writes to `st` and we check the return value.
+    if unsafe { libc::statvfs(path.as_ptr(), st.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // Safe because statvfs() succeeded.
+    Ok(unsafe { st.assume_init() }.f_flag)
+}
+
+/// Canonicalize the longest existing prefix of `path` and append the rest of it, which doesn't
+/// exist yet.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+    Ok(existing.canonicalize()?.join(rest))
+}
+
+/// Undo the octal escaping (`\040` for a space, ...) applied to paths in mountinfo.
+#[cfg(target_os = "linux")]
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(digits) = bytes.get(i + 1..i + 4)
+            && let Ok(c) = u8::from_str_radix(std::str::from_utf8(digits).unwrap_or(""), 8)
+        {
+            out.push(c);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 impl Drop for BindMountManager {
     fn drop(&mut self) {
         // Attempt to clean up on drop (synchronously)
@@ -250,6 +798,28 @@ impl Drop for BindMountManager {
 mod tests {
     use super::*;
 
+    #[cfg(target_os = "linux")]
+    fn mount(source: &str, target: &Path, fstype: &str, flags: libc::c_ulong) -> Result<()> {
+        use std::ffi::CString;
+
+        let cstr = |s: &str| CString::new(s).unwrap();
+        let (source, fstype) = (cstr(source), cstr(fstype));
+        let target = cstr(target.to_str().unwrap());
+        let ret = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_parse_bind_mount() {
         let bind = BindMount::parse("proc:/proc").unwrap();
@@ -263,12 +833,440 @@ mod tests {
         let bind = BindMount::parse("sys:/sys").unwrap();
         assert_eq!(bind.source, PathBuf::from("/sys"));
         assert_eq!(bind.target, PathBuf::from("/sys"));
+        assert!(!bind.recursive);
+
+        let bind = BindMount::parse("/host/path:/container/path:rec").unwrap();
+        assert_eq!(bind.source, PathBuf::from("/host/path"));
+        assert_eq!(bind.target, PathBuf::from("/container/path"));
+        assert!(bind.recursive);
+    }
+
+    #[test]
+    fn test_bind_mounts_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("binds.json");
+        std::fs::write(
+            &path,
+            r#"[
+                {"source": "/host/data", "target": "/data", "propagation": "private",
+                 "recursive": true, "readonly": true},
+                {"source": "host/logs", "target": "logs"}
+            ]"#,
+        )
+        .unwrap();
+        let binds = BindMount::load_from_file(&path).unwrap();
+        assert_eq!(
+            binds,
+            [
+                BindMount {
+                    source: PathBuf::from("/host/data"),
+                    target: PathBuf::from("/data"),
+                    propagation: Propagation::Private,
+                    recursive: true,
+                    readonly: true,
+                },
+                BindMount::parse("/host/logs:logs").unwrap(),
+            ]
+        );
+
+        // What is saved loads back the same.
+        let saved = temp.path().join("saved.json");
+        BindMount::save_to_file(&saved, &binds).unwrap();
+        assert_eq!(BindMount::load_from_file(&saved).unwrap(), binds);
+
+        for (json, error) in [
+            (
+                r#"[{"source": "/a", "target": "/x"}, {"source": "/b", "target": "x"}]"#,
+                "duplicate target",
+            ),
+            (r#"[{"source": "../etc", "target": "/x"}]"#, "`..`"),
+            (r#"[{"source": "/a", "target": "/x/../../y"}]"#, "`..`"),
+            (r#"[{"source": "/a"}]"#, "missing field `target`"),
+            (
+                r#"[{"source": "/a", "target": "/x", "propagation": "up"}]"#,
+                "unknown variant",
+            ),
+        ] {
+            std::fs::write(&path, json).unwrap();
+            let err = BindMount::load_from_file(&path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{json}");
+            assert!(err.to_string().contains(error), "{err}");
+        }
     }
 
     #[test]
     fn test_invalid_bind_mount() {
         assert!(BindMount::parse("invalid").is_err());
         assert!(BindMount::parse("too:many:colons").is_err());
+        assert!(BindMount::parse("too:many:colons:rec").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_unescape_mountinfo() {
+        assert_eq!(unescape_mountinfo("/mnt/a\\040b"), "/mnt/a b");
+        assert_eq!(unescape_mountinfo("/mnt/tab\\011"), "/mnt/tab\t");
+        assert_eq!(unescape_mountinfo("/plain"), "/plain");
+        assert_eq!(unescape_mountinfo("/trailing\\04"), "/trailing\\04");
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_reapply_bind_mounts() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("data"), b"hello").unwrap();
+        let mountpoint = temp.path().join("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+
+        let manager = BindMountManager::new(&mountpoint);
+        let bind = BindMount {
+            source: source.clone(),
+            target: PathBuf::from("/volume"),
+            propagation: Propagation::default(),
+            recursive: true,
+            readonly: false,
+        };
+        crate::unwrap_or_skip_eperm!(manager.mount_all(&[bind]).await, "bind mount");
+        let target = mountpoint.join("volume");
+        assert!(is_mount_point(&target).unwrap());
+        assert_eq!(std::fs::read(target.join("data")).unwrap(), b"hello");
+
+        // Tearing down what is under the mount point drops the bind mounts stacked on it.
+        manager.do_unmount(&target).unwrap();
+        assert!(!is_mount_point(&target).unwrap());
+        assert!(!target.join("data").exists());
+
+        manager.reapply_bind_mounts().await.unwrap();
+        assert!(is_mount_point(&target).unwrap());
+        assert_eq!(std::fs::read(target.join("data")).unwrap(), b"hello");
+
+        // Already active mounts are left alone.
+        manager.reapply_bind_mounts().await.unwrap();
+        manager.do_unmount(&target).unwrap();
+        assert!(!is_mount_point(&target).unwrap());
+
+        manager.unmount_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_set_target_readonly() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        let mountpoint = temp.path().join("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+
+        let manager = BindMountManager::new(&mountpoint);
+        let bind = BindMount {
+            source: source.clone(),
+            target: PathBuf::from("/volume"),
+            propagation: Propagation::default(),
+            recursive: true,
+            readonly: false,
+        };
+        crate::unwrap_or_skip_eperm!(manager.mount_all(&[bind]).await, "bind mount");
+        let target = mountpoint.join("volume");
+        std::fs::write(target.join("rw"), b"rw").unwrap();
+        assert_eq!(manager.target_readonly("/volume").await, Some(false));
+
+        manager.set_target_readonly("/volume", true).await.unwrap();
+        assert_eq!(manager.target_readonly(&target).await, Some(true));
+        let err = std::fs::write(target.join("ro"), b"ro").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+        // Only the bind mount is read-only, not its source.
+        std::fs::write(source.join("ro"), b"ro").unwrap();
+
+        // The state survives a reapply.
+        manager.do_unmount(&target).unwrap();
+        manager.reapply_bind_mounts().await.unwrap();
+        let err = std::fs::write(target.join("ro"), b"ro").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+
+        manager.set_target_readonly(&target, false).await.unwrap();
+        std::fs::write(target.join("rw"), b"rw again").unwrap();
+
+        let err = manager
+            .set_target_readonly("/unknown", true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(manager.target_readonly("/unknown").await, None);
+
+        manager.unmount_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_mount_all_rolls_back() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("data"), b"hello").unwrap();
+        let mountpoint = temp.path().join("mnt");
+        std::fs::create_dir_all(mountpoint.join("existing")).unwrap();
+        let bind = |source: &Path, target: &str| BindMount {
+            source: source.to_path_buf(),
+            target: PathBuf::from(target),
+            propagation: Propagation::default(),
+            recursive: true,
+            readonly: false,
+        };
+
+        let manager = BindMountManager::new(&mountpoint);
+        crate::unwrap_or_skip_eperm!(
+            manager.mount_all(&[bind(&source, "/existing")]).await,
+            "bind mount"
+        );
+        manager.unmount_all().await.unwrap();
+
+        let err = manager
+            .mount_all(&[
+                bind(&source, "/existing"),
+                bind(&source, "/deep/volume"),
+                bind(Path::new("/nonexistent"), "/other"),
+            ])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for target in ["existing", "deep/volume"] {
+            assert!(
+                !is_mount_point(&mountpoint.join(target)).unwrap(),
+                "{target}"
+            );
+            assert_eq!(manager.target_readonly(format!("/{target}")).await, None);
+        }
+        // Only what the call created is removed.
+        assert!(mountpoint.join("existing").is_dir());
+        assert!(!mountpoint.join("deep").exists());
+        assert_eq!(std::fs::read(source.join("data")).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_bind_mount_propagation() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir_all(source.join("from_source")).unwrap();
+        std::fs::create_dir_all(source.join("from_target")).unwrap();
+        let source_str = source.to_str().unwrap();
+        // Make the source a shared mount, as on most hosts, for propagation to show.
+        crate::unwrap_or_skip_eperm!(
+            mount(source_str, &source, "none", libc::MS_BIND),
+            "bind mount"
+        );
+        mount("none", &source, "none", libc::MS_SHARED).unwrap();
+        let manager = BindMountManager::new(temp.path().join("mnt"));
+
+        // (propagation, target, whether mounts go source -> target, target -> source)
+        for (propagation, name, to_target, to_source) in [
+            (Propagation::Private, "private", false, false),
+            (Propagation::Slave, "slave", true, false),
+            (Propagation::Shared, "shared", true, true),
+        ] {
+            let bind = BindMount::parse(&format!("{source_str}:/{name}"))
+                .unwrap()
+                .with_propagation(propagation);
+            manager.mount_all(&[bind]).await.unwrap();
+            let target = temp.path().join("mnt").join(name);
+
+            mount("tmpfs", &target.join("from_target"), "tmpfs", 0).unwrap();
+            mount("tmpfs", &source.join("from_source"), "tmpfs", 0).unwrap();
+            assert_eq!(
+                is_mount_point(&target.join("from_source")).unwrap(),
+                to_target,
+                "{name}"
+            );
+            assert_eq!(
+                is_mount_point(&source.join("from_target")).unwrap(),
+                to_source,
+                "{name}"
+            );
+
+            for path in [
+                target.join("from_target"),
+                target.join("from_source"),
+                source.join("from_source"),
+                source.join("from_target"),
+            ] {
+                manager.do_unmount(&path).unwrap();
+            }
+            manager.unmount_all().await.unwrap();
+        }
+        manager.do_unmount(&source).unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_bind_mount_recursive() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        crate::unwrap_or_skip_eperm!(
+            mount("tmpfs", &source.join("sub"), "tmpfs", 0),
+            "tmpfs mount"
+        );
+        std::fs::write(source.join("sub/data"), b"hello").unwrap();
+        let manager = BindMountManager::new(temp.path().join("mnt"));
+
+        for (recursive, name) in [(false, "flat"), (true, "rec")] {
+            let bind = BindMount::parse(&format!("{}:/{name}", source.display()))
+                .unwrap()
+                .with_recursive(recursive);
+            manager.mount_all(&[bind]).await.unwrap();
+            let target = temp.path().join("mnt").join(name);
+            assert_eq!(is_mount_point(&target.join("sub")).unwrap(), recursive);
+            assert_eq!(target.join("sub/data").exists(), recursive);
+        }
+
+        manager.unmount_all().await.unwrap();
+        manager.do_unmount(&source.join("sub")).unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_mounted_targets() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        let mountpoint = temp.path().join("mnt");
+        let manager = BindMountManager::new(&mountpoint);
+        let binds = ["/one", "/two"]
+            .map(|target| BindMount::parse(&format!("{}:{target}", source.display())).unwrap());
+        crate::unwrap_or_skip_eperm!(manager.mount_all(&binds).await, "bind mount");
+        let (one, two) = (mountpoint.join("one"), mountpoint.join("two"));
+        assert_eq!(manager.mounted_targets().await, [one.clone(), two.clone()]);
+        assert!(manager.is_mounted("/one").await);
+        assert!(manager.is_mounted(&two).await);
+        assert!(!manager.is_mounted("/three").await);
+
+        manager.unmount("/one").await.unwrap();
+        assert!(!is_mount_point(&one).unwrap());
+        assert_eq!(manager.mounted_targets().await, [two.as_path()]);
+        assert!(!manager.is_mounted(&one).await);
+        assert!(manager.is_mounted("/two").await);
+        let err = manager.unmount("/one").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        manager.unmount_all().await.unwrap();
+        assert!(!is_mount_point(&two).unwrap());
+        assert!(manager.mounted_targets().await.is_empty());
+        assert!(!manager.is_mounted("/two").await);
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_unmount_all_with_timeout() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("data"), b"hello").unwrap();
+        let manager = BindMountManager::new(temp.path().join("mnt"));
+        let target = temp.path().join("mnt/volume");
+        let mount = || BindMount::parse(&format!("{}:/volume", source.display())).unwrap();
+        crate::unwrap_or_skip_eperm!(manager.mount_all(&[mount()]).await, "bind mount");
+
+        // The open file keeps the mount busy until it is closed during the backoff.
+        let file = std::fs::File::open(target.join("data")).unwrap();
+        let start = std::time::Instant::now();
+        let (res, ()) = tokio::join!(
+            manager.unmount_all_with_timeout(Duration::from_secs(10)),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                drop(file);
+            }
+        );
+        res.unwrap();
+        // Detaching would have taken place right away or after the full timeout.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(10), "{elapsed:?}");
+        assert!(!is_mount_point(&target).unwrap());
+
+        // A mount busy past the deadline is detached.
+        manager.mount_all(&[mount()]).await.unwrap();
+        let file = std::fs::File::open(target.join("data")).unwrap();
+        manager
+            .unmount_all_with_timeout(Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(!is_mount_point(&target).unwrap());
+        drop(file);
+    }
+
+    #[tokio::test]
+    #[cfg(target_os = "linux")]
+    async fn test_readonly_keeps_mount_flags() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        crate::unwrap_or_skip_eperm!(
+            mount(
+                "tmpfs",
+                &source,
+                "tmpfs",
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC
+            ),
+            "tmpfs mount"
+        );
+        let manager = BindMountManager::new(temp.path().join("mnt"));
+        let bind = BindMount::parse(&format!("{}:/volume", source.display())).unwrap();
+        manager.mount_all(&[bind]).await.unwrap();
+        let target = temp.path().join("mnt/volume");
+        let target_cstr = std::ffi::CString::new(target.to_str().unwrap()).unwrap();
+        let kept = libc::ST_NOSUID | libc::ST_NODEV | libc::ST_NOEXEC;
+
+        for readonly in [true, false] {
+            manager
+                .set_target_readonly("/volume", readonly)
+                .await
+                .unwrap();
+            let flags = mount_flags(&target_cstr).unwrap();
+            assert_eq!(flags & kept, kept);
+            assert_eq!(flags & libc::ST_RDONLY != 0, readonly);
+            let res = std::fs::write(target.join("file"), b"data");
+            if readonly {
+                assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EROFS));
+            } else {
+                res.unwrap();
+            }
+        }
+
+        manager.unmount_all().await.unwrap();
+        manager.do_unmount(&source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mount_target_confined() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("file"), b"hello").unwrap();
+        let mountpoint = temp.path().join("mnt");
+        std::fs::create_dir_all(mountpoint.join("dir")).unwrap();
+        std::fs::create_dir(temp.path().join("outside")).unwrap();
+        std::os::unix::fs::symlink(temp.path().join("outside"), mountpoint.join("link")).unwrap();
+        let manager = BindMountManager::new(&mountpoint);
+
+        for target in ["/../escape", "/new/../../escape", "/link/escape"] {
+            let bind = BindMount::parse(&format!("{}:{target}", source.display())).unwrap();
+            let err = manager.mount_all(&[bind]).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{target}");
+        }
+        assert!(!temp.path().join("escape").exists());
+        assert!(!temp.path().join("outside/escape").exists());
+        assert!(!mountpoint.join("new").exists());
+
+        // The source and an existing target must both be directories, or both not be.
+        let bind = BindMount::parse(&format!("{}:/dir", source.join("file").display())).unwrap();
+        let err = manager.mount_all(&[bind]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        std::fs::write(mountpoint.join("file"), b"").unwrap();
+        let bind = BindMount::parse(&format!("{}:/file", source.display())).unwrap();
+        let err = manager.mount_all(&[bind]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
     }
 
     #[tokio::test]
@@ -287,6 +1285,9 @@ mod tests {
         let bind = BindMount {
             source: source.clone(),
             target: std::path::PathBuf::from("mnt"),
+            propagation: Propagation::default(),
+            recursive: true,
+            readonly: false,
         };
 
         let result = manager.mount_all(&[bind]).await;
@@ -11,6 +11,10 @@ pub struct IdMapEntry {
     pub len: u32,
 }
 
+/// The id of `nobody` and `nogroup`, which ids outside the mapped ranges map to when the
+/// kernel's overflow ids can't be read.
+const DEFAULT_OVERFLOW_ID: u32 = 65534;
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct IdMappings {
     pub uid_map: Vec<IdMapEntry>,
@@ -29,11 +33,11 @@ impl IdMappings {
         let overflow_uid = fs::read_to_string("/proc/sys/kernel/overflowuid")
             .ok()
             .and_then(|s| s.trim().parse().ok())
-            .unwrap_or_default();
+            .unwrap_or(DEFAULT_OVERFLOW_ID);
         let overflow_gid = fs::read_to_string("/proc/sys/kernel/overflowgid")
             .ok()
             .and_then(|s| s.trim().parse().ok())
-            .unwrap_or_default();
+            .unwrap_or(DEFAULT_OVERFLOW_ID);
         IdMappings {
             uid_map,
             gid_map,
@@ -83,7 +87,8 @@ impl IdMappings {
 
     /// Finds the mapped ID based on the provided mappings.
     ///
-    /// If no mapping is found, returns the original ID.
+    /// If the mappings are empty, returns the original ID. An ID outside all the mapped ranges
+    /// maps to the overflow ID, `nobody` or `nogroup`.
     ///
     /// - `direct` is `true`: Reverse mapping (Host -> Container).
     /// - `direct` is `false`: Forward mapping (Container -> Host).
@@ -93,16 +98,18 @@ impl IdMappings {
             return id;
         }
         for entry in map {
-            if direct {
+            let (from, to) = if direct {
                 // Reverse mapping: check if id is in host range
-                if id >= entry.host && id < entry.host + entry.len {
-                    return entry.to + (id - entry.host);
-                }
+                (entry.host, entry.to)
             } else {
                 // Forward mapping: check if id is in container range
-                if id >= entry.to && id < entry.to + entry.len {
-                    return entry.host + (id - entry.to);
-                }
+                (entry.to, entry.host)
+            };
+            // A range may end at the top of the id space, so `from + len` could overflow.
+            if let Some(offset) = id.checked_sub(from)
+                && offset < entry.len
+            {
+                return to + offset;
             }
         }
 
@@ -185,4 +192,42 @@ mod tests {
         assert_eq!(id_mappings.gid_map[1].to, 65534);
         assert_eq!(id_mappings.gid_map[1].len, 1);
     }
+
+    #[test]
+    fn test_find_mapping_boundaries() {
+        let id_mappings: IdMappings = "uidmapping=100000:0:1000:4294967290:2000:6,gidmapping=5:5:1"
+            .parse()
+            .unwrap();
+        let overflow_uid = id_mappings.overflow_uid;
+        assert_ne!(overflow_uid, 0);
+
+        // Container -> host, at both ends of each range and just outside.
+        assert_eq!(id_mappings.get_uid(0), 100000);
+        assert_eq!(id_mappings.get_uid(999), 100999);
+        assert_eq!(id_mappings.get_uid(1000), overflow_uid);
+        assert_eq!(id_mappings.get_uid(1999), overflow_uid);
+        assert_eq!(id_mappings.get_uid(2000), 4294967290);
+        assert_eq!(id_mappings.get_uid(2005), u32::MAX);
+        assert_eq!(id_mappings.get_uid(2006), overflow_uid);
+
+        // Host -> container, including a range ending at the top of the id space.
+        assert_eq!(id_mappings.find_mapping(99999, true, true), overflow_uid);
+        assert_eq!(id_mappings.find_mapping(100000, true, true), 0);
+        assert_eq!(id_mappings.find_mapping(100999, true, true), 999);
+        assert_eq!(id_mappings.find_mapping(101000, true, true), overflow_uid);
+        assert_eq!(
+            id_mappings.find_mapping(4294967289, true, true),
+            overflow_uid
+        );
+        assert_eq!(id_mappings.find_mapping(u32::MAX, true, true), 2005);
+
+        assert_eq!(id_mappings.get_gid(5), 5);
+        assert_eq!(id_mappings.get_gid(4), id_mappings.overflow_gid);
+        assert_eq!(id_mappings.get_gid(6), id_mappings.overflow_gid);
+
+        // Without mappings ids are passed through.
+        let id_mappings = IdMappings::default();
+        assert_eq!(id_mappings.get_uid(1234), 1234);
+        assert_eq!(id_mappings.find_mapping(u32::MAX, true, false), u32::MAX);
+    }
 }
@@ -60,16 +60,23 @@ impl Display for GPath {
     }
 }
 
+/// Build a timestamp from the seconds and nanoseconds fields of a stat. Nanoseconds out of the
+/// `0..1_000_000_000` range, as a corrupt inode or a foreign filesystem may report, are clamped
+/// into it rather than trusted.
+pub fn timestamp_from_stat(sec: i64, nsec: i64) -> Timestamp {
+    Timestamp::new(sec, nsec.clamp(0, 999_999_999) as u32)
+}
+
 pub fn convert_stat64_to_file_attr(stat: stat64) -> FileAttr {
     FileAttr {
         ino: stat.st_ino,
         size: stat.st_size as u64,
         blocks: stat.st_blocks as u64,
-        atime: Timestamp::new(stat.st_atime, stat.st_atime_nsec.try_into().unwrap()),
-        mtime: Timestamp::new(stat.st_mtime, stat.st_mtime_nsec.try_into().unwrap()),
-        ctime: Timestamp::new(stat.st_ctime, stat.st_ctime_nsec.try_into().unwrap()),
+        atime: timestamp_from_stat(stat.st_atime, stat.st_atime_nsec),
+        mtime: timestamp_from_stat(stat.st_mtime, stat.st_mtime_nsec),
+        ctime: timestamp_from_stat(stat.st_ctime, stat.st_ctime_nsec),
         #[cfg(target_os = "macos")]
-        crtime: Timestamp::new(0, 0), // Set crtime to 0 for non-macOS platforms
+        crtime: timestamp_from_stat(stat.st_birthtime, stat.st_birthtime_nsec),
         kind: filetype_from_mode(stat.st_mode as u32),
         perm: (stat.st_mode & 0o7777) as u16,
         nlink: stat.st_nlink as u32,
@@ -116,9 +123,26 @@ pub fn filetype_from_mode(st_mode: u32) -> FileType {
     error!("wrong st mode : {st_mode}");
     unreachable!();
 }
+
+/// The file type a directory entry's `d_type` stands for. `None` for `DT_UNKNOWN`, which some
+/// filesystems report for every entry, and for values outside the known set, in which cases the
+/// type has to come from a stat of the entry.
+pub fn d_type_to_filetype(d_type: u8) -> Option<FileType> {
+    match d_type {
+        libc::DT_FIFO => Some(FileType::NamedPipe),
+        libc::DT_CHR => Some(FileType::CharDevice),
+        libc::DT_DIR => Some(FileType::Directory),
+        libc::DT_BLK => Some(FileType::BlockDevice),
+        libc::DT_REG => Some(FileType::RegularFile),
+        libc::DT_LNK => Some(FileType::Symlink),
+        libc::DT_SOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::GPath;
+    use super::{FileType, GPath, d_type_to_filetype, timestamp_from_stat};
 
     #[test]
     fn test_from_string() {
@@ -126,4 +150,33 @@ mod tests {
         let gapth = GPath::from(path);
         assert_eq!(gapth.to_string(), String::from("release"))
     }
+
+    #[test]
+    fn test_d_type_to_filetype() {
+        for (d_type, kind) in [
+            (libc::DT_FIFO, FileType::NamedPipe),
+            (libc::DT_CHR, FileType::CharDevice),
+            (libc::DT_DIR, FileType::Directory),
+            (libc::DT_BLK, FileType::BlockDevice),
+            (libc::DT_REG, FileType::RegularFile),
+            (libc::DT_LNK, FileType::Symlink),
+            (libc::DT_SOCK, FileType::Socket),
+        ] {
+            assert_eq!(d_type_to_filetype(d_type), Some(kind));
+        }
+        assert_eq!(d_type_to_filetype(libc::DT_UNKNOWN), None);
+        assert_eq!(d_type_to_filetype(0xff), None);
+    }
+
+    #[test]
+    fn test_timestamp_from_stat() {
+        let ts = timestamp_from_stat(1_700_000_000, 123_456_789);
+        assert_eq!((ts.sec, ts.nsec), (1_700_000_000, 123_456_789));
+        let ts = timestamp_from_stat(1_700_000_000, 5_000_000_000);
+        assert_eq!((ts.sec, ts.nsec), (1_700_000_000, 999_999_999));
+        let ts = timestamp_from_stat(-1, -1);
+        assert_eq!((ts.sec, ts.nsec), (-1, 0));
+        let ts = timestamp_from_stat(i64::MAX, i64::MIN);
+        assert_eq!((ts.sec, ts.nsec), (i64::MAX, 0));
+    }
 }
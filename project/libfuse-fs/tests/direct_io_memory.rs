@@ -0,0 +1,113 @@
+//! Checks that `pwrite_direct()` copies large writes through bounded memory.
+//!
+//! Counting allocations takes a `#[global_allocator]`, which would apply to every unit test if
+//! it was declared in the library, so this test is a binary of its own.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+
+use libfuse_fs::passthrough::DirectIoWrites;
+use libfuse_fs::passthrough::async_io::O_DIRECT;
+use libfuse_fs::passthrough::util::{DIRECT_IO_CHUNK, DIRECT_IO_MEM_ALIGN, pwrite_direct};
+
+/// Counts the bytes each thread has allocated.
+struct CountingAlloc;
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Safe because the caller upholds the contract of `alloc()`.
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let _ = LIVE.try_with(|live| {
+                live.set(live.get() + layout.size() as isize);
+                let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+            });
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Safe because the caller upholds the contract of `dealloc()`.
+        unsafe { System.dealloc(ptr, layout) };
+        let _ = LIVE.try_with(|live| live.set(live.get() - layout.size() as isize));
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Run `f` and return the most memory it had allocated on this thread at any time.
+fn peak_allocated<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    LIVE.set(0);
+    PEAK.set(0);
+    let res = f();
+    (res, PEAK.get().max(0) as usize)
+}
+
+#[test]
+fn test_pwrite_direct_bounded_memory() {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let path = tmp_dir.path().join("file");
+    let file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .custom_flags(O_DIRECT)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("skip test_pwrite_direct_bounded_memory: {e}");
+            return;
+        }
+    };
+    let fd = file.as_raw_fd();
+    const LEN: usize = 32 * 1024 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+    // An aligned write and one covering partial blocks at both ends.
+    for (offset, len, mode) in [
+        (0, LEN, DirectIoWrites::Strict),
+        (4096 + 100, LEN - 300, DirectIoWrites::Compat),
+    ] {
+        let (written, peak) =
+            peak_allocated(|| pwrite_direct(fd, &data[..len], offset as u64, 4096, mode));
+        match written {
+            Ok(written) => assert_eq!(written, len),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && offset == 0 => {
+                eprintln!("skip test_pwrite_direct_bounded_memory: no direct I/O");
+                return;
+            }
+            Err(e) => panic!("pwrite_direct failed: {e}"),
+        }
+        assert!(
+            peak <= DIRECT_IO_CHUNK + 2 * DIRECT_IO_MEM_ALIGN,
+            "peak {peak}"
+        );
+
+        let mut content = vec![0u8; len];
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_exact_at(&mut content, offset as u64)
+            .unwrap();
+        assert!(content == data[..len], "offset {offset}");
+    }
+    // The data before the unaligned write was kept.
+    let mut head = vec![0u8; 4096 + 100];
+    std::fs::File::open(&path)
+        .unwrap()
+        .read_exact_at(&mut head, 0)
+        .unwrap();
+    assert!(head == data[..4096 + 100]);
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().len(),
+        (4096 + 100 + LEN - 300) as u64
+    );
+}
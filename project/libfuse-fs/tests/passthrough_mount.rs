@@ -0,0 +1,186 @@
+//! Mounts `PassthroughFs` over a temporary directory and checks that real syscalls on the
+//! mountpoint reach the host directory.
+//!
+//! Needs `/dev/fuse` and either root or a working `fusermount3`. Where mounting isn't permitted
+//! the test is skipped, unless `RUN_PRIVILEGED_TESTS=1` is set.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use libfuse_fs::passthrough::{Config, PassthroughFs};
+use rfuse3::MountOptions;
+use rfuse3::raw::Session;
+
+/// Detaches the mount on drop, in case the test panics before unmounting.
+struct MountGuard(PathBuf);
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        let Ok(path) = CString::new(self.0.as_os_str().as_bytes()) else {
+            return;
+        };
+        // Safe because `path` is a valid C string. Fails harmlessly if already unmounted.
+        if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+            let _ = std::process::Command::new("fusermount3")
+                .arg("-uz")
+                .arg(&self.0)
+                .output();
+        }
+    }
+}
+
+fn skip_or_panic(ctx: &str, err: io::Error) {
+    let denied = matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM | libc::EACCES | libc::ENOENT)
+    ) || err.kind() == io::ErrorKind::PermissionDenied;
+    if !denied || std::env::var("RUN_PRIVILEGED_TESTS").as_deref() == Ok("1") {
+        panic!("{ctx} failed: {err:?}");
+    }
+    eprintln!("skip {ctx}: {err:?}");
+}
+
+fn getxattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let mut buf = vec![0u8; 256];
+    // Safe because the kernel only writes up to `buf.len()` bytes into `buf`.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+fn setxattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    // Safe because the kernel only reads `value.len()` bytes from `value`.
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The file operations run against the mountpoint `mnt`, checked against the host directory
+/// `src` it exports.
+fn exercise(mnt: &Path, src: &Path) {
+    // create + write
+    std::fs::write(mnt.join("file"), b"hello world").unwrap();
+    assert_eq!(std::fs::read(src.join("file")).unwrap(), b"hello world");
+
+    // read what the host wrote
+    std::fs::write(src.join("host"), b"from the host").unwrap();
+    assert_eq!(std::fs::read(mnt.join("host")).unwrap(), b"from the host");
+
+    // stat
+    let md = std::fs::metadata(mnt.join("file")).unwrap();
+    let host_md = std::fs::metadata(src.join("file")).unwrap();
+    assert!(md.is_file());
+    assert_eq!(md.len(), 11);
+    assert_eq!(md.mode(), host_md.mode());
+    assert_eq!(md.mtime(), host_md.mtime());
+
+    // mkdir + readdir
+    std::fs::create_dir(mnt.join("dir")).unwrap();
+    assert!(src.join("dir").is_dir());
+    let mut names: Vec<_> = std::fs::read_dir(mnt)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["dir", "file", "host"]);
+
+    // rename
+    std::fs::rename(mnt.join("file"), mnt.join("dir/renamed")).unwrap();
+    assert!(!src.join("file").exists());
+    assert_eq!(
+        std::fs::read(src.join("dir/renamed")).unwrap(),
+        b"hello world"
+    );
+
+    // xattr, where the host filesystem has user xattrs
+    let renamed = mnt.join("dir/renamed");
+    match setxattr(&renamed, "user.test", b"value") {
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            eprintln!("no user xattrs on the host, skipping xattr checks");
+        }
+        res => {
+            res.unwrap();
+            assert_eq!(
+                getxattr(&src.join("dir/renamed"), "user.test").unwrap(),
+                b"value"
+            );
+            assert_eq!(getxattr(&renamed, "user.test").unwrap(), b"value");
+        }
+    }
+
+    // unlink + rmdir
+    std::fs::remove_file(&renamed).unwrap();
+    std::fs::remove_dir(mnt.join("dir")).unwrap();
+    std::fs::remove_file(mnt.join("host")).unwrap();
+    assert_eq!(std::fs::read_dir(src).unwrap().count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_passthrough_mount() {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let src = tmp_dir.path().join("src");
+    let mnt = tmp_dir.path().join("mnt");
+    std::fs::create_dir(&src).unwrap();
+    std::fs::create_dir(&mnt).unwrap();
+
+    let cfg = Config {
+        root_dir: src.clone(),
+        xattr: true,
+        ..Default::default()
+    };
+    let fs = PassthroughFs::<()>::new(cfg).unwrap();
+    if let Err(err) = fs.import().await {
+        return skip_or_panic("import", err);
+    }
+
+    let mut mount_options = MountOptions::default();
+    mount_options
+        .uid(unsafe { libc::getuid() })
+        .gid(unsafe { libc::getgid() });
+    let handle = match Session::new(mount_options).mount(fs, &mnt).await {
+        Ok(handle) => handle,
+        Err(err) => return skip_or_panic("mount", err),
+    };
+    let guard = MountGuard(mnt.clone());
+
+    // The syscalls block until the session answers, so they can't run on the runtime's own
+    // threads. A failed assertion surfaces as an error here, after which we still unmount.
+    let result = tokio::task::spawn_blocking({
+        let (mnt, src) = (mnt.clone(), src.clone());
+        move || exercise(&mnt, &src)
+    })
+    .await;
+    handle.unmount().await.unwrap();
+    drop(guard);
+
+    if let Err(err) = result {
+        std::panic::resume_unwind(err.into_panic());
+    }
+}
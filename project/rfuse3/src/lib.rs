@@ -235,10 +235,99 @@ impl From<SystemTime> for Timestamp {
     }
 }
 
+#[cfg(all(test, target_os = "linux", feature = "unprivileged"))]
+thread_local! {
+    /// The directories `find_fusermount3()` searches instead of `PATH` on this thread, so that a
+    /// test can hide the binary without changing the environment of the whole process.
+    pub(crate) static FUSERMOUNT3_SEARCH_PATH: std::cell::RefCell<Option<std::ffi::OsString>> =
+        const { std::cell::RefCell::new(None) };
+}
+
 #[cfg(all(target_os = "linux", feature = "unprivileged"))]
 fn find_fusermount3() -> io::Result<PathBuf> {
-    which::which("fusermount3")
-        .map_err(|err| io::Error::other(format!("find fusermount3 binary failed {err:?}")))
+    #[cfg(test)]
+    let found = match FUSERMOUNT3_SEARCH_PATH.with_borrow(Clone::clone) {
+        Some(paths) => which::which_in("fusermount3", Some(paths), "/"),
+        None => which::which("fusermount3"),
+    };
+    #[cfg(not(test))]
+    let found = which::which("fusermount3");
+    found.map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("find fusermount3 binary failed {err:?}"),
+        )
+    })
+}
+
+/// Explain why mounting through `fusermount3` failed with `err`: the binary is missing,
+/// `/dev/fuse` is absent, or the binary can't mount for other users, not being setuid root, and
+/// `/dev/fuse` isn't accessible to the caller either. Falls back to `err` itself when none of
+/// these is the cause.
+#[cfg(all(target_os = "linux", feature = "unprivileged"))]
+fn diagnose_unprivileged_mount(err: io::Error) -> io::Error {
+    use std::os::unix::fs::PermissionsExt;
+
+    const DEV_FUSE: &str = "/dev/fuse";
+
+    let fusermount = match find_fusermount3() {
+        Ok(path) => path,
+        Err(find_err) => {
+            return io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("fusermount3 is not installed or not in PATH ({find_err}), install fuse3"),
+            )
+        }
+    };
+    if !std::path::Path::new(DEV_FUSE).exists() {
+        return io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{DEV_FUSE} does not exist, is the fuse kernel module loaded?"),
+        );
+    }
+    // A setuid root fusermount3 opens /dev/fuse itself, whatever the caller may access.
+    let setuid = std::fs::metadata(&fusermount)
+        .map(|metadata| metadata.permissions().mode() & libc::S_ISUID != 0)
+        .unwrap_or(true);
+    if setuid {
+        return io::Error::new(err.kind(), format!("fusermount3 mount failed: {err}"));
+    }
+    if !nix::unistd::geteuid().is_root() {
+        return io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not setuid root", fusermount.display()),
+        );
+    }
+    if nix::unistd::access(
+        DEV_FUSE,
+        nix::unistd::AccessFlags::R_OK | nix::unistd::AccessFlags::W_OK,
+    )
+    .is_err()
+    {
+        return io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("no read and write permission on {DEV_FUSE}"),
+        );
+    }
+
+    io::Error::new(err.kind(), format!("fusermount3 mount failed: {err}"))
+}
+
+/// Whether the process may mount filesystems itself, that is, has `CAP_SYS_ADMIN` in its
+/// effective set.
+#[cfg(all(target_os = "linux", feature = "unprivileged"))]
+fn has_cap_sys_admin() -> bool {
+    const CAP_SYS_ADMIN: u32 = 21;
+
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let caps = status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))?;
+            u64::from_str_radix(caps.trim(), 16).ok()
+        })
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
 }
 
 #[cfg(target_os = "macos")]
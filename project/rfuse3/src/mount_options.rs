@@ -16,6 +16,8 @@ pub const DEFAULT_MAX_WRITE: u32 = 128 * 1024;
 pub struct MountOptions {
     // Options implemented within rfuse3
     pub(crate) nonempty: bool,
+    #[cfg(all(target_os = "linux", feature = "unprivileged"))]
+    pub(crate) privileged_fallback: bool,
 
     // mount options
     pub(crate) allow_other: bool,
@@ -49,6 +51,8 @@ pub struct MountOptions {
     pub(crate) write_back: bool,
     pub(crate) direct_io: bool,
     pub(crate) force_readdir_plus: bool,
+    pub(crate) cache_symlinks: bool,
+    pub(crate) atomic_o_trunc: bool,
 
     // FUSE transfer size options
     /// Maximum size of write requests. Default is 128KB.
@@ -65,6 +69,8 @@ impl Default for MountOptions {
     fn default() -> Self {
         Self {
             nonempty: false,
+            #[cfg(all(target_os = "linux", feature = "unprivileged"))]
+            privileged_fallback: false,
             allow_other: false,
             allow_root: false,
             custom_options: None,
@@ -94,6 +100,8 @@ impl Default for MountOptions {
             write_back: false,
             direct_io: false,
             force_readdir_plus: false,
+            cache_symlinks: true,
+            atomic_o_trunc: true,
             max_write: NonZeroU32::new(DEFAULT_MAX_WRITE).unwrap(),
             max_readahead: None,
             rootmode: None,
@@ -159,6 +167,15 @@ impl MountOptions {
         self
     }
 
+    /// when mounting without root permission through `fusermount3` fails, mount with root
+    /// permission instead if the process has `CAP_SYS_ADMIN`, default is disable.
+    #[cfg(all(target_os = "linux", feature = "unprivileged"))]
+    pub fn privileged_fallback(&mut self, privileged_fallback: bool) -> &mut Self {
+        self.privileged_fallback = privileged_fallback;
+
+        self
+    }
+
     /// set fuse filesystem `default_permissions` mount option, default is disable.
     ///
     /// When `default_permissions` is set, the [`raw::access`] and [`path::access`] is useless.
@@ -231,6 +248,35 @@ impl MountOptions {
         self
     }
 
+    /// try to set the `FUSE_CACHE_SYMLINKS` to let the kernel cache symlink targets in the page
+    /// cache, so repeated path resolution doesn't issue `readlink` every time, default is enable.
+    ///
+    /// # Notes:
+    ///
+    /// a cached target is dropped with the inode, or when its attributes change or it's
+    /// invalidated by [`Notify::invalid_inode`](crate::notify::Notify::invalid_inode). Since a
+    /// symlink's target can't be changed in place, a symlink replaced on the backing store gets
+    /// a new inode, which the kernel picks up once the entry times out.
+    pub fn cache_symlinks(&mut self, cache_symlinks: bool) -> &mut Self {
+        self.cache_symlinks = cache_symlinks;
+
+        self
+    }
+
+    /// try to set the `FUSE_ATOMIC_O_TRUNC` to let the kernel pass `O_TRUNC` on to
+    /// [`Filesystem::open`](crate::raw::Filesystem::open) instead of truncating with a separate
+    /// `setattr` first, default is enable.
+    ///
+    /// # Notes:
+    ///
+    /// when enabled, the filesystem must apply `O_TRUNC` itself while opening, otherwise files
+    /// opened with it keep their content.
+    pub fn atomic_o_trunc(&mut self, atomic_o_trunc: bool) -> &mut Self {
+        self.atomic_o_trunc = atomic_o_trunc;
+
+        self
+    }
+
     /// set custom options for fuse filesystem, the custom options will be used in mount
     pub fn custom_options(&mut self, custom_options: impl Into<OsString>) -> &mut Self {
         self.custom_options = Some(custom_options.into());
@@ -255,8 +301,9 @@ impl MountOptions {
         self
     }
 
-    /// Set the maximum readahead size. If not set, uses the kernel's default value.
-    /// Larger values can improve sequential read performance.
+    /// Set the maximum readahead size in bytes, sent to the kernel in the init reply. If not set,
+    /// uses the kernel's default value. Larger values can improve sequential read performance on
+    /// fast backing stores; values above the maximum reported by the kernel are clamped to it.
     ///
     /// # Example
     /// ```
@@ -13,7 +13,7 @@ use crate::helper::get_bincode_config;
 use crate::raw::abi::{
     fuse_notify_code, fuse_notify_delete_out, fuse_notify_inval_entry_out,
     fuse_notify_inval_inode_out, fuse_notify_poll_wakeup_out, fuse_notify_retrieve_out,
-    fuse_notify_store_out, fuse_out_header, FUSE_NOTIFY_DELETE_OUT_SIZE,
+    fuse_notify_store_out, fuse_out_header, FUSE_EXPIRE_ONLY, FUSE_NOTIFY_DELETE_OUT_SIZE,
     FUSE_NOTIFY_INVAL_ENTRY_OUT_SIZE, FUSE_NOTIFY_INVAL_INODE_OUT_SIZE,
     FUSE_NOTIFY_POLL_WAKEUP_OUT_SIZE, FUSE_NOTIFY_RETRIEVE_OUT_SIZE, FUSE_NOTIFY_STORE_OUT_SIZE,
     FUSE_OUT_HEADER_SIZE,
@@ -83,7 +83,11 @@ impl Notify {
                 Either::Left(data)
             }
 
-            NotifyKind::InvalidEntry { parent, name } => {
+            NotifyKind::InvalidEntry {
+                parent,
+                name,
+                expire_only,
+            } => {
                 let out_header = fuse_out_header {
                     len: (FUSE_OUT_HEADER_SIZE + FUSE_NOTIFY_INVAL_ENTRY_OUT_SIZE) as u32,
                     error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
@@ -93,7 +97,7 @@ impl Notify {
                 let invalid_entry_out = fuse_notify_inval_entry_out {
                     parent: *parent,
                     namelen: name.len() as _,
-                    _padding: 0,
+                    flags: if *expire_only { FUSE_EXPIRE_ONLY } else { 0 },
                 };
 
                 let mut data =
@@ -226,7 +230,29 @@ impl Notify {
 
     /// try to notify the invalidation about a directory entry.
     pub async fn invalid_entry(mut self, parent: u64, name: OsString) {
-        let _ = self.notify(NotifyKind::InvalidEntry { parent, name }).await;
+        let _ = self
+            .notify(NotifyKind::InvalidEntry {
+                parent,
+                name,
+                expire_only: false,
+            })
+            .await;
+    }
+
+    /// try to notify a directory entry has expired. Unlike [`invalid_entry`], the entry stays in
+    /// the dentry cache, along with anything mounted on it, and is only looked up again to
+    /// revalidate it on its next use. Kernels before protocol 7.38 (Linux 6.2) ignore the flag
+    /// and invalidate the entry fully, the same as [`invalid_entry`].
+    ///
+    /// [`invalid_entry`]: Notify::invalid_entry
+    pub async fn expire_entry(mut self, parent: u64, name: OsString) {
+        let _ = self
+            .notify(NotifyKind::InvalidEntry {
+                parent,
+                name,
+                expire_only: true,
+            })
+            .await;
     }
 
     /// try to notify a directory entry has been deleted.
@@ -274,8 +300,12 @@ enum NotifyKind {
     /// notify the cache invalidation about an inode.
     InvalidInode { inode: u64, offset: i64, len: i64 },
 
-    /// notify the invalidation about a directory entry.
-    InvalidEntry { parent: u64, name: OsString },
+    /// notify the invalidation about a directory entry, or only its expiry.
+    InvalidEntry {
+        parent: u64,
+        name: OsString,
+        expire_only: bool,
+    },
 
     /// notify a directory entry has been deleted.
     Delete {
@@ -299,3 +329,43 @@ enum NotifyKind {
         size: u32,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::mpsc;
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn test_expire_entry() {
+        let (sender, mut receiver) = mpsc::unbounded();
+        for (expire, flags) in [(false, 0), (true, FUSE_EXPIRE_ONLY)] {
+            let notify = Notify::new(sender.clone());
+            // Sending on an unbounded channel never waits.
+            if expire {
+                notify
+                    .expire_entry(1, OsString::from("name"))
+                    .now_or_never();
+            } else {
+                notify
+                    .invalid_entry(1, OsString::from("name"))
+                    .now_or_never();
+            }
+            let Ok(Some(Either::Right((header, name)))) = receiver.try_next() else {
+                panic!("expected a header and a name");
+            };
+            let len = (FUSE_OUT_HEADER_SIZE + FUSE_NOTIFY_INVAL_ENTRY_OUT_SIZE) as u32;
+            assert_eq!(header[..4], len.to_ne_bytes());
+            assert_eq!(
+                header[4..8],
+                (fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32).to_ne_bytes()
+            );
+            let out = &header[FUSE_OUT_HEADER_SIZE..];
+            assert_eq!(out[..8], 1u64.to_ne_bytes());
+            assert_eq!(out[8..12], 4u32.to_ne_bytes());
+            assert_eq!(out[12..16], flags.to_ne_bytes());
+            assert_eq!(&name[..], b"name");
+        }
+    }
+}
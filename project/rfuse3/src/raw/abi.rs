@@ -172,6 +172,49 @@ pub const FUSE_VOL_RENAME: u32 = 1 << 30;
 #[cfg(target_os = "macos")]
 pub const FUSE_XTIMES: u32 = 1 << 31;
 
+/// Init flags paired with the protocol minor version which introduced them.
+const INIT_FLAG_MIN_MINOR: &[(u32, u32)] = &[
+    (FUSE_ASYNC_READ, 6),
+    #[cfg(feature = "file-lock")]
+    (FUSE_POSIX_LOCKS, 7),
+    (FUSE_FILE_OPS, 9),
+    (FUSE_ATOMIC_O_TRUNC, 9),
+    (FUSE_BIG_WRITES, 9),
+    (FUSE_EXPORT_SUPPORT, 10),
+    (FUSE_DONT_MASK, 12),
+    #[cfg(not(target_os = "macos"))]
+    (FUSE_SPLICE_WRITE, 14),
+    #[cfg(not(target_os = "macos"))]
+    (FUSE_SPLICE_MOVE, 14),
+    #[cfg(not(target_os = "macos"))]
+    (FUSE_SPLICE_READ, 14),
+    (FUSE_FLOCK_LOCKS, 17),
+    (FUSE_HAS_IOCTL_DIR, 18),
+    (FUSE_AUTO_INVAL_DATA, 20),
+    (FUSE_DO_READDIRPLUS, 21),
+    (FUSE_READDIRPLUS_AUTO, 21),
+    (FUSE_ASYNC_DIO, 22),
+    (FUSE_WRITEBACK_CACHE, 23),
+    (FUSE_NO_OPEN_SUPPORT, 23),
+    (FUSE_PARALLEL_DIROPS, 25),
+    (FUSE_HANDLE_KILLPRIV, 26),
+    (FUSE_POSIX_ACL, 26),
+    (FUSE_ABORT_ERROR, 27),
+    (FUSE_MAX_PAGES, 28),
+    (FUSE_CACHE_SYMLINKS, 28),
+    (FUSE_NO_OPENDIR_SUPPORT, 29),
+    (FUSE_EXPLICIT_INVAL_DATA, 30),
+    (FUSE_MAP_ALIGNMENT, 31),
+];
+
+/// The init flags a kernel speaking protocol minor version `minor` doesn't know about.
+pub fn unsupported_init_flags(minor: u32) -> u32 {
+    INIT_FLAG_MIN_MINOR
+        .iter()
+        .filter(|(_, min_minor)| minor < *min_minor)
+        .fold(0, |flags, (flag, _)| flags | flag)
+}
+
 // CUSE init request/reply flags
 // use unrestricted ioctl
 // pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1 << 0;
@@ -819,14 +862,20 @@ pub struct fuse_access_in {
 #[derive(Debug, Deserialize)]
 #[allow(non_camel_case_types)]
 pub struct fuse_init_in {
-    pub(crate) _major: u32,
-    pub(crate) _minor: u32,
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
     pub max_readahead: u32,
     pub flags: u32,
 }
 
 pub const FUSE_INIT_OUT_SIZE: usize = mem::size_of::<fuse_init_out>();
 
+/// `fuse_init_out` size understood by kernels before protocol 7.5.
+pub const FUSE_COMPAT_INIT_OUT_SIZE: usize = 8;
+
+/// `fuse_init_out` size understood by kernels before protocol 7.23.
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
 #[derive(Debug, Serialize)]
 #[allow(non_camel_case_types)]
 pub struct fuse_init_out {
@@ -1016,9 +1065,13 @@ pub const FUSE_NOTIFY_INVAL_ENTRY_OUT_SIZE: usize = mem::size_of::<fuse_notify_i
 pub struct fuse_notify_inval_entry_out {
     pub parent: u64,
     pub namelen: u32,
-    pub(crate) _padding: u32,
+    pub flags: u32,
 }
 
+/// Only expire the entry instead of dropping it, see `Notify::expire_entry` (7.38, ignored by
+/// older kernels)
+pub const FUSE_EXPIRE_ONLY: u32 = 1 << 0;
+
 pub const FUSE_NOTIFY_DELETE_OUT_SIZE: usize = mem::size_of::<fuse_notify_delete_out>();
 
 #[derive(Debug, Serialize)]
@@ -1,4 +1,6 @@
 use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
 
 #[cfg(all(not(feature = "tokio-runtime"), feature = "async-io-runtime"))]
 pub use async_io::FuseConnection;
@@ -11,3 +13,111 @@ mod async_io;
 mod tokio;
 
 pub(crate) type CompleteIoResult<T, U> = (T, io::Result<U>);
+
+/// A reply moved into a pipe by [`fill_splice_pipe`], to be spliced to the FUSE device with
+/// [`splice_reply`].
+#[cfg(target_os = "linux")]
+pub(crate) struct SplicePipe {
+    pipe_r: OwnedFd,
+    total: usize,
+}
+
+/// Move the reply `header` followed by `len` bytes at `offset` of `fd` into a pipe with
+/// `splice(2)`, instead of copying the data through userspace. This is the part that may block
+/// on `fd`, so it is done before taking the device's write lock.
+///
+/// Returns `None` when the data can't be spliced, e.g. because `fd` doesn't support it or ends
+/// before `offset + len`, so that the caller can fall back to reading it. `header` must already
+/// account for all `len` bytes.
+#[cfg(target_os = "linux")]
+pub(crate) fn fill_splice_pipe(
+    header: &[u8],
+    fd: BorrowedFd<'_>,
+    offset: u64,
+    len: usize,
+) -> io::Result<Option<SplicePipe>> {
+    let total = header.len() + len;
+    let mut fds = [0; 2];
+    // Safe because this only writes to `fds` and we check the return value.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created these fds and nothing else owns them.
+    let (pipe_r, pipe_w) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    // The device takes the whole reply from one splice, so the pipe has to hold all of it.
+    // Safe because this doesn't modify any memory and we check the return value.
+    let size = unsafe { libc::fcntl(pipe_w.as_raw_fd(), libc::F_GETPIPE_SZ) };
+    if size < 0 || (size as usize) < total {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let size =
+            unsafe { libc::fcntl(pipe_w.as_raw_fd(), libc::F_SETPIPE_SZ, total as libc::c_int) };
+        if size < 0 || (size as usize) < total {
+            return Ok(None);
+        }
+    }
+
+    // Safe because this only reads from `header` and we check the return value.
+    let n = unsafe {
+        libc::write(
+            pipe_w.as_raw_fd(),
+            header.as_ptr() as *const libc::c_void,
+            header.len(),
+        )
+    };
+    if n != header.len() as isize {
+        return Ok(None);
+    }
+    let mut off = offset as libc::loff_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        // Safe because this doesn't modify any memory we own and we check the return value.
+        let n = unsafe {
+            libc::splice(
+                fd.as_raw_fd(),
+                &mut off,
+                pipe_w.as_raw_fd(),
+                std::ptr::null_mut(),
+                remaining,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        match n {
+            // A short file would leave the header's length wrong.
+            0 => return Ok(None),
+            n if n < 0 => {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Ok(None);
+            }
+            n => remaining -= n as usize,
+        }
+    }
+    Ok(Some(SplicePipe { pipe_r, total }))
+}
+
+/// Write a reply filled in by [`fill_splice_pipe`] to the FUSE device `dev`. The device takes
+/// the whole reply from the pipe at once.
+#[cfg(target_os = "linux")]
+pub(crate) fn splice_reply(dev: BorrowedFd<'_>, pipe: SplicePipe) -> io::Result<()> {
+    let SplicePipe { pipe_r, total } = pipe;
+    // Safe because this doesn't modify any memory we own and we check the return value.
+    let n = unsafe {
+        libc::splice(
+            pipe_r.as_raw_fd(),
+            std::ptr::null_mut(),
+            dev.as_raw_fd(),
+            std::ptr::null_mut(),
+            total,
+            libc::SPLICE_F_MOVE,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n as usize != total {
+        return Err(io::Error::from(io::ErrorKind::WriteZero));
+    }
+    Ok(())
+}
@@ -110,6 +110,20 @@ impl FuseConnection {
         }
     }
 
+    /// A connection over `file` instead of the FUSE device, so tests can read the replies the
+    /// session writes.
+    #[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+    pub(crate) fn from_file(file: File) -> Self {
+        Self {
+            unmount_notify: Arc::new(Notify::new()),
+            mode: ConnectionMode::Block(BlockFuseConnection {
+                file,
+                read: Mutex::new(()),
+                write: Mutex::new(()),
+            }),
+        }
+    }
+
     #[cfg(all(target_os = "linux", feature = "unprivileged"))]
     pub async fn new_with_unprivileged(
         mount_options: MountOptions,
@@ -174,6 +188,29 @@ impl FuseConnection {
         }
     }
 
+    /// Reply with `header` followed by `len` bytes at `offset` of `fd`, see
+    /// [`fill_splice_pipe`](super::fill_splice_pipe). Returns `Ok(false)` without sending
+    /// anything when the data can't be spliced.
+    #[cfg(target_os = "linux")]
+    pub async fn splice_reply(
+        &self,
+        header: &[u8],
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<bool> {
+        let Some(pipe) = super::fill_splice_pipe(header, fd, offset, len)? else {
+            return Ok(false);
+        };
+        let _guard = match &self.mode {
+            ConnectionMode::Block(connection) => connection.write.lock().await,
+            #[cfg(feature = "unprivileged")]
+            ConnectionMode::NonBlock(connection) => connection.write.lock().await,
+        };
+        super::splice_reply(self.as_fd(), pipe)?;
+        Ok(true)
+    }
+
     pub async fn write_vectored<T: Deref<Target = [u8]> + Send, U: Deref<Target = [u8]> + Send>(
         &self,
         data: T,
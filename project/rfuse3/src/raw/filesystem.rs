@@ -168,6 +168,27 @@ pub trait Filesystem {
         Err(libc::ENOSYS.into())
     }
 
+    /// read data like [`read`][Filesystem::read], but allow replying with a range of a file
+    /// descriptor instead of the data itself. The session then moves the data into the FUSE
+    /// device with `splice(2)` on Linux, without copying it through userspace, and falls back to
+    /// reading it when the descriptor doesn't support splicing. The default implementation
+    /// calls [`read`][Filesystem::read].
+    async fn read_splice(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyRead>
+    where
+        Self: Sync,
+    {
+        self.read(req, inode, fh, offset, size)
+            .await
+            .map(Into::into)
+    }
+
     /// write data. Write should return exactly the number of bytes requested except on error. An
     /// exception to this is when the file has been opened in `direct_io` mode, in which case the
     /// return value of the write system call will reflect the return value of this operation. `fh`
@@ -227,6 +227,41 @@ impl<FS: Filesystem + std::marker::Sync> Filesystem for LoggingFileSystem<FS> {
         result
     }
 
+    async fn read_splice(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyRead> {
+        let id = self.next_log_id.fetch_add(1, Ordering::Relaxed);
+        let method = "read_splice";
+        let args = vec![
+            ("inode", inode.to_string()),
+            ("fh", fh.to_string()),
+            ("offset", offset.to_string()),
+            ("size", size.to_string()),
+        ];
+        self.log_start(&req, id, method, &args);
+        let result = self.inner.read_splice(req, inode, fh, offset, size).await;
+        match result {
+            Ok(ReplyRead::Data(ref data)) => debug!(
+                "ID: {} [{}] {} - Read {} bytes",
+                id,
+                self.fsname,
+                method,
+                data.len()
+            ),
+            Ok(ReplyRead::Splice { len, .. }) => debug!(
+                "ID: {} [{}] {} - Splice {} bytes",
+                id, self.fsname, method, len
+            ),
+            Err(_) => {}
+        }
+        result
+    }
+
     async fn write(
         &self,
         req: Request,
@@ -1,6 +1,8 @@
 //! reply structures.
 use std::ffi::OsString;
 use std::num::NonZeroU32;
+use std::os::fd::AsFd;
+use std::sync::Arc;
 use std::time::Duration;
 
 use bytes::Bytes;
@@ -10,7 +12,7 @@ use crate::helper::mode_from_kind_and_perm;
 use crate::mount_options::DEFAULT_MAX_WRITE;
 use crate::raw::abi::{
     fuse_attr, fuse_attr_out, fuse_bmap_out, fuse_entry_out, fuse_kstatfs, fuse_lseek_out,
-    fuse_open_out, fuse_poll_out, fuse_statfs_out, fuse_write_out,
+    fuse_open_out, fuse_poll_out, fuse_statfs_out, fuse_write_out, DEFAULT_TIME_GRAN,
 };
 #[cfg(feature = "file-lock")]
 use crate::raw::abi::{fuse_file_lock, fuse_lk_out};
@@ -89,12 +91,16 @@ impl From<FileAttr> for fuse_attr {
 pub struct ReplyInit {
     /// Maximum size of write requests.
     pub max_write: NonZeroU32,
+    /// Granularity of the timestamps the filesystem stores, in nanoseconds, at most one second.
+    /// The kernel rounds the timestamps it generates itself down to a multiple of it.
+    pub time_gran: NonZeroU32,
 }
 
 impl Default for ReplyInit {
     fn default() -> Self {
         Self {
             max_write: NonZeroU32::new(DEFAULT_MAX_WRITE).expect("default max_write is non-zero"),
+            time_gran: NonZeroU32::new(DEFAULT_TIME_GRAN).expect("default time_gran is non-zero"),
         }
     }
 }
@@ -159,6 +165,40 @@ impl From<Bytes> for ReplyData {
     }
 }
 
+#[derive(Clone)]
+/// read reply, see [`read_splice`][crate::raw::Filesystem::read_splice].
+pub enum ReplyRead {
+    /// the data, copied into the reply.
+    Data(Bytes),
+    /// `len` bytes at `offset` of `fd`, which the session moves into the reply with `splice(2)`
+    /// where possible and reads otherwise.
+    Splice {
+        fd: Arc<dyn AsFd + Send + Sync>,
+        offset: u64,
+        len: u32,
+    },
+}
+
+impl std::fmt::Debug for ReplyRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Data(data) => f.debug_tuple("Data").field(data).finish(),
+            Self::Splice { fd, offset, len } => f
+                .debug_struct("Splice")
+                .field("fd", &fd.as_fd())
+                .field("offset", offset)
+                .field("len", len)
+                .finish(),
+        }
+    }
+}
+
+impl From<ReplyData> for ReplyRead {
+    fn from(reply: ReplyData) -> Self {
+        Self::Data(reply.data)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 /// open reply.
 pub struct ReplyOpen {
@@ -20,7 +20,7 @@ use crate::raw::reply::ReplyXAttr;
 use crate::raw::request::Request;
 use crate::SetAttr;
 
-use super::utils::{apply_direct_io, reply_error_in_worker, spawn};
+use super::utils::{apply_direct_io, read_reply_data, reply_error_in_worker, spawn};
 use super::worker::{DispatchCtx, WorkItem};
 pub(super) async fn worker_lookup<FS: Filesystem + Send + Sync + 'static>(
     ctx: &Arc<DispatchCtx<FS>>,
@@ -203,6 +203,7 @@ pub(super) async fn worker_read<FS: Filesystem + Send + Sync + 'static>(
     };
     let fs = ctx.fs.clone();
     let resp = ctx.resp.clone();
+    let connection = ctx.connection.clone();
     spawn(debug_span!("fuse_read_worker"), async move {
         debug!(
             unique = item.unique,
@@ -211,22 +212,29 @@ pub(super) async fn worker_read<FS: Filesystem + Send + Sync + 'static>(
             offset = read_in.offset,
             "read (worker)"
         );
-        let mut reply_data = match fs
-            .read(
+        let reply = fs
+            .read_splice(
                 Request::from(&item),
                 item.in_header.nodeid,
                 read_in.fh,
                 read_in.offset,
                 read_in.size,
             )
-            .await
-        {
+            .await;
+        let reply = match reply {
+            Ok(reply) => {
+                read_reply_data(reply, read_in.size, item.unique, connection.as_deref()).await
+            }
+            Err(err) => Err(err),
+        };
+        let mut reply_data = match reply {
             Err(err) => {
                 let data = reply_error_in_worker(err, item.unique).expect("serialize out_header");
                 let _ = resp.unbounded_send(Either::Left(data));
                 return;
             }
-            Ok(reply) => reply.data,
+            Ok(None) => return,
+            Ok(Some(data)) => data,
         };
         if reply_data.len() > read_in.size as usize {
             reply_data.truncate(read_in.size as usize);
@@ -13,7 +13,8 @@ pub(crate) use worker::WorkItem;
 
 // Internal types used across submodules
 use utils::{
-    apply_direct_io, is_forget_opcode, reply_error_in_place, spawn, InHeaderLite, ReadResult,
+    apply_direct_io, is_forget_opcode, read_reply_data, reply_error_in_place, spawn,
+    write_init_out, InHeaderLite, ReadResult,
 };
 use worker::{DispatchCtx, Workers};
 
@@ -72,8 +73,6 @@ use tokio::task::JoinHandle;
 use tokio::{fs::read_dir, task};
 use tracing::{debug, debug_span, error, instrument, warn};
 
-#[cfg(all(target_os = "linux", feature = "unprivileged"))]
-use crate::find_fusermount3;
 use crate::helper::*;
 use crate::notify::Notify;
 use crate::raw::abi::*;
@@ -84,12 +83,17 @@ use crate::raw::filesystem::Filesystem;
 use crate::raw::reply::ReplyXAttr;
 use crate::raw::request::Request;
 use crate::raw::FuseData;
+#[cfg(all(target_os = "linux", feature = "unprivileged"))]
+use crate::{diagnose_unprivileged_mount, find_fusermount3, has_cap_sys_admin};
 use crate::{MountOptions, SetAttr};
 
 /// A Future which returns when a file system is unmounted
 ///
 /// when drop the [`MountHandle`], it will unmount Filesystem in background task, if user want to
-/// wait unmount completely, use [`MountHandle::unmount`]
+/// wait unmount completely, use [`MountHandle::unmount`]. On a current-thread runtime, or outside
+/// of any runtime, there may be nobody left to drive the background task, so the mount is lazily
+/// detached from a thread of its own instead. Nothing waits for that thread, the process may exit
+/// before it is done: callers which need the mount gone should use [`MountHandle::unmount`].
 #[derive(Debug)]
 pub struct MountHandle {
     inner: Option<MountHandleInner>,
@@ -119,7 +123,24 @@ impl Drop for MountHandle {
 
             #[cfg(all(not(feature = "async-io-runtime"), feature = "tokio-runtime"))]
             {
-                task::spawn(inner.inner_unmount());
+                // A current-thread runtime only drives the background task while the embedder
+                // keeps calling into it, and the handle is often dropped as the runtime itself
+                // winds down, so detach the mount without the runtime. That waits for
+                // fusermount3, which mustn't stall the thread dropping the handle.
+                use tokio::runtime::{Handle, RuntimeFlavor};
+
+                match Handle::try_current() {
+                    Ok(handle) if handle.runtime_flavor() != RuntimeFlavor::CurrentThread => {
+                        task::spawn(inner.inner_unmount());
+                    }
+                    _ => {
+                        std::thread::spawn(move || {
+                            if let Err(err) = inner.blocking_unmount() {
+                                error!("unmount {:?} failed: {}", inner.mount_path, err);
+                            }
+                        });
+                    }
+                }
             }
         }
     }
@@ -138,6 +159,45 @@ struct MountHandleInner {
 }
 
 impl MountHandleInner {
+    /// Stop the session and lazily detach the mount without awaiting anything.
+    ///
+    /// Used when the handle is dropped outside of a runtime which can run the background unmount
+    /// task, the session task stops on its own once the connection goes away. This blocks, so the
+    /// drop runs it on a thread of its own.
+    #[cfg(all(not(feature = "async-io-runtime"), feature = "tokio-runtime"))]
+    fn blocking_unmount(&self) -> IoResult<()> {
+        self.destroy_notify.notify();
+
+        #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+        {
+            mount::unmount(&self.mount_path, MntFlags::MNT_FORCE)?;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            #[cfg(all(target_os = "linux", feature = "unprivileged"))]
+            if self.unprivileged {
+                let binary_path = find_fusermount3()?;
+                let status = std::process::Command::new(binary_path)
+                    .args([
+                        OsStr::new("-u"),
+                        OsStr::new("-z"),
+                        self.mount_path.as_os_str(),
+                    ])
+                    .status()?;
+                if !status.success() {
+                    return Err(IoError::other("call fusermount3 -u -z to unmount failed"));
+                }
+
+                return Ok(());
+            }
+
+            mount::umount2(&self.mount_path, mount::MntFlags::MNT_DETACH)?;
+        }
+
+        Ok(())
+    }
+
     async fn inner_unmount(self) -> IoResult<()> {
         self.destroy_notify.notify();
 
@@ -273,6 +333,25 @@ impl Future for MountHandle {
 /// this limit to prevent thread explosion during large directory deletions (matching
 /// libfuse behavior).
 ///
+/// # Runtime
+///
+/// The session only spawns `Send` tasks and moves blocking `/dev/fuse` reads to the blocking
+/// pool, so it runs on a current-thread runtime as well as a multi-thread one, which lets
+/// embedders drive it from an existing single-threaded executor:
+///
+/// ```ignore
+/// let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+/// rt.block_on(async {
+///     let handle = Session::new(MountOptions::default()).mount(fs, "/mnt").await?;
+///     handle.await
+/// })?;
+/// ```
+///
+/// With a single thread every filesystem operation shares the thread with the request loop, so
+/// the filesystem must not block it, and in particular must never access its own mount point
+/// synchronously, which would deadlock. To unmount, await [`MountHandle::unmount`] rather than
+/// dropping the handle, see [`MountHandle`].
+///
 /// # Example
 ///
 /// ```ignore
@@ -358,6 +437,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             let ctx = Arc::new(DispatchCtx {
                 fs,
                 resp: self.response_sender.clone(),
+                connection: self.fuse_connection.clone(),
                 direct_io: self.mount_options.direct_io,
                 _inflight: self.inflight.clone(),
                 _inflight_notify: self.inflight_notify.clone(),
@@ -455,6 +535,10 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     }
 
     /// mount the filesystem without root permission.
+    ///
+    /// If `fusermount3` fails, the error tells why: it is missing, not setuid root, or
+    /// `/dev/fuse` is absent or not accessible. With [`MountOptions::privileged_fallback`] the
+    /// filesystem is then mounted with [`mount`](Self::mount) instead, if the process may.
     #[cfg(all(target_os = "linux", feature = "unprivileged"))]
     pub async fn mount_with_unprivileged<P: AsRef<Path>>(
         mut self,
@@ -466,12 +550,29 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         self.mount_empty_check(mount_path).await?;
 
         let notify = Arc::new(async_notify::Notify::new());
-        let fuse_connection = FuseConnection::new_with_unprivileged(
+        let fuse_connection = match FuseConnection::new_with_unprivileged(
             self.mount_options.clone(),
             mount_path,
             notify.clone(),
         )
-        .await?;
+        .await
+        {
+            Ok(fuse_connection) => fuse_connection,
+            Err(err) => {
+                let err = diagnose_unprivileged_mount(err);
+                if self.mount_options.privileged_fallback && has_cap_sys_admin() {
+                    warn!(
+                        "unprivileged mount {:?} failed: {}, mount with root permission",
+                        mount_path, err
+                    );
+
+                    return self.mount(fs, mount_path).await;
+                }
+                error!("unprivileged mount {:?} failed: {}", mount_path, err);
+
+                return Err(err);
+            }
+        };
 
         self.fuse_connection.replace(Arc::new(fuse_connection));
 
@@ -689,58 +790,65 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     ) -> IoResult<NonZeroU32> {
         use std::io::ErrorKind;
 
-        let header_buffer = vec![0; FUSE_IN_HEADER_SIZE];
-        let data_buffer =
-            AlignedBuffer::try_new(FUSE_MIN_READ_BUFFER_SIZE).map_err(IoError::other)?;
+        // A kernel speaking a newer major version sends FUSE_INIT again after our reply.
+        loop {
+            let header_buffer = vec![0; FUSE_IN_HEADER_SIZE];
+            let data_buffer =
+                AlignedBuffer::try_new(FUSE_MIN_READ_BUFFER_SIZE).map_err(IoError::other)?;
 
-        let (data_buffer, in_header) = match self
-            .read_fuse_request(fuse_connection, header_buffer, data_buffer)
-            .await
-        {
-            ReadResult::Destroy => {
-                return Err(IoError::new(
-                    ErrorKind::UnexpectedEof,
-                    "init stage get destroy result",
-                ));
-            }
+            let (data_buffer, in_header) = match self
+                .read_fuse_request(fuse_connection, header_buffer, data_buffer)
+                .await
+            {
+                ReadResult::Destroy => {
+                    return Err(IoError::new(
+                        ErrorKind::UnexpectedEof,
+                        "init stage get destroy result",
+                    ));
+                }
 
-            ReadResult::Request {
-                in_header,
-                data_buffer,
-                ..
-            } => {
-                let in_header = in_header?;
-                (data_buffer, in_header)
-            }
-        };
+                ReadResult::Request {
+                    in_header,
+                    data_buffer,
+                    ..
+                } => {
+                    let in_header = in_header?;
+                    (data_buffer, in_header)
+                }
+            };
 
-        let request = Request::from(&in_header);
+            let request = Request::from(&in_header);
 
-        let opcode = match fuse_opcode::try_from(in_header.opcode) {
-            Err(err) => {
-                debug!("receive unknown opcode {}", err.0);
+            let opcode = match fuse_opcode::try_from(in_header.opcode) {
+                Err(err) => {
+                    debug!("receive unknown opcode {}", err.0);
 
-                reply_error_in_place(libc::ENOSYS.into(), request, &self.response_sender).await;
+                    reply_error_in_place(libc::ENOSYS.into(), request, &self.response_sender).await;
 
-                return Err(IoError::other(format!("receive unknown opcode {}", err.0)));
-            }
+                    return Err(IoError::other(format!("receive unknown opcode {}", err.0)));
+                }
 
-            Ok(opcode) => opcode,
-        };
+                Ok(opcode) => opcode,
+            };
 
-        debug!("receive opcode {}", opcode);
+            debug!("receive opcode {}", opcode);
 
-        if opcode != fuse_opcode::FUSE_INIT {
-            error!(?opcode, "received unexpected opcode");
+            if opcode != fuse_opcode::FUSE_INIT {
+                error!(?opcode, "received unexpected opcode");
 
-            return Err(IoError::other(format!("unexpected opcode {opcode:?}")));
-        }
+                return Err(IoError::other(format!("unexpected opcode {opcode:?}")));
+            }
 
-        let data_size = in_header.len as usize - FUSE_IN_HEADER_SIZE;
-        let data_ref = &data_buffer[..data_size];
+            let data_size = in_header.len as usize - FUSE_IN_HEADER_SIZE;
+            let data_ref = &data_buffer[..data_size];
 
-        self.handle_init(request, data_ref, fuse_connection, fs)
-            .await
+            if let Some(max_write) = self
+                .handle_init(request, data_ref, fuse_connection, fs)
+                .await?
+            {
+                return Ok(max_write);
+            }
+        }
     }
 
     #[instrument(level = "debug", skip(self, header_buffer, data_buffer), ret)]
@@ -824,7 +932,8 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
     }
 
     async fn dispatch(&mut self) -> IoResult<()> {
-        let fuse_connection = self.fuse_connection.take().unwrap();
+        // Keep the connection in the session too, spliced read replies write to it directly.
+        let fuse_connection = self.fuse_connection.clone().unwrap();
         let fs = self.filesystem.take().expect("filesystem not init");
         // defer worker initialization until after FUSE INIT handshake
 
@@ -1183,7 +1292,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         data: &[u8],
         fuse_connection: &FuseConnection,
         fs: &FS,
-    ) -> IoResult<NonZeroU32> {
+    ) -> IoResult<Option<NonZeroU32>> {
         let init_in = match get_bincode_config().deserialize::<fuse_init_in>(data) {
             Err(err) => {
                 error!(
@@ -1217,68 +1326,132 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
 
         debug!("fuse_init {:?}", init_in);
 
+        if init_in.major < FUSE_KERNEL_VERSION {
+            error!(
+                "unsupported fuse protocol version {}.{}",
+                init_in.major, init_in.minor
+            );
+
+            let init_out_header = fuse_out_header {
+                len: FUSE_OUT_HEADER_SIZE as u32,
+                error: -libc::EPROTO,
+                unique: request.unique,
+            };
+
+            let init_out_header_data = get_bincode_config()
+                .serialize(&init_out_header)
+                .expect("won't happened");
+
+            if let Err(err) = fuse_connection
+                .write_vectored::<_, Vec<u8>>(init_out_header_data, None)
+                .await
+                .1
+            {
+                error!("write error init out data to /dev/fuse failed {}", err);
+            }
+
+            return Err(IoError::from_raw_os_error(libc::EPROTO));
+        }
+
+        // Like libfuse, tell a kernel speaking a newer major version which one we speak and wait
+        // for it to send FUSE_INIT again with it.
+        if init_in.major > FUSE_KERNEL_VERSION {
+            debug!(
+                "kernel fuse protocol version {}.{} is newer, replying with ours",
+                init_in.major, init_in.minor
+            );
+
+            let init_out = fuse_init_out {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                max_readahead: 0,
+                flags: 0,
+                max_background: 0,
+                congestion_threshold: 0,
+                max_write: 0,
+                time_gran: 0,
+                max_pages: 0,
+                map_alignment: 0,
+                unused: [0; 8],
+            };
+
+            write_init_out(
+                fuse_connection,
+                request.unique,
+                &init_out,
+                FUSE_COMPAT_INIT_OUT_SIZE,
+            )
+            .await?;
+
+            return Ok(None);
+        }
+
+        // Only negotiate the capabilities the kernel's protocol version knows about, an older
+        // kernel may leave unknown bits set or misinterpret them in the reply.
+        let kernel_flags = init_in.flags & !unsupported_init_flags(init_in.minor);
+
         let mut reply_flags = 0;
 
         // TODO: most of these FUSE_* flags should be controllable by the consuming crate.
-        if init_in.flags & FUSE_ASYNC_READ > 0 {
+        if kernel_flags & FUSE_ASYNC_READ > 0 {
             debug!("enable FUSE_ASYNC_READ");
 
             reply_flags |= FUSE_ASYNC_READ;
         }
 
         #[cfg(feature = "file-lock")]
-        if init_in.flags & FUSE_POSIX_LOCKS > 0 {
+        if kernel_flags & FUSE_POSIX_LOCKS > 0 {
             debug!("enable FUSE_POSIX_LOCKS");
 
             reply_flags |= FUSE_POSIX_LOCKS;
         }
 
-        if init_in.flags & FUSE_FILE_OPS > 0 {
+        if kernel_flags & FUSE_FILE_OPS > 0 {
             debug!("enable FUSE_FILE_OPS");
 
             reply_flags |= FUSE_FILE_OPS;
         }
 
-        if init_in.flags & FUSE_ATOMIC_O_TRUNC > 0 {
+        if kernel_flags & FUSE_ATOMIC_O_TRUNC > 0 && self.mount_options.atomic_o_trunc {
             debug!("enable FUSE_ATOMIC_O_TRUNC");
 
             reply_flags |= FUSE_ATOMIC_O_TRUNC;
         }
 
-        if init_in.flags & FUSE_EXPORT_SUPPORT > 0 {
+        if kernel_flags & FUSE_EXPORT_SUPPORT > 0 {
             debug!("enable FUSE_EXPORT_SUPPORT");
 
             reply_flags |= FUSE_EXPORT_SUPPORT;
         }
 
-        if init_in.flags & FUSE_BIG_WRITES > 0 {
+        if kernel_flags & FUSE_BIG_WRITES > 0 {
             debug!("enable FUSE_BIG_WRITES");
 
             reply_flags |= FUSE_BIG_WRITES;
         }
 
-        if init_in.flags & FUSE_DONT_MASK > 0 && self.mount_options.dont_mask {
+        if kernel_flags & FUSE_DONT_MASK > 0 && self.mount_options.dont_mask {
             debug!("enable FUSE_DONT_MASK");
 
             reply_flags |= FUSE_DONT_MASK;
         }
 
         #[cfg(not(target_os = "macos"))]
-        if init_in.flags & FUSE_SPLICE_WRITE > 0 {
+        if kernel_flags & FUSE_SPLICE_WRITE > 0 {
             debug!("enable FUSE_SPLICE_WRITE");
 
             reply_flags |= FUSE_SPLICE_WRITE;
         }
 
         #[cfg(not(target_os = "macos"))]
-        if init_in.flags & FUSE_SPLICE_MOVE > 0 {
+        if kernel_flags & FUSE_SPLICE_MOVE > 0 {
             debug!("enable FUSE_SPLICE_MOVE");
 
             reply_flags |= FUSE_SPLICE_MOVE;
         }
 
         #[cfg(not(target_os = "macos"))]
-        if init_in.flags & FUSE_SPLICE_READ > 0 {
+        if kernel_flags & FUSE_SPLICE_READ > 0 {
             debug!("enable FUSE_SPLICE_READ");
 
             reply_flags |= FUSE_SPLICE_READ;
@@ -1295,108 +1468,110 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             reply_flags |= FUSE_HAS_IOCTL_DIR;
         }*/
 
-        if init_in.flags & FUSE_AUTO_INVAL_DATA > 0 {
+        if kernel_flags & FUSE_AUTO_INVAL_DATA > 0 {
             debug!("enable FUSE_AUTO_INVAL_DATA");
 
             reply_flags |= FUSE_AUTO_INVAL_DATA;
         }
 
-        if init_in.flags & FUSE_DO_READDIRPLUS > 0 || self.mount_options.force_readdir_plus {
+        if kernel_flags & FUSE_DO_READDIRPLUS > 0
+            || (self.mount_options.force_readdir_plus && init_in.minor >= 21)
+        {
             debug!("enable FUSE_DO_READDIRPLUS");
 
             reply_flags |= FUSE_DO_READDIRPLUS;
         }
 
-        if init_in.flags & FUSE_READDIRPLUS_AUTO > 0 && !self.mount_options.force_readdir_plus {
+        if kernel_flags & FUSE_READDIRPLUS_AUTO > 0 && !self.mount_options.force_readdir_plus {
             debug!("enable FUSE_READDIRPLUS_AUTO");
 
             reply_flags |= FUSE_READDIRPLUS_AUTO;
         }
 
-        if init_in.flags & FUSE_ASYNC_DIO > 0 {
+        if kernel_flags & FUSE_ASYNC_DIO > 0 {
             debug!("enable FUSE_ASYNC_DIO");
 
             reply_flags |= FUSE_ASYNC_DIO;
         }
 
-        if init_in.flags & FUSE_WRITEBACK_CACHE > 0 && self.mount_options.write_back {
+        if kernel_flags & FUSE_WRITEBACK_CACHE > 0 && self.mount_options.write_back {
             debug!("enable FUSE_WRITEBACK_CACHE");
 
             reply_flags |= FUSE_WRITEBACK_CACHE;
         }
 
-        if init_in.flags & FUSE_NO_OPEN_SUPPORT > 0 && self.mount_options.no_open_support {
+        if kernel_flags & FUSE_NO_OPEN_SUPPORT > 0 && self.mount_options.no_open_support {
             debug!("enable FUSE_NO_OPEN_SUPPORT");
 
             reply_flags |= FUSE_NO_OPEN_SUPPORT;
         }
 
-        if init_in.flags & FUSE_PARALLEL_DIROPS > 0 {
+        if kernel_flags & FUSE_PARALLEL_DIROPS > 0 {
             debug!("enable FUSE_PARALLEL_DIROPS");
 
             reply_flags |= FUSE_PARALLEL_DIROPS;
         }
 
-        if init_in.flags & FUSE_HANDLE_KILLPRIV > 0 && self.mount_options.handle_killpriv {
+        if kernel_flags & FUSE_HANDLE_KILLPRIV > 0 && self.mount_options.handle_killpriv {
             debug!("enable FUSE_HANDLE_KILLPRIV");
 
             reply_flags |= FUSE_HANDLE_KILLPRIV;
         }
 
-        if init_in.flags & FUSE_POSIX_ACL > 0 && self.mount_options.default_permissions {
+        if kernel_flags & FUSE_POSIX_ACL > 0 && self.mount_options.default_permissions {
             debug!("enable FUSE_POSIX_ACL");
 
             reply_flags |= FUSE_POSIX_ACL;
         }
 
-        if init_in.flags & FUSE_MAX_PAGES > 0 {
+        if kernel_flags & FUSE_MAX_PAGES > 0 {
             debug!("enable FUSE_MAX_PAGES");
 
             reply_flags |= FUSE_MAX_PAGES;
         }
 
-        if init_in.flags & FUSE_CACHE_SYMLINKS > 0 {
+        if kernel_flags & FUSE_CACHE_SYMLINKS > 0 && self.mount_options.cache_symlinks {
             debug!("enable FUSE_CACHE_SYMLINKS");
 
             reply_flags |= FUSE_CACHE_SYMLINKS;
         }
 
-        if init_in.flags & FUSE_NO_OPENDIR_SUPPORT > 0 && self.mount_options.no_open_dir_support {
+        if kernel_flags & FUSE_NO_OPENDIR_SUPPORT > 0 && self.mount_options.no_open_dir_support {
             debug!("enable FUSE_NO_OPENDIR_SUPPORT");
 
             reply_flags |= FUSE_NO_OPENDIR_SUPPORT;
         }
 
         #[cfg(target_os = "macos")]
-        if init_in.flags & FUSE_ALLOCATE > 0 {
+        if kernel_flags & FUSE_ALLOCATE > 0 {
             debug!("enable FUSE_ALLOCATE");
 
             reply_flags |= FUSE_ALLOCATE;
         }
 
         #[cfg(target_os = "macos")]
-        if init_in.flags & FUSE_EXCHANGE_DATA > 0 {
+        if kernel_flags & FUSE_EXCHANGE_DATA > 0 {
             debug!("enable FUSE_EXCHANGE_DATA");
 
             reply_flags |= FUSE_EXCHANGE_DATA;
         }
 
         #[cfg(target_os = "macos")]
-        if init_in.flags & FUSE_CASE_INSENSITIVE > 0 {
+        if kernel_flags & FUSE_CASE_INSENSITIVE > 0 {
             debug!("enable FUSE_CASE_INSENSITIVE");
 
             reply_flags |= FUSE_CASE_INSENSITIVE;
         }
 
         #[cfg(target_os = "macos")]
-        if init_in.flags & FUSE_VOL_RENAME > 0 {
+        if kernel_flags & FUSE_VOL_RENAME > 0 {
             debug!("enable FUSE_VOL_RENAME");
 
             reply_flags |= FUSE_VOL_RENAME;
         }
 
         #[cfg(target_os = "macos")]
-        if init_in.flags & FUSE_XTIMES > 0 {
+        if kernel_flags & FUSE_XTIMES > 0 {
             debug!("enable FUSE_XTIMES");
 
             reply_flags |= FUSE_XTIMES;
@@ -1427,11 +1602,19 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             }
         };
 
-        // Use max_readahead from mount_options if set, otherwise use kernel's value
-        let max_readahead = self
-            .mount_options
-            .max_readahead
-            .unwrap_or(init_in.max_readahead);
+        // Use max_readahead from mount_options if set, otherwise use kernel's value. The kernel
+        // reports the largest readahead it accepts, so a bigger request is clamped to it.
+        let max_readahead = match self.mount_options.max_readahead {
+            Some(max_readahead) if max_readahead > init_in.max_readahead => {
+                warn!(
+                    "max_readahead {} exceeds kernel maximum {}, clamping",
+                    max_readahead, init_in.max_readahead
+                );
+                init_in.max_readahead
+            }
+            Some(max_readahead) => max_readahead,
+            None => init_in.max_readahead,
+        };
 
         let max_write = if self.mount_options.max_write.get() < init_reply.max_write.get() {
             self.mount_options.max_write
@@ -1447,7 +1630,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
             max_background: DEFAULT_MAX_BACKGROUND,
             congestion_threshold: DEFAULT_CONGESTION_THRESHOLD,
             max_write: max_write.get(),
-            time_gran: DEFAULT_TIME_GRAN,
+            time_gran: init_reply.time_gran.get(),
             max_pages: DEFAULT_MAX_PAGES,
             map_alignment: DEFAULT_MAP_ALIGNMENT,
             unused: [0; 8],
@@ -1455,34 +1638,20 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
 
         debug!("fuse init out {:?}", init_out);
 
-        let out_header = fuse_out_header {
-            len: (FUSE_OUT_HEADER_SIZE + FUSE_INIT_OUT_SIZE) as u32,
-            error: 0,
-            unique: request.unique,
+        // Older kernels expect a shorter reply and reject the full-size one.
+        let init_out_size = if init_in.minor < 5 {
+            FUSE_COMPAT_INIT_OUT_SIZE
+        } else if init_in.minor < 23 {
+            FUSE_COMPAT_22_INIT_OUT_SIZE
+        } else {
+            FUSE_INIT_OUT_SIZE
         };
 
-        let mut data = Vec::with_capacity(FUSE_OUT_HEADER_SIZE + FUSE_INIT_OUT_SIZE);
-
-        get_bincode_config()
-            .serialize_into(&mut data, &out_header)
-            .expect("won't happened");
-        get_bincode_config()
-            .serialize_into(&mut data, &init_out)
-            .expect("won't happened");
-
-        if let Err(err) = fuse_connection
-            .write_vectored::<_, Vec<u8>>(data, None)
-            .await
-            .1
-        {
-            error!("write init out data to /dev/fuse failed {}", err);
-
-            return Err(err);
-        }
+        write_init_out(fuse_connection, request.unique, &init_out, init_out_size).await?;
 
         debug!("fuse init done");
 
-        Ok(max_write)
+        Ok(Some(max_write))
     }
 
     #[instrument(skip(self, data, fs))]
@@ -2433,6 +2602,7 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         };
 
         let mut resp_sender = self.response_sender.clone();
+        let fuse_connection = self.fuse_connection.clone();
         let fs = fs.clone();
 
         spawn(debug_span!("fuse_read"), async move {
@@ -2441,8 +2611,8 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                 request.unique, in_header.nodeid, read_in
             );
 
-            let mut reply_data = match fs
-                .read(
+            let reply = match fs
+                .read_splice(
                     request,
                     in_header.nodeid,
                     read_in.fh,
@@ -2457,7 +2627,26 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
                     return;
                 }
 
-                Ok(reply_data) => reply_data.data,
+                Ok(reply) => reply,
+            };
+
+            let mut reply_data = match read_reply_data(
+                reply,
+                read_in.size,
+                request.unique,
+                fuse_connection.as_deref(),
+            )
+            .await
+            {
+                Err(err) => {
+                    reply_error_in_place(err, request, resp_sender).await;
+
+                    return;
+                }
+
+                Ok(None) => return,
+
+                Ok(Some(reply_data)) => reply_data,
             };
 
             if reply_data.len() > read_in.size as _ {
@@ -4515,3 +4704,387 @@ impl<FS: Filesystem + Send + Sync + 'static> Session<FS> {
         });
     }
 }
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    feature = "unprivileged",
+    feature = "tokio-runtime"
+))]
+mod tests {
+    use std::io::ErrorKind;
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::raw::reply::{FileAttr, ReplyAttr, ReplyData, ReplyEntry, ReplyInit};
+    use crate::{FileType, Timestamp};
+
+    struct EmptyFs;
+
+    impl Filesystem for EmptyFs {
+        async fn init(&self, _req: Request) -> crate::Result<ReplyInit> {
+            Ok(ReplyInit::default())
+        }
+
+        async fn destroy(&self, _req: Request) {}
+
+        #[cfg(feature = "file-lock")]
+        async fn getlk(
+            &self,
+            _req: Request,
+            _inode: u64,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+        ) -> crate::Result<crate::raw::reply::ReplyLock> {
+            Err(libc::ENOSYS.into())
+        }
+
+        #[cfg(feature = "file-lock")]
+        async fn setlk(
+            &self,
+            _req: Request,
+            _inode: u64,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+            _block: bool,
+        ) -> crate::Result<()> {
+            Err(libc::ENOSYS.into())
+        }
+    }
+
+    /// Run `handle_init()` for a kernel speaking protocol `major.minor` which offers `flags`,
+    /// returning its result and the reply it wrote.
+    async fn init(
+        mount_options: MountOptions,
+        major: u32,
+        minor: u32,
+        max_readahead: u32,
+        flags: u32,
+    ) -> (IoResult<Option<NonZeroU32>>, Vec<u8>) {
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+
+        let (session_end, kernel_end) = UnixStream::pair().unwrap();
+        let connection = FuseConnection::from_file(std::os::fd::OwnedFd::from(session_end).into());
+        let data: Vec<u8> = [major, minor, max_readahead, flags]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let request = Request {
+            unique: 1,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+
+        let res = Session::new(mount_options)
+            .handle_init(request, &data, &connection, &EmptyFs)
+            .await;
+        drop(connection);
+
+        let mut reply = Vec::new();
+        (&kernel_end).read_to_end(&mut reply).unwrap();
+        (res, reply)
+    }
+
+    /// The `u32` at `offset` of a reply.
+    fn reply_u32(reply: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(reply[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_init_masks_flags_unknown_to_kernel() {
+        let mut mount_options = MountOptions::default();
+        mount_options.write_back(true);
+        let flags = FUSE_ASYNC_READ | FUSE_WRITEBACK_CACHE | FUSE_PARALLEL_DIROPS;
+
+        // FUSE_WRITEBACK_CACHE came with 7.23 and FUSE_PARALLEL_DIROPS with 7.25.
+        let (res, reply) = init(mount_options.clone(), 7, 22, 4096, flags).await;
+        assert!(res.unwrap().is_some());
+        assert_eq!(
+            reply.len(),
+            FUSE_OUT_HEADER_SIZE + FUSE_COMPAT_22_INIT_OUT_SIZE
+        );
+        assert_eq!(reply_u32(&reply, 0) as usize, reply.len());
+        assert_eq!(
+            reply_u32(&reply, FUSE_OUT_HEADER_SIZE + 12),
+            FUSE_ASYNC_READ
+        );
+
+        let (res, reply) = init(mount_options, 7, FUSE_KERNEL_MINOR_VERSION, 4096, flags).await;
+        assert!(res.unwrap().is_some());
+        assert_eq!(reply.len(), FUSE_OUT_HEADER_SIZE + FUSE_INIT_OUT_SIZE);
+        assert_eq!(reply_u32(&reply, FUSE_OUT_HEADER_SIZE + 12), flags);
+    }
+
+    #[tokio::test]
+    async fn test_init_clamps_max_readahead() {
+        const KERNEL_MAX_READAHEAD: u32 = 128 * 1024;
+
+        for (requested, expected) in [
+            (None, KERNEL_MAX_READAHEAD),
+            (Some(KERNEL_MAX_READAHEAD * 2), KERNEL_MAX_READAHEAD),
+            (Some(KERNEL_MAX_READAHEAD), KERNEL_MAX_READAHEAD),
+            (Some(4096), 4096),
+        ] {
+            let mut mount_options = MountOptions::default();
+            mount_options.max_readahead(requested);
+            let (res, reply) = init(mount_options, 7, 31, KERNEL_MAX_READAHEAD, 0).await;
+            assert!(res.unwrap().is_some());
+            assert_eq!(
+                reply_u32(&reply, FUSE_OUT_HEADER_SIZE + 8),
+                expected,
+                "requested {requested:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_init_newer_major_version() {
+        let (res, reply) = init(MountOptions::default(), 8, 0, 4096, FUSE_ASYNC_READ).await;
+        // The session waits for the kernel to send FUSE_INIT again.
+        assert!(res.unwrap().is_none());
+        assert_eq!(
+            reply.len(),
+            FUSE_OUT_HEADER_SIZE + FUSE_COMPAT_INIT_OUT_SIZE
+        );
+        assert_eq!(reply_u32(&reply, 4), 0);
+        assert_eq!(reply_u32(&reply, FUSE_OUT_HEADER_SIZE), FUSE_KERNEL_VERSION);
+        assert_eq!(
+            reply_u32(&reply, FUSE_OUT_HEADER_SIZE + 4),
+            FUSE_KERNEL_MINOR_VERSION
+        );
+
+        // An older major version is refused.
+        let (res, reply) = init(MountOptions::default(), 6, 0, 4096, FUSE_ASYNC_READ).await;
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EPROTO));
+        assert_eq!(reply_u32(&reply, 4) as i32, -libc::EPROTO);
+    }
+
+    /// A root directory holding the symlink `link`, which counts the readlink requests it
+    /// answers.
+    struct SymlinkFs {
+        target: Arc<std::sync::Mutex<&'static str>>,
+        readlinks: Arc<AtomicUsize>,
+    }
+
+    impl SymlinkFs {
+        const LINK_INO: u64 = 2;
+        const TTL: Duration = Duration::from_secs(60);
+
+        fn attr(&self, ino: u64) -> FileAttr {
+            let (kind, perm, size) = if ino == Self::LINK_INO {
+                let size = self.target.lock().unwrap().len() as u64;
+                (FileType::Symlink, 0o777, size)
+            } else {
+                (FileType::Directory, 0o755, 0)
+            };
+            FileAttr {
+                ino,
+                size,
+                blocks: 0,
+                atime: Timestamp::new(0, 0),
+                mtime: Timestamp::new(0, 0),
+                ctime: Timestamp::new(0, 0),
+                kind,
+                perm,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                blksize: 4096,
+            }
+        }
+    }
+
+    impl Filesystem for SymlinkFs {
+        async fn init(&self, _req: Request) -> crate::Result<ReplyInit> {
+            Ok(ReplyInit::default())
+        }
+
+        async fn destroy(&self, _req: Request) {}
+
+        async fn lookup(
+            &self,
+            _req: Request,
+            parent: u64,
+            name: &OsStr,
+        ) -> crate::Result<ReplyEntry> {
+            if parent != 1 || name != "link" {
+                return Err(libc::ENOENT.into());
+            }
+            Ok(ReplyEntry {
+                ttl: Self::TTL,
+                attr: self.attr(Self::LINK_INO),
+                generation: 0,
+            })
+        }
+
+        async fn getattr(
+            &self,
+            _req: Request,
+            inode: u64,
+            _fh: Option<u64>,
+            _flags: u32,
+        ) -> crate::Result<ReplyAttr> {
+            Ok(ReplyAttr {
+                ttl: Self::TTL,
+                attr: self.attr(inode),
+            })
+        }
+
+        async fn readlink(&self, _req: Request, _inode: u64) -> crate::Result<ReplyData> {
+            self.readlinks.fetch_add(1, Ordering::SeqCst);
+            let target = *self.target.lock().unwrap();
+            Ok(Bytes::from_static(target.as_bytes()).into())
+        }
+
+        #[cfg(feature = "file-lock")]
+        async fn getlk(
+            &self,
+            _req: Request,
+            _inode: u64,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+        ) -> crate::Result<crate::raw::reply::ReplyLock> {
+            Err(libc::ENOSYS.into())
+        }
+
+        #[cfg(feature = "file-lock")]
+        async fn setlk(
+            &self,
+            _req: Request,
+            _inode: u64,
+            _fh: u64,
+            _lock_owner: u64,
+            _start: u64,
+            _end: u64,
+            _type: u32,
+            _pid: u32,
+            _block: bool,
+        ) -> crate::Result<()> {
+            Err(libc::ENOSYS.into())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_symlinks() {
+        for cache_symlinks in [false, true] {
+            let mount_path = std::env::temp_dir().join(format!(
+                "rfuse3-cache-symlinks-{}-{cache_symlinks}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&mount_path).unwrap();
+            let target = Arc::new(std::sync::Mutex::new("target-a"));
+            let readlinks = Arc::new(AtomicUsize::new(0));
+            let fs = SymlinkFs {
+                target: target.clone(),
+                readlinks: readlinks.clone(),
+            };
+
+            let mut mount_options = MountOptions::default();
+            mount_options.cache_symlinks(cache_symlinks);
+            let session = Session::new(mount_options);
+            let notify = session.get_notify();
+            let handle = match session.mount(fs, &mount_path).await {
+                Ok(handle) => handle,
+                Err(err) => {
+                    std::fs::remove_dir(&mount_path).unwrap();
+                    eprintln!("skip test_cache_symlinks: {err}");
+                    return;
+                }
+            };
+
+            // The syscalls block until the session answers, so they can't run on the
+            // runtime's own threads.
+            let link = mount_path.join("link");
+            let read_link = || {
+                let link = link.clone();
+                async move {
+                    tokio::task::spawn_blocking(move || std::fs::read_link(link))
+                        .await
+                        .unwrap()
+                }
+            };
+
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                results.push(read_link().await);
+            }
+            let readlinks_before_change = readlinks.load(Ordering::SeqCst);
+
+            *target.lock().unwrap() = "target-b";
+            let after_change = read_link().await;
+
+            // The notification is written to the device asynchronously, so wait for the
+            // kernel to act on it.
+            notify.invalid_inode(SymlinkFs::LINK_INO, 0, 0).await;
+            let mut after_invalidation = read_link().await;
+            for _ in 0..100 {
+                if after_invalidation.as_ref().ok() == Some(&PathBuf::from("target-b")) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                after_invalidation = read_link().await;
+            }
+
+            handle.unmount().await.unwrap();
+            std::fs::remove_dir(&mount_path).unwrap();
+
+            for res in results {
+                assert_eq!(res.unwrap(), PathBuf::from("target-a"));
+            }
+            assert_eq!(after_invalidation.unwrap(), PathBuf::from("target-b"));
+            if cache_symlinks {
+                // The kernel answers from its cache until the inode is invalidated.
+                assert_eq!(readlinks_before_change, 1);
+                assert_eq!(after_change.unwrap(), PathBuf::from("target-a"));
+            } else {
+                assert_eq!(readlinks_before_change, 2);
+                assert_eq!(after_change.unwrap(), PathBuf::from("target-b"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unprivileged_mount_without_fusermount() {
+        let mount_path =
+            std::env::temp_dir().join(format!("rfuse3-no-fusermount-{}", std::process::id()));
+        std::fs::create_dir_all(&mount_path).unwrap();
+        let empty_path = mount_path.join("empty-path");
+        std::fs::create_dir_all(&empty_path).unwrap();
+
+        // Hide fusermount3, whether or not it is installed.
+        crate::FUSERMOUNT3_SEARCH_PATH.set(Some(empty_path.into_os_string()));
+        let mut mount_options = MountOptions::default();
+        mount_options.nonempty(true);
+        let res = Session::new(mount_options)
+            .mount_with_unprivileged(EmptyFs, &mount_path)
+            .await;
+        crate::FUSERMOUNT3_SEARCH_PATH.set(None);
+        std::fs::remove_dir_all(&mount_path).unwrap();
+
+        let Err(err) = res else {
+            panic!("mount without fusermount3 should fail");
+        };
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(
+            err.to_string()
+                .contains("fusermount3 is not installed or not in PATH"),
+            "{err}"
+        );
+    }
+}
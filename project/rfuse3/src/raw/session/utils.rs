@@ -7,6 +7,7 @@ use futures_util::sink::{Sink, SinkExt};
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::io::Result as IoResult;
+use std::os::fd::AsFd;
 use std::pin::pin;
 use tracing::Span;
 
@@ -19,6 +20,8 @@ use tracing::Instrument;
 use crate::helper::*;
 use crate::raw::abi::*;
 use crate::raw::buffer_pool::AlignedBuffer;
+use crate::raw::connection::FuseConnection;
+use crate::raw::reply::ReplyRead;
 use crate::raw::request::Request;
 use crate::Errno;
 
@@ -77,6 +80,90 @@ where
     let _ = pin!(sender).send(Either::Left(data)).await;
 }
 
+/// Turn a read reply into its data, or send it right away by splicing the data into the FUSE
+/// device when `connection` supports it. Returns `Ok(None)` when the reply has been sent.
+pub(super) async fn read_reply_data(
+    reply: ReplyRead,
+    size: u32,
+    unique: u64,
+    connection: Option<&FuseConnection>,
+) -> Result<Option<Bytes>, Errno> {
+    let (fd, offset, len) = match reply {
+        ReplyRead::Data(data) => return Ok(Some(data)),
+        ReplyRead::Splice { fd, offset, len } => (fd, offset, len.min(size) as usize),
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Some(connection) = connection {
+        let out_header = fuse_out_header {
+            len: (FUSE_OUT_HEADER_SIZE + len) as u32,
+            error: 0,
+            unique,
+        };
+        let header = get_bincode_config()
+            .serialize(&out_header)
+            .expect("won't happened");
+
+        match connection
+            .splice_reply(&header, fd.as_fd(), offset, len)
+            .await
+        {
+            Ok(true) => return Ok(None),
+            Ok(false) => {}
+            Err(err) => {
+                tracing::error!(
+                    "splice read reply failed {}, request unique {}",
+                    err,
+                    unique
+                );
+
+                return Err(err.into());
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (unique, connection);
+
+    let mut buf = vec![0; len];
+    let n = nix::sys::uio::pread(fd.as_fd(), &mut buf, offset as _)
+        .map_err(|err| Errno::from(err as i32))?;
+    buf.truncate(n);
+
+    Ok(Some(Bytes::from(buf)))
+}
+
+/// Reply to the `FUSE_INIT` request `unique` with the first `size` bytes of `init_out`.
+pub(super) async fn write_init_out(
+    connection: &FuseConnection,
+    unique: u64,
+    init_out: &fuse_init_out,
+    size: usize,
+) -> IoResult<()> {
+    let out_header = fuse_out_header {
+        len: (FUSE_OUT_HEADER_SIZE + size) as u32,
+        error: 0,
+        unique,
+    };
+
+    let mut data = Vec::with_capacity(FUSE_OUT_HEADER_SIZE + FUSE_INIT_OUT_SIZE);
+
+    get_bincode_config()
+        .serialize_into(&mut data, &out_header)
+        .expect("won't happened");
+    get_bincode_config()
+        .serialize_into(&mut data, init_out)
+        .expect("won't happened");
+    data.truncate(FUSE_OUT_HEADER_SIZE + size);
+
+    if let Err(err) = connection.write_vectored::<_, Vec<u8>>(data, None).await.1 {
+        tracing::error!("write init out data to /dev/fuse failed {}", err);
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
 /// Spawn an async task with proper instrumentation
 #[inline]
 pub(super) fn spawn<F>(span: Span, fut: F)
@@ -17,6 +17,7 @@ use tokio::task;
 use tokio::task::JoinHandle;
 
 use crate::raw::abi::fuse_opcode;
+use crate::raw::connection::FuseConnection;
 use crate::raw::filesystem::Filesystem;
 use crate::raw::FuseData;
 
@@ -63,6 +64,7 @@ impl Drop for InflightGuard {
 pub(crate) struct DispatchCtx<FS: Filesystem + Send + Sync + 'static> {
     pub(crate) fs: Arc<FS>,
     pub(crate) resp: UnboundedSender<FuseData>,
+    pub(crate) connection: Option<Arc<FuseConnection>>,
     pub(crate) direct_io: bool,
     pub(crate) _inflight: Arc<AtomicUsize>,
     pub(crate) _inflight_notify: Arc<async_notify::Notify>,